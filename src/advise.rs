@@ -0,0 +1,198 @@
+//! Tells the kernel how local images are read while large files are downloaded.
+//!
+//! Downloading a large file reads the image sequentially, in reads that [`BatchedReads`] makes
+//! large. Once such a run of reads has gone on for a while, the part of the image ahead of it is
+//! requested in advance. Once it has gone on for very long, what it read is dropped from the page
+//! cache again, so that a single large download doesn't push everything else out of it.
+//!
+//! The range ahead is requested with `WILLNEED` rather than declaring the file sequential, which
+//! Linux applies to the whole file and all downloads sharing it.
+//!
+//! [`BatchedReads`]: crate::batch::BatchedReads
+
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+/// Reads smaller than this are taken to be metadata or the start of a download, and neither
+/// continue nor interrupt a run of sequential reads.
+const MIN_RUN_READ: usize = 64 * 1024;
+
+/// How long a run of sequential reads must be before the image ahead of it is requested.
+const READ_AHEAD_AFTER: u64 = 1024 * 1024;
+
+/// How far ahead of a run of sequential reads the image is requested.
+const READ_AHEAD: u64 = 4 * 1024 * 1024;
+
+/// How long a run of sequential reads must be before what it read is dropped from the page
+/// cache. Shorter downloads may well be repeated and are left cached.
+const DROP_BEHIND_AFTER: u64 = 64 * 1024 * 1024;
+
+/// The least amount of data dropped from the page cache at once.
+const DROP_BEHIND_STEP: u64 = 4 * 1024 * 1024;
+
+/// The hints given about parts of the image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Hint {
+    /// The range will be read soon.
+    WillNeed,
+    /// The range won't be read again soon.
+    DontNeed,
+}
+
+/// Something that takes hints about how ranges of it will be read. Hints are only advice, so
+/// failing to give them isn't an error.
+pub(crate) trait Advise {
+    fn advise(&self, hint: Hint, offset: u64, len: u64);
+}
+
+#[cfg(target_os = "linux")]
+impl Advise for std::fs::File {
+    fn advise(&self, hint: Hint, offset: u64, len: u64) {
+        use std::os::fd::AsRawFd;
+
+        let advice = match hint {
+            Hint::WillNeed => libc::POSIX_FADV_WILLNEED,
+            Hint::DontNeed => libc::POSIX_FADV_DONTNEED,
+        };
+        // SAFETY: The file descriptor is open for as long as `self` lives.
+        unsafe { libc::posix_fadvise(self.as_raw_fd(), offset as i64, len as i64, advice) };
+    }
+}
+
+/// A memory-mapped image, along with the file it maps.
+#[cfg(all(feature = "mmap", unix))]
+pub(crate) struct MappedFile {
+    map: io::Cursor<memmap2::Mmap>,
+    /// Takes the hints for the page cache, where the platform has them.
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    file: std::fs::File,
+}
+
+#[cfg(all(feature = "mmap", unix))]
+impl MappedFile {
+    pub(crate) fn new(map: memmap2::Mmap, file: std::fs::File) -> Self {
+        Self {
+            map: io::Cursor::new(map),
+            file,
+        }
+    }
+}
+
+#[cfg(all(feature = "mmap", unix))]
+impl Advise for MappedFile {
+    fn advise(&self, hint: Hint, offset: u64, len: u64) {
+        let map = self.map.get_ref();
+        let offset = offset.min(map.len() as u64) as usize;
+        let len = len.min((map.len() - offset) as u64) as usize;
+        match hint {
+            Hint::WillNeed => {
+                let _ = map.advise_range(memmap2::Advice::WillNeed, offset, len);
+            }
+            Hint::DontNeed => {
+                // SAFETY: The map is read-only and the image isn't modified while it is served,
+                // as promised to `Vfs::new_mmap`, so the pages dropped read back the same.
+                let _ = unsafe {
+                    map.unchecked_advise_range(memmap2::UncheckedAdvice::DontNeed, offset, len)
+                };
+                // That only unmaps the pages, the page cache holds on to them
+                #[cfg(target_os = "linux")]
+                self.file.advise(hint, offset as u64, len as u64);
+            }
+        }
+    }
+}
+
+#[cfg(all(feature = "mmap", unix))]
+impl Read for MappedFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.map.read(buf)
+    }
+}
+
+#[cfg(all(feature = "mmap", unix))]
+impl Seek for MappedFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.map.seek(pos)
+    }
+}
+
+/// A seekable stream that gives hints about long runs of sequential reads to what it reads.
+pub(crate) struct Advised<T> {
+    inner: T,
+    pos: u64,
+    /// Where the current run of sequential reads started and ended.
+    run: (u64, u64),
+    /// How far ahead of the run the image was requested.
+    advised_to: u64,
+    /// Up to where the run was dropped from the page cache.
+    dropped_to: u64,
+}
+
+impl<T: Advise> Advised<T> {
+    pub(crate) fn new(inner: T) -> Self {
+        Self {
+            inner,
+            pos: 0,
+            run: (u64::MAX, u64::MAX),
+            advised_to: 0,
+            dropped_to: 0,
+        }
+    }
+
+    /// Records a read of `n` bytes at the current position, giving the hints it calls for.
+    fn record_read(&mut self, n: usize) {
+        let end = self.pos + n as u64;
+        let (start, run_end) = self.run;
+        let start = if self.pos == run_end {
+            start
+        } else {
+            self.advised_to = self.pos;
+            self.dropped_to = self.pos;
+            self.pos
+        };
+        self.run = (start, end);
+
+        if end - start >= READ_AHEAD_AFTER && end + READ_AHEAD / 2 > self.advised_to {
+            let from = self.advised_to.max(end);
+            let to = end + READ_AHEAD;
+            self.inner.advise(Hint::WillNeed, from, to - from);
+            self.advised_to = to;
+        }
+        if end - start >= DROP_BEHIND_AFTER && end - self.dropped_to >= DROP_BEHIND_STEP {
+            // Pages the kernel was still adding to its lists when the previous step was dropped
+            // stay cached, so each step covers the one before again
+            let from = self.dropped_to.saturating_sub(DROP_BEHIND_STEP).max(start);
+            self.inner.advise(Hint::DontNeed, from, end - from);
+            self.dropped_to = end;
+        }
+    }
+}
+
+impl<T: Read + Advise> Read for Advised<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if buf.len() >= MIN_RUN_READ && n > 0 {
+            self.record_read(n);
+        }
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl<T: Write> Write for Advised<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<T: Seek> Seek for Advised<T> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.pos = self.inner.seek(pos)?;
+        Ok(self.pos)
+    }
+}
@@ -0,0 +1,164 @@
+//! Images in the Android sparse format that `img2simg` and the Android build produce.
+//!
+//! The disk is stored as a list of chunks that either hold data, repeat a 4-byte value, or are
+//! left out. The chunk headers are read when the image is opened and the chunks are then read in
+//! place, so the image never has to be expanded with `simg2img`.
+
+use crate::{
+    format::{invalid_data, unsupported},
+    image::Disk,
+};
+use std::io::{self, Read, Seek, SeekFrom};
+
+/// The magic bytes Android sparse images start with.
+const MAGIC: &[u8; 4] = b"\x3a\xff\x26\xed";
+
+const CHUNK_RAW: u16 = 0xcac1;
+const CHUNK_FILL: u16 = 0xcac2;
+const CHUNK_DONT_CARE: u16 = 0xcac3;
+const CHUNK_CRC32: u16 = 0xcac4;
+
+/// Where the contents of a chunk come from.
+#[derive(Debug, Clone, Copy)]
+enum Source {
+    /// The chunk is stored at the given offset in the image file.
+    Raw(u64),
+    /// The chunk repeats the given bytes.
+    Fill([u8; 4]),
+    /// The chunk was left out and reads as zeros.
+    Zero,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Chunk {
+    /// The offset in the disk of the first byte of the chunk.
+    offset: u64,
+    source: Source,
+}
+
+/// A seekable stream over the disk in an Android sparse image.
+pub(crate) struct AndroidSparse {
+    inner: Box<dyn Disk>,
+    chunks: Vec<Chunk>,
+    len: u64,
+    pos: u64,
+}
+
+impl AndroidSparse {
+    /// Reads the headers of all chunks of the sparse image on `inner`.
+    pub(crate) fn open(mut inner: Box<dyn Disk>) -> io::Result<Self> {
+        let mut header = [0u8; 28];
+        inner.seek(SeekFrom::Start(0))?;
+        inner.read_exact(&mut header)?;
+        if &header[..4] != MAGIC {
+            return Err(invalid_data("not an Android sparse image"));
+        }
+        if u16_at(&header, 4) != 1 {
+            return Err(unsupported(
+                "the Android sparse image version isn't supported",
+            ));
+        }
+        let header_len = u64::from(u16_at(&header, 8));
+        let chunk_header_len = u64::from(u16_at(&header, 10));
+        let block_size = u64::from(u32_at(&header, 12));
+        let total_blocks = u64::from(u32_at(&header, 16));
+        let total_chunks = u32_at(&header, 20);
+        if header_len < 28 || chunk_header_len < 12 || block_size == 0 || block_size % 4 != 0 {
+            return Err(invalid_data("the Android sparse image header is malformed"));
+        }
+
+        let mut chunks = Vec::with_capacity(total_chunks.min(1 << 16) as usize);
+        let (mut at, mut offset) = (header_len, 0);
+        for _ in 0..total_chunks {
+            let mut chunk = [0u8; 12];
+            inner.seek(SeekFrom::Start(at))?;
+            inner.read_exact(&mut chunk)?;
+            let len = u64::from(u32_at(&chunk, 4)) * block_size;
+            let stored_len = u64::from(u32_at(&chunk, 8));
+            let data = at + chunk_header_len;
+            let source = match u16_at(&chunk, 0) {
+                CHUNK_RAW if stored_len == chunk_header_len + len => Source::Raw(data),
+                CHUNK_FILL if stored_len == chunk_header_len + 4 => {
+                    let mut value = [0u8; 4];
+                    inner.seek(SeekFrom::Start(data))?;
+                    inner.read_exact(&mut value)?;
+                    Source::Fill(value)
+                }
+                CHUNK_DONT_CARE => Source::Zero,
+                CHUNK_CRC32 => {
+                    at += stored_len;
+                    continue;
+                }
+                _ => return Err(invalid_data("an Android sparse image chunk is malformed")),
+            };
+            if len > 0 {
+                chunks.push(Chunk { offset, source });
+            }
+            at += stored_len;
+            offset = offset
+                .checked_add(len)
+                .ok_or_else(|| invalid_data("the Android sparse image chunks are too large"))?;
+        }
+        if offset != total_blocks * block_size {
+            return Err(invalid_data(
+                "the Android sparse image chunks don't add up to its size",
+            ));
+        }
+
+        Ok(Self {
+            inner,
+            chunks,
+            len: offset,
+            pos: 0,
+        })
+    }
+}
+
+impl Read for AndroidSparse {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.len || buf.is_empty() {
+            return Ok(0);
+        }
+        // The chunk that holds the byte at the current position
+        let index = self.chunks.partition_point(|c| c.offset <= self.pos) - 1;
+        let chunk = self.chunks[index];
+        let end = self.chunks.get(index + 1).map_or(self.len, |c| c.offset);
+        let in_chunk = self.pos - chunk.offset;
+        let n = (end - self.pos).min(buf.len() as u64) as usize;
+
+        match chunk.source {
+            Source::Raw(offset) => {
+                self.inner.seek(SeekFrom::Start(offset + in_chunk))?;
+                self.inner.read_exact(&mut buf[..n])?;
+            }
+            Source::Fill(value) => {
+                for (i, byte) in buf[..n].iter_mut().enumerate() {
+                    *byte = value[(in_chunk as usize + i) % 4];
+                }
+            }
+            Source::Zero => buf[..n].fill(0),
+        }
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for AndroidSparse {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.pos = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+        }
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "seek to a negative offset"))?;
+        Ok(self.pos)
+    }
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(bytes[offset..offset + 2].try_into().expect("2 bytes"))
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().expect("4 bytes"))
+}
@@ -0,0 +1,105 @@
+//! The public extension point for keeping a record of every storage operation.
+
+use crate::Operation;
+use std::{
+    fmt::Debug,
+    time::{Duration, Instant, SystemTime},
+};
+use unftp_core::storage::Error;
+
+/// Receives an [`AuditEvent`] for every storage operation once it is done, for access logs that
+/// compliance requires.
+///
+/// Pass the sink to [`VfsBuilder::audit_sink`](crate::VfsBuilder::audit_sink). Unlike
+/// [`TransferHooks`](crate::TransferHooks), which follow transfers as they go, a sink gets one
+/// event per operation, successful or not, with everything about it. Downloads are recorded once
+/// the last byte was read, or once they failed or the client stopped receiving.
+///
+/// The sink is called on the tasks that serve clients, so it should return quickly and without
+/// blocking, for instance by sending the event to a channel that a task writing the log reads.
+///
+/// # Example
+///
+/// ```rust
+/// use unftp_sbe_fatfs::{AuditEvent, AuditSink, VfsBuilder};
+///
+/// /// Writes an access log to standard error.
+/// #[derive(Debug)]
+/// struct AccessLog;
+///
+/// impl AuditSink for AccessLog {
+///     fn record(&self, event: &AuditEvent<'_>) {
+///         let result = match event.result {
+///             Ok(()) => "ok".to_string(),
+///             Err(e) => e.to_string(),
+///         };
+///         eprintln!("{} {:?} {result} {:?}", event.user, event.operation, event.bytes);
+///     }
+/// }
+///
+/// let vfs = VfsBuilder::new("path/to/fat/image.img")
+///     .audit_sink(AccessLog)
+///     .build();
+/// ```
+pub trait AuditSink: Debug + Send + Sync {
+    /// Records `event`, a storage operation that is done.
+    fn record(&self, event: &AuditEvent<'_>);
+}
+
+/// A storage operation that is done, as passed to an [`AuditSink`].
+#[derive(Debug, Clone, Copy)]
+#[non_exhaustive]
+pub struct AuditEvent<'a> {
+    /// When the operation started.
+    pub time: SystemTime,
+    /// How long the operation took, until the last byte was read for downloads.
+    pub duration: Duration,
+    /// The user who asked for the operation, by the name they display as.
+    pub user: &'a str,
+    /// The operation, with its paths as the client gave them.
+    pub operation: Operation<'a>,
+    /// Whether the operation succeeded, or the error it failed with.
+    pub result: Result<(), &'a Error>,
+    /// The bytes a download or upload transferred, or `None` for other operations and for
+    /// transfers that failed without telling how far they got.
+    pub bytes: Option<u64>,
+}
+
+/// When an operation started, for its [`AuditEvent`].
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Started {
+    time: SystemTime,
+    instant: Instant,
+}
+
+impl Started {
+    pub(crate) fn now() -> Self {
+        Self {
+            time: SystemTime::now(),
+            instant: Instant::now(),
+        }
+    }
+
+    /// Returns how long ago the operation started.
+    pub(crate) fn elapsed(&self) -> Duration {
+        self.instant.elapsed()
+    }
+
+    /// Returns the event of the operation started then, which is done now.
+    pub(crate) fn event<'a>(
+        &self,
+        user: &'a str,
+        operation: Operation<'a>,
+        result: Result<(), &'a Error>,
+        bytes: Option<u64>,
+    ) -> AuditEvent<'a> {
+        AuditEvent {
+            time: self.time,
+            duration: self.elapsed(),
+            user,
+            operation,
+            result,
+            bytes,
+        }
+    }
+}
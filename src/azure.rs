@@ -0,0 +1,167 @@
+//! Images stored as Azure Storage blobs, read with ranged GET requests.
+
+use crate::{
+    cloud::{UtcTime, encode_path, hmac_sha256, host_and_path},
+    http::{HttpSource, SignRequest},
+};
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use std::{fmt, io, time::SystemTime};
+
+/// The version of the Blob service REST API requests are made against.
+const API_VERSION: &str = "2021-08-06";
+
+/// The location of an image stored in Azure Blob Storage, and the credentials to read it with.
+///
+/// Requests are authorized with either the storage account's access key or a shared access
+/// signature (SAS). Without either, only blobs in containers with public access can be read.
+///
+/// # Example
+///
+/// ```rust
+/// use unftp_sbe_fatfs::AzureBlob;
+///
+/// let blob = AzureBlob::new("mystorageaccount", "images", "firmware/fat.img")
+///     .sas_token("sv=2021-08-06&ss=b&srt=o&sp=r&sig=...");
+/// ```
+#[derive(Clone)]
+pub struct AzureBlob {
+    account: String,
+    container: String,
+    blob: String,
+    endpoint: Option<String>,
+    credentials: Option<Credentials>,
+}
+
+#[derive(Clone)]
+enum Credentials {
+    AccessKey(String),
+    SasToken(String),
+}
+
+impl AzureBlob {
+    /// Refers to the given blob in a container of the given storage account.
+    pub fn new<A: Into<String>, C: Into<String>, B: Into<String>>(
+        account: A,
+        container: C,
+        blob: B,
+    ) -> Self {
+        Self {
+            account: account.into(),
+            container: container.into(),
+            blob: blob.into(),
+            endpoint: None,
+            credentials: None,
+        }
+    }
+
+    /// Sets the base URL of the blob service, for instance `http://127.0.0.1:10000/devstoreaccount1`
+    /// for the Azurite emulator. Defaults to `https://<account>.blob.core.windows.net`.
+    pub fn endpoint<S: Into<String>>(mut self, endpoint: S) -> Self {
+        self.endpoint = Some(endpoint.into());
+        self
+    }
+
+    /// Authorizes requests with a base64 encoded access key of the storage account.
+    pub fn access_key<S: Into<String>>(mut self, access_key: S) -> Self {
+        self.credentials = Some(Credentials::AccessKey(access_key.into()));
+        self
+    }
+
+    /// Authorizes requests with a shared access signature, given as the query string it is
+    /// usually handed out as.
+    pub fn sas_token<S: Into<String>>(mut self, sas_token: S) -> Self {
+        let sas_token = sas_token.into();
+        self.credentials = Some(Credentials::SasToken(
+            sas_token.trim_start_matches('?').to_string(),
+        ));
+        self
+    }
+
+    /// Returns the HTTP(S) URL of the blob.
+    fn url(&self) -> String {
+        let endpoint = match &self.endpoint {
+            Some(endpoint) => endpoint.trim_end_matches('/').to_string(),
+            None => format!("https://{}.blob.core.windows.net", self.account),
+        };
+        format!("{endpoint}/{}/{}", self.container, encode_path(&self.blob))
+    }
+}
+
+impl fmt::Debug for AzureBlob {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AzureBlob")
+            .field("account", &self.account)
+            .field("container", &self.container)
+            .field("blob", &self.blob)
+            .field("endpoint", &self.endpoint)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Creates the source that reads `blob` with ranged GET requests.
+pub(crate) fn source(blob: AzureBlob) -> HttpSource {
+    let url = blob.url();
+    match blob.credentials {
+        Some(Credentials::AccessKey(key)) => HttpSource::with_signer(
+            url,
+            Signer {
+                account: blob.account,
+                key,
+            },
+        ),
+        Some(Credentials::SasToken(token)) => HttpSource::new(format!("{url}?{token}")),
+        None => HttpSource::new(url),
+    }
+}
+
+/// Signs requests with a storage account access key, following the Shared Key scheme.
+struct Signer {
+    account: String,
+    key: String,
+}
+
+impl SignRequest for Signer {
+    fn sign(&self, url: &str, range: &str) -> io::Result<Vec<(&'static str, String)>> {
+        let key = BASE64
+            .decode(&self.key)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let date = http_date(SystemTime::now());
+        let (_, path) = host_and_path(url);
+
+        // GET, then the standard headers from Content-Encoding to If-Unmodified-Since, all
+        // empty, then the range, the x-ms-* headers and the resource
+        let string_to_sign = format!(
+            "GET\n\n\n\n\n\n\n\n\n\n\n{range}\nx-ms-date:{date}\nx-ms-version:{API_VERSION}\n/{}{path}",
+            self.account
+        );
+        let signature = BASE64.encode(hmac_sha256(&key, &string_to_sign));
+
+        Ok(vec![
+            ("x-ms-date", date),
+            ("x-ms-version", API_VERSION.to_string()),
+            (
+                "authorization",
+                format!("SharedKey {}:{signature}", self.account),
+            ),
+        ])
+    }
+}
+
+/// Formats `time` the way HTTP dates are written, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`.
+fn http_date(time: SystemTime) -> String {
+    const WEEKDAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let t = UtcTime::from(time);
+    format!(
+        "{}, {:02} {} {:04} {:02}:{:02}:{:02} GMT",
+        WEEKDAYS[t.weekday as usize],
+        t.day,
+        MONTHS[t.month as usize - 1],
+        t.year,
+        t.hour,
+        t.min,
+        t.sec
+    )
+}
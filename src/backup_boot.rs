@@ -0,0 +1,114 @@
+//! Mounts FAT32 filesystems whose boot sector is damaged from the backup of it they keep.
+//!
+//! FAT32 keeps a copy of its boot sector at sector 6 by convention. When the filesystem can't be
+//! mounted, that copy is looked for, and if it holds a boot sector the filesystem is mounted
+//! through a disk that has the copy in place of the boot sector.
+
+use crate::{
+    image::Disk,
+    partition::{SECTOR_SIZE, is_boot_sector},
+};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+/// The sector FAT32 filesystems keep the backup of their boot sector at.
+const BACKUP_SECTOR: u64 = 6;
+
+/// A disk whose boot sector is read from and written to the backup of it instead.
+pub(crate) struct BackupBoot {
+    inner: Box<dyn Disk>,
+    /// The size of the sectors, which the backup is found at a multiple of.
+    sector_len: u64,
+    pos: u64,
+}
+
+impl BackupBoot {
+    /// Returns `disk` with its boot sector replaced by the backup of it, or `None` if `disk`
+    /// doesn't keep a backup of a FAT32 boot sector.
+    pub(crate) fn open(mut disk: Box<dyn Disk>) -> io::Result<Option<Self>> {
+        let Some(sector_len) = find(&mut disk)? else {
+            return Ok(None);
+        };
+        disk.seek(SeekFrom::Start(0))?;
+        Ok(Some(Self {
+            inner: disk,
+            sector_len,
+            pos: 0,
+        }))
+    }
+
+    /// Returns the offset that the position of the stream maps to, along with how many bytes
+    /// from there on may be read or written at once.
+    fn mapped(&self, len: usize) -> (u64, usize) {
+        if self.pos < self.sector_len {
+            let within = ((self.sector_len - self.pos) as usize).min(len);
+            (self.pos + BACKUP_SECTOR * self.sector_len, within)
+        } else {
+            (self.pos, len)
+        }
+    }
+}
+
+/// Returns the size of the sectors of the FAT32 filesystem on `disk` if it keeps a backup of its
+/// boot sector, or `None` if it doesn't.
+pub(crate) fn find<T: Read + Seek>(disk: &mut T) -> io::Result<Option<u64>> {
+    // The size of sectors is only told by the boot sector, so each one is tried
+    for sector_len in [512, 1024, 2048, 4096] {
+        let mut sector = [0; SECTOR_SIZE as usize];
+        disk.seek(SeekFrom::Start(BACKUP_SECTOR * sector_len))?;
+        match disk.read_exact(&mut sector) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        }
+        let bytes_per_sector = u16::from_le_bytes([sector[11], sector[12]]) as u64;
+        let sectors_per_fat = u16::from_le_bytes([sector[22], sector[23]]);
+        let backup_sector = u16::from_le_bytes([sector[50], sector[51]]) as u64;
+        if is_boot_sector(&sector)
+            && bytes_per_sector == sector_len
+            && sectors_per_fat == 0
+            && backup_sector == BACKUP_SECTOR
+        {
+            return Ok(Some(sector_len));
+        }
+    }
+    Ok(None)
+}
+
+impl Read for BackupBoot {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let (offset, len) = self.mapped(buf.len());
+        self.inner.seek(SeekFrom::Start(offset))?;
+        let n = self.inner.read(&mut buf[..len])?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Write for BackupBoot {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let (offset, len) = self.mapped(buf.len());
+        self.inner.seek(SeekFrom::Start(offset))?;
+        let n = self.inner.write(&buf[..len])?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl Seek for BackupBoot {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.pos = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => {
+                let len = self.inner.seek(SeekFrom::End(0))?;
+                len.checked_add_signed(offset)
+            }
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+        }
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "seek to a negative offset"))?;
+        Ok(self.pos)
+    }
+}
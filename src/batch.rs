@@ -0,0 +1,122 @@
+//! Batches the cluster-sized reads fatfs makes while reading a file into larger reads.
+//!
+//! fatfs reads files a cluster at a time, looking up the next cluster in the FAT in between.
+//! Clusters are usually stored one after the other, so once reads continue where the previous
+//! one ended, the following clusters are read along with it in a single read of the image.
+
+use crate::{format::read_up_to, image::Disk};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+/// Reads smaller than this are taken to be metadata, such as FAT entries and directory entries,
+/// rather than file data. Clusters are at least a sector.
+const MIN_DATA_READ: usize = 512;
+
+/// The size of the first batch read when reads turn sequential.
+const MIN_BATCH: u64 = 64 * 1024;
+
+/// The size batches grow to while reads stay sequential.
+const MAX_BATCH: u64 = 1024 * 1024;
+
+/// A seekable stream that reads ahead in growing batches while data is read sequentially.
+pub(crate) struct BatchedReads {
+    inner: Box<dyn Disk>,
+    len: u64,
+    pos: u64,
+    /// The offset of the batch read last, and its contents.
+    batch: (u64, Vec<u8>),
+    /// Where the last data read ended, which is where a sequential read continues.
+    data_end: u64,
+    /// The size of the next batch.
+    batch_size: u64,
+}
+
+impl BatchedReads {
+    pub(crate) fn new(mut inner: Box<dyn Disk>) -> io::Result<Self> {
+        let len = inner.seek(SeekFrom::End(0))?;
+        Ok(Self {
+            inner,
+            len,
+            pos: 0,
+            batch: (0, Vec::new()),
+            data_end: u64::MAX,
+            batch_size: MIN_BATCH,
+        })
+    }
+
+    /// Returns the batched bytes from the current position on, if any.
+    fn batched(&self) -> &[u8] {
+        let (start, batch) = &self.batch;
+        match self.pos.checked_sub(*start) {
+            Some(offset) if offset < batch.len() as u64 => &batch[offset as usize..],
+            _ => &[],
+        }
+    }
+}
+
+impl Read for BatchedReads {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let is_data = buf.len() >= MIN_DATA_READ;
+        if self.batched().is_empty() && is_data && self.pos == self.data_end {
+            let size = self.batch_size.min(self.len.saturating_sub(self.pos));
+            let mut batch = vec![0; size as usize];
+            self.inner.seek(SeekFrom::Start(self.pos))?;
+            let n = read_up_to(&mut *self.inner, &mut batch)?;
+            batch.truncate(n);
+            self.batch = (self.pos, batch);
+            self.batch_size = (self.batch_size * 2).min(MAX_BATCH);
+        }
+
+        let batched = self.batched();
+        let n = if batched.is_empty() {
+            if is_data {
+                self.batch_size = MIN_BATCH;
+            }
+            self.inner.seek(SeekFrom::Start(self.pos))?;
+            self.inner.read(buf)?
+        } else {
+            let n = batched.len().min(buf.len());
+            buf[..n].copy_from_slice(&batched[..n]);
+            n
+        };
+        self.pos += n as u64;
+        if is_data {
+            self.data_end = self.pos;
+        }
+        Ok(n)
+    }
+}
+
+impl Write for BatchedReads {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.seek(SeekFrom::Start(self.pos))?;
+        let n = self.inner.write(buf)?;
+
+        let (start, batch) = &mut self.batch;
+        let end = self.pos + n as u64;
+        let from = self.pos.max(*start);
+        let to = end.min(*start + batch.len() as u64);
+        if from < to {
+            batch[(from - *start) as usize..(to - *start) as usize]
+                .copy_from_slice(&buf[(from - self.pos) as usize..(to - self.pos) as usize]);
+        }
+        self.pos = end;
+        self.len = self.len.max(end);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl Seek for BatchedReads {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.pos = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+        }
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "seek to a negative offset"))?;
+        Ok(self.pos)
+    }
+}
@@ -0,0 +1,155 @@
+//! Keeps recently read blocks of an image in memory.
+//!
+//! fatfs reads the FAT a few bytes at a time and directories an entry at a time, going back to
+//! the same sectors over and over. On slow images, such as remote or compressed ones, serving
+//! those from memory saves most of the cost of walking the filesystem.
+
+use crate::{format::read_up_to, image::Disk, memory::Reservation};
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::{self, Read, Seek, SeekFrom, Write},
+};
+
+/// The buffer put in front of image files, which holds the FAT and directory sectors fatfs read
+/// last so that it doesn't need a system call for every few bytes it reads.
+pub(crate) const FILE_BUFFER: BlockCacheConfig = BlockCacheConfig {
+    block_size: 4096,
+    blocks: 8,
+};
+
+/// The block size and capacity of a [`BlockCache`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct BlockCacheConfig {
+    pub(crate) block_size: u64,
+    pub(crate) blocks: usize,
+}
+
+/// A seekable stream that serves reads from the blocks of `inner` it read recently, evicting the
+/// least recently used block when full.
+///
+/// Reads of at least a block are passed through without caching, so that downloading large files
+/// doesn't evict the metadata cached along the way. Writes go through to `inner` right away and
+/// update the cached blocks they touch.
+pub(crate) struct BlockCache {
+    inner: Box<dyn Disk>,
+    config: BlockCacheConfig,
+    len: u64,
+    pos: u64,
+    /// Cached blocks by index, with the tick they were last used at.
+    blocks: HashMap<u64, (Vec<u8>, u64)>,
+    /// The indexes of the cached blocks by the tick they were last used at.
+    by_use: BTreeMap<u64, u64>,
+    tick: u64,
+    /// The memory the cached blocks take when the cache is full, if reserved.
+    _memory: Reservation,
+}
+
+impl BlockCache {
+    /// Caches the blocks of `inner`, holding `memory` for as long as the cache is around.
+    pub(crate) fn new(
+        mut inner: Box<dyn Disk>,
+        config: BlockCacheConfig,
+        memory: Reservation,
+    ) -> io::Result<Self> {
+        let len = inner.seek(SeekFrom::End(0))?;
+        Ok(Self {
+            inner,
+            config,
+            len,
+            pos: 0,
+            blocks: HashMap::new(),
+            by_use: BTreeMap::new(),
+            tick: 0,
+            _memory: memory,
+        })
+    }
+
+    /// Returns the block with the given index, reading it unless it's cached.
+    fn block(&mut self, index: u64) -> io::Result<&[u8]> {
+        self.tick += 1;
+        if let Some((_, used)) = self.blocks.get_mut(&index) {
+            self.by_use.remove(used);
+            *used = self.tick;
+        } else {
+            let start = index * self.config.block_size;
+            let mut data = vec![0; (self.len - start).min(self.config.block_size) as usize];
+            self.inner.seek(SeekFrom::Start(start))?;
+            let n = read_up_to(&mut *self.inner, &mut data)?;
+            data.truncate(n);
+            if self.blocks.len() >= self.config.blocks
+                && let Some((_, evicted)) = self.by_use.pop_first()
+            {
+                self.blocks.remove(&evicted);
+            }
+            self.blocks.insert(index, (data, self.tick));
+        }
+        self.by_use.insert(self.tick, index);
+        Ok(&self.blocks[&index].0)
+    }
+}
+
+impl Read for BlockCache {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let max = self.len.saturating_sub(self.pos).min(buf.len() as u64) as usize;
+        if max == 0 {
+            return Ok(0);
+        }
+        let block_size = self.config.block_size;
+        let index = self.pos / block_size;
+        let start = (self.pos % block_size) as usize;
+
+        let n = if max as u64 >= block_size && !self.blocks.contains_key(&index) {
+            self.inner.seek(SeekFrom::Start(self.pos))?;
+            self.inner.read(&mut buf[..max])?
+        } else {
+            let block = self.block(index)?;
+            let n = block.len().saturating_sub(start).min(max);
+            buf[..n].copy_from_slice(&block[start..start + n]);
+            n
+        };
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Write for BlockCache {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.seek(SeekFrom::Start(self.pos))?;
+        let n = self.inner.write(buf)?;
+
+        let block_size = self.config.block_size;
+        let end = self.pos + n as u64;
+        for index in self.pos / block_size..end.div_ceil(block_size) {
+            if let Some((block, _)) = self.blocks.get_mut(&index) {
+                let block_start = index * block_size;
+                let from = self.pos.max(block_start);
+                let to = end.min(block_start + block.len() as u64);
+                if from < to {
+                    block[(from - block_start) as usize..(to - block_start) as usize]
+                        .copy_from_slice(
+                            &buf[(from - self.pos) as usize..(to - self.pos) as usize],
+                        );
+                }
+            }
+        }
+        self.pos = end;
+        self.len = self.len.max(end);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl Seek for BlockCache {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.pos = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+        }
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "seek to a negative offset"))?;
+        Ok(self.pos)
+    }
+}
@@ -0,0 +1,73 @@
+//! Virtual disks stored as a table of fixed-size blocks, each of which is either stored somewhere
+//! in the image file or left out because it is all zeros.
+
+use crate::image::Disk;
+use std::io::{self, Read, Seek, SeekFrom};
+
+/// Where the contents of a block of a virtual disk come from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Block {
+    /// The block reads as zeros.
+    Zero,
+    /// The block is stored at the given offset in the image file.
+    Stored(u64),
+}
+
+/// A seekable stream over a virtual disk made up of blocks.
+pub(crate) struct BlockMap {
+    inner: Box<dyn Disk>,
+    block_size: u64,
+    /// The size of the virtual disk, which may end part way into the last block.
+    len: u64,
+    blocks: Vec<Block>,
+    pos: u64,
+}
+
+impl BlockMap {
+    /// Serves a disk of `len` bytes from the blocks of `block_size` bytes in `blocks`. Blocks
+    /// missing from the end of the list read as zeros.
+    pub(crate) fn new(inner: Box<dyn Disk>, block_size: u64, len: u64, blocks: Vec<Block>) -> Self {
+        Self {
+            inner,
+            block_size,
+            len,
+            blocks,
+            pos: 0,
+        }
+    }
+}
+
+impl Read for BlockMap {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let in_block = self.pos % self.block_size;
+        let n = (self.block_size - in_block)
+            .min(self.len.saturating_sub(self.pos))
+            .min(buf.len() as u64) as usize;
+        if n == 0 {
+            return Ok(0);
+        }
+
+        let index = (self.pos / self.block_size) as usize;
+        match self.blocks.get(index).copied().unwrap_or(Block::Zero) {
+            Block::Zero => buf[..n].fill(0),
+            Block::Stored(offset) => {
+                self.inner.seek(SeekFrom::Start(offset + in_block))?;
+                self.inner.read_exact(&mut buf[..n])?;
+            }
+        }
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for BlockMap {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.pos = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+        }
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "seek to a negative offset"))?;
+        Ok(self.pos)
+    }
+}
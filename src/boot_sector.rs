@@ -0,0 +1,143 @@
+//! The geometry of filesystems as their boot sectors tell it, as returned by
+//! [`Vfs::boot_sector_info`](crate::Vfs::boot_sector_info).
+
+use fatfs::FatType;
+use std::fmt;
+
+/// The FAT variant a filesystem is formatted with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum FatVariant {
+    /// FAT12, found on floppy disks and small volumes of up to 4084 clusters.
+    Fat12,
+    /// FAT16, for volumes of up to 65524 clusters.
+    Fat16,
+    /// FAT32, for larger volumes, which keeps its root directory in clusters like others.
+    Fat32,
+    /// exFAT, which is only served with the `exfat` feature.
+    ExFat,
+}
+
+impl From<FatType> for FatVariant {
+    fn from(fat_type: FatType) -> Self {
+        match fat_type {
+            FatType::Fat12 => FatVariant::Fat12,
+            FatType::Fat16 => FatVariant::Fat16,
+            FatType::Fat32 => FatVariant::Fat32,
+        }
+    }
+}
+
+/// Tells the variant the way it is usually written, such as `FAT32`.
+impl fmt::Display for FatVariant {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            FatVariant::Fat12 => "FAT12",
+            FatVariant::Fat16 => "FAT16",
+            FatVariant::Fat32 => "FAT32",
+            FatVariant::ExFat => "exFAT",
+        })
+    }
+}
+
+/// The geometry of a filesystem as its boot sector tells it, as returned by
+/// [`Vfs::boot_sector_info`](crate::Vfs::boot_sector_info).
+///
+/// # Example
+///
+/// ```rust,no_run
+/// use unftp_sbe_fatfs::Vfs;
+///
+/// # async fn run() -> Result<(), Box<dyn std::error::Error>> {
+/// let info = Vfs::new("path/to/fat/image.img").boot_sector_info().await?;
+/// println!(
+///     "{}, {} sectors of {} bytes, {} bytes per cluster",
+///     info.variant,
+///     info.total_sectors,
+///     info.bytes_per_sector,
+///     info.bytes_per_sector * info.sectors_per_cluster
+/// );
+/// if let Some(serial) = info.serial_number {
+///     println!("serial number {:04X}-{:04X}", serial >> 16, serial & 0xFFFF);
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct BootSectorInfo {
+    /// The FAT variant the filesystem is formatted with.
+    pub variant: FatVariant,
+    /// The size of sectors, in bytes.
+    pub bytes_per_sector: u32,
+    /// The size of clusters, in sectors.
+    pub sectors_per_cluster: u32,
+    /// The sectors before the first FAT, which hold the boot sector among others.
+    pub reserved_sectors: u32,
+    /// The number of copies of the FAT.
+    pub fats: u8,
+    /// The size of the filesystem, in sectors.
+    pub total_sectors: u64,
+    /// The serial number the filesystem was given when formatted, which tells images apart
+    /// where labels don't, shown by Windows as two groups of four hexadecimal digits. `None` for
+    /// FAT12 and FAT16 filesystems formatted by DOS before 4.0, which don't keep one.
+    pub serial_number: Option<u32>,
+    /// The name of the system that formatted the filesystem, such as `MSDOS5.0` or `mkfs.fat`,
+    /// without its padding.
+    pub oem_name: String,
+}
+
+impl BootSectorInfo {
+    /// Reads the geometry of the FAT12, FAT16 or FAT32 filesystem of `fat_type` from its boot
+    /// sector `sector`, which fatfs checked when mounting it.
+    pub(crate) fn fat(sector: &[u8; 512], fat_type: FatType) -> Self {
+        let u16_at = |offset: usize| u16::from_le_bytes([sector[offset], sector[offset + 1]]);
+        let u32_at =
+            |offset: usize| u32::from_le_bytes(sector[offset..offset + 4].try_into().unwrap());
+        // The extended boot record comes after the fields only FAT32 has
+        let extended = match fat_type {
+            FatType::Fat32 => 64,
+            _ => 36,
+        };
+        Self {
+            variant: fat_type.into(),
+            bytes_per_sector: u16_at(11) as u32,
+            sectors_per_cluster: sector[13] as u32,
+            reserved_sectors: u16_at(14) as u32,
+            fats: sector[16],
+            total_sectors: match u16_at(19) {
+                0 => u32_at(32) as u64,
+                sectors => sectors as u64,
+            },
+            serial_number: matches!(sector[extended + 2], 0x28 | 0x29)
+                .then(|| u32_at(extended + 3)),
+            oem_name: oem_name(sector),
+        }
+    }
+
+    /// Reads the geometry of the exFAT filesystem whose boot sector is `sector`, which keeps
+    /// the sizes of sectors and clusters as powers of two, and the sectors before its FAT as the
+    /// offset of the FAT.
+    #[cfg(feature = "exfat")]
+    pub(crate) fn exfat(sector: &[u8; 512]) -> Self {
+        let bytes_per_sector_shift = sector[108].min(12);
+        let sectors_per_cluster_shift = sector[109].min(25);
+        Self {
+            variant: FatVariant::ExFat,
+            bytes_per_sector: 1 << bytes_per_sector_shift,
+            sectors_per_cluster: 1 << sectors_per_cluster_shift,
+            reserved_sectors: u32::from_le_bytes(sector[80..84].try_into().unwrap()),
+            fats: sector[110],
+            total_sectors: u64::from_le_bytes(sector[72..80].try_into().unwrap()),
+            serial_number: Some(u32::from_le_bytes(sector[100..104].try_into().unwrap())),
+            oem_name: oem_name(sector),
+        }
+    }
+}
+
+/// Returns the OEM name in the boot sector `sector`, without the spaces it is padded with.
+fn oem_name(sector: &[u8; 512]) -> String {
+    String::from_utf8_lossy(&sector[3..11])
+        .trim_end()
+        .to_string()
+}
@@ -0,0 +1,69 @@
+//! Reuses the buffers files are read into for downloads.
+//!
+//! Every download reads its file in chunks, each into a buffer of its own that is handed to the
+//! client's data connection. With many concurrent transfers, allocating and freeing those
+//! buffers for every chunk keeps the allocator busy, so buffers are returned here once they were
+//! sent and handed out again for the next chunk.
+
+use bytes::{Bytes, BytesMut};
+use std::sync::Mutex;
+
+/// The size of the buffers of a [`BufferPool`], and how many it keeps around.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct BufferPoolConfig {
+    pub(crate) size: usize,
+    pub(crate) count: usize,
+}
+
+impl Default for BufferPoolConfig {
+    fn default() -> Self {
+        Self {
+            size: crate::download::CHUNK_SIZE,
+            count: 64,
+        }
+    }
+}
+
+/// A pool of equally sized buffers that keeps up to a fixed number of them for reuse.
+#[derive(Debug)]
+pub(crate) struct BufferPool {
+    config: BufferPoolConfig,
+    free: Mutex<Vec<BytesMut>>,
+}
+
+impl BufferPool {
+    pub(crate) fn new(config: BufferPoolConfig) -> Self {
+        Self {
+            config,
+            free: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Returns the size of the buffers.
+    pub(crate) fn size(&self) -> usize {
+        self.config.size
+    }
+
+    /// Takes a buffer from the pool, or allocates one if none are free.
+    pub(crate) fn take(&self) -> BytesMut {
+        let free = self.free.lock().ok().and_then(|mut free| free.pop());
+        free.unwrap_or_else(|| BytesMut::zeroed(self.config.size))
+    }
+
+    /// Returns a buffer to the pool once nothing refers to it anymore, unless the pool is full.
+    pub(crate) fn give_back(&self, buffer: Bytes) {
+        let Ok(mut buffer) = buffer.try_into_mut() else {
+            return;
+        };
+        if buffer.capacity() != self.config.size {
+            return;
+        }
+        // Buffers handed out are always full size, reads fill them from the start
+        buffer.resize(self.config.size, 0);
+        if let Ok(mut free) = self.free.lock()
+            && free.len() < self.config.count
+        {
+            free.push(buffer);
+        }
+    }
+}
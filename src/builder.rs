@@ -0,0 +1,730 @@
+//! Configures [`Vfs`] instances beyond what its constructors offer.
+
+use crate::{
+    AuditSink, CorruptionPolicy, DirtyVolume, MemoryBudget, Mode, PartitionSelect, PathDecoding,
+    PathRedaction, TransferHooks, UserPolicy, Vfs,
+    block_cache::BlockCacheConfig,
+    buffer_pool::BufferPoolConfig,
+    image::{Image, Memory, Static},
+};
+use fatfs::{FsOptions, OemCpConverter, TimeProvider};
+use std::{path::Path, sync::Arc};
+
+/// Builds a [`Vfs`] with non-default settings.
+///
+/// Besides the crate's own settings this exposes the mount options of the underlying fatfs
+/// library.
+///
+/// # Example
+///
+/// ```rust
+/// use unftp_sbe_fatfs::{Mode, VfsBuilder};
+///
+/// let vfs = VfsBuilder::new("path/to/fat/image.img")
+///     .mode(Mode::ReadWrite)
+///     .update_accessed_date(true)
+///     .build();
+/// ```
+#[derive(Debug, Clone)]
+pub struct VfsBuilder {
+    image: Image,
+    partition: PartitionSelect,
+    mode: Mode,
+    fs_options: FsConfig,
+    policy: Option<Arc<dyn UserPolicy>>,
+    hooks: Option<Arc<dyn TransferHooks>>,
+    audit: Option<Arc<dyn AuditSink>>,
+    #[cfg(feature = "http")]
+    block_size: Option<u64>,
+    #[cfg(feature = "http")]
+    disk_cache: Option<crate::DiskCache>,
+    #[cfg(all(feature = "direct-io", target_os = "linux"))]
+    direct_io: bool,
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    io_uring: bool,
+}
+
+impl VfsBuilder {
+    /// Starts building a virtual file system for the FAT image file at the given path.
+    pub fn new<P: AsRef<Path>>(img_path: P) -> Self {
+        Self::with_image(Image::File(img_path.as_ref().to_path_buf()))
+    }
+
+    /// Starts building a virtual file system for a FAT image split into several files. See
+    /// [`Vfs::new_split`].
+    pub fn new_split<I, P>(parts: I) -> Self
+    where
+        I: IntoIterator<Item = P>,
+        P: AsRef<Path>,
+    {
+        Self::with_image(Image::split(parts))
+    }
+
+    /// Starts building a virtual file system for a FAT image held in memory.
+    pub fn from_bytes(bytes: Vec<u8>) -> Self {
+        Self::with_image(Image::Memory(Memory::new(bytes)))
+    }
+
+    /// Starts building a virtual file system for a FAT image compiled into the binary. See
+    /// [`Vfs::from_static`].
+    pub fn from_static(bytes: &'static [u8]) -> Self {
+        Self::with_image(Image::Static(Static(bytes)))
+    }
+
+    /// Starts building a virtual file system for a FAT image read from a custom source. See
+    /// [`Vfs::from_source`].
+    pub fn from_source(source: impl crate::ImageSource + 'static) -> Self {
+        Self::with_image(Image::Source(Arc::new(source)))
+    }
+
+    /// Starts building a virtual file system for a FAT image on a web server. See
+    /// [`Vfs::from_url`].
+    #[cfg(feature = "http")]
+    pub fn from_url<S: Into<String>>(url: S) -> Self {
+        Self::with_image(Image::http(url.into()))
+    }
+
+    /// Starts building a virtual file system for a FAT image stored in S3. See
+    /// [`Vfs::from_s3`].
+    #[cfg(feature = "s3")]
+    pub fn from_s3(object: crate::S3Object) -> Self {
+        Self::with_image(Image::s3(object))
+    }
+
+    /// Starts building a virtual file system for a FAT image stored in Azure Blob Storage. See
+    /// [`Vfs::from_azure`].
+    #[cfg(feature = "azure")]
+    pub fn from_azure(blob: crate::AzureBlob) -> Self {
+        Self::with_image(Image::azure(blob))
+    }
+
+    /// Starts building a virtual file system for a FAT image stored in Google Cloud Storage.
+    /// See [`Vfs::from_gcs`].
+    #[cfg(feature = "gcs")]
+    pub fn from_gcs(object: crate::GcsObject) -> Self {
+        Self::with_image(Image::gcs(object))
+    }
+
+    fn with_image(image: Image) -> Self {
+        Self {
+            image,
+            partition: PartitionSelect::default(),
+            mode: Mode::default(),
+            fs_options: FsConfig::default(),
+            policy: None,
+            hooks: None,
+            audit: None,
+            #[cfg(feature = "http")]
+            block_size: None,
+            #[cfg(feature = "http")]
+            disk_cache: None,
+            #[cfg(all(feature = "direct-io", target_os = "linux"))]
+            direct_io: false,
+            #[cfg(all(feature = "io-uring", target_os = "linux"))]
+            io_uring: false,
+        }
+    }
+
+    /// Selects the partition that holds the FAT filesystem. Defaults to
+    /// [`PartitionSelect::Auto`], which serves bare filesystems whole and otherwise finds the
+    /// first FAT partition.
+    pub fn partition(mut self, partition: PartitionSelect) -> Self {
+        self.partition = partition;
+        self
+    }
+
+    /// Sets whether clients may modify the image. Defaults to [`Mode::ReadOnly`].
+    pub fn mode(mut self, mode: Mode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Sets whether fatfs updates the accessed date of directory entries when files are read or
+    /// written. Defaults to false.
+    ///
+    /// Note that this turns downloads into writes, so it only has an effect in
+    /// [`Mode::ReadWrite`].
+    pub fn update_accessed_date(mut self, enabled: bool) -> Self {
+        self.fs_options.update_accessed_date = enabled;
+        self
+    }
+
+    /// Sets the time provider fatfs uses to timestamp created and modified entries. Defaults to
+    /// the local time.
+    pub fn time_provider(mut self, time_provider: &'static (dyn TimeProvider + Sync)) -> Self {
+        self.fs_options.time_provider = Some(time_provider);
+        self
+    }
+
+    /// Sets the converter fatfs uses between the OEM code page of short file names and Unicode.
+    /// Defaults to a lossy converter that only handles ASCII.
+    pub fn oem_cp_converter(
+        mut self,
+        oem_cp_converter: &'static (dyn OemCpConverter + Sync),
+    ) -> Self {
+        self.fs_options.oem_cp_converter = Some(oem_cp_converter);
+        self
+    }
+
+    /// Keeps the `blocks` most recently read blocks of `block_size` bytes of the image in memory.
+    /// Off by default.
+    ///
+    /// This serves the FAT sectors and directory clusters fatfs keeps going back to from memory,
+    /// which pays off for images that are slow to read, such as remote or compressed ones. Reads
+    /// of a block or more, as when downloading files, bypass the cache. Writes go through to the
+    /// image right away.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `block_size` or `blocks` is zero.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use unftp_sbe_fatfs::VfsBuilder;
+    ///
+    /// // Cache up to 4 MiB in blocks of 4 KiB
+    /// let vfs = VfsBuilder::new("path/to/fat/image.img")
+    ///     .block_cache(4096, 1024)
+    ///     .build();
+    /// ```
+    pub fn block_cache(mut self, block_size: u64, blocks: usize) -> Self {
+        assert!(block_size > 0, "block size must not be zero");
+        assert!(blocks > 0, "block cache capacity must not be zero");
+        self.fs_options.block_cache = Some(BlockCacheConfig { block_size, blocks });
+        self
+    }
+
+    /// Sets whether the File Allocation Table is kept in memory. Defaults to false.
+    ///
+    /// The table is read in full the first time a cluster chain is followed, and read again when
+    /// the image is reopened, for instance because it was replaced. This spares the seeks back to
+    /// the table while reading large files, at the cost of memory for each [`Vfs`], up to
+    /// 16 MiB. Tables larger than that aren't cached. Without it, the 64 KiB of the table looked
+    /// up last are kept, which is enough for reading files and resuming downloads without a read
+    /// of the image for every cluster.
+    pub fn cache_fat(mut self, enabled: bool) -> Self {
+        self.fs_options.cache_fat = enabled;
+        self
+    }
+
+    /// Copies the whole image into memory when it is first opened, provided it is no larger than
+    /// `max_size` bytes. Off by default.
+    ///
+    /// After that the image isn't read again until it is replaced, so listings and downloads no
+    /// longer depend on how fast it can be read. Compressed images and virtual disks are copied
+    /// decoded. Call [`Vfs::warm_up`] to do this at startup rather than when the first client
+    /// connects.
+    ///
+    /// Images larger than `max_size` are read from where they are as usual. Images served in
+    /// [`Mode::ReadWrite`] are never copied, so that writes go to the image.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use unftp_sbe_fatfs::VfsBuilder;
+    ///
+    /// // Serve an EFI system partition from memory
+    /// let vfs = VfsBuilder::new("path/to/esp.img")
+    ///     .load_into_memory(256 * 1024 * 1024)
+    ///     .build();
+    /// ```
+    pub fn load_into_memory(mut self, max_size: u64) -> Self {
+        self.fs_options.preload = Some(max_size);
+        self
+    }
+
+    /// Sets the size of the buffers files are read into for downloads, and how many of them are
+    /// kept for reuse once sent. Defaults to 64 buffers of 64 KiB.
+    ///
+    /// Each download reads a few buffers ahead of the client. Buffers go back to a pool shared by
+    /// all downloads once they were sent, so that busy servers don't allocate a buffer for every
+    /// chunk of every transfer. Buffers beyond `count` are allocated as needed and freed after
+    /// use, and a `count` of zero disables reuse.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `size` isn't a power of two of at least 512 bytes. That keeps the chunks read
+    /// aligned to clusters.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use unftp_sbe_fatfs::VfsBuilder;
+    ///
+    /// // Read downloads in chunks of 256 KiB, keeping up to 32 MiB of buffers around
+    /// let vfs = VfsBuilder::new("path/to/fat/image.img")
+    ///     .transfer_buffers(256 * 1024, 128)
+    ///     .build();
+    /// ```
+    pub fn transfer_buffers(mut self, size: usize, count: usize) -> Self {
+        assert!(
+            size >= 512 && size.is_power_of_two(),
+            "transfer buffer size must be a power of two of at least 512 bytes"
+        );
+        self.fs_options.buffers = BufferPoolConfig { size, count };
+        self
+    }
+
+    /// Sets how many times the image may be opened at once. Defaults to 8.
+    ///
+    /// Downloads open the image for themselves unless they can reuse a filesystem an earlier one
+    /// opened, which means reading the partition table and the boot sector and, for remote images,
+    /// fetching them. When many clients connect at once, opens beyond the limit wait for the
+    /// earlier ones to finish rather than all reading the image at the same time.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max` is zero.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use unftp_sbe_fatfs::VfsBuilder;
+    ///
+    /// let vfs = VfsBuilder::new("path/to/fat/image.img")
+    ///     .max_concurrent_opens(2)
+    ///     .build();
+    /// ```
+    pub fn max_concurrent_opens(mut self, max: usize) -> Self {
+        assert!(
+            max > 0,
+            "the maximum number of concurrent opens must not be zero"
+        );
+        self.fs_options.max_opens = Some(max);
+        self
+    }
+
+    /// Limits how many downloads and uploads may be in progress at once. Unlimited by default.
+    ///
+    /// Transfers beyond the limit are refused with a transient error, which FTP clients are told
+    /// with a 450 reply, rather than all slowing down. That keeps images on slow storage from being
+    /// read in ever smaller pieces as more clients connect. The limit covers all clones of the
+    /// [`Vfs`], and so all connections to it.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max` is zero.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use unftp_sbe_fatfs::VfsBuilder;
+    ///
+    /// // An SD card in a USB card reader can only serve a few clients at a time
+    /// let vfs = VfsBuilder::new("/dev/sdb1")
+    ///     .max_transfers(4)
+    ///     .build();
+    /// ```
+    pub fn max_transfers(mut self, max: usize) -> Self {
+        assert!(max > 0, "the maximum number of transfers must not be zero");
+        self.fs_options.max_transfers = Some(max);
+        self
+    }
+
+    /// Limits each download and upload to `bytes_per_second`. Unlimited by default.
+    ///
+    /// Transfers that a [`UserPolicy`] throttles go at the lower of both rates.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `bytes_per_second` is zero.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use unftp_sbe_fatfs::VfsBuilder;
+    ///
+    /// // No client may take more than 10 MiB/s
+    /// let vfs = VfsBuilder::new("path/to/fat/image.img")
+    ///     .max_transfer_rate(10 * 1024 * 1024)
+    ///     .build();
+    /// ```
+    pub fn max_transfer_rate(mut self, bytes_per_second: u64) -> Self {
+        assert!(bytes_per_second > 0, "the transfer rate must not be zero");
+        self.fs_options.transfer_rate = Some(bytes_per_second);
+        self
+    }
+
+    /// Limits all downloads and uploads together to `bytes_per_second`, shared between them.
+    /// Unlimited by default.
+    ///
+    /// This caps the bandwidth the server takes from a link it shares with other traffic,
+    /// however many clients are connected. The limit covers all clones of the [`Vfs`], and so all
+    /// connections to it, on top of any limit per transfer.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `bytes_per_second` is zero.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use unftp_sbe_fatfs::VfsBuilder;
+    ///
+    /// // Leave most of a gigabit link to production traffic
+    /// let vfs = VfsBuilder::new("path/to/fat/image.img")
+    ///     .max_total_rate(20 * 1024 * 1024)
+    ///     .build();
+    /// ```
+    pub fn max_total_rate(mut self, bytes_per_second: u64) -> Self {
+        assert!(
+            bytes_per_second > 0,
+            "the total transfer rate must not be zero"
+        );
+        self.fs_options.total_rate = Some(bytes_per_second);
+        self
+    }
+
+    /// Reserves the memory of caches and transfers from `budget`, which other file systems may
+    /// share. Unlimited by default. See [`MemoryBudget`] for what counts against it.
+    ///
+    /// When the budget is spent, caches aren't kept and the image is read from where it is
+    /// instead, and transfers wait for the memory of others to be given back before they start.
+    /// That keeps a server serving many large images to many clients from running out of memory,
+    /// at the cost of speed.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use unftp_sbe_fatfs::{MemoryBudget, VfsBuilder};
+    ///
+    /// static MEMORY: MemoryBudget = MemoryBudget::new(64 * 1024 * 1024);
+    ///
+    /// let vfs = VfsBuilder::new("path/to/fat/image.img")
+    ///     .block_cache(4096, 1024)
+    ///     .cache_fat(true)
+    ///     .memory_budget(&MEMORY)
+    ///     .build();
+    /// ```
+    pub fn memory_budget(mut self, budget: &'static MemoryBudget) -> Self {
+        self.fs_options.memory = Some(budget);
+        self
+    }
+
+    /// Has `policy` decide, for each operation, whether the user who asked for it may go ahead,
+    /// and how fast a download or upload may go. See [`UserPolicy`].
+    ///
+    /// The policy only applies to this [`Vfs`] and its clones, so servers can serve the same
+    /// image with different policies on different ports.
+    pub fn user_policy(mut self, policy: impl UserPolicy + 'static) -> Self {
+        self.policy = Some(Arc::new(policy));
+        self
+    }
+
+    /// Tells `hooks` about the downloads and uploads that start and complete, and about the
+    /// operations that fail. See [`TransferHooks`].
+    ///
+    /// Like a user policy the hooks only apply to this [`Vfs`] and its clones.
+    pub fn transfer_hooks(mut self, hooks: impl TransferHooks + 'static) -> Self {
+        self.hooks = Some(Arc::new(hooks));
+        self
+    }
+
+    /// Records every operation with `sink` once it is done, with the user, the paths, the result
+    /// and the bytes transferred. See [`AuditSink`].
+    ///
+    /// Like a user policy the sink only applies to this [`Vfs`] and its clones.
+    pub fn audit_sink(mut self, sink: impl AuditSink + 'static) -> Self {
+        self.audit = Some(Arc::new(sink));
+        self
+    }
+
+    /// Redacts the paths clients give wherever they are emitted: in the `tracing` spans and
+    /// logs of operations, and in OpenTelemetry spans with the `opentelemetry` feature. Defaults
+    /// to emitting paths as they are. See [`PathRedaction`].
+    ///
+    /// The causes of failures are logged with their paths redacted as well, while the errors
+    /// operations return keep them, such as [`FatError::NotFound`](crate::FatError::NotFound).
+    ///
+    /// Metrics carry no paths to begin with. User policies, transfer hooks, audit sinks and
+    /// [`Vfs::stats`] still get paths as they are, being up to the application.
+    pub fn redact_paths(mut self, redaction: PathRedaction) -> Self {
+        self.fs_options.redact_paths = Some(redaction);
+        self
+    }
+
+    /// Sets how the paths clients give are read when they aren't valid UTF-8. Defaults to
+    /// [`PathDecoding::Lossy`], with which such paths are not found. See [`PathDecoding`].
+    pub fn path_decoding(mut self, decoding: PathDecoding) -> Self {
+        self.fs_options.path_decoding = decoding;
+        self
+    }
+
+    /// Sets how operations deal with damage they find on the filesystem, such as cluster chains
+    /// that loop or end early. Defaults to [`CorruptionPolicy::Strict`], with which they fail.
+    /// See [`CorruptionPolicy`].
+    pub fn corruption_policy(mut self, policy: CorruptionPolicy) -> Self {
+        self.fs_options.corruption_policy = policy;
+        self
+    }
+
+    /// Compares the FAT with its copies when opening the image, logging a warning that tells
+    /// which entries differ, since a damaged FAT is otherwise trusted as it is. With
+    /// [`CorruptionPolicy::Paranoid`] an image whose FATs differ is refused instead. Defaults to
+    /// off.
+    ///
+    /// The FATs are read in whole, once, and again whenever a remote image turns out to have
+    /// been replaced. exFAT filesystems aren't compared.
+    pub fn verify_fat_copies(mut self, verify: bool) -> Self {
+        self.fs_options.verify_fat_copies = verify;
+        self
+    }
+
+    /// Sets what to do with a volume that wasn't cleanly unmounted, as told by the dirty flag it
+    /// is marked with, which is checked when the image is opened. Defaults to
+    /// [`DirtyVolume::Warn`]. See [`DirtyVolume`].
+    pub fn dirty_volume(mut self, handling: DirtyVolume) -> Self {
+        self.fs_options.dirty_volume = handling;
+        self
+    }
+
+    /// Looks for cross-linked files when opening the image, walking its whole directory tree and
+    /// FAT like [`Vfs::validate`](crate::Vfs::validate), and logs a warning whenever a file whose
+    /// cluster chain runs into that of another is downloaded, naming the other, as one of them
+    /// holds what was written to the other. Defaults to off.
+    ///
+    /// The walk is done once, and again whenever a remote image turns out to have been replaced.
+    /// exFAT filesystems aren't walked.
+    pub fn warn_cross_links(mut self, warn: bool) -> Self {
+        self.fs_options.warn_cross_links = warn;
+        self
+    }
+
+    /// Names the directories partitions are served under in [`PartitionSelect::All`] mode after
+    /// their volume labels, such as `/BOOT` and `/DATA` rather than `/p0` and `/p1`, so that
+    /// clients can tell they found the right card image. Partitions without a label, or whose
+    /// label another partition has too or that isn't a valid file name, keep their `pN` name.
+    /// Defaults to off.
+    ///
+    /// Every partition is mounted to read its label the first time the partitions are listed.
+    pub fn name_partitions_by_label(mut self, enabled: bool) -> Self {
+        self.fs_options.name_partitions_by_label = enabled;
+        self
+    }
+
+    /// Keeps the sizes [`Vfs::dir_size`](crate::Vfs::dir_size) finds, so that asking for the
+    /// size of the same directory again doesn't walk the tree below it again. Defaults to off.
+    ///
+    /// The sizes are kept until the filesystem is written, or reopened because the image was
+    /// replaced.
+    pub fn cache_dir_sizes(mut self, enabled: bool) -> Self {
+        self.fs_options.cache_dir_sizes = enabled;
+        self
+    }
+
+    /// Lists directories, and gives their metadata, with the size of what they hold rather than
+    /// no size, adding up the sizes of the files in them and in the directories down to
+    /// `max_depth` directories below them, as [`Vfs::dir_size`](crate::Vfs::dir_size) does, so
+    /// that GUI clients show how much each folder takes. A `max_depth` of 0 only counts the files
+    /// in the directories themselves. Defaults to off.
+    ///
+    /// Listing a directory then walks the trees below the directories in it, so the sizes are
+    /// kept as with [`VfsBuilder::cache_dir_sizes`]. Directories whose size can't be told, such
+    /// as on damaged images, are listed without it.
+    pub fn dir_sizes_in_listings(mut self, max_depth: u32) -> Self {
+        self.fs_options.dir_sizes_in_listings = Some(max_depth);
+        self
+    }
+
+    /// Keeps up to `entries` checksums that [`Vfs::checksum`](crate::Vfs::checksum) and `XMD5`
+    /// computed, so that asking for the checksum of the same file again, such as of a firmware
+    /// image clients verify after every download, doesn't read all of it from the image again.
+    /// Defaults to 0, which keeps none.
+    ///
+    /// Checksums are kept by the path, size and modification time of the file and the
+    /// generation of the image, so that files that were written or images that were replaced
+    /// have theirs computed anew. Once as many are kept as allowed, the ones computed first make
+    /// way for new ones. They are kept in memory for as long as the file system, shared by its
+    /// clones.
+    pub fn cache_checksums(mut self, entries: usize) -> Self {
+        self.fs_options.cache_checksums = entries;
+        self
+    }
+
+    /// Sets the size of the aligned blocks a remote image is fetched in, in bytes. Defaults to
+    /// 64 KiB. Has no effect on local images.
+    ///
+    /// Larger blocks mean fewer round trips but more data transferred that may not be needed.
+    /// A multiple of the filesystem's cluster size works best.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `bytes` is zero.
+    #[cfg(feature = "http")]
+    pub fn block_size(mut self, bytes: u64) -> Self {
+        assert!(bytes > 0, "block size must not be zero");
+        self.block_size = Some(bytes);
+        self
+    }
+
+    /// Caches the parts of a remote image that have been read on local disk. Has no effect on
+    /// local images.
+    #[cfg(feature = "http")]
+    pub fn disk_cache(mut self, cache: crate::DiskCache) -> Self {
+        self.disk_cache = Some(cache);
+        self
+    }
+
+    /// Sets whether a local image, typically a block device such as `/dev/sdb1` or
+    /// `/dev/mmcblk0p1`, is opened with `O_DIRECT`. Defaults to false. Has no effect on other
+    /// images.
+    ///
+    /// This bypasses the page cache, so that what is served is what is on the device rather than
+    /// what the kernel cached of it, and serving a large card doesn't evict other data from the
+    /// cache. Reads and writes are done in sector-aligned chunks as `O_DIRECT` requires.
+    #[cfg(all(feature = "direct-io", target_os = "linux"))]
+    pub fn direct_io(mut self, enabled: bool) -> Self {
+        self.direct_io = enabled;
+        self
+    }
+
+    /// Sets whether a local image is read and written through io_uring rather than with a system
+    /// call for every access. Defaults to false. Has no effect on other images, or on images
+    /// opened with [`direct_io`](Self::direct_io).
+    ///
+    /// While files are downloaded, the part of the image that follows each read is requested
+    /// along with it, so that the kernel reads it while the data read before is sent to the
+    /// client. Where the kernel doesn't offer io_uring, as in some containers, the image is read
+    /// with regular system calls.
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    pub fn io_uring(mut self, enabled: bool) -> Self {
+        self.io_uring = enabled;
+        self
+    }
+
+    /// Creates the virtual file system. Like [`Vfs::new`] this doesn't access the image yet.
+    pub fn build(self) -> Vfs {
+        let image = self.image;
+        #[cfg(feature = "http")]
+        let image = {
+            let mut image = image;
+            if let Some(block_size) = self.block_size {
+                image = image.with_block_size(block_size);
+            }
+            if let Some(cache) = self.disk_cache {
+                image = image.with_disk_cache(cache);
+            }
+            image
+        };
+        #[cfg(all(feature = "direct-io", target_os = "linux"))]
+        let image = if self.direct_io {
+            image.with_direct_io()
+        } else {
+            image
+        };
+        #[cfg(all(feature = "io-uring", target_os = "linux"))]
+        let image = if self.io_uring {
+            image.with_io_uring()
+        } else {
+            image
+        };
+        Vfs {
+            policy: self.policy,
+            hooks: self.hooks,
+            audit: self.audit,
+            ..Vfs::with_config(image, self.partition, self.mode, self.fs_options)
+        }
+    }
+}
+
+/// The fatfs mount options of a [`Vfs`], along with how the disk is accessed underneath fatfs
+/// and how files are read for downloads.
+///
+/// Unlike fatfs' own `FsOptions` this requires the converter and time provider to be `Sync` so
+/// that a `Vfs` can be shared between threads.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct FsConfig {
+    /// Has fatfs write the accessed date of files it reads.
+    pub(crate) update_accessed_date: bool,
+    time_provider: Option<&'static (dyn TimeProvider + Sync)>,
+    oem_cp_converter: Option<&'static (dyn OemCpConverter + Sync)>,
+    /// Caches the disk's blocks in memory, if set.
+    pub(crate) block_cache: Option<BlockCacheConfig>,
+    /// Keeps the FAT in memory.
+    pub(crate) cache_fat: bool,
+    /// The buffers downloads are read into.
+    pub(crate) buffers: BufferPoolConfig,
+    /// Copies images of up to this size into memory, if set.
+    pub(crate) preload: Option<u64>,
+    /// How many times the image may be opened at once, if not the default.
+    pub(crate) max_opens: Option<usize>,
+    /// How many transfers may be in progress at once, if limited.
+    pub(crate) max_transfers: Option<usize>,
+    /// The bytes per second of each transfer, if limited.
+    pub(crate) transfer_rate: Option<u64>,
+    /// The bytes per second of all transfers together, if limited.
+    pub(crate) total_rate: Option<u64>,
+    /// The memory that caches and transfers reserve theirs from, if limited.
+    pub(crate) memory: Option<&'static MemoryBudget>,
+    /// How paths are redacted in logs and traces, if they are.
+    pub(crate) redact_paths: Option<PathRedaction>,
+    /// How paths that aren't valid UTF-8 are read.
+    pub(crate) path_decoding: PathDecoding,
+    /// How damage found on the filesystem is dealt with.
+    pub(crate) corruption_policy: CorruptionPolicy,
+    /// Compares the copies of the FAT when opening the image.
+    pub(crate) verify_fat_copies: bool,
+    /// What to do with volumes that weren't cleanly unmounted.
+    pub(crate) dirty_volume: DirtyVolume,
+    /// Looks for cross-linked files when opening the image, to warn of them when downloaded.
+    pub(crate) warn_cross_links: bool,
+    /// Names partitions after their volume labels in [`PartitionSelect::All`] mode.
+    pub(crate) name_partitions_by_label: bool,
+    /// Keeps the sizes [`Vfs::dir_size`](crate::Vfs::dir_size) found.
+    pub(crate) cache_dir_sizes: bool,
+    /// Lists directories with the size of what they hold, counted down to this depth.
+    pub(crate) dir_sizes_in_listings: Option<u32>,
+    /// How many checksums [`Vfs::checksum`](crate::Vfs::checksum) keeps, none if 0.
+    pub(crate) cache_checksums: usize,
+}
+
+impl FsConfig {
+    /// Converts into the options fatfs takes when mounting a filesystem.
+    pub(crate) fn to_fs_options(self) -> FsOptions {
+        let mut options = FsOptions::new().update_accessed_date(self.update_accessed_date);
+        if let Some(time_provider) = self.time_provider {
+            options = options.time_provider(time_provider);
+        }
+        if let Some(oem_cp_converter) = self.oem_cp_converter {
+            options = options.oem_cp_converter(oem_cp_converter);
+        }
+        options
+    }
+}
+
+/// The converter, time provider and memory budget compare by address, being the same only if
+/// they are the same object.
+impl PartialEq for FsConfig {
+    fn eq(&self, other: &Self) -> bool {
+        fn same<T: ?Sized>(a: Option<&T>, b: Option<&T>) -> bool {
+            match (a, b) {
+                (Some(a), Some(b)) => std::ptr::addr_eq(a, b),
+                (a, b) => a.is_none() && b.is_none(),
+            }
+        }
+
+        self.update_accessed_date == other.update_accessed_date
+            && same(self.time_provider, other.time_provider)
+            && same(self.oem_cp_converter, other.oem_cp_converter)
+            && self.block_cache == other.block_cache
+            && self.cache_fat == other.cache_fat
+            && self.buffers == other.buffers
+            && self.preload == other.preload
+            && self.max_opens == other.max_opens
+            && self.max_transfers == other.max_transfers
+            && self.transfer_rate == other.transfer_rate
+            && self.total_rate == other.total_rate
+            && same(self.memory, other.memory)
+            && self.redact_paths == other.redact_paths
+            && self.path_decoding == other.path_decoding
+            && self.corruption_policy == other.corruption_policy
+            && self.verify_fat_copies == other.verify_fat_copies
+            && self.dirty_volume == other.dirty_volume
+            && self.warn_cross_links == other.warn_cross_links
+            && self.name_partitions_by_label == other.name_partitions_by_label
+            && self.cache_dir_sizes == other.cache_dir_sizes
+            && self.dir_sizes_in_listings == other.dir_sizes_in_listings
+            && self.cache_checksums == other.cache_checksums
+    }
+}
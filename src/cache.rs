@@ -0,0 +1,325 @@
+//! Caches the blocks of remote images on local disk.
+
+use crate::{
+    hash::fnv1a,
+    remote::{RangeRead, RemoteStat, fetch_blocks},
+};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    io::{self, ErrorKind},
+    path::{Path, PathBuf},
+    sync::{
+        Arc, Mutex, MutexGuard,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::SystemTime,
+};
+
+/// The file next to the cached blocks of an image that records which version of the image they
+/// belong to.
+const VERSION_FILE: &str = "version";
+
+/// Distinguishes temporary files written concurrently by threads of this process.
+static TEMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// A directory to cache the blocks of remote images in, so that reading the same parts of an
+/// image again doesn't go back to the remote store.
+///
+/// Once the cache grows beyond its maximum size the least recently used blocks are evicted.
+/// Whenever an image is opened its cached blocks are checked against the version the remote
+/// store reports, such as the ETag, and discarded if the image was replaced.
+/// Clones share the same bookkeeping, so virtual file systems built with clones of one
+/// `DiskCache` share its size limit. Don't point more than one `DiskCache` at the same
+/// directory.
+///
+/// # Example
+///
+/// ```rust
+/// use unftp_sbe_fatfs::{DiskCache, VfsBuilder};
+///
+/// let cache = DiskCache::new("/var/cache/unftp").max_size(4 * 1024 * 1024 * 1024);
+/// let vfs = VfsBuilder::from_url("https://example.com/images/fat.img")
+///     .disk_cache(cache)
+///     .build();
+/// ```
+#[derive(Debug, Clone)]
+pub struct DiskCache {
+    dir: PathBuf,
+    max_size: u64,
+    index: Arc<Mutex<Option<Index>>>,
+}
+
+impl DiskCache {
+    /// Caches blocks in the given directory, which is created if needed. The maximum size
+    /// defaults to 1 GiB.
+    pub fn new<P: AsRef<Path>>(dir: P) -> Self {
+        Self {
+            dir: dir.as_ref().to_path_buf(),
+            max_size: 1024 * 1024 * 1024,
+            index: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Sets the number of bytes the cached blocks may take up in total.
+    pub fn max_size(mut self, bytes: u64) -> Self {
+        self.max_size = bytes;
+        self
+    }
+
+    /// Locks the bookkeeping of the cache, scanning the cache directory on first use.
+    fn lock_index(&self) -> io::Result<MutexGuard<'_, Option<Index>>> {
+        let mut guard = self
+            .index
+            .lock()
+            .map_err(|_| io::Error::other("disk cache lock poisoned"))?;
+        if guard.is_none() {
+            *guard = Some(Index::scan(&self.dir)?);
+        }
+        Ok(guard)
+    }
+
+    /// Records that `path` was just used, with the given size if it was just added, and evicts
+    /// blocks until the cache fits its maximum size again.
+    fn touch(&self, path: &Path, added: Option<u64>) -> io::Result<()> {
+        let mut guard = self.lock_index()?;
+        let index = guard.as_mut().expect("the index was loaded by lock_index");
+        index.touch(path, added);
+        while index.total > self.max_size {
+            let Some(evicted) = index.pop_oldest() else {
+                break;
+            };
+            match fs::remove_file(&evicted) {
+                Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes the cached blocks in `dir`, the subdirectory of one image.
+    fn clear(&self, dir: &Path) -> io::Result<()> {
+        let mut guard = self.lock_index()?;
+        let index = guard.as_mut().expect("the index was loaded by lock_index");
+        index.remove_dir(dir);
+        match fs::remove_dir_all(dir) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Which cached blocks exist and in which order they were used.
+#[derive(Debug, Default)]
+struct Index {
+    /// The size and last use of every cached block.
+    blocks: HashMap<PathBuf, (u64, u64)>,
+    /// The cached blocks by last use.
+    by_use: BTreeMap<u64, PathBuf>,
+    /// The total size of the cached blocks.
+    total: u64,
+    /// Counts uses, to order them.
+    clock: u64,
+}
+
+impl Index {
+    /// Builds the index from the blocks found in `dir`, ordered by modification time.
+    fn scan(dir: &Path) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let mut found = Vec::new();
+        for source_dir in fs::read_dir(dir)? {
+            let source_dir = source_dir?;
+            if !source_dir.file_type()?.is_dir() {
+                continue;
+            }
+            for block in fs::read_dir(source_dir.path())? {
+                let block = block?;
+                let path = block.path();
+                if path.file_name().is_some_and(|name| name == VERSION_FILE) {
+                    continue;
+                }
+                // Left behind by an interrupted write
+                if path.extension().is_some_and(|ext| ext == "tmp") {
+                    let _ = fs::remove_file(&path);
+                    continue;
+                }
+                let metadata = block.metadata()?;
+                let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+                found.push((modified, path, metadata.len()));
+            }
+        }
+        found.sort();
+
+        let mut index = Self::default();
+        for (_, path, len) in found {
+            index.touch(&path, Some(len));
+        }
+        Ok(index)
+    }
+
+    fn touch(&mut self, path: &Path, added: Option<u64>) {
+        self.clock += 1;
+        let size = match self.blocks.get(path) {
+            Some(&(size, last_use)) => {
+                self.by_use.remove(&last_use);
+                size
+            }
+            None => {
+                let size = added.unwrap_or(0);
+                self.total += size;
+                size
+            }
+        };
+        self.blocks.insert(path.to_path_buf(), (size, self.clock));
+        self.by_use.insert(self.clock, path.to_path_buf());
+    }
+
+    fn remove_dir(&mut self, dir: &Path) {
+        let removed: Vec<PathBuf> = self
+            .blocks
+            .keys()
+            .filter(|path| path.starts_with(dir))
+            .cloned()
+            .collect();
+        for path in removed {
+            if let Some((size, last_use)) = self.blocks.remove(&path) {
+                self.by_use.remove(&last_use);
+                self.total -= size;
+            }
+        }
+    }
+
+    fn pop_oldest(&mut self) -> Option<PathBuf> {
+        let (_, path) = self.by_use.pop_first()?;
+        if let Some((size, _)) = self.blocks.remove(&path) {
+            self.total -= size;
+        }
+        Some(path)
+    }
+}
+
+/// A remote image whose blocks are cached in a [`DiskCache`].
+#[derive(Debug)]
+pub(crate) struct CachedSource {
+    inner: Arc<dyn RangeRead>,
+    cache: DiskCache,
+    block_size: u64,
+    /// The subdirectory of the cache directory that holds the blocks of this image.
+    dir: PathBuf,
+    /// The size of the image when it was last looked up.
+    len: AtomicU64,
+}
+
+impl CachedSource {
+    pub(crate) fn new(inner: Arc<dyn RangeRead>, cache: DiskCache, block_size: u64) -> Self {
+        // Blocks of different sizes don't mix, so the block size is part of the directory name
+        let dir = cache.dir.join(format!(
+            "{:016x}-{block_size}",
+            fnv1a(inner.cache_key().as_bytes())
+        ));
+        Self {
+            inner,
+            cache,
+            block_size,
+            dir,
+            len: AtomicU64::new(u64::MAX),
+        }
+    }
+
+    fn path(&self, index: u64) -> PathBuf {
+        self.dir.join(index.to_string())
+    }
+
+    /// Returns the contents of the block with the given index if it is cached.
+    fn cached(&self, index: u64) -> io::Result<Option<Vec<u8>>> {
+        let path = self.path(index);
+        match fs::read(&path) {
+            Ok(data) => {
+                self.cache.touch(&path, None)?;
+                Ok(Some(data))
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Adds the block with the given index to the cache.
+    fn store(&self, index: u64, data: &[u8]) -> io::Result<()> {
+        // Write to a temporary file first so that other threads never see a partial block
+        fs::create_dir_all(&self.dir)?;
+        let temp = self.dir.join(format!(
+            "{index}.{}.{}.tmp",
+            std::process::id(),
+            TEMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::write(&temp, data)?;
+        let path = self.path(index);
+        fs::rename(&temp, &path)?;
+        self.cache.touch(&path, Some(data.len() as u64))
+    }
+}
+
+impl RangeRead for CachedSource {
+    fn stat(&self) -> io::Result<RemoteStat> {
+        let stat = self.inner.stat()?;
+        // Without a version from the store, a change in size is the best hint of a new image
+        let version = match &stat.version {
+            Some(version) => format!("version {version}"),
+            None => format!("size {}", stat.len),
+        };
+
+        let version_file = self.dir.join(VERSION_FILE);
+        let cached_version = match fs::read_to_string(&version_file) {
+            Ok(cached_version) => Some(cached_version),
+            Err(e) if e.kind() == ErrorKind::NotFound => None,
+            Err(e) => return Err(e),
+        };
+        if cached_version.as_deref() != Some(&*version) {
+            self.cache.clear(&self.dir)?;
+            fs::create_dir_all(&self.dir)?;
+            fs::write(&version_file, version)?;
+        }
+        self.len.store(stat.len, Ordering::Relaxed);
+        Ok(stat)
+    }
+
+    fn cache_key(&self) -> String {
+        self.inner.cache_key()
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8], version: Option<&str>) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let first = offset / self.block_size;
+        let last = (offset + buf.len() as u64 - 1) / self.block_size;
+        let block = match self.cached(first)? {
+            Some(block) => block,
+            None => {
+                // Fetch the following blocks that aren't cached either along with this one
+                let count = (first..=last)
+                    .take_while(|&i| i == first || !self.path(i).exists())
+                    .count() as u64;
+                let blocks = fetch_blocks(
+                    &*self.inner,
+                    self.block_size,
+                    first,
+                    count,
+                    self.len.load(Ordering::Relaxed),
+                    version,
+                )?;
+                for (i, data) in (first..).zip(&blocks) {
+                    if !data.is_empty() {
+                        self.store(i, data)?;
+                    }
+                }
+                blocks.into_iter().next().unwrap_or_default()
+            }
+        };
+
+        let start = ((offset % self.block_size) as usize).min(block.len());
+        let n = (block.len() - start).min(buf.len());
+        buf[..n].copy_from_slice(&block[start..start + n]);
+        Ok(n)
+    }
+}
@@ -0,0 +1,192 @@
+//! The checksums files are verified with, as returned by
+//! [`Vfs::checksum`](crate::Vfs::checksum).
+
+use fatfs::DateTime;
+use md5::{Digest as _, Md5};
+use sha2::Sha256;
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    path::PathBuf,
+};
+
+/// The algorithm a checksum is computed with by [`Vfs::checksum`](crate::Vfs::checksum).
+///
+/// # Example
+///
+/// ```rust,no_run
+/// use unftp_sbe_fatfs::{ChecksumAlgorithm, Vfs};
+///
+/// # async fn run() -> Result<(), Box<dyn std::error::Error>> {
+/// let vfs = Vfs::new("path/to/fat/image.img");
+/// let algorithm = ChecksumAlgorithm::Sha256;
+/// let checksum = vfs.checksum("/firmware.bin", algorithm).await?;
+/// // Such as in a reply to HASH
+/// println!("{algorithm} {checksum} /firmware.bin");
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum ChecksumAlgorithm {
+    /// MD5, as `XMD5` and `SITE MD5` reply with.
+    Md5,
+    /// SHA-256, as `HASH` and `XSHA256` reply with.
+    Sha256,
+    /// CRC-32, as ZIP files and `XCRC` use, given as 8 hexadecimal digits.
+    Crc32,
+}
+
+/// Tells the algorithm by the name `HASH` knows it by, such as `SHA-256`.
+impl fmt::Display for ChecksumAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ChecksumAlgorithm::Md5 => "MD5",
+            ChecksumAlgorithm::Sha256 => "SHA-256",
+            ChecksumAlgorithm::Crc32 => "CRC32",
+        })
+    }
+}
+
+/// Computes a checksum of what it is fed.
+pub(crate) enum Hasher {
+    Md5(Md5),
+    Sha256(Sha256),
+    Crc32(crc32fast::Hasher),
+}
+
+impl Hasher {
+    pub(crate) fn new(algorithm: ChecksumAlgorithm) -> Self {
+        match algorithm {
+            ChecksumAlgorithm::Md5 => Hasher::Md5(Md5::new()),
+            ChecksumAlgorithm::Sha256 => Hasher::Sha256(sha2::Digest::new()),
+            ChecksumAlgorithm::Crc32 => Hasher::Crc32(crc32fast::Hasher::new()),
+        }
+    }
+
+    pub(crate) fn update(&mut self, bytes: &[u8]) {
+        match self {
+            Hasher::Md5(md5) => md5.update(bytes),
+            Hasher::Sha256(sha256) => sha2::Digest::update(sha256, bytes),
+            Hasher::Crc32(crc32) => crc32.update(bytes),
+        }
+    }
+
+    /// Returns the checksum of what it was fed, in lowercase hexadecimal, as `md5sum` and
+    /// `sha256sum` tell it.
+    pub(crate) fn finish(self) -> String {
+        match self {
+            Hasher::Md5(md5) => hex(&md5.finalize()),
+            Hasher::Sha256(sha256) => hex(&sha2::Digest::finalize(sha256)),
+            Hasher::Crc32(crc32) => format!("{:08x}", crc32.finalize()),
+        }
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// What a checksum kept by [`Checksums`] was computed from, which changes when the file does.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) struct Key {
+    /// The absolute path of the file.
+    pub(crate) path: PathBuf,
+    pub(crate) len: u64,
+    /// When the file was last modified, as its entry tells, down to the millisecond. Taken as it
+    /// is rather than as a [`SystemTime`](std::time::SystemTime), which dates that don't exist,
+    /// such as the unset dates of some entries, can't be made into.
+    pub(crate) modified: [u16; 7],
+    /// The generation of the image the file was read from.
+    pub(crate) generation: u64,
+    pub(crate) algorithm: ChecksumAlgorithm,
+}
+
+impl Key {
+    /// Returns `modified` as [`Key::modified`] has it.
+    pub(crate) fn modified(modified: &DateTime) -> [u16; 7] {
+        let DateTime { date, time } = modified;
+        [
+            date.year,
+            date.month,
+            date.day,
+            time.hour,
+            time.min,
+            time.sec,
+            time.millis,
+        ]
+    }
+}
+
+/// The checksums computed, kept as set with
+/// [`VfsBuilder::cache_checksums`](crate::VfsBuilder::cache_checksums). Once as many are kept
+/// as allowed, the ones computed first make way for new ones.
+#[derive(Default)]
+pub(crate) struct Checksums {
+    checksums: HashMap<Key, String>,
+    /// The keys of the checksums, in the order they were computed.
+    order: VecDeque<Key>,
+}
+
+impl Checksums {
+    pub(crate) fn get(&self, key: &Key) -> Option<String> {
+        self.checksums.get(key).cloned()
+    }
+
+    /// Keeps `checksum`, computed from `key`, making way for it if `max` are kept already.
+    pub(crate) fn insert(&mut self, key: Key, checksum: String, max: usize) {
+        while self.order.len() >= max {
+            let Some(oldest) = self.order.pop_front() else {
+                return;
+            };
+            self.checksums.remove(&oldest);
+        }
+        if self.checksums.insert(key.clone(), checksum).is_none() {
+            self.order.push_back(key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{ChecksumAlgorithm, VfsBuilder};
+    use std::{fs, io::Write};
+
+    /// The offset within directory entries of the modification date.
+    const MODIFIED_DATE: usize = 24;
+
+    #[tokio::test]
+    async fn cached_checksums_of_files_without_dates() {
+        let image = std::env::temp_dir().join(format!("checksum-date-{}.img", std::process::id()));
+        let file = fs::File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&image)
+            .unwrap();
+        file.set_len(1024 * 1024).unwrap();
+        fatfs::format_volume(&file, fatfs::FormatVolumeOptions::new()).unwrap();
+        {
+            let fs = fatfs::FileSystem::new(&file, fatfs::FsOptions::new()).unwrap();
+            let mut zero = fs.root_dir().create_file("ZERO.BIN").unwrap();
+            zero.write_all(b"hello").unwrap();
+        }
+        // Unsets the modification date, 1980-00-00, as some systems leave it
+        let mut bytes = fs::read(&image).unwrap();
+        let entry = bytes
+            .windows(11)
+            .position(|name| name == b"ZERO    BIN")
+            .unwrap();
+        bytes[entry + MODIFIED_DATE..entry + MODIFIED_DATE + 2].fill(0);
+        fs::write(&image, bytes).unwrap();
+
+        let vfs = VfsBuilder::new(&image).cache_checksums(4).build();
+        let computed = vfs.checksum("/ZERO.BIN", ChecksumAlgorithm::Crc32).await;
+        let cached = vfs.checksum("/ZERO.BIN", ChecksumAlgorithm::Crc32).await;
+        fs::remove_file(&image).unwrap();
+
+        assert_eq!(computed.unwrap(), "3610a686");
+        assert_eq!(cached.unwrap(), "3610a686");
+    }
+}
@@ -0,0 +1,85 @@
+//! Helpers shared by the sources for cloud object stores.
+
+#[cfg(any(feature = "s3", feature = "azure"))]
+use hmac::{Hmac, KeyInit, Mac};
+#[cfg(any(feature = "s3", feature = "azure"))]
+use sha2::Sha256;
+#[cfg(any(feature = "s3", feature = "azure"))]
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Percent-encodes everything but unreserved characters and slashes, so that an object name can
+/// be used as a URL path.
+pub(crate) fn encode_path(s: &str) -> String {
+    let mut encoded = String::with_capacity(s.len());
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+                encoded.push(b as char)
+            }
+            _ => encoded.push_str(&format!("%{b:02X}")),
+        }
+    }
+    encoded
+}
+
+/// Splits a URL into its host and its path, which starts with a slash.
+#[cfg(any(feature = "s3", feature = "azure"))]
+pub(crate) fn host_and_path(url: &str) -> (&str, &str) {
+    let rest = url.split_once("://").map_or(url, |(_, rest)| rest);
+    match rest.find('/') {
+        Some(i) => rest.split_at(i),
+        None => (rest, "/"),
+    }
+}
+
+#[cfg(any(feature = "s3", feature = "azure"))]
+pub(crate) fn hmac_sha256(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// A point in time broken down into its UTC calendar fields, for request signatures.
+#[cfg(any(feature = "s3", feature = "azure"))]
+pub(crate) struct UtcTime {
+    pub(crate) year: u64,
+    pub(crate) month: u64,
+    pub(crate) day: u64,
+    pub(crate) hour: u64,
+    pub(crate) min: u64,
+    pub(crate) sec: u64,
+    /// The day of the week, where 0 is Sunday.
+    #[cfg(feature = "azure")]
+    pub(crate) weekday: u64,
+}
+
+#[cfg(any(feature = "s3", feature = "azure"))]
+impl From<SystemTime> for UtcTime {
+    fn from(time: SystemTime) -> Self {
+        let secs = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+        let (days, secs) = (secs / 86400, secs % 86400);
+
+        // Converts days since 1970-01-01 to a civil date, see
+        // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+        let z = days + 719_468;
+        let era = z / 146_097;
+        let doe = z % 146_097;
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+
+        Self {
+            year: yoe + era * 400 + u64::from(month <= 2),
+            month,
+            day,
+            hour: secs / 3600,
+            min: secs / 60 % 60,
+            sec: secs % 60,
+            // 1970-01-01 was a Thursday
+            #[cfg(feature = "azure")]
+            weekday: (days + 4) % 7,
+        }
+    }
+}
@@ -0,0 +1,149 @@
+//! Which clusters of FAT filesystems are in use, as returned by
+//! [`Vfs::cluster_map`](crate::Vfs::cluster_map).
+
+use crate::volume::FatVolume;
+use std::{io, ops::Range};
+
+/// Whether a cluster is in use, as the FAT marks it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ClusterState {
+    /// The cluster is free.
+    Free,
+    /// The cluster holds a file or a directory, or is lost to one that was deleted without
+    /// freeing it.
+    Used,
+    /// The cluster is marked as bad, and is never used.
+    Bad,
+}
+
+/// Which clusters of a FAT filesystem are in use, as returned by
+/// [`Vfs::cluster_map`](crate::Vfs::cluster_map), kept as a bitmap of one bit per cluster.
+///
+/// Clusters are numbered as in the FAT, from 2 up to but not including [`ClusterMap::end`].
+///
+/// # Example
+///
+/// ```rust,no_run
+/// use unftp_sbe_fatfs::Vfs;
+///
+/// # async fn run() -> Result<(), Box<dyn std::error::Error>> {
+/// let map = Vfs::new("path/to/fat/image.img").cluster_map().await?;
+/// println!("{} runs of used clusters", map.used_ranges().count());
+/// if let Some(last) = map.last_used() {
+///     // Everything after the last used cluster could be cut off before distributing the image
+///     println!("used up to {} bytes into the data area", (last - 1) as u64 * map.cluster_size());
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClusterMap {
+    cluster_size: u64,
+    end: u32,
+    /// A bit per cluster from cluster 2 on, set if the cluster is used.
+    used: Vec<u64>,
+    /// The bad clusters, in order, which are few if any.
+    bad: Vec<u32>,
+}
+
+impl ClusterMap {
+    /// Reads which clusters of the filesystem of `volume` are in use from its FAT.
+    pub(crate) fn read(volume: &FatVolume) -> io::Result<Self> {
+        let raw = volume.raw();
+        let end = raw.end_cluster();
+        let mut used = vec![0; (end - 2).div_ceil(64) as usize];
+        let mut bad = Vec::new();
+        raw.for_each_link(|cluster, link| match link {
+            0 => {}
+            _ if raw.is_bad(link) => bad.push(cluster),
+            _ => {
+                let bit = cluster - 2;
+                used[(bit / 64) as usize] |= 1 << (bit % 64);
+            }
+        })?;
+        Ok(Self {
+            cluster_size: raw.cluster_len(),
+            end,
+            used,
+            bad,
+        })
+    }
+
+    /// Returns the size of clusters, in bytes.
+    pub fn cluster_size(&self) -> u64 {
+        self.cluster_size
+    }
+
+    /// Returns the number of the cluster after the last one.
+    pub fn end(&self) -> u32 {
+        self.end
+    }
+
+    /// Returns the number of clusters of the filesystem.
+    pub fn len(&self) -> u32 {
+        self.end - 2
+    }
+
+    /// Whether the filesystem has no clusters, which fatfs doesn't mount.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the state of `cluster`, or `None` if it isn't a cluster of the filesystem.
+    pub fn state(&self, cluster: u32) -> Option<ClusterState> {
+        if !(2..self.end).contains(&cluster) {
+            return None;
+        }
+        Some(if self.is_used(cluster) {
+            ClusterState::Used
+        } else if self.bad.binary_search(&cluster).is_ok() {
+            ClusterState::Bad
+        } else {
+            ClusterState::Free
+        })
+    }
+
+    /// Whether `cluster` is used. `false` for bad clusters and numbers past the last cluster.
+    pub fn is_used(&self, cluster: u32) -> bool {
+        let Some(bit) = cluster.checked_sub(2).filter(|_| cluster < self.end) else {
+            return false;
+        };
+        self.used[(bit / 64) as usize] & (1 << (bit % 64)) != 0
+    }
+
+    /// Returns the bitmap of used clusters, whose bit `n % 64` of word `n / 64` tells whether
+    /// cluster `n + 2` is used.
+    pub fn bitmap(&self) -> &[u64] {
+        &self.used
+    }
+
+    /// Returns the clusters along with their states, in order.
+    pub fn iter(&self) -> impl Iterator<Item = (u32, ClusterState)> + '_ {
+        (2..self.end).map(|cluster| (cluster, self.state(cluster).unwrap()))
+    }
+
+    /// Returns the runs of used clusters, in order, which tell how fragmented the filesystem is.
+    pub fn used_ranges(&self) -> impl Iterator<Item = Range<u32>> + '_ {
+        let mut cluster = 2;
+        std::iter::from_fn(move || {
+            let start = (cluster..self.end).find(|&c| self.is_used(c))?;
+            let end = (start..self.end)
+                .find(|&c| !self.is_used(c))
+                .unwrap_or(self.end);
+            cluster = end;
+            Some(start..end)
+        })
+    }
+
+    /// Returns the last used cluster, or `None` if none is, which tells how much of the data area
+    /// of the image is actually used.
+    pub fn last_used(&self) -> Option<u32> {
+        let (word, bits) = self
+            .used
+            .iter()
+            .enumerate()
+            .rfind(|(_, bits)| **bits != 0)?;
+        Some(word as u32 * 64 + (63 - bits.leading_zeros()) + 2)
+    }
+}
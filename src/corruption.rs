@@ -0,0 +1,36 @@
+//! The public setting that tells how damage found on FAT filesystems is dealt with.
+
+/// How operations deal with damage they find on FAT12, FAT16 and FAT32 filesystems, such as
+/// cluster chains that loop or end early, as set with
+/// [`VfsBuilder::corruption_policy`](crate::VfsBuilder::corruption_policy).
+///
+/// Production servers want to fail rather than serve wrong data, while forensic users want to
+/// get at whatever can still be read. exFAT filesystems are always dealt with strictly.
+///
+/// # Example
+///
+/// ```rust
+/// use unftp_sbe_fatfs::{CorruptionPolicy, VfsBuilder};
+///
+/// // Recover what can be read from the card of a dying camera
+/// let vfs = VfsBuilder::new("path/to/fat/image.img")
+///     .corruption_policy(CorruptionPolicy::Lenient)
+///     .build();
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[non_exhaustive]
+pub enum CorruptionPolicy {
+    /// Fails the operations that run into damage, with
+    /// [`FatError::CorruptFat`](crate::FatError::CorruptFat).
+    #[default]
+    Strict,
+    /// Serves what can be read, logging a warning for what can't. Listings skip the entries that
+    /// can't be read and end a directory whose cluster chain loops where it does, lookups go by
+    /// those listings, and downloads of files whose cluster chains end early or loop end there.
+    Lenient,
+    /// Fails like [`Strict`](CorruptionPolicy::Strict), and checks more as it goes: that long
+    /// names belong to the entries they come with, as their checksums tell, and that the cluster
+    /// chains of files hold as many clusters as their sizes take, not more, which listings check
+    /// for every file and metadata for the file it is of.
+    Paranoid,
+}
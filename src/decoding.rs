@@ -0,0 +1,53 @@
+//! The public setting that tells how paths that aren't valid UTF-8 are read.
+
+use std::{
+    borrow::Cow,
+    path::{Path, PathBuf},
+};
+
+/// How the paths clients give are read when they aren't valid UTF-8, as set with
+/// [`VfsBuilder::path_decoding`](crate::VfsBuilder::path_decoding).
+///
+/// Names on FAT filesystems are Unicode, so paths are looked up as text. Clients that predate
+/// UTF-8, or run with a legacy locale, send paths in the encoding of their system instead, most
+/// often Latin-1 or its Windows variant.
+///
+/// # Example
+///
+/// ```rust
+/// use unftp_sbe_fatfs::{PathDecoding, VfsBuilder};
+///
+/// // A client asking for `caf\xe9.txt` gets `café.txt`
+/// let vfs = VfsBuilder::new("path/to/fat/image.img")
+///     .path_decoding(PathDecoding::Latin1)
+///     .build();
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[non_exhaustive]
+pub enum PathDecoding {
+    /// Replaces the bytes that aren't valid UTF-8 with U+FFFD, which no name matches, so such
+    /// paths are not found.
+    #[default]
+    Lossy,
+    /// Reads paths that aren't valid UTF-8 as Latin-1 (ISO 8859-1) as a whole, in which every
+    /// byte is a character.
+    Latin1,
+    /// Refuses paths that aren't valid UTF-8 as file names that aren't allowed.
+    Reject,
+}
+
+impl PathDecoding {
+    /// Returns `path` as valid UTF-8, decoded if it isn't, or `None` if such paths are refused.
+    pub(crate) fn decode(self, path: &Path) -> Option<Cow<'_, Path>> {
+        if path.to_str().is_some() {
+            return Some(Cow::Borrowed(path));
+        }
+        let bytes = path.as_os_str().as_encoded_bytes();
+        let decoded = match self {
+            PathDecoding::Lossy => String::from_utf8_lossy(bytes).into_owned(),
+            PathDecoding::Latin1 => bytes.iter().map(|&byte| char::from(byte)).collect(),
+            PathDecoding::Reject => return None,
+        };
+        Some(Cow::Owned(PathBuf::from(decoded)))
+    }
+}
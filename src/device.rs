@@ -0,0 +1,222 @@
+//! Disks accessed through handles that only accept reads and writes of whole sectors: Linux block
+//! devices opened with `O_DIRECT`, which bypasses the page cache, and Windows physical drives and
+//! volumes such as `\\.\PhysicalDrive2` and `\\.\E:`.
+//!
+//! Reads and writes on such handles must be aligned to the logical sector size in both offset and
+//! length, and on Linux go through an aligned buffer. fatfs reads and writes at arbitrary offsets,
+//! so its requests are served from a window of whole sectors that is read as a unit.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+/// The alignment of reads and writes, which covers both 512-byte and 4 KiB logical sectors.
+const ALIGN: usize = 4096;
+
+/// The smallest logical sector size, which the size of every device is a multiple of.
+const SECTOR: u64 = 512;
+
+/// The size of the window of the device that is read at once.
+const WINDOW: usize = 128 * 1024;
+
+/// A seekable stream over a device that is read and written in whole sectors.
+pub(crate) struct DeviceDisk {
+    file: File,
+    len: u64,
+    pos: u64,
+    /// Backs the window, with room to align its start.
+    buf: Vec<u8>,
+    /// The offset of the window in the device and the number of bytes read into it.
+    window: Option<(u64, usize)>,
+}
+
+impl DeviceDisk {
+    /// Opens the block device at `path` with `O_DIRECT`.
+    #[cfg(all(feature = "direct-io", target_os = "linux"))]
+    pub(crate) fn open_direct(path: &Path, writable: bool) -> io::Result<Self> {
+        use std::os::unix::fs::OpenOptionsExt;
+
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(writable)
+            .custom_flags(libc::O_DIRECT)
+            .open(path)?;
+        // Block devices report a size of zero in their metadata, seeking finds their real size
+        let len = file.seek(SeekFrom::End(0))?;
+        Ok(Self::new(file, len))
+    }
+
+    /// Opens the Windows physical drive or volume at `path`.
+    ///
+    /// Windows refuses writes to the sectors of a volume while a filesystem is mounted on it, so
+    /// mounted volumes can only be read.
+    #[cfg(windows)]
+    pub(crate) fn open_windows(path: &Path, writable: bool) -> io::Result<Self> {
+        use std::os::windows::io::AsRawHandle;
+        use windows_sys::Win32::System::{
+            IO::DeviceIoControl,
+            Ioctl::{GET_LENGTH_INFORMATION, IOCTL_DISK_GET_LENGTH_INFO},
+        };
+
+        let file = OpenOptions::new().read(true).write(writable).open(path)?;
+        // Neither the metadata nor seeking to the end tell the size of a device
+        let mut info = GET_LENGTH_INFORMATION { Length: 0 };
+        let mut returned = 0;
+        // SAFETY: The handle is open for as long as `file` lives and the output buffer is a
+        // `GET_LENGTH_INFORMATION` of the size passed along.
+        let ok = unsafe {
+            DeviceIoControl(
+                file.as_raw_handle(),
+                IOCTL_DISK_GET_LENGTH_INFO,
+                std::ptr::null(),
+                0,
+                (&raw mut info).cast(),
+                size_of::<GET_LENGTH_INFORMATION>() as u32,
+                &mut returned,
+                std::ptr::null_mut(),
+            )
+        };
+        if ok == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self::new(file, info.Length as u64))
+    }
+
+    fn new(file: File, len: u64) -> Self {
+        Self {
+            file,
+            len,
+            pos: 0,
+            buf: vec![0; WINDOW + ALIGN],
+            window: None,
+        }
+    }
+
+    /// Makes sure the window holds the byte at the current position, returning the offset of
+    /// that byte in the window and the number of bytes from there on.
+    fn load(&mut self) -> io::Result<(usize, usize)> {
+        match self.window {
+            Some((start, filled)) if (start..start + filled as u64).contains(&self.pos) => {}
+            _ => {
+                let start = self.pos & !(ALIGN as u64 - 1);
+                // Devices fail reads past their end rather than returning less
+                let want = (self.len - start)
+                    .next_multiple_of(SECTOR)
+                    .min(WINDOW as u64);
+                let filled = loop {
+                    match read_at(
+                        &self.file,
+                        &mut aligned(&mut self.buf)[..want as usize],
+                        start,
+                    ) {
+                        Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                        result => break result?,
+                    }
+                };
+                self.window = Some((start, filled));
+            }
+        }
+        let (start, filled) = self.window.expect("just loaded");
+        let offset = (self.pos - start) as usize;
+        Ok((offset, filled.saturating_sub(offset)))
+    }
+}
+
+impl Read for DeviceDisk {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.len || buf.is_empty() {
+            return Ok(0);
+        }
+        let (offset, available) = self.load()?;
+        let n = available.min(buf.len());
+        buf[..n].copy_from_slice(&aligned(&mut self.buf)[offset..offset + n]);
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Write for DeviceDisk {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.pos >= self.len {
+            return Err(io::ErrorKind::WriteZero.into());
+        }
+        // Update the window and write back the whole sectors the change falls in
+        let (offset, available) = self.load()?;
+        let n = available.min(buf.len());
+        let (start, filled) = self.window.expect("just loaded");
+        let first = offset & !(ALIGN - 1);
+        let end = (offset + n).next_multiple_of(ALIGN).min(filled);
+        let window = aligned(&mut self.buf);
+        window[offset..offset + n].copy_from_slice(&buf[..n]);
+        write_all_at(&self.file, &window[first..end], start + first as u64)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for DeviceDisk {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.pos = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+        }
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "seek to a negative offset"))?;
+        Ok(self.pos)
+    }
+}
+
+/// Tells whether `path` names a Windows device, such as `\\.\PhysicalDrive2` or `\\.\E:`,
+/// rather than a file.
+#[cfg(windows)]
+pub(crate) fn is_device_path(path: &Path) -> bool {
+    path.as_os_str()
+        .to_str()
+        .is_some_and(|path| path.starts_with(r"\\.\"))
+}
+
+/// The aligned part of `buf` that holds the window.
+fn aligned(buf: &mut [u8]) -> &mut [u8] {
+    let start = buf.as_ptr().align_offset(ALIGN);
+    &mut buf[start..start + WINDOW]
+}
+
+#[cfg(unix)]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    std::os::unix::fs::FileExt::read_at(file, buf, offset)
+}
+
+#[cfg(unix)]
+fn write_all_at(file: &File, buf: &[u8], offset: u64) -> io::Result<()> {
+    std::os::unix::fs::FileExt::write_all_at(file, buf, offset)
+}
+
+#[cfg(windows)]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    std::os::windows::fs::FileExt::seek_read(file, buf, offset)
+}
+
+#[cfg(windows)]
+fn write_all_at(file: &File, mut buf: &[u8], mut offset: u64) -> io::Result<()> {
+    while !buf.is_empty() {
+        match std::os::windows::fs::FileExt::seek_write(file, buf, offset) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(n) => {
+                buf = &buf[n..];
+                offset += n as u64;
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
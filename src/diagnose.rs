@@ -0,0 +1,178 @@
+//! Works out why a volume can't be mounted, so that errors tell users what is wrong with their
+//! image and what to do about it, rather than just that its boot sector is invalid.
+
+use crate::partition::{SECTOR_SIZE, boot_sector_len, is_boot_sector};
+use std::{
+    fmt,
+    io::{self, Read, Seek, SeekFrom},
+};
+
+/// How much of the start of a volume is read to recognize what it holds, which reaches up to
+/// the superblock of Btrfs.
+const PROBE_LEN: u64 = 0x10048;
+
+/// Filesystems and volumes other than FAT, by the offset and the value of their magic bytes.
+const FOREIGN: [(usize, &[u8], &str); 9] = [
+    (3, b"NTFS    ", "an NTFS filesystem"),
+    (0, b"XFSB", "an XFS filesystem"),
+    (0, b"LUKS\xba\xbe", "a LUKS encrypted volume"),
+    (32, b"NXSB", "an APFS container"),
+    (1024, b"H+\x00\x04", "an HFS+ filesystem"),
+    (1024, b"HX\x00\x05", "an HFSX filesystem"),
+    (1080, b"\x53\xef", "an ext2, ext3 or ext4 filesystem"),
+    (
+        0x8001,
+        b"CD001",
+        "an ISO 9660 filesystem without a FAT boot image",
+    ),
+    (0x10040, b"_BHRfS_M", "a Btrfs filesystem"),
+];
+
+/// What a volume that couldn't be mounted holds instead of a FAT filesystem.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Diagnosis {
+    /// The volume is empty.
+    Empty,
+    /// The volume ends before the boot sector does, after this many bytes.
+    TooShort(u64),
+    /// Another filesystem, or another kind of volume, described with its article.
+    Foreign(&'static str),
+    /// An exFAT filesystem, which fatfs can't mount.
+    #[cfg(not(feature = "exfat"))]
+    ExFat,
+    /// An MBR partition table rather than a boot sector.
+    Mbr,
+    /// A GPT rather than a boot sector.
+    Gpt,
+    /// A boot sector of nothing but zeros.
+    Blank,
+    /// A boot sector without the signature that ends boot sectors.
+    NoSignature,
+    /// A FAT filesystem that takes up more than the volume.
+    Truncated {
+        /// The size of the volume.
+        len: u64,
+        /// The size of the filesystem, as its boot sector tells.
+        expected: u64,
+    },
+}
+
+impl Diagnosis {
+    /// Adds the diagnosis to `e`, the error mounting failed with.
+    pub(crate) fn explain(self, e: io::Error) -> io::Error {
+        io::Error::new(e.kind(), format!("{e}: {self}"))
+    }
+}
+
+impl fmt::Display for Diagnosis {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Diagnosis::Empty => f.write_str("the image is empty"),
+            Diagnosis::TooShort(len) => write!(
+                f,
+                "the image is only {len} bytes long, too short for a boot sector, so it was \
+                 likely truncated"
+            ),
+            Diagnosis::Foreign(name) => write!(f, "the image holds {name} rather than FAT"),
+            #[cfg(not(feature = "exfat"))]
+            Diagnosis::ExFat => f.write_str(
+                "the image holds an exFAT filesystem, which is only served with the `exfat` \
+                 feature",
+            ),
+            Diagnosis::Mbr | Diagnosis::Gpt => {
+                let table = if *self == Diagnosis::Mbr {
+                    "an MBR"
+                } else {
+                    "a GPT"
+                };
+                write!(
+                    f,
+                    "the image starts with {table} partition table rather than a filesystem, \
+                     select the partition to serve with `PartitionSelect`"
+                )
+            }
+            Diagnosis::Blank => {
+                f.write_str("the boot sector is all zeros, so the image was likely never formatted")
+            }
+            Diagnosis::NoSignature => f.write_str(
+                "the boot sector lacks the 0x55 0xAA signature, so the image is corrupt or not a \
+                 disk image at all",
+            ),
+            Diagnosis::Truncated { len, expected } => write!(
+                f,
+                "the filesystem takes up {expected} bytes but the image ends after {len}, so it \
+                 was likely truncated"
+            ),
+        }
+    }
+}
+
+/// Works out what the `len` bytes of `disk` from `start` on hold instead of a FAT filesystem
+/// that fits in them, or returns `None` if nothing is obviously wrong.
+pub(crate) fn diagnose<T: Read + Seek>(
+    disk: &mut T,
+    start: u64,
+    len: u64,
+) -> io::Result<Option<Diagnosis>> {
+    let mut head = Vec::new();
+    disk.seek(SeekFrom::Start(start))?;
+    disk.take(len.min(PROBE_LEN)).read_to_end(&mut head)?;
+    if head.is_empty() {
+        return Ok(Some(Diagnosis::Empty));
+    }
+    let Some(sector) = head.first_chunk::<{ SECTOR_SIZE as usize }>() else {
+        return Ok(Some(Diagnosis::TooShort(head.len() as u64)));
+    };
+
+    let found = |offset: usize, magic: &[u8]| head.get(offset..offset + magic.len()) == Some(magic);
+    if let Some((_, _, name)) = FOREIGN
+        .iter()
+        .find(|(offset, magic, _)| found(*offset, magic))
+    {
+        return Ok(Some(Diagnosis::Foreign(name)));
+    }
+    #[cfg(not(feature = "exfat"))]
+    if found(3, b"EXFAT   ") {
+        return Ok(Some(Diagnosis::ExFat));
+    }
+    if found(SECTOR_SIZE as usize, b"EFI PART") {
+        return Ok(Some(Diagnosis::Gpt));
+    }
+    if sector.iter().all(|&b| b == 0) {
+        return Ok(Some(Diagnosis::Blank));
+    }
+    if sector[510..] != [0x55, 0xAA] {
+        return Ok(Some(Diagnosis::NoSignature));
+    }
+    if !is_boot_sector(sector) {
+        // Partition entries start with whether they are bootable, and used ones have a type
+        let entries = sector[446..510].chunks_exact(16);
+        if entries.clone().all(|entry| matches!(entry[0], 0x00 | 0x80))
+            && entries.clone().any(|entry| entry[4] != 0)
+        {
+            return Ok(Some(Diagnosis::Mbr));
+        }
+        return Ok(None);
+    }
+    Ok(truncated(sector, len))
+}
+
+/// Tells whether the FAT filesystem on the `len` bytes of `disk` takes up more than them, which
+/// fatfs only notices once it reads past their end, or returns `None` if it doesn't.
+pub(crate) fn check_len<T: Read + Seek>(disk: &mut T, len: u64) -> io::Result<Option<Diagnosis>> {
+    let mut sector = [0; SECTOR_SIZE as usize];
+    disk.seek(SeekFrom::Start(0))?;
+    match disk.read_exact(&mut sector) {
+        Ok(()) if is_boot_sector(&sector) => Ok(truncated(&sector, len)),
+        Ok(()) => Ok(None),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Returns the diagnosis of a truncated volume if the filesystem whose boot sector is `sector`
+/// takes up more than `len` bytes.
+fn truncated(sector: &[u8; SECTOR_SIZE as usize], len: u64) -> Option<Diagnosis> {
+    let expected = boot_sector_len(sector);
+    (expected > len).then_some(Diagnosis::Truncated { len, expected })
+}
@@ -0,0 +1,50 @@
+//! How much directory trees hold, as returned by [`Vfs::dir_size`](crate::Vfs::dir_size).
+
+use std::ops::AddAssign;
+
+/// How much a directory and everything below it holds, like `du` tells it, as returned by
+/// [`Vfs::dir_size`](crate::Vfs::dir_size).
+///
+/// # Example
+///
+/// ```rust,no_run
+/// use unftp_sbe_fatfs::Vfs;
+///
+/// # async fn run() -> Result<(), Box<dyn std::error::Error>> {
+/// let vfs = Vfs::new("path/to/fat/image.img");
+/// for folder in ["/DCIM", "/MUSIC"] {
+///     let size = vfs.dir_size(folder).await?;
+///     println!("{folder}: {} bytes in {} files", size.bytes, size.files);
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[non_exhaustive]
+pub struct DirSize {
+    /// The sizes of the files added up, in bytes.
+    pub bytes: u64,
+    /// The number of files.
+    pub files: u64,
+    /// The number of directories below the directory, not counting itself.
+    pub directories: u64,
+}
+
+impl DirSize {
+    /// Returns the size of a single file of `len` bytes.
+    pub(crate) fn file(len: u64) -> Self {
+        Self {
+            bytes: len,
+            files: 1,
+            directories: 0,
+        }
+    }
+}
+
+impl AddAssign for DirSize {
+    fn add_assign(&mut self, other: Self) {
+        self.bytes += other.bytes;
+        self.files += other.files;
+        self.directories += other.directories;
+    }
+}
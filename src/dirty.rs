@@ -0,0 +1,32 @@
+//! The public setting that tells what to do with volumes that weren't cleanly unmounted.
+
+/// What to do with a FAT volume marked dirty, as set with
+/// [`VfsBuilder::dirty_volume`](crate::VfsBuilder::dirty_volume).
+///
+/// Systems mark FAT volumes dirty while they have them mounted and clear the mark when they
+/// unmount them, so a volume that is still marked was pulled or lost power while in use. Its
+/// directories may be half updated, and files may hold less than their entries tell. exFAT volumes
+/// aren't checked.
+///
+/// # Example
+///
+/// ```rust
+/// use unftp_sbe_fatfs::{DirtyVolume, VfsBuilder};
+///
+/// // Have the volume checked with fsck.fat rather than serve it as it is
+/// let vfs = VfsBuilder::new("path/to/fat/image.img")
+///     .dirty_volume(DirtyVolume::Refuse)
+///     .build();
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[non_exhaustive]
+pub enum DirtyVolume {
+    /// Serves the volume, logging a warning when it is opened.
+    #[default]
+    Warn,
+    /// Refuses to serve the volume, failing operations with
+    /// [`FatError::DirtyVolume`](crate::FatError::DirtyVolume).
+    Refuse,
+    /// Serves the volume without a word.
+    Proceed,
+}
@@ -0,0 +1,250 @@
+//! Streams downloads to clients while the file is read in the background.
+
+use crate::{AccessGuard, Vfs, buffer_pool::BufferPool, format::read_up_to, memory::Reservation};
+use bytes::Bytes;
+use std::{
+    io::{self, Read},
+    pin::Pin,
+    sync::{Arc, atomic::Ordering},
+    task::{Context, Poll, ready},
+};
+use tokio::{
+    io::{AsyncBufRead, AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf},
+    sync::{OwnedSemaphorePermit, mpsc},
+};
+use unftp_core::storage::{Error, ErrorKind};
+
+/// The size of the chunks downloads are read from the image in, unless configured otherwise.
+pub(crate) const CHUNK_SIZE: usize = 64 * 1024;
+
+/// The number of chunks read ahead of what the client has received.
+pub(crate) const READ_AHEAD_CHUNKS: usize = 4;
+
+/// The number of chunks a download holds at most: those read ahead, the one being read and the
+/// one being sent.
+pub(crate) const CHUNKS_IN_FLIGHT: usize = READ_AHEAD_CHUNKS + 2;
+
+/// The sending half of the channel a download's chunks are passed through.
+pub(crate) type ChunkSender = mpsc::Sender<io::Result<Bytes>>;
+
+/// An async reader over the chunks of a file that a blocking task reads and sends ahead.
+///
+/// The channel holds at most [`READ_AHEAD_CHUNKS`] chunks, so reading stays that far ahead of
+/// the client and stops when the download is dropped. An error ends the download.
+///
+/// Chunks are read into buffers from `buffers` and given back once the client received them.
+/// [`write_to`](Self::write_to) writes them out as they are rather than copying them first.
+pub(crate) struct Download {
+    chunks: mpsc::Receiver<io::Result<Bytes>>,
+    buffers: Arc<BufferPool>,
+    chunk: Bytes,
+    pos: usize,
+    /// Counts the download among the transfers in progress until it is dropped.
+    _transfer: Option<OwnedSemaphorePermit>,
+    /// The memory of the chunks read ahead.
+    _memory: Reservation,
+}
+
+impl Download {
+    /// Reads the chunks from `chunks`, starting with `first`, which was received already.
+    pub(crate) fn new(
+        first: Bytes,
+        chunks: mpsc::Receiver<io::Result<Bytes>>,
+        buffers: Arc<BufferPool>,
+        transfer: Option<OwnedSemaphorePermit>,
+        memory: Reservation,
+    ) -> Self {
+        Self {
+            chunks,
+            buffers,
+            chunk: first,
+            pos: 0,
+            _transfer: transfer,
+            _memory: memory,
+        }
+    }
+
+    /// Writes the rest of the download to `output`, returning the number of bytes written.
+    pub(crate) async fn write_to<W>(mut self, output: &mut W) -> io::Result<u64>
+    where
+        W: AsyncWrite + Unpin + ?Sized,
+    {
+        let mut written = 0;
+        loop {
+            if self.pos < self.chunk.len() {
+                output.write_all(&self.chunk[self.pos..]).await?;
+                written += (self.chunk.len() - self.pos) as u64;
+            }
+            self.buffers.give_back(std::mem::take(&mut self.chunk));
+            self.pos = 0;
+            match self.chunks.recv().await {
+                Some(chunk) => self.chunk = chunk?,
+                None => break,
+            }
+        }
+        output.flush().await?;
+        Ok(written)
+    }
+}
+
+impl AsyncBufRead for Download {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<&[u8]>> {
+        let this = self.get_mut();
+        while this.pos == this.chunk.len() {
+            match ready!(this.chunks.poll_recv(cx)) {
+                Some(Ok(chunk)) => {
+                    this.buffers
+                        .give_back(std::mem::replace(&mut this.chunk, chunk));
+                    this.pos = 0;
+                }
+                Some(Err(e)) => return Poll::Ready(Err(e)),
+                None => break,
+            }
+        }
+        Poll::Ready(Ok(&this.chunk[this.pos..]))
+    }
+
+    fn consume(mut self: Pin<&mut Self>, amt: usize) {
+        self.pos += amt;
+    }
+}
+
+impl AsyncRead for Download {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let chunk = ready!(self.as_mut().poll_fill_buf(cx))?;
+        let n = chunk.len().min(buf.remaining());
+        buf.put_slice(&chunk[..n]);
+        self.consume(n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Refuses to download a file of `len` bytes from `start_pos` on if that's beyond its end, which
+/// FTP clients are told with a 550 reply. Resuming at the very end downloads nothing.
+pub(crate) fn check_start_pos(start_pos: u64, len: u64) -> Result<(), Error> {
+    if start_pos > len {
+        return Err(Error::new(
+            ErrorKind::PermanentFileNotAvailable,
+            format!("cannot resume at byte {start_pos} of a file of {len} bytes"),
+        ));
+    }
+    Ok(())
+}
+
+/// Read access to the filesystem of a [`Vfs`] that a download takes for each chunk it reads
+/// rather than for the whole transfer, so that a client that takes its time receiving doesn't
+/// keep uploads and other changes waiting.
+///
+/// A filesystem of its own that the download reads through caches what it read, so the access
+/// is only taken as long as nothing was written in between.
+pub(crate) struct Gate<'a> {
+    vfs: &'a Vfs,
+    /// How many times the filesystem was written to when the download opened its filesystem.
+    writes: u64,
+}
+
+impl<'a> Gate<'a> {
+    /// Starts counting the writes to the filesystem of `vfs` from now on. Read access must be held
+    /// while the download opens its filesystem, so that nothing is written in between.
+    pub(crate) fn new(vfs: &'a Vfs) -> Self {
+        let writes = vfs.shared.writes.load(Ordering::Acquire);
+        Self { vfs, writes }
+    }
+
+    /// Takes read access, held until the guard is dropped, or returns `None` if the filesystem
+    /// was written to since the gate was created.
+    pub(crate) fn enter(&self) -> Option<AccessGuard<'a>> {
+        let access = self.vfs.lock_access(crate::Access::Read);
+        (self.vfs.shared.writes.load(Ordering::Acquire) == self.writes).then_some(access)
+    }
+}
+
+/// How far [`send_chunks`] got.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Sent {
+    /// The file was sent to its end, or the download was dropped.
+    All,
+    /// The filesystem was written to after this many bytes were sent, so the rest has to be
+    /// read through a filesystem opened anew.
+    Interrupted(u64),
+}
+
+/// Reads `reader`, a file positioned at `start_pos`, to its end in chunks and sends them to the
+/// download, stopping early if the download was dropped. That is noticed before every read of
+/// `reader`, so no more than a cluster or a batch of clusters is read after the client went
+/// away. The chunks are read into buffers taken from `buffers` and are as large as those.
+///
+/// Each read of `reader` takes read access through `gate`, which isn't held while the chunks
+/// wait for the client. Once the filesystem was written to in between, the chunk being read is
+/// dropped and [`Sent::Interrupted`] tells how many bytes were sent before.
+///
+/// Chunks end at multiples of the chunk size in the file, which are multiples of the cluster
+/// size too, so that resumed downloads don't read every cluster in two parts.
+///
+/// A read error is sent to the download as well as returned, so that the client sees the
+/// download fail and the caller can tell whether the image went away.
+pub(crate) fn send_chunks(
+    reader: &mut impl Read,
+    start_pos: u64,
+    buffers: &BufferPool,
+    chunks: &ChunkSender,
+    gate: &Gate<'_>,
+) -> io::Result<Sent> {
+    let size = buffers.size();
+    let mut chunk_len = size - (start_pos % size as u64) as usize;
+    let mut reader = Gated {
+        reader,
+        chunks,
+        gate,
+        interrupted: false,
+    };
+    let mut sent = 0;
+    loop {
+        let mut chunk = buffers.take();
+        let n = match read_up_to(&mut reader, &mut chunk[..chunk_len]) {
+            Ok(n) => n,
+            Err(e) => {
+                let _ = chunks.blocking_send(Err(io::Error::new(e.kind(), e.to_string())));
+                return Err(e);
+            }
+        };
+        if reader.interrupted {
+            return Ok(Sent::Interrupted(sent));
+        }
+        chunk_len = size;
+        if n == 0 {
+            return Ok(Sent::All);
+        }
+        chunk.truncate(n);
+        if chunks.blocking_send(Ok(chunk.freeze())).is_err() {
+            return Ok(Sent::All);
+        }
+        sent += n as u64;
+    }
+}
+
+/// A reader that takes read access for every read, and ends as soon as the download it reads
+/// for was dropped or the filesystem was written to, without reading on.
+struct Gated<'a, 'v, R> {
+    reader: &'a mut R,
+    chunks: &'a ChunkSender,
+    gate: &'a Gate<'v>,
+    interrupted: bool,
+}
+
+impl<R: Read> Read for Gated<'_, '_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.interrupted || self.chunks.is_closed() {
+            return Ok(0);
+        }
+        let Some(_access) = self.gate.enter() else {
+            self.interrupted = true;
+            return Ok(0);
+        };
+        self.reader.read(buf)
+    }
+}
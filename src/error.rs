@@ -0,0 +1,131 @@
+//! The causes of failed operations that are specific to FAT filesystems, for embedders to match
+//! on rather than parse error messages.
+
+use crate::Fs;
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+use unftp_core::storage::{Error, ErrorKind};
+
+/// Why an operation on a FAT filesystem failed, as found among the sources of the errors
+/// operations return. See [`FatError::of`].
+///
+/// Errors that don't have a cause of their own here, such as failing to read the image, carry
+/// the I/O error instead, as returned by [`Error::get_io_error`].
+///
+/// # Example
+///
+/// ```rust,no_run
+/// use unftp_core::auth::DefaultUser;
+/// use unftp_core::storage::StorageBackend;
+/// use unftp_sbe_fatfs::{FatError, Vfs};
+///
+/// # async fn run() {
+/// let vfs = Vfs::new("path/to/fat/image.img");
+/// if let Err(e) = vfs.metadata(&DefaultUser, "/docs/readme.txt").await {
+///     match FatError::of(&e) {
+///         Some(FatError::NotFound { component, .. }) => println!("there is no {component}"),
+///         Some(FatError::CorruptFat(reason)) => eprintln!("the image needs repair: {reason}"),
+///         _ => eprintln!("{e}"),
+///     }
+/// }
+/// # }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[non_exhaustive]
+pub enum FatError {
+    /// The path doesn't lead to a file or directory.
+    #[error("{}: {component:?} not found", path.display())]
+    NotFound {
+        /// The path looked up, made absolute.
+        path: PathBuf,
+        /// The name in the path that no entry has.
+        component: String,
+    },
+    /// The path goes through a file as if it were a directory, or names a file where a directory
+    /// is expected.
+    #[error("{}: {component:?} is not a directory", path.display())]
+    NotADirectory {
+        /// The path looked up, made absolute.
+        path: PathBuf,
+        /// The name in the path of the file.
+        component: String,
+    },
+    /// The FAT, or the directories it chains together, are inconsistent, for the reason given.
+    #[error("the FAT is corrupt: {0}")]
+    CorruptFat(String),
+    /// The volume wasn't cleanly unmounted, and such volumes are refused, as set with
+    /// [`VfsBuilder::dirty_volume`](crate::VfsBuilder::dirty_volume).
+    #[error("the volume wasn't cleanly unmounted, check it with fsck.fat or chkdsk")]
+    DirtyVolume,
+    /// The image holds a variant of FAT that isn't served, such as exFAT without the `exfat`
+    /// feature.
+    #[error("unsupported FAT variant: {0}")]
+    UnsupportedVariant(String),
+}
+
+impl FatError {
+    pub(crate) fn not_found(path: &Path, component: &str) -> Self {
+        FatError::NotFound {
+            path: path.to_path_buf(),
+            component: component.to_string(),
+        }
+    }
+
+    pub(crate) fn not_a_directory(path: &Path, component: &str) -> Self {
+        FatError::NotADirectory {
+            path: path.to_path_buf(),
+            component: component.to_string(),
+        }
+    }
+
+    /// Returns the cause of `error` if it is specific to FAT filesystems.
+    pub fn of(error: &Error) -> Option<&FatError> {
+        std::error::Error::source(error)?.downcast_ref()
+    }
+
+    /// Returns the kind of error operations that fail with this cause return, which determines
+    /// the reply clients get. None of them are worth retrying.
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            FatError::NotFound { .. } | FatError::CorruptFat(_) => {
+                ErrorKind::PermanentFileNotAvailable
+            }
+            FatError::NotADirectory { .. } => ErrorKind::PermanentDirectoryNotAvailable,
+            FatError::DirtyVolume | FatError::UnsupportedVariant(_) => ErrorKind::LocalError,
+        }
+    }
+}
+
+impl From<FatError> for Error {
+    fn from(e: FatError) -> Self {
+        Error::new(e.kind(), e)
+    }
+}
+
+/// Returns the error of reading a directory on the way to `path` that failed with `e`. fatfs
+/// reads past the end of the volume when a cluster chain leads out of it, which takes a corrupt
+/// FAT. Other failures to read the image may well pass, so clients are told to retry.
+pub(crate) fn dir_error(path: &Path, e: io::Error) -> Error {
+    if e.kind() == io::ErrorKind::UnexpectedEof {
+        return FatError::CorruptFat(format!(
+            "a cluster chain on the way to {} leads past the end of the image, which may be \
+             truncated",
+            path.display()
+        ))
+        .into();
+    }
+    Error::new(ErrorKind::TransientFileNotAvailable, e)
+}
+
+/// Returns the error of a write to `fs` that failed with `e`, telling clients that the filesystem
+/// is full if it is, which fatfs doesn't tell apart from other failures.
+pub(crate) fn write_error(fs: &Fs, e: io::Error) -> Error {
+    match fs.stats() {
+        Ok(stats) if stats.free_clusters() == 0 => {
+            Error::new(ErrorKind::InsufficientStorageSpaceError, e)
+        }
+        _ => Error::from(e),
+    }
+}
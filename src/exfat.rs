@@ -0,0 +1,212 @@
+//! Read-only exFAT support, backed by the `exfat` crate.
+//!
+//! fatfs only understands FAT12/16/32, so exFAT volumes are served through this module instead.
+//! The `exfat` crate doesn't expose timestamps, so all entries report the FAT epoch as their
+//! modification time.
+
+use crate::{
+    BootSectorInfo, FatError, Meta,
+    buffer_pool::BufferPool,
+    download::{ChunkSender, Gate, Sent, check_start_pos, send_chunks},
+    image::Disk,
+};
+use ::exfat::{ExFat, directory::Item};
+use std::{
+    io::{self, Read, Seek, SeekFrom},
+    path::{Component, Path, PathBuf},
+};
+use unftp_core::storage::{Error, ErrorKind, Fileinfo, Result};
+
+/// The file system name exFAT boot sectors carry at offset 3.
+const EXFAT_SIGNATURE: &[u8; 8] = b"EXFAT   ";
+
+/// Tells whether `disk` holds an exFAT volume, leaving it positioned at the start.
+pub(crate) fn is_exfat(disk: &mut dyn Disk) -> io::Result<bool> {
+    let mut boot = [0u8; 11];
+    disk.seek(SeekFrom::Start(0))?;
+    let found = match disk.read_exact(&mut boot) {
+        Ok(()) => &boot[3..11] == EXFAT_SIGNATURE,
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => false,
+        Err(e) => return Err(e),
+    };
+    disk.seek(SeekFrom::Start(0))?;
+    Ok(found)
+}
+
+/// An opened exFAT volume.
+pub(crate) struct ExFatVolume {
+    root: Vec<Item<Box<dyn Disk>>>,
+    boot_sector: BootSectorInfo,
+    volume_label: Option<String>,
+}
+
+impl ExFatVolume {
+    pub(crate) fn open(mut disk: Box<dyn Disk>) -> Result<Self> {
+        let mut sector = [0; 512];
+        disk.seek(SeekFrom::Start(0))?;
+        disk.read_exact(&mut sector)?;
+        disk.seek(SeekFrom::Start(0))?;
+        let exfat =
+            ExFat::open(disk).map_err(|e| Error::new(ErrorKind::PermanentFileNotAvailable, e))?;
+        Ok(Self {
+            volume_label: exfat.volume_label().map(str::to_string),
+            boot_sector: BootSectorInfo::exfat(&sector),
+            root: exfat.into_iter().collect(),
+        })
+    }
+
+    /// Returns the volume label, `None` if the volume has none.
+    pub(crate) fn volume_label(&self) -> Option<String> {
+        self.volume_label.clone()
+    }
+
+    /// Returns the geometry of the volume as its boot sector tells it.
+    pub(crate) fn boot_sector_info(&self) -> BootSectorInfo {
+        self.boot_sector.clone()
+    }
+
+    /// Returns the metadata of the entry at the given normalized path.
+    pub(crate) fn metadata(&mut self, path: &Path) -> Result<Meta> {
+        if is_root(path) {
+            return Ok(Meta::virtual_dir());
+        }
+        self.with_item(path, |item| Ok(meta(item)))
+    }
+
+    /// Lists the directory at the given normalized path.
+    pub(crate) fn list(&mut self, path: &Path) -> Result<Vec<Fileinfo<PathBuf, Meta>>> {
+        if is_root(path) {
+            return Ok(file_infos(&self.root));
+        }
+        self.with_item(path, |item| match item {
+            Item::Directory(dir) => Ok(file_infos(&dir.open().map_err(io_error)?)),
+            Item::File(file) => Err(not_a_directory(path, file.name())),
+        })
+    }
+
+    /// Sends the contents of the file at the given normalized path from `start_pos` onwards to a
+    /// download, chunk by chunk.
+    pub(crate) fn read(
+        &mut self,
+        path: &Path,
+        start_pos: u64,
+        buffers: &BufferPool,
+        chunks: &ChunkSender,
+        gate: &Gate<'_>,
+    ) -> Result<Sent> {
+        let Some(access) = gate.enter() else {
+            return Ok(Sent::Interrupted(0));
+        };
+        self.with_item(path, |item| {
+            let Item::File(file) = item else {
+                return Err(ErrorKind::FileNameNotAllowedError.into());
+            };
+            check_start_pos(start_pos, file.len())?;
+            // Empty files have no clusters and therefore no reader
+            let Some(mut reader) = file.open().map_err(io_error)? else {
+                return Ok(Sent::All);
+            };
+            reader
+                .seek(SeekFrom::Start(start_pos))
+                .map_err(Error::from)?;
+            drop(access);
+            send_chunks(&mut reader, start_pos, buffers, chunks, gate).map_err(Error::from)
+        })
+    }
+
+    /// Checks that the given normalized path is a directory.
+    pub(crate) fn cwd(&mut self, path: &Path) -> Result<()> {
+        if is_root(path) {
+            return Ok(());
+        }
+        self.with_item(path, |item| match item {
+            Item::Directory(_) => Ok(()),
+            Item::File(file) => Err(not_a_directory(path, file.name())),
+        })
+    }
+
+    /// Looks up the item at the given normalized path and runs `f` on it.
+    fn with_item<R>(
+        &mut self,
+        path: &Path,
+        f: impl FnOnce(&mut Item<Box<dyn Disk>>) -> Result<R>,
+    ) -> Result<R> {
+        let components: Vec<String> = path
+            .components()
+            .filter_map(|c| match c {
+                Component::Normal(name) => Some(name.to_string_lossy().into_owned()),
+                _ => None,
+            })
+            .collect();
+        lookup(path, &mut self.root, &components, f)
+    }
+}
+
+/// Descends into `items` along `components`, the rest of `path`, running `f` on the item the
+/// last one names.
+fn lookup<R>(
+    path: &Path,
+    items: &mut [Item<Box<dyn Disk>>],
+    components: &[String],
+    f: impl FnOnce(&mut Item<Box<dyn Disk>>) -> Result<R>,
+) -> Result<R> {
+    let Some((name, rest)) = components.split_first() else {
+        return Err(ErrorKind::FileNameNotAllowedError.into());
+    };
+
+    // exFAT compares names case-insensitively
+    let item = items
+        .iter_mut()
+        .find(|item| item_name(item).eq_ignore_ascii_case(name))
+        .ok_or_else(|| FatError::not_found(&Path::new("/").join(path), name))?;
+    if rest.is_empty() {
+        return f(item);
+    }
+
+    match item {
+        Item::Directory(dir) => lookup(path, &mut dir.open().map_err(io_error)?, rest, f),
+        Item::File(file) => Err(not_a_directory(path, file.name())),
+    }
+}
+
+/// Returns the error of looking up the given normalized path through the file named `name`.
+fn not_a_directory(path: &Path, name: &str) -> Error {
+    FatError::not_a_directory(&Path::new("/").join(path), name).into()
+}
+
+fn is_root(path: &Path) -> bool {
+    path.components()
+        .all(|c| !matches!(c, Component::Normal(_)))
+}
+
+fn item_name(item: &Item<Box<dyn Disk>>) -> &str {
+    match item {
+        Item::Directory(dir) => dir.name(),
+        Item::File(file) => file.name(),
+    }
+}
+
+fn meta(item: &Item<Box<dyn Disk>>) -> Meta {
+    match item {
+        Item::Directory(_) => Meta::virtual_dir(),
+        Item::File(file) => Meta {
+            is_dir: false,
+            len: file.len(),
+            ..Meta::virtual_dir()
+        },
+    }
+}
+
+fn file_infos(items: &[Item<Box<dyn Disk>>]) -> Vec<Fileinfo<PathBuf, Meta>> {
+    items
+        .iter()
+        .map(|item| Fileinfo {
+            path: item_name(item).into(),
+            metadata: meta(item),
+        })
+        .collect()
+}
+
+fn io_error<E: std::error::Error + Send + Sync + 'static>(e: E) -> Error {
+    Error::new(ErrorKind::PermanentFileNotAvailable, e)
+}
@@ -0,0 +1,181 @@
+//! Keeps the File Allocation Table of a FAT filesystem in memory, or the part of it read last.
+//!
+//! fatfs looks up every cluster of a file in the FAT as it goes, seeking back and forth between
+//! the FAT and the data. Seeking into a file, such as when a download is resumed, follows the
+//! file's cluster chain from its start, a lookup per cluster. Holding the whole table in memory
+//! turns those lookups into memory reads. Otherwise the table is read a window at a time, so that
+//! following the chain of a file of gigabytes reads a few megabytes of the FAT in large pieces,
+//! rather than a few bytes at a time from an image that may be remote.
+
+use crate::{
+    MemoryBudget,
+    format::read_up_to,
+    image::Disk,
+    memory::{self, Reservation},
+};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+/// Upper bound on the size of the FAT we're willing to keep in memory. This covers FAT32 volumes
+/// of 16 GiB with 4 KiB clusters, or 128 GiB with 32 KiB clusters.
+const MAX_FAT_SIZE: u64 = 16 * 1024 * 1024;
+
+/// The size of the windows of the FAT read when the whole table isn't kept in memory. That's
+/// the entries of 16384 clusters of FAT32, or 64 MiB of a file with 4 KiB clusters.
+const FAT_WINDOW: u64 = 64 * 1024;
+
+/// A seekable stream that serves reads of the first FAT of the filesystem on `inner` from
+/// memory, reading the aligned window of the table that holds what is read unless that window
+/// was read last. The window can be the whole table.
+///
+/// Writes go through to `inner` right away and update the window in memory.
+pub(crate) struct FatCache {
+    inner: Box<dyn Disk>,
+    /// The offset of the first FAT.
+    start: u64,
+    fat_len: u64,
+    window_len: u64,
+    /// The window of the FAT read last, along with its offset in the FAT.
+    window: Option<(u64, Vec<u8>)>,
+    pos: u64,
+    /// The memory the whole FAT takes, if it is kept, reserved up front.
+    _memory: Reservation,
+}
+
+impl FatCache {
+    /// Wraps `inner` if it holds a FAT filesystem, otherwise returns it as it is. The whole FAT
+    /// is kept in memory if `whole` is set, the FAT isn't too large for that and `memory` has
+    /// room for it.
+    pub(crate) fn wrap(
+        mut inner: Box<dyn Disk>,
+        whole: bool,
+        memory: Option<&'static MemoryBudget>,
+    ) -> io::Result<Box<dyn Disk>> {
+        let mut bpb = [0u8; 40];
+        inner.seek(SeekFrom::Start(0))?;
+        let n = read_up_to(&mut *inner, &mut bpb)?;
+        inner.seek(SeekFrom::Start(0))?;
+        let Some((start, fat_len)) = (n == bpb.len()).then(|| fat_region(&bpb)).flatten() else {
+            return Ok(inner);
+        };
+        let reservation = Some(fat_len)
+            .filter(|&fat_len| whole && fat_len <= MAX_FAT_SIZE)
+            .and_then(|fat_len| memory::try_reserve(memory, fat_len));
+        let window_len = match reservation {
+            Some(_) => fat_len,
+            None => FAT_WINDOW.min(fat_len),
+        };
+        Ok(Box::new(Self {
+            inner,
+            start,
+            fat_len,
+            window_len,
+            window: None,
+            pos: 0,
+            _memory: reservation.unwrap_or_default(),
+        }))
+    }
+
+    /// Returns the FAT from `offset` to the end of the window that holds it, reading that
+    /// window unless it was read last.
+    fn fat_at(&mut self, offset: u64) -> io::Result<&[u8]> {
+        let read = matches!(
+            &self.window,
+            Some((from, window)) if (*from..*from + window.len() as u64).contains(&offset)
+        );
+        if !read {
+            let from = offset / self.window_len * self.window_len;
+            let mut window = vec![0; self.window_len.min(self.fat_len - from) as usize];
+            self.inner.seek(SeekFrom::Start(self.start + from))?;
+            let n = read_up_to(&mut *self.inner, &mut window)?;
+            window.truncate(n);
+            self.window = Some((from, window));
+        }
+        let (from, window) = self.window.as_ref().expect("just read");
+        Ok(window.get((offset - from) as usize..).unwrap_or_default())
+    }
+}
+
+/// Finds the offset and size of the first FAT from the start of a FAT boot sector, or `None` if
+/// it isn't one. exFAT volumes aren't served by fatfs and aren't recognized.
+fn fat_region(bpb: &[u8; 40]) -> Option<(u64, u64)> {
+    let bytes_per_sector = u16::from_le_bytes([bpb[11], bpb[12]]) as u64;
+    let reserved_sectors = u16::from_le_bytes([bpb[14], bpb[15]]) as u64;
+    let fats = bpb[16];
+    let sectors_per_fat = match u16::from_le_bytes([bpb[22], bpb[23]]) {
+        // FAT32 keeps the size in a field of its own
+        0 => u32::from_le_bytes(bpb[36..40].try_into().unwrap()) as u64,
+        sectors => sectors as u64,
+    };
+    let valid = bytes_per_sector.is_power_of_two()
+        && (512..=4096).contains(&bytes_per_sector)
+        && reserved_sectors != 0
+        && fats != 0
+        && sectors_per_fat != 0;
+    valid.then_some((
+        reserved_sectors * bytes_per_sector,
+        sectors_per_fat * bytes_per_sector,
+    ))
+}
+
+impl Read for FatCache {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let (start, fat_len) = (self.start, self.fat_len);
+        if !(start..start + fat_len).contains(&self.pos) {
+            // Don't read past the FAT into what's cached
+            let max = if self.pos < start {
+                (start - self.pos).min(buf.len() as u64) as usize
+            } else {
+                buf.len()
+            };
+            self.inner.seek(SeekFrom::Start(self.pos))?;
+            let n = self.inner.read(&mut buf[..max])?;
+            self.pos += n as u64;
+            return Ok(n);
+        }
+
+        let fat = self.fat_at(self.pos - start)?;
+        let n = fat.len().min(buf.len());
+        buf[..n].copy_from_slice(&fat[..n]);
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Write for FatCache {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.seek(SeekFrom::Start(self.pos))?;
+        let n = self.inner.write(buf)?;
+
+        let end = self.pos + n as u64;
+        if let Some((offset, window)) = &mut self.window {
+            let window_start = self.start + *offset;
+            let from = self.pos.max(window_start);
+            let to = end.min(window_start + window.len() as u64);
+            if from < to {
+                window[(from - window_start) as usize..(to - window_start) as usize]
+                    .copy_from_slice(&buf[(from - self.pos) as usize..(to - self.pos) as usize]);
+            }
+        }
+        self.pos = end;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl Seek for FatCache {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.pos = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => {
+                let len = self.inner.seek(SeekFrom::End(0))?;
+                len.checked_add_signed(offset)
+            }
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+        }
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "seek to a negative offset"))?;
+        Ok(self.pos)
+    }
+}
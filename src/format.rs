@@ -0,0 +1,314 @@
+//! Images stored in a format that has to be decoded before the filesystem in them can be read,
+//! such as compressed images.
+//!
+//! Formats are recognized by their magic bytes, whatever the image is called and wherever it is
+//! stored. Decoded images can only be read.
+
+#[cfg(any(
+    feature = "android-sparse",
+    feature = "gzip",
+    feature = "qcow2",
+    feature = "vhd",
+    feature = "vmdk",
+    feature = "xz",
+    feature = "zstd"
+))]
+use crate::image::ReadOnly;
+use crate::{
+    MemoryBudget,
+    image::{Disk, Image},
+};
+use std::io::{self, SeekFrom};
+#[cfg(any(feature = "gzip", feature = "xz", feature = "zstd"))]
+use std::{
+    fs::{self, File},
+    io::{Read, Seek},
+    path::PathBuf,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+/// Distinguishes the temporary files of images expanded by this process.
+#[cfg(any(feature = "gzip", feature = "xz", feature = "zstd"))]
+static TEMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// The formats images can be stored in besides raw disk images.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    AndroidSparse,
+    Gzip,
+    Qcow2,
+    Vhd,
+    Vhdx,
+    Vmdk,
+    VmdkDescriptor,
+    Xz,
+    Zstd,
+}
+
+impl Format {
+    /// Recognizes the format from the first bytes of an image.
+    fn detect(magic: &[u8]) -> Option<Self> {
+        if magic.starts_with(&[0x3a, 0xff, 0x26, 0xed]) {
+            Some(Format::AndroidSparse)
+        } else if magic.starts_with(&[0x1f, 0x8b]) {
+            Some(Format::Gzip)
+        } else if magic.starts_with(b"QFI\xfb") {
+            Some(Format::Qcow2)
+        } else if magic.starts_with(b"conectix") {
+            Some(Format::Vhd)
+        } else if magic.starts_with(b"vhdxfile") {
+            Some(Format::Vhdx)
+        } else if magic.starts_with(b"KDMV") {
+            Some(Format::Vmdk)
+        } else if magic.starts_with(b"# Disk DescriptorFile") {
+            Some(Format::VmdkDescriptor)
+        } else if magic.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+            Some(Format::Xz)
+        } else if magic.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Some(Format::Zstd)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the name of the format and the feature that adds support for it.
+    fn describe(self) -> (&'static str, &'static str) {
+        match self {
+            Format::AndroidSparse => ("Android sparse", "android-sparse"),
+            Format::Gzip => ("gzip-compressed", "gzip"),
+            Format::Qcow2 => ("qcow2", "qcow2"),
+            Format::Vhd => ("dynamic VHD", "vhd"),
+            Format::Vhdx => ("VHDX", "vhd"),
+            Format::Vmdk | Format::VmdkDescriptor => ("VMDK", "vmdk"),
+            Format::Xz => ("xz-compressed", "xz"),
+            Format::Zstd => ("zstd-compressed", "zstd"),
+        }
+    }
+}
+
+/// Decodes an image for everything that opens it, so that the work is shared.
+#[derive(Debug, Default)]
+pub(crate) struct Decoder {
+    /// The image expanded into a temporary file, along with the generation of the image it was
+    /// expanded from.
+    #[cfg(any(feature = "gzip", feature = "xz", feature = "zstd"))]
+    expanded: Mutex<Option<(u64, Arc<TempImage>)>>,
+    /// The budget that decompressed frames of seekable zstd images are kept in, if memory is
+    /// limited.
+    #[cfg(feature = "zstd")]
+    memory: Option<&'static MemoryBudget>,
+}
+
+impl Decoder {
+    /// Creates a decoder that keeps decompressed data in `memory`, if memory is limited.
+    #[allow(unused_variables, clippy::needless_update)]
+    pub(crate) fn new(memory: Option<&'static MemoryBudget>) -> Self {
+        Self {
+            #[cfg(feature = "zstd")]
+            memory,
+            ..Self::default()
+        }
+    }
+
+    /// Opens a new stream over `image`, decoded if it is stored in a format other than a raw
+    /// disk image.
+    pub(crate) fn open(&self, image: &Image, writable: bool) -> io::Result<Box<dyn Disk>> {
+        let mut disk = image.open(writable)?;
+        // Long enough for the first line of VMDK descriptor files
+        let mut magic = [0u8; 21];
+        let n = read_up_to(&mut *disk, &mut magic)?;
+        disk.seek(SeekFrom::Start(0))?;
+        match Format::detect(&magic[..n]) {
+            Some(format) => self.decode(format, image, disk),
+            None => Ok(disk),
+        }
+    }
+
+    #[allow(unused_variables)]
+    fn decode(
+        &self,
+        format: Format,
+        image: &Image,
+        disk: Box<dyn Disk>,
+    ) -> io::Result<Box<dyn Disk>> {
+        match format {
+            #[cfg(feature = "android-sparse")]
+            Format::AndroidSparse => Ok(Box::new(ReadOnly(
+                crate::android_sparse::AndroidSparse::open(disk)?,
+            ))),
+            #[cfg(feature = "gzip")]
+            Format::Gzip => self.expanded(image, disk, gunzip),
+            #[cfg(feature = "qcow2")]
+            Format::Qcow2 => Ok(Box::new(ReadOnly(crate::qcow2::Qcow2::open(disk)?))),
+            #[cfg(feature = "vhd")]
+            Format::Vhd => Ok(Box::new(ReadOnly(crate::vhd::open_vhd(disk)?))),
+            #[cfg(feature = "vhd")]
+            Format::Vhdx => Ok(Box::new(ReadOnly(crate::vhd::open_vhdx(disk)?))),
+            #[cfg(feature = "vmdk")]
+            Format::Vmdk => Ok(Box::new(ReadOnly(crate::vmdk::SparseVmdk::open(disk)?))),
+            #[cfg(feature = "vmdk")]
+            Format::VmdkDescriptor => crate::vmdk::open_descriptor(image, disk),
+            #[cfg(feature = "xz")]
+            Format::Xz => self.expanded(image, disk, unxz),
+            #[cfg(feature = "zstd")]
+            Format::Zstd => {
+                let mut disk = disk;
+                if crate::zstd::is_seekable(&mut *disk)? {
+                    let zstd = crate::zstd::SeekableZstd::open(disk)?;
+                    // Without room for its frames in memory the image is expanded on disk
+                    match crate::memory::try_reserve(self.memory, zstd.cache_len()) {
+                        Some(reservation) => Ok(Box::new(ReadOnly(zstd.holding(reservation)))),
+                        None => self.expanded(image, zstd.into_inner()?, crate::zstd::expand),
+                    }
+                } else {
+                    self.expanded(image, disk, crate::zstd::expand)
+                }
+            }
+            #[allow(unreachable_patterns)]
+            _ => {
+                let (name, feature) = format.describe();
+                Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    format!("serving {name} images requires the `{feature}` feature"),
+                ))
+            }
+        }
+    }
+
+    /// Returns a stream over the image expanded into a temporary file by `expand`, expanding it
+    /// unless that happened already since the image last changed.
+    #[cfg(any(feature = "gzip", feature = "xz", feature = "zstd"))]
+    fn expanded(
+        &self,
+        image: &Image,
+        disk: Box<dyn Disk>,
+        expand: fn(Box<dyn Disk>, &mut File) -> io::Result<()>,
+    ) -> io::Result<Box<dyn Disk>> {
+        let mut expanded = self
+            .expanded
+            .lock()
+            .map_err(|_| io::Error::other("decoder lock poisoned"))?;
+        let generation = image.generation();
+        let temp = match &*expanded {
+            Some((expanded_at, temp)) if *expanded_at == generation => Arc::clone(temp),
+            _ => {
+                let temp = Arc::new(TempImage::create(|file| expand(disk, file))?);
+                *expanded = Some((generation, Arc::clone(&temp)));
+                temp
+            }
+        };
+        Ok(Box::new(ReadOnly(TempStream {
+            file: File::open(&temp.path)?,
+            _image: temp,
+        })))
+    }
+}
+
+/// Reads into `buf` until it is full or the stream ends, returning the number of bytes read.
+pub(crate) fn read_up_to(
+    reader: &mut (impl io::Read + ?Sized),
+    buf: &mut [u8],
+) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+/// Wraps `e` in an error of kind [`io::ErrorKind::InvalidData`], for images that are malformed.
+pub(crate) fn invalid_data<E>(e: E) -> io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+/// Wraps `e` in an error of kind [`io::ErrorKind::Unsupported`], for images that use parts of
+/// their format this crate can't read.
+#[cfg(any(
+    feature = "android-sparse",
+    feature = "qcow2",
+    feature = "vhd",
+    feature = "vmdk"
+))]
+pub(crate) fn unsupported<E>(e: E) -> io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::Unsupported, e)
+}
+
+/// Decompresses a gzip-compressed image, including ones made up of several gzip members.
+#[cfg(feature = "gzip")]
+fn gunzip(disk: Box<dyn Disk>, out: &mut File) -> io::Result<()> {
+    let mut decoder = flate2::read::MultiGzDecoder::new(io::BufReader::new(disk));
+    io::copy(&mut decoder, out).map(drop)
+}
+
+/// Decompresses an xz-compressed image.
+#[cfg(feature = "xz")]
+fn unxz(disk: Box<dyn Disk>, out: &mut File) -> io::Result<()> {
+    lzma_rs::xz_decompress(&mut io::BufReader::new(disk), out)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// A temporary file holding an expanded image, removed once no stream uses it anymore.
+#[cfg(any(feature = "gzip", feature = "xz", feature = "zstd"))]
+#[derive(Debug)]
+struct TempImage {
+    path: PathBuf,
+}
+
+#[cfg(any(feature = "gzip", feature = "xz", feature = "zstd"))]
+impl TempImage {
+    /// Creates an empty temporary file and has `fill` write the image to it.
+    fn create(fill: impl FnOnce(&mut File) -> io::Result<()>) -> io::Result<Self> {
+        let path = std::env::temp_dir().join(format!(
+            "unftp-sbe-fatfs-{}-{}.img",
+            std::process::id(),
+            TEMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        let mut file = File::create_new(&path)?;
+        // From here on dropping the image removes the file, also when filling it fails
+        let image = Self { path };
+        fill(&mut file)?;
+        Ok(image)
+    }
+}
+
+#[cfg(any(feature = "gzip", feature = "xz", feature = "zstd"))]
+impl Drop for TempImage {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// A stream over a [`TempImage`] that keeps the file around while it is open.
+#[cfg(any(feature = "gzip", feature = "xz", feature = "zstd"))]
+struct TempStream {
+    file: File,
+    _image: Arc<TempImage>,
+}
+
+#[cfg(any(feature = "gzip", feature = "xz", feature = "zstd"))]
+impl Read for TempStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.file.read(buf)
+    }
+}
+
+#[cfg(any(feature = "gzip", feature = "xz", feature = "zstd"))]
+impl Seek for TempStream {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.file.seek(pos)
+    }
+}
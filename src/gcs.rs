@@ -0,0 +1,118 @@
+//! Images stored as Google Cloud Storage objects, read with ranged GET requests.
+
+use crate::{
+    cloud::encode_path,
+    http::{HttpSource, SignRequest},
+};
+use std::{fmt, io, str::FromStr};
+
+/// The location of an image stored in Google Cloud Storage, and the credentials to read it with.
+///
+/// Requests are authorized with an OAuth 2.0 access token, such as the one printed by
+/// `gcloud auth print-access-token`. Without one, only publicly readable objects can be read.
+///
+/// # Example
+///
+/// ```rust
+/// use unftp_sbe_fatfs::GcsObject;
+///
+/// let object: GcsObject = "gs://build-artifacts/firmware/fat.img".parse().unwrap();
+/// let object = object.access_token("ya29....");
+/// ```
+#[derive(Clone)]
+pub struct GcsObject {
+    bucket: String,
+    object: String,
+    endpoint: Option<String>,
+    access_token: Option<String>,
+}
+
+impl GcsObject {
+    /// Refers to the object with the given name in the given bucket.
+    pub fn new<B: Into<String>, O: Into<String>>(bucket: B, object: O) -> Self {
+        Self {
+            bucket: bucket.into(),
+            object: object.into(),
+            endpoint: None,
+            access_token: None,
+        }
+    }
+
+    /// Sets the base URL of the storage service, for instance to use an emulator. Defaults to
+    /// `https://storage.googleapis.com`.
+    pub fn endpoint<S: Into<String>>(mut self, endpoint: S) -> Self {
+        self.endpoint = Some(endpoint.into());
+        self
+    }
+
+    /// Authorizes requests with an OAuth 2.0 access token.
+    pub fn access_token<S: Into<String>>(mut self, access_token: S) -> Self {
+        self.access_token = Some(access_token.into());
+        self
+    }
+
+    /// Returns the HTTP(S) URL of the object.
+    fn url(&self) -> String {
+        let endpoint = self
+            .endpoint
+            .as_deref()
+            .unwrap_or("https://storage.googleapis.com")
+            .trim_end_matches('/');
+        format!("{endpoint}/{}/{}", self.bucket, encode_path(&self.object))
+    }
+}
+
+impl fmt::Debug for GcsObject {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("GcsObject")
+            .field("bucket", &self.bucket)
+            .field("object", &self.object)
+            .field("endpoint", &self.endpoint)
+            .finish_non_exhaustive()
+    }
+}
+
+/// The error returned when parsing a malformed `gs://` URL into a [`GcsObject`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseGcsUrlError;
+
+impl fmt::Display for ParseGcsUrlError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid Cloud Storage URL, expected the form gs://bucket/object")
+    }
+}
+
+impl std::error::Error for ParseGcsUrlError {}
+
+impl FromStr for GcsObject {
+    type Err = ParseGcsUrlError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (bucket, object) = s
+            .strip_prefix("gs://")
+            .and_then(|s| s.split_once('/'))
+            .ok_or(ParseGcsUrlError)?;
+        if bucket.is_empty() || object.is_empty() {
+            return Err(ParseGcsUrlError);
+        }
+        Ok(Self::new(bucket, object))
+    }
+}
+
+/// Creates the source that reads `object` with ranged GET requests.
+pub(crate) fn source(object: GcsObject) -> HttpSource {
+    let url = object.url();
+    match object.access_token {
+        Some(access_token) => HttpSource::with_signer(url, BearerToken(access_token)),
+        None => HttpSource::new(url),
+    }
+}
+
+/// Authorizes requests with an OAuth 2.0 bearer token.
+struct BearerToken(String);
+
+impl SignRequest for BearerToken {
+    fn sign(&self, _url: &str, _range: &str) -> io::Result<Vec<(&'static str, String)>> {
+        Ok(vec![("authorization", format!("Bearer {}", self.0))])
+    }
+}
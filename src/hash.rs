@@ -0,0 +1,10 @@
+//! Hashes whose values are kept or shown, such as in the names of cache directories and in
+//! redacted paths, so they mustn't change between releases.
+
+/// Hashes `bytes` with 64-bit FNV-1a, which unlike the hashers of the standard library is
+/// specified, so its hashes don't change between releases.
+pub(crate) fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
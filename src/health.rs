@@ -0,0 +1,36 @@
+//! The status readiness probes gate on, as returned by [`Vfs::health_check`](crate::Vfs::health_check).
+
+use std::time::Duration;
+use unftp_core::storage::{Error, Result};
+
+/// Whether a [`Vfs`](crate::Vfs) could open its image and list the root directory, as returned
+/// by [`Vfs::health_check`](crate::Vfs::health_check).
+#[derive(Debug)]
+#[non_exhaustive]
+pub struct Health {
+    /// Whether the image could be opened and its filesystem mounted, or why not. In
+    /// [`PartitionSelect::All`](crate::PartitionSelect::All) mode, that's the partition table and
+    /// the filesystems of all partitions.
+    pub image: Result<()>,
+    /// The number of entries in the root directory, or why it couldn't be listed. `None` if the
+    /// image couldn't be opened, so listing wasn't tried.
+    pub root: Option<Result<usize>>,
+    /// How long the check took.
+    pub duration: Duration,
+}
+
+impl Health {
+    /// Returns whether the image could be opened and its root directory listed, so that the file
+    /// system is ready to serve clients.
+    pub fn is_healthy(&self) -> bool {
+        self.image.is_ok() && matches!(self.root, Some(Ok(_)))
+    }
+
+    /// Returns the error the check failed with, if it did.
+    pub fn error(&self) -> Option<&Error> {
+        match (&self.image, &self.root) {
+            (Err(e), _) | (Ok(()), Some(Err(e))) => Some(e),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,194 @@
+//! The public extension point for following transfers as they start, complete and fail.
+
+use crate::{AuditSink, Operation, Vfs, audit::Started, span::OperationSpan, stats::Recorder};
+use std::{
+    fmt::Debug,
+    io,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll, ready},
+    time::Duration,
+};
+use tokio::io::{AsyncRead, ReadBuf};
+use unftp_core::storage::{Error, ErrorKind};
+
+/// Callbacks for the lifecycle of transfers and the failures of operations, for accounting,
+/// progress displays and alerting.
+///
+/// Implement the methods of interest and pass the hooks to
+/// [`VfsBuilder::transfer_hooks`](crate::VfsBuilder::transfer_hooks). Users are given by the
+/// name they display as, since a download completes after the operation that started it
+/// returned. Paths are as the client gave them.
+///
+/// The hooks are called on the tasks that serve clients, so they should return quickly and
+/// without blocking, for instance by sending what they were told to a channel.
+///
+/// # Example
+///
+/// ```rust
+/// use std::{path::Path, sync::atomic::{AtomicU64, Ordering}, time::Duration};
+/// use unftp_sbe_fatfs::{TransferHooks, VfsBuilder};
+///
+/// /// Counts the bytes downloaded in full.
+/// #[derive(Debug, Default)]
+/// struct Accounting {
+///     bytes: AtomicU64,
+/// }
+///
+/// impl TransferHooks for Accounting {
+///     fn on_get_complete(&self, _user: &str, _path: &Path, bytes: u64, _duration: Duration) {
+///         self.bytes.fetch_add(bytes, Ordering::Relaxed);
+///     }
+/// }
+///
+/// let vfs = VfsBuilder::new("path/to/fat/image.img")
+///     .transfer_hooks(Accounting::default())
+///     .build();
+/// ```
+#[allow(unused_variables)]
+pub trait TransferHooks: Debug + Send + Sync {
+    /// Called when a download of the file at `path` starts at byte `start_pos`, once the file
+    /// was found.
+    fn on_get_start(&self, user: &str, path: &Path, start_pos: u64) {}
+
+    /// Called when a download of the file at `path` sent the last of its `bytes`, `duration`
+    /// after it started.
+    fn on_get_complete(&self, user: &str, path: &Path, bytes: u64, duration: Duration) {}
+
+    /// Called when an upload to the file at `path` starts at byte `start_pos`.
+    fn on_put_start(&self, user: &str, path: &Path, start_pos: u64) {}
+
+    /// Called when an upload to the file at `path` wrote the last of its `bytes`, `duration`
+    /// after it started.
+    fn on_put_complete(&self, user: &str, path: &Path, bytes: u64, duration: Duration) {}
+
+    /// Called when `operation` failed with `error`, including downloads that fail while data
+    /// is sent and those the client stops receiving before the end, which fail with
+    /// [`ErrorKind::ConnectionClosed`].
+    fn on_error(&self, user: &str, operation: Operation<'_>, error: &Error) {}
+}
+
+/// An async reader over a download that counts what it serves in `stats`, and tells `hooks` and
+/// `audit`, if there are any, and `span` when it was read to the end, failed, or was dropped
+/// before either.
+pub(crate) struct Tracked<R> {
+    inner: R,
+    stats: Arc<Recorder>,
+    /// The path the download is counted under in `stats`.
+    key: PathBuf,
+    hooks: Option<Arc<dyn TransferHooks>>,
+    audit: Option<Arc<dyn AuditSink>>,
+    /// The span of the download, until it ended.
+    span: Option<OperationSpan>,
+    user: String,
+    path: PathBuf,
+    started: Started,
+    bytes: u64,
+    /// Whether the hooks were told how the download ended.
+    ended: bool,
+}
+
+impl<R> Tracked<R> {
+    /// Tracks `inner`, the download of `path` for `user` from `vfs`, counting it in the stats of
+    /// `vfs` and telling its hooks that it started. The download started at `started`, and its
+    /// span is ended along with it.
+    pub(crate) fn start(
+        inner: R,
+        vfs: &Vfs,
+        user: String,
+        path: PathBuf,
+        start_pos: u64,
+        (started, span): (Started, OperationSpan),
+    ) -> Self {
+        let stats = Arc::clone(&vfs.shared.stats);
+        let key = vfs.absolute_path(&path);
+        stats.started(&key);
+        if let Some(hooks) = &vfs.hooks {
+            hooks.on_get_start(&user, &path, start_pos);
+        }
+        Self {
+            inner,
+            stats,
+            key,
+            hooks: vfs.hooks.clone(),
+            audit: vfs.audit.clone(),
+            span: Some(span),
+            user,
+            path,
+            started,
+            bytes: 0,
+            ended: false,
+        }
+    }
+
+    /// Tells the span, the audit sink and the hooks that the download ended, or failed with
+    /// `error`.
+    fn end(&mut self, error: Option<&Error>) {
+        self.ended = true;
+        if let Some(span) = self.span.take() {
+            span.transferred(self.bytes);
+            span.end(error);
+        }
+        let operation = Operation::Download(&self.path);
+        if let Some(audit) = &self.audit {
+            let result = error.map_or(Ok(()), Err);
+            audit.record(
+                &self
+                    .started
+                    .event(&self.user, operation, result, Some(self.bytes)),
+            );
+        }
+        if let Some(hooks) = &self.hooks {
+            match error {
+                Some(error) => hooks.on_error(&self.user, operation, error),
+                None => hooks.on_get_complete(
+                    &self.user,
+                    &self.path,
+                    self.bytes,
+                    self.started.elapsed(),
+                ),
+            }
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for Tracked<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let filled = buf.filled().len();
+        if let Err(e) = ready!(Pin::new(&mut this.inner).poll_read(cx, buf)) {
+            if !this.ended {
+                let error = Error::from(io::Error::new(e.kind(), e.to_string()));
+                #[cfg(feature = "metrics")]
+                crate::metrics::failed(Operation::Download(&this.path), error.kind());
+                this.end(Some(&error));
+            }
+            return Poll::Ready(Err(e));
+        }
+        let n = buf.filled().len() - filled;
+        if n > 0 {
+            this.bytes += n as u64;
+            this.stats.served(&this.key, n as u64);
+        }
+        if n == 0 && buf.remaining() > 0 && !this.ended {
+            this.end(None);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<R> Drop for Tracked<R> {
+    fn drop(&mut self) {
+        if !self.ended {
+            self.end(Some(&Error::new(
+                ErrorKind::ConnectionClosed,
+                "the download was dropped before the end",
+            )));
+        }
+    }
+}
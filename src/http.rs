@@ -0,0 +1,129 @@
+//! Images hosted on a web server, read with HTTP range requests.
+
+use crate::remote::{ImageChanged, RangeRead, RemoteStat};
+use std::io::{self, Read};
+use ureq::{Agent, Error};
+
+/// Adds authentication headers to the requests for an image.
+pub(crate) trait SignRequest: Send + Sync {
+    /// Returns the headers to send along with a GET request for `range` of the image at `url`.
+    fn sign(&self, url: &str, range: &str) -> io::Result<Vec<(&'static str, String)>>;
+}
+
+/// An image at an HTTP(S) URL whose server supports range requests.
+pub(crate) struct HttpSource {
+    agent: Agent,
+    url: String,
+    signer: Option<Box<dyn SignRequest>>,
+}
+
+impl HttpSource {
+    pub(crate) fn new(url: String) -> Self {
+        Self {
+            agent: Agent::new_with_defaults(),
+            url,
+            signer: None,
+        }
+    }
+
+    /// An image at a URL that requires authenticated requests.
+    #[cfg(any(feature = "s3", feature = "azure", feature = "gcs"))]
+    pub(crate) fn with_signer(url: String, signer: impl SignRequest + 'static) -> Self {
+        Self {
+            signer: Some(Box::new(signer)),
+            ..Self::new(url)
+        }
+    }
+
+    /// Requests `len` bytes starting at `offset`, insisting on a partial response so that a
+    /// server ignoring the range doesn't send us the whole image.
+    ///
+    /// With a `version`, the range is only sent if the image is still at that version. Servers
+    /// respond with the whole image otherwise, which is reported as [`ImageChanged`].
+    fn get_range(
+        &self,
+        offset: u64,
+        len: u64,
+        version: Option<&str>,
+    ) -> io::Result<ureq::http::Response<ureq::Body>> {
+        let range = format!("bytes={}-{}", offset, offset + len - 1);
+        let mut request = self.agent.get(&self.url).header("Range", &range);
+        if let Some(version) = version {
+            request = request.header("If-Range", version);
+        }
+        if let Some(signer) = &self.signer {
+            for (name, value) in signer.sign(&self.url, &range)? {
+                request = request.header(name, value);
+            }
+        }
+        let response = request.call().map_err(io_error)?;
+        match response.status().as_u16() {
+            206 => Ok(response),
+            200 if version.is_some() => Err(ImageChanged.into()),
+            _ => Err(io::Error::other(format!(
+                "{} doesn't support range requests",
+                self.url
+            ))),
+        }
+    }
+}
+
+impl std::fmt::Debug for HttpSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("HttpSource")
+            .field("url", &self.url)
+            .finish_non_exhaustive()
+    }
+}
+
+impl RangeRead for HttpSource {
+    fn stat(&self) -> io::Result<RemoteStat> {
+        let response = self.get_range(0, 1, None)?;
+        let header = |name| response.headers().get(name).and_then(|v| v.to_str().ok());
+
+        // The total size comes with the Content-Range header of any partial response, e.g.
+        // "bytes 0-0/1048576"
+        let len = header("Content-Range")
+            .and_then(|v| v.rsplit_once('/'))
+            .and_then(|(_, total)| total.parse().ok())
+            .ok_or_else(|| io::Error::other(format!("{} didn't report its size", self.url)))?;
+
+        // If-Range only accepts strong ETags, so fall back to the modification date for weak ones
+        let version = header("ETag")
+            .filter(|etag| !etag.starts_with("W/"))
+            .or_else(|| header("Last-Modified"))
+            .map(str::to_string);
+        Ok(RemoteStat { len, version })
+    }
+
+    fn cache_key(&self) -> String {
+        // Query strings can hold credentials, such as Azure's shared access signatures
+        let url = self.url.split_once('?').map_or(&*self.url, |(url, _)| url);
+        url.to_string()
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8], version: Option<&str>) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let mut response = self.get_range(offset, buf.len() as u64, version)?;
+        let mut body = response.body_mut().as_reader();
+        let mut filled = 0;
+        while filled < buf.len() {
+            match body.read(&mut buf[filled..])? {
+                0 => break,
+                n => filled += n,
+            }
+        }
+        Ok(filled)
+    }
+}
+
+fn io_error(e: Error) -> io::Error {
+    match e {
+        Error::StatusCode(404 | 410) => io::ErrorKind::NotFound.into(),
+        Error::StatusCode(401 | 403) => io::ErrorKind::PermissionDenied.into(),
+        Error::Io(e) => e,
+        e => io::Error::other(e),
+    }
+}
@@ -0,0 +1,470 @@
+//! The byte streams that FAT images are read from.
+
+use crate::{
+    block_cache::{BlockCache, FILE_BUFFER},
+    memory::Reservation,
+};
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+/// A seekable byte stream containing a FAT filesystem, as handed to fatfs.
+pub(crate) trait Disk: Read + Write + Seek + Send {}
+
+impl<T: Read + Write + Seek + Send> Disk for T {}
+
+/// Describes where a FAT image lives and how to open it.
+#[derive(Debug, Clone)]
+pub(crate) enum Image {
+    /// A regular file or block device, accessed with read, write and seek system calls, or a
+    /// Windows physical drive or volume, accessed in whole sectors.
+    File(PathBuf),
+    /// A regular file that is memory-mapped when opened.
+    #[cfg(feature = "mmap")]
+    Mmap(PathBuf),
+    /// A block device opened with `O_DIRECT`, bypassing the page cache.
+    #[cfg(all(feature = "direct-io", target_os = "linux"))]
+    Direct(PathBuf),
+    /// A regular file or block device read and written through io_uring.
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    Uring(PathBuf),
+    /// An image split into several files, which are read one after the other.
+    Split(Vec<PathBuf>),
+    /// An image held in memory.
+    Memory(Memory),
+    /// An image compiled into the binary, which can only be read.
+    Static(Static),
+    /// An image on a remote store, which can only be read.
+    #[cfg(feature = "http")]
+    Remote(crate::remote::Remote),
+    /// An image read from a source implemented outside this crate.
+    Source(Arc<dyn crate::ImageSource>),
+}
+
+impl Image {
+    /// An image split into the files at `parts`.
+    pub(crate) fn split<I, P>(parts: I) -> Self
+    where
+        I: IntoIterator<Item = P>,
+        P: AsRef<std::path::Path>,
+    {
+        Image::Split(
+            parts
+                .into_iter()
+                .map(|part| part.as_ref().to_path_buf())
+                .collect(),
+        )
+    }
+
+    /// An image at an HTTP(S) URL.
+    #[cfg(feature = "http")]
+    pub(crate) fn http(url: String) -> Self {
+        Image::Remote(crate::remote::Remote::new(crate::http::HttpSource::new(
+            url,
+        )))
+    }
+
+    /// Fetches remote images in blocks of `block_size` bytes. Other images are returned as they
+    /// are.
+    #[cfg(feature = "http")]
+    pub(crate) fn with_block_size(self, block_size: u64) -> Self {
+        match self {
+            Image::Remote(mut remote) => {
+                remote.block_size = block_size;
+                Image::Remote(remote)
+            }
+            image => image,
+        }
+    }
+
+    /// Caches the blocks of remote images in `cache`. Other images are returned as they are.
+    #[cfg(feature = "http")]
+    pub(crate) fn with_disk_cache(self, cache: crate::DiskCache) -> Self {
+        match self {
+            Image::Remote(mut remote) => {
+                let source = crate::cache::CachedSource::new(
+                    std::sync::Arc::clone(&remote.source),
+                    cache,
+                    remote.block_size,
+                );
+                remote.source = std::sync::Arc::new(source);
+                Image::Remote(remote)
+            }
+            image => image,
+        }
+    }
+
+    /// Opens local images with `O_DIRECT`. Other images are returned as they are.
+    #[cfg(all(feature = "direct-io", target_os = "linux"))]
+    pub(crate) fn with_direct_io(self) -> Self {
+        match self {
+            Image::File(path) => Image::Direct(path),
+            image => image,
+        }
+    }
+
+    /// Accesses local images through io_uring. Other images are returned as they are.
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    pub(crate) fn with_io_uring(self) -> Self {
+        match self {
+            Image::File(path) => Image::Uring(path),
+            image => image,
+        }
+    }
+
+    /// An image stored as an S3 object.
+    #[cfg(feature = "s3")]
+    pub(crate) fn s3(object: crate::S3Object) -> Self {
+        Image::Remote(crate::remote::Remote::new(crate::s3::source(object)))
+    }
+
+    /// An image stored as an Azure Storage blob.
+    #[cfg(feature = "azure")]
+    pub(crate) fn azure(blob: crate::AzureBlob) -> Self {
+        Image::Remote(crate::remote::Remote::new(crate::azure::source(blob)))
+    }
+
+    /// An image stored as a Google Cloud Storage object.
+    #[cfg(feature = "gcs")]
+    pub(crate) fn gcs(object: crate::GcsObject) -> Self {
+        Image::Remote(crate::remote::Remote::new(crate::gcs::source(object)))
+    }
+
+    /// Returns a number that changes whenever the image turns out to have been replaced, so that
+    /// open streams should be reopened. Only remote images are checked for this, see
+    /// [`Image::revalidate`].
+    pub(crate) fn generation(&self) -> u64 {
+        match self {
+            #[cfg(feature = "http")]
+            Image::Remote(remote) => remote.generation(),
+            _ => 0,
+        }
+    }
+
+    /// Checks whether a remote image was replaced, updating its generation if so.
+    pub(crate) fn revalidate(&self) -> io::Result<()> {
+        match self {
+            #[cfg(feature = "http")]
+            Image::Remote(remote) => remote.revalidate(),
+            _ => Ok(()),
+        }
+    }
+
+    /// Opens a new stream over the image.
+    ///
+    /// Streams opened with `writable` set to false reject writes, as do images that can only be
+    /// read regardless.
+    pub(crate) fn open(&self, writable: bool) -> io::Result<Box<dyn Disk>> {
+        match self {
+            #[cfg(windows)]
+            Image::File(path) if crate::device::is_device_path(path) => {
+                let disk = crate::device::DeviceDisk::open_windows(path, writable)?;
+                if writable {
+                    Ok(Box::new(disk))
+                } else {
+                    Ok(Box::new(ReadOnly(disk)))
+                }
+            }
+            Image::File(path) if writable => buffered(advised(
+                OpenOptions::new().read(true).write(true).open(path)?,
+            )),
+            Image::File(path) => buffered(ReadOnly(advised(File::open(path)?))),
+            #[cfg(feature = "mmap")]
+            Image::Mmap(path) => {
+                let file = File::open(path)?;
+                // SAFETY: The caller of `Vfs::new_mmap` promised that the image isn't modified
+                // while it is being served.
+                let map = unsafe { memmap2::Mmap::map(&file)? };
+                #[cfg(unix)]
+                return Ok(Box::new(ReadOnly(crate::advise::Advised::new(
+                    crate::advise::MappedFile::new(map, file),
+                ))));
+                #[cfg(not(unix))]
+                Ok(Box::new(ReadOnly(io::Cursor::new(map))))
+            }
+            #[cfg(all(feature = "direct-io", target_os = "linux"))]
+            Image::Direct(path) => {
+                let disk = crate::device::DeviceDisk::open_direct(path, writable)?;
+                if writable {
+                    Ok(Box::new(disk))
+                } else {
+                    Ok(Box::new(ReadOnly(disk)))
+                }
+            }
+            #[cfg(all(feature = "io-uring", target_os = "linux"))]
+            Image::Uring(path) => {
+                let file = OpenOptions::new().read(true).write(writable).open(path)?;
+                // Kernels and sandboxes without io_uring get regular system calls instead
+                match (crate::uring::UringDisk::new(file), writable) {
+                    (Ok(disk), true) => buffered(disk),
+                    (Ok(disk), false) => buffered(ReadOnly(disk)),
+                    (Err(file), true) => buffered(file),
+                    (Err(file), false) => buffered(ReadOnly(file)),
+                }
+            }
+            Image::Split(paths) => {
+                let split = crate::split::Split::open(&crate::split::parts(paths)?, writable)?;
+                if writable {
+                    buffered(split)
+                } else {
+                    buffered(ReadOnly(split))
+                }
+            }
+            Image::Memory(memory) if writable => Ok(Box::new(memory.open())),
+            Image::Memory(memory) => Ok(Box::new(ReadOnly(memory.open()))),
+            Image::Static(Static(bytes)) => Ok(Box::new(ReadOnly(io::Cursor::new(*bytes)))),
+            #[cfg(feature = "http")]
+            Image::Remote(remote) => Ok(Box::new(remote.open()?)),
+            Image::Source(source) => {
+                let disk = crate::source::SourceDisk::open(Arc::clone(source))?;
+                if writable {
+                    Ok(Box::new(disk))
+                } else {
+                    Ok(Box::new(ReadOnly(disk)))
+                }
+            }
+        }
+    }
+
+    /// Names the image for spans, by the path of local images and without any secrets for
+    /// remote ones.
+    pub(crate) fn name(&self) -> String {
+        match self {
+            Image::File(path) => path.display().to_string(),
+            #[cfg(feature = "mmap")]
+            Image::Mmap(path) => path.display().to_string(),
+            #[cfg(all(feature = "direct-io", target_os = "linux"))]
+            Image::Direct(path) => path.display().to_string(),
+            #[cfg(all(feature = "io-uring", target_os = "linux"))]
+            Image::Uring(path) => path.display().to_string(),
+            Image::Split(parts) => parts
+                .first()
+                .map(|part| part.display().to_string())
+                .unwrap_or_default(),
+            Image::Memory(_) => "memory".to_string(),
+            Image::Static(_) => "static".to_string(),
+            #[cfg(feature = "http")]
+            Image::Remote(remote) => remote.source.cache_key(),
+            Image::Source(_) => "source".to_string(),
+        }
+    }
+
+    /// The path of images on the local filesystem.
+    #[cfg(feature = "vmdk")]
+    pub(crate) fn path(&self) -> Option<&std::path::Path> {
+        match self {
+            Image::File(path) => Some(path),
+            #[cfg(feature = "mmap")]
+            Image::Mmap(path) => Some(path),
+            #[cfg(all(feature = "io-uring", target_os = "linux"))]
+            Image::Uring(path) => Some(path),
+            _ => None,
+        }
+    }
+}
+
+/// Gives the kernel hints while a local image file is read sequentially, where it takes them.
+fn advised(file: File) -> impl Disk {
+    #[cfg(target_os = "linux")]
+    let file = crate::advise::Advised::new(file);
+    file
+}
+
+/// Buffers reads of a stream over local files, which fatfs otherwise reads a few bytes at a time
+/// with a system call each.
+fn buffered(disk: impl Disk + 'static) -> io::Result<Box<dyn Disk>> {
+    Ok(Box::new(BlockCache::new(
+        Box::new(disk),
+        FILE_BUFFER,
+        Reservation::default(),
+    )?))
+}
+
+/// Adapts a read-only stream to fatfs, which insists on `Write` even when only reading.
+///
+/// Any attempt to write fails with `PermissionDenied`.
+pub(crate) struct ReadOnly<T>(pub(crate) T);
+
+impl<T: Read> Read for ReadOnly<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl<T: Seek> Seek for ReadOnly<T> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.0.seek(pos)
+    }
+}
+
+impl<T> Write for ReadOnly<T> {
+    fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+        Err(io::ErrorKind::PermissionDenied.into())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Restricts a stream to the `len` bytes starting at `start`, for instance to a partition.
+///
+/// Offsets are relative to `start`, so fatfs sees the slice as a disk of its own.
+pub(crate) struct Slice<T> {
+    inner: T,
+    start: u64,
+    len: u64,
+    pos: u64,
+}
+
+impl<T: Seek> Slice<T> {
+    pub(crate) fn new(mut inner: T, start: u64, len: u64) -> io::Result<Self> {
+        inner.seek(SeekFrom::Start(start))?;
+        Ok(Self {
+            inner,
+            start,
+            len,
+            pos: 0,
+        })
+    }
+}
+
+impl<T: Read> Read for Slice<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let max = self.len.saturating_sub(self.pos).min(buf.len() as u64) as usize;
+        let n = self.inner.read(&mut buf[..max])?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl<T: Write> Write for Slice<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let max = self.len.saturating_sub(self.pos).min(buf.len() as u64) as usize;
+        if max == 0 && !buf.is_empty() {
+            return Err(io::ErrorKind::WriteZero.into());
+        }
+        let n = self.inner.write(&buf[..max])?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<T: Seek> Seek for Slice<T> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+        }
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "seek to a negative offset"))?;
+        self.inner.seek(SeekFrom::Start(self.start + new_pos))?;
+        self.pos = new_pos;
+        Ok(new_pos)
+    }
+}
+
+/// The bytes of an in-memory image, shared by all streams opened over it so that writes through
+/// one are seen by the others.
+#[derive(Clone)]
+pub(crate) struct Memory(Arc<Mutex<Vec<u8>>>);
+
+impl Memory {
+    pub(crate) fn new(bytes: Vec<u8>) -> Self {
+        Self(Arc::new(Mutex::new(bytes)))
+    }
+
+    fn open(&self) -> MemoryDisk {
+        MemoryDisk {
+            bytes: Arc::clone(&self.0),
+            pos: 0,
+        }
+    }
+}
+
+impl std::fmt::Debug for Memory {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let len = self.0.lock().map(|bytes| bytes.len()).ok();
+        f.debug_struct("Memory").field("len", &len).finish()
+    }
+}
+
+/// The bytes of an image with a static lifetime, typically from `include_bytes!`.
+#[derive(Clone, Copy)]
+pub(crate) struct Static(pub(crate) &'static [u8]);
+
+impl std::fmt::Debug for Static {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Static")
+            .field("len", &self.0.len())
+            .finish()
+    }
+}
+
+/// A stream over an in-memory image.
+///
+/// Unlike a `Cursor<Vec<u8>>` this never grows the image: writes past its end fail with
+/// `WriteZero`, as they would on a disk.
+struct MemoryDisk {
+    bytes: Arc<Mutex<Vec<u8>>>,
+    pos: u64,
+}
+
+impl MemoryDisk {
+    fn lock(&self) -> io::Result<std::sync::MutexGuard<'_, Vec<u8>>> {
+        self.bytes
+            .lock()
+            .map_err(|_| io::Error::other("in-memory image lock poisoned"))
+    }
+}
+
+impl Read for MemoryDisk {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let bytes = self.lock()?;
+        let start = (self.pos as usize).min(bytes.len());
+        let n = (bytes.len() - start).min(buf.len());
+        buf[..n].copy_from_slice(&bytes[start..start + n]);
+        drop(bytes);
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Write for MemoryDisk {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut bytes = self.lock()?;
+        let start = (self.pos as usize).min(bytes.len());
+        let n = (bytes.len() - start).min(buf.len());
+        if n == 0 && !buf.is_empty() {
+            return Err(io::ErrorKind::WriteZero.into());
+        }
+        bytes[start..start + n].copy_from_slice(&buf[..n]);
+        drop(bytes);
+        self.pos += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for MemoryDisk {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let len = self.lock()?.len() as u64;
+        self.pos = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+        }
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "seek to a negative offset"))?;
+        Ok(self.pos)
+    }
+}
@@ -0,0 +1,67 @@
+//! The capacity of FAT filesystems, as returned by [`Vfs::image_info`](crate::Vfs::image_info).
+
+use crate::{FatVariant, volume::FatVolume};
+use std::io;
+
+/// The variant and capacity of a FAT filesystem, as returned by
+/// [`Vfs::image_info`](crate::Vfs::image_info).
+///
+/// # Example
+///
+/// ```rust,no_run
+/// use unftp_sbe_fatfs::Vfs;
+///
+/// # async fn run() -> Result<(), Box<dyn std::error::Error>> {
+/// let info = Vfs::new("path/to/fat/image.img").image_info().await?;
+/// println!(
+///     "{}: {} of {} bytes free",
+///     info.variant,
+///     info.free_clusters as u64 * info.cluster_size,
+///     info.total_clusters as u64 * info.cluster_size
+/// );
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct ImageInfo {
+    /// The FAT variant the filesystem is formatted with.
+    pub variant: FatVariant,
+    /// The size of clusters, in bytes.
+    pub cluster_size: u64,
+    /// The number of clusters of the filesystem, which are either used, free or bad.
+    pub total_clusters: u32,
+    /// The number of clusters the FAT marks as in use.
+    pub used_clusters: u32,
+    /// The number of clusters the FAT marks as free.
+    pub free_clusters: u32,
+    /// The number of clusters the FAT marks as bad, which are never used.
+    pub bad_clusters: u32,
+    /// The number of entries the root directory holds on FAT12 and FAT16, `None` on FAT32, whose
+    /// root directory grows like others.
+    pub root_entries: Option<u32>,
+}
+
+impl ImageInfo {
+    /// Reads the capacity of the filesystem of `volume`, counting the free and bad clusters in
+    /// its FAT rather than trusting the count FAT32 keeps, which may be stale.
+    pub(crate) fn read(volume: &FatVolume) -> io::Result<Self> {
+        let raw = volume.raw();
+        let (mut free, mut bad) = (0, 0);
+        raw.for_each_link(|_, link| match link {
+            0 => free += 1,
+            _ if raw.is_bad(link) => bad += 1,
+            _ => {}
+        })?;
+        let total = raw.end_cluster() - 2;
+        Ok(Self {
+            variant: volume.fat_type().into(),
+            cluster_size: raw.cluster_len(),
+            total_clusters: total,
+            used_clusters: total.saturating_sub(free + bad),
+            free_clusters: free,
+            bad_clusters: bad,
+            root_entries: raw.root_entries(),
+        })
+    }
+}
@@ -1,4 +1,39 @@
-//! A storage backend for [libunftp](https://github.com/bolcom/libunftp) that provides read-only access to FAT filesystem images.
+//! A storage backend for [libunftp](https://github.com/bolcom/libunftp) that provides access to FAT filesystem images.
+//!
+//! Images are served read-only by default. Uploads, directory management and renames are available
+//! when a [`Vfs`] is created in [`Mode::ReadWrite`].
+//!
+//! With the `exfat` feature enabled, exFAT images are detected and served as well. These are
+//! always read-only, whatever the mode.
+//!
+//! With the `gzip` feature enabled, gzip-compressed images such as `foo.img.gz` are recognized by
+//! their magic bytes and decompressed into a temporary file in [`std::env::temp_dir`] when first
+//! opened. The `xz` and `zstd` features do the same for xz- and zstd-compressed images, except
+//! that images in zstd's seekable format are read in place, decompressing only the frames that
+//! are needed.
+//! Compressed images are read-only as well.
+//!
+//! With the `qcow2` feature enabled, QEMU qcow2 virtual disks are recognized too and the disk in
+//! them is read in place. Partitions are then looked up on that disk. The `vhd` feature does the
+//! same for the dynamic VHD and VHDX disks exported by Hyper-V and Azure. Fixed VHDs are raw disks
+//! with a footer, so they are served without it. The `vmdk` feature adds VMware's monolithic sparse
+//! and stream-optimized VMDK disks, and flat ones when given the descriptor file of a local image.
+//! The `android-sparse` feature reads the sparse images of the Android build in place, without
+//! expanding them with `simg2img` first.
+//!
+//! Every operation runs in a [`tracing`](https://docs.rs/tracing) span named `fatfs`, which
+//! records the user, the paths, the bytes transferred and how long it took. Failures are logged
+//! in the span at `INFO` level along with the error that caused them, such as the I/O error
+//! behind a `550` reply.
+//!
+//! With the `metrics` feature enabled, opens, listings, downloads, bytes served, errors by kind
+//! and the durations of operations are exported as Prometheus metrics prefixed with `fatfs_`,
+//! registered with the default registry alongside libunftp's own.
+//!
+//! With the `opentelemetry` feature enabled, every operation emits an OpenTelemetry span named
+//! after it, such as `fatfs.download`, through the global tracer provider. Spans carry the image,
+//! the FTP paths, the user and the bytes transferred, and downloads end theirs once the last byte
+//! was read.
 //!
 //! This crate implements a storage backend for the libunftp FTP server library, allowing you to serve files from FAT filesystem images (`.img` files) over FTP.
 //!
@@ -49,29 +84,177 @@
 //!
 //! # Limitations
 //!
-//! - Read-only access (no file uploads, deletions, or modifications)
+//! - No file deletions
+//! - Directories can be renamed but not moved to another parent directory
 //! - No support for symbolic links
 
+#[cfg(any(target_os = "linux", all(feature = "mmap", unix)))]
+mod advise;
+#[cfg(feature = "android-sparse")]
+mod android_sparse;
+mod audit;
+#[cfg(feature = "azure")]
+mod azure;
+mod backup_boot;
+mod batch;
+mod block_cache;
+#[cfg(feature = "vhd")]
+mod block_map;
+mod boot_sector;
+mod buffer_pool;
+mod builder;
+#[cfg(feature = "http")]
+mod cache;
+mod checksum;
+#[cfg(any(feature = "s3", feature = "azure", feature = "gcs"))]
+mod cloud;
+mod cluster_map;
+mod corruption;
+mod decoding;
+#[cfg(any(all(feature = "direct-io", target_os = "linux"), windows))]
+mod device;
+mod diagnose;
+mod dir_size;
+mod dirty;
+mod download;
+mod error;
+#[cfg(feature = "exfat")]
+mod exfat;
+mod fat_cache;
+mod format;
+#[cfg(feature = "gcs")]
+mod gcs;
+mod hash;
+mod health;
+mod hooks;
+#[cfg(feature = "http")]
+mod http;
+mod image;
+mod image_info;
+mod media;
+mod memory;
+#[cfg(feature = "metrics")]
+mod metrics;
+mod open_error;
+mod open_limit;
+mod overlay;
+mod partition;
+mod policy;
+mod preload;
+#[cfg(feature = "qcow2")]
+mod qcow2;
+mod raw;
+mod redact;
+mod registry;
+#[cfg(feature = "http")]
+mod remote;
+mod repair;
+#[cfg(feature = "s3")]
+mod s3;
+mod sanitize;
+mod source;
+mod span;
+mod split;
+mod spool;
+mod stats;
+mod throttle;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+mod uring;
+mod validate;
+#[cfg(feature = "vhd")]
+mod vhd;
+#[cfg(feature = "vmdk")]
+mod vmdk;
+mod volume;
+mod warm_up;
+#[cfg(feature = "zstd")]
+mod zstd;
+
+pub use audit::{AuditEvent, AuditSink};
+#[cfg(feature = "azure")]
+pub use azure::AzureBlob;
+pub use boot_sector::{BootSectorInfo, FatVariant};
+pub use builder::VfsBuilder;
+#[cfg(feature = "http")]
+pub use cache::DiskCache;
+pub use checksum::ChecksumAlgorithm;
+use checksum::Hasher;
+pub use cluster_map::{ClusterMap, ClusterState};
+pub use corruption::CorruptionPolicy;
+pub use decoding::PathDecoding;
+pub use dir_size::DirSize;
+pub use dirty::DirtyVolume;
+/// The fatfs version in use, for implementing its `TimeProvider` and `OemCpConverter` traits.
+pub use fatfs;
+
 use async_trait::async_trait;
-use fatfs::{DateTime, DirEntry, FileSystem, FsOptions};
+use buffer_pool::BufferPool;
+use builder::FsConfig;
+use download::Sent;
+pub use error::FatError;
+use fatfs::{Date, DateTime, FileSystem, Time};
+#[cfg(feature = "gcs")]
+pub use gcs::{GcsObject, ParseGcsUrlError};
+pub use health::Health;
+use hooks::Tracked;
+pub use hooks::TransferHooks;
+use image::{Disk, Image, Memory, Static};
+pub use image_info::ImageInfo;
+pub use memory::MemoryBudget;
+pub use open_error::OpenError;
+pub use partition::{Guid, ParseGuidError, PartitionSelect};
+pub use policy::{Decision, Operation, UserPolicy};
+pub use redact::PathRedaction;
+use redact::{Redacted, RedactedCause};
+pub use repair::RepairReport;
+#[cfg(feature = "s3")]
+pub use s3::{ParseS3UrlError, S3Object};
+pub use source::ImageSource;
+use span::OperationSpan;
+use spool::Spool;
+use stats::Timed;
+pub use stats::{DownloadStats, Latencies, LatencyHistogram, Stats};
 use std::{
+    collections::{HashMap, HashSet},
     fmt::Debug,
-    fs::File,
-    io::{Cursor, Read, Seek, SeekFrom},
+    io::{self, Read, Seek, SeekFrom, Write},
     path::{Path, PathBuf},
-    time::Duration,
-    time::SystemTime,
+    sync::{
+        Arc, Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+    time::{Duration, Instant, SystemTime},
+};
+use throttle::{RateLimit, Throttled};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt},
+    sync::{OnceCell, OwnedSemaphorePermit, Semaphore, mpsc},
+    task::AbortHandle,
 };
 use unftp_core::{
     auth::UserDetail,
-    storage::{Error, ErrorKind, Fileinfo, Metadata, Result, StorageBackend},
+    storage::{Error, ErrorKind, FEATURE_SITEMD5, Fileinfo, Metadata, Result, StorageBackend},
 };
+pub use validate::{CrossLink, Finding, ValidationReport};
+use volume::{FatEntry, FatVolume};
+
+/// The size of the chunks uploads are handed to fatfs in.
+const WRITE_CHUNK_SIZE: usize = 64 * 1024;
+
+/// The number of chunks of an upload received ahead of fatfs writing them.
+const UPLOAD_CHUNKS: usize = 4;
 
-/// A virtual file system that provides read-only access to FAT filesystem images.
+/// How many filesystems opened for downloads are kept open for the next downloads once done.
+const IDLE_READERS: usize = 4;
+
+/// How many of the entries that differ between the copies of the FAT are told.
+const MISMATCHES_REPORTED: usize = 8;
+
+/// A virtual file system that provides access to FAT filesystem images.
 ///
 /// This struct implements the `StorageBackend` trait from libunftp, allowing it to be used
-/// as a storage backend for an FTP server. It provides read-only access to the contents
-/// of a FAT filesystem image file.
+/// as a storage backend for an FTP server. It provides access to the contents of a FAT
+/// filesystem image file, read-only unless created with [`Vfs::new_rw`].
 ///
 /// # Example
 ///
@@ -80,42 +263,2027 @@ use unftp_core::{
 ///
 /// let vfs = Vfs::new("path/to/fat/image.img");
 /// ```
-#[derive(Debug, Clone)]
+///
+/// The FAT image is opened lazily on first use and the resulting filesystem handle is kept
+/// around for subsequent operations. Clones of a `Vfs`, such as the one libunftp makes for every
+/// connection, share that handle along with all caches. So do file systems created for the same
+/// image files with the same configuration, such as those of servers listening on several ports.
+#[derive(Clone)]
 pub struct Vfs {
-    img_path: PathBuf,
+    shared: Arc<Shared>,
+    /// Decides what users may do and how fast, if anything decides.
+    policy: Option<Arc<dyn UserPolicy>>,
+    /// Told about transfers and failed operations, if anything is.
+    hooks: Option<Arc<dyn TransferHooks>>,
+    /// Told about every operation once it is done, if anything is.
+    audit: Option<Arc<dyn AuditSink>>,
+}
+
+/// The state of a [`Vfs`], shared by all its clones.
+struct Shared {
+    image: Image,
+    /// Decodes the image if it is compressed, shared with the partitions in
+    /// [`PartitionSelect::All`] mode.
+    decoder: Arc<format::Decoder>,
+    /// The buffers downloads are read into, shared with the partitions in
+    /// [`PartitionSelect::All`] mode.
+    buffers: Arc<BufferPool>,
+    /// The copy of the image in memory, if one is kept, shared with the partitions in
+    /// [`PartitionSelect::All`] mode.
+    preload: Arc<preload::Preload>,
+    /// Limits how many times the image is opened at once, shared with the partitions in
+    /// [`PartitionSelect::All`] mode.
+    opens: Arc<open_limit::OpenLimit>,
+    /// Limits how many transfers are in progress at once, if they are limited, shared with the
+    /// partitions in [`PartitionSelect::All`] mode.
+    transfers: Option<Arc<Semaphore>>,
+    /// Limits the bytes per second of all transfers together, if they are limited.
+    total_rate: Option<Arc<RateLimit>>,
+    /// Counts the downloads served and times operations, shared with the partitions in
+    /// [`PartitionSelect::All`] mode.
+    stats: Arc<stats::Recorder>,
+    partition: PartitionSelect,
+    mode: Mode,
+    fs_options: FsConfig,
+    /// Taken shared by operations that only read the filesystem and exclusively by those that
+    /// modify it, before locking `fs`, so that writes never overlap with reads in progress.
+    access: RwLock<()>,
+    /// How many times `access` was taken exclusively, which tells downloads, which take it
+    /// shared a chunk at a time, whether the filesystem may have changed in between.
+    writes: AtomicU64,
+    fs: Mutex<Option<FsHandle>>,
+    /// Filesystems that downloads opened for themselves and no longer use, along with the
+    /// generation of the image they were opened at.
+    readers: Mutex<Vec<(u64, FsHandle)>>,
+    /// The generation of the image the cached filesystem handle was opened at.
+    fs_generation: AtomicU64,
+    /// Set when the media of the image went away, until the image could be opened again.
+    media_lost: AtomicBool,
+    /// The generation of the image whose filesystem was checked when opened, `u64::MAX` if
+    /// none was.
+    checked: AtomicU64,
+    /// The blocks [`Vfs::repair`] wrote to the image while it is served read-only.
+    overlay: overlay::Overlay,
+    /// The path of the file or directory each cross-linked entry, by its offset, shares clusters
+    /// with, as found when the image was opened with [`VfsBuilder::warn_cross_links`].
+    cross_linked: Mutex<HashMap<u64, PathBuf>>,
+    /// The sizes [`Vfs::dir_size`] found, by absolute path, when kept with
+    /// [`VfsBuilder::cache_dir_sizes`], until the filesystem is written or reopened, along with
+    /// how deep they were counted.
+    dir_sizes: Mutex<HashMap<(PathBuf, Option<u32>), DirSize>>,
+    /// The checksums [`Vfs::checksum`] computed, when kept with [`VfsBuilder::cache_checksums`].
+    checksums: Mutex<checksum::Checksums>,
+    volumes: OnceCell<Vec<Volume>>,
+    /// Whether this is a partition served in [`PartitionSelect::All`] mode, whose failures the
+    /// file system of the whole image counts in the metrics.
+    #[cfg(feature = "metrics")]
+    volume: bool,
+}
+
+/// A partition served as a top-level directory in [`PartitionSelect::All`] mode.
+#[derive(Debug, Clone)]
+struct Volume {
+    name: String,
+    vfs: Vfs,
+}
+
+/// Names `volumes` after their volume labels, see [`VfsBuilder::name_partitions_by_label`].
+async fn name_by_label(volumes: &mut [Volume]) {
+    let mut labels = Vec::with_capacity(volumes.len());
+    for volume in volumes.iter() {
+        let label = volume.vfs.volume_label().await.ok().flatten();
+        labels.push(label.filter(|label| {
+            label.trim() == label
+                && !matches!(label.as_str(), "" | "." | "..")
+                && !label.contains(|c: char| c.is_control() || c == '/' || c == '\\')
+        }));
+    }
+    for (index, label) in labels.iter().enumerate() {
+        let Some(label) = label else {
+            continue;
+        };
+        let others = labels
+            .iter()
+            .flatten()
+            .chain(volumes.iter().map(|v| &v.name));
+        if others
+            .filter(|other| other.eq_ignore_ascii_case(label))
+            .count()
+            == 1
+        {
+            volumes[index].name = label.clone();
+        }
+    }
+}
+
+/// Where a path given to the storage backend ends up.
+enum Route {
+    /// A path within this file system's own FAT filesystem.
+    Local(PathBuf),
+    /// The virtual root directory that lists the partitions in [`PartitionSelect::All`] mode.
+    Partitions,
+    /// A path within one of the partitions in [`PartitionSelect::All`] mode.
+    Partition(Box<Vfs>, PathBuf),
+}
+
+/// How an operation uses the filesystem of a [`Vfs`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Access {
+    /// The operation only reads, alongside any other reads.
+    Read,
+    /// The operation modifies the filesystem and needs it to itself.
+    Write,
 }
 
-impl Vfs {
-    /// Creates a new virtual file system that provides access to the FAT image file
-    /// at the given path.
+/// Access to the filesystem of a [`Vfs`] taken with [`Vfs::lock_access`], held until dropped.
+/// The guards are never read, only dropped.
+#[allow(dead_code)]
+enum AccessGuard<'a> {
+    Read(RwLockReadGuard<'a, ()>),
+    Write(RwLockWriteGuard<'a, ()>),
+}
+
+/// Whether `result` failed because the media of the image went away, see [`Vfs::media_error`].
+fn is_lost<R>(result: &Result<R>) -> bool {
+    matches!(result, Err(e) if e.kind() == ErrorKind::TransientFileNotAvailable)
+}
+
+/// Cancels a task when dropped, unless it started or finished already.
+struct CancelOnDrop(AbortHandle);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Whether a [`Vfs`] allows modifications to its image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Mode {
+    /// Clients can list and download but any modification is refused with a permission error.
+    /// The image is opened read-only.
+    #[default]
+    ReadOnly,
+    /// Clients can additionally upload files, create and remove directories and rename entries.
+    /// The image is opened for writing, which fails if the image file isn't writable.
+    ReadWrite,
+}
+
+/// An opened filesystem that can be moved between threads.
+///
+/// fatfs' `FsOptions` holds `&'static dyn` references to the OEM code page converter and the time
+/// provider without a `Sync` bound, which makes `FileSystem` `!Send`.
+enum FsHandle {
+    /// A FAT12, FAT16 or FAT32 filesystem.
+    Fat(FatVolume),
+    /// An exFAT filesystem, which is always read-only.
+    #[cfg(feature = "exfat")]
+    ExFat(exfat::ExFatVolume),
+}
+
+/// The fatfs filesystem type used throughout the crate.
+type Fs = FileSystem<Box<dyn Disk>>;
+
+// SAFETY: The only non-`Send` parts of a `FileSystem` are the `&'static` converter and time
+// provider references in its `FsOptions`. These are either fatfs' defaults, which are stateless
+// unit structs, or come from `FsConfig` which requires them to be `Sync`. Either way sharing them
+// between threads is sound. The other variants are `Send` on their own.
+unsafe impl Send for FsHandle {}
+
+impl Shared {
+    fn new(image: Image, partition: PartitionSelect, mode: Mode, fs_options: FsConfig) -> Self {
+        #[cfg(feature = "metrics")]
+        metrics::register();
+        Self {
+            image,
+            decoder: Arc::new(format::Decoder::new(fs_options.memory)),
+            buffers: Arc::new(BufferPool::new(fs_options.buffers)),
+            preload: Arc::new(preload::Preload::new(fs_options.preload, fs_options.memory)),
+            opens: Arc::new(open_limit::OpenLimit::new(
+                fs_options
+                    .max_opens
+                    .unwrap_or(open_limit::DEFAULT_MAX_OPENS),
+            )),
+            transfers: fs_options
+                .max_transfers
+                .map(|max| Arc::new(Semaphore::new(max.min(Semaphore::MAX_PERMITS)))),
+            total_rate: fs_options
+                .total_rate
+                .map(|bytes_per_second| Arc::new(RateLimit::new(bytes_per_second))),
+            stats: Arc::default(),
+            partition,
+            mode,
+            fs_options,
+            access: RwLock::new(()),
+            fs: Mutex::new(None),
+            readers: Mutex::new(Vec::new()),
+            writes: AtomicU64::new(0),
+            fs_generation: AtomicU64::new(0),
+            media_lost: AtomicBool::new(false),
+            checked: AtomicU64::new(u64::MAX),
+            overlay: overlay::Overlay::default(),
+            cross_linked: Mutex::default(),
+            dir_sizes: Mutex::default(),
+            checksums: Mutex::default(),
+            volumes: OnceCell::new(),
+            #[cfg(feature = "metrics")]
+            volume: false,
+        }
+    }
+}
+
+impl Debug for Vfs {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Vfs")
+            .field("image", &self.shared.image)
+            .field("partition", &self.shared.partition)
+            .field("mode", &self.shared.mode)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Vfs {
+    /// Creates a new virtual file system that provides access to the FAT image file
+    /// at the given path.
+    ///
+    /// Disk images with an MBR or GPT partition table are served too: the first partition that
+    /// holds a FAT filesystem is picked automatically, see [`PartitionSelect::Auto`].
+    ///
+    /// The path may also be a block device such as `/dev/sdb1` or `/dev/mmcblk0p1`, to serve an
+    /// attached SD card without imaging it first. On Linux, `VfsBuilder::direct_io` of the
+    /// `direct-io` feature bypasses the page cache when doing so. On Windows, physical drives and
+    /// volumes such as `\\.\PhysicalDrive2` and `\\.\E:` are read in whole sectors as Windows
+    /// requires. Windows doesn't allow writing to a volume while it is mounted.
+    ///
+    /// Pulling the media, such as the SD card in a card reader, makes operations fail with a
+    /// transient error until it is inserted again, after which the image is reopened.
+    ///
+    /// # Arguments
+    ///
+    /// * `img_path` - The path to the FAT filesystem image file
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use unftp_sbe_fatfs::Vfs;
+    ///
+    /// let vfs = Vfs::new("path/to/fat/image.img");
+    /// ```
+    pub fn new<P: AsRef<Path>>(img_path: P) -> Self {
+        Self::with_image(Image::File(img_path.as_ref().to_path_buf()), Mode::ReadOnly)
+    }
+
+    /// Creates a new virtual file system like [`Vfs::new`], but opens the image and mounts its
+    /// filesystem right away, so that a mistyped path or an image that isn't FAT is reported at
+    /// startup rather than when the first client connects.
+    ///
+    /// The filesystem stays open for the operations to come. This blocks while the image is
+    /// read, so call it before the server starts or with [`tokio::task::spawn_blocking`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an error telling the path of the image and why it couldn't be opened or mounted,
+    /// along with what the image holds instead where that can be told, such as another
+    /// filesystem or a partition table. Images that end before their filesystem does, as
+    /// happens when copying them was cut short, are refused too.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use unftp_sbe_fatfs::Vfs;
+    ///
+    /// let vfs = match Vfs::try_new("path/to/fat/image.img") {
+    ///     Ok(vfs) => vfs,
+    ///     Err(e) => {
+    ///         eprintln!("{e}");
+    ///         std::process::exit(1);
+    ///     }
+    /// };
+    /// ```
+    pub fn try_new<P: AsRef<Path>>(img_path: P) -> std::result::Result<Self, OpenError> {
+        let vfs = Self::new(&img_path);
+        vfs.with_handle(Access::Read, |_| Ok(()))
+            .and_then(|()| vfs.check_len())
+            .map_err(|e| OpenError::new(img_path.as_ref(), e))?;
+        Ok(vfs)
+    }
+
+    /// Creates a new virtual file system that provides read-write access to the FAT image file
+    /// at the given path.
+    ///
+    /// Write operations are applied directly to the image. Don't serve the same image from more
+    /// than one writable `Vfs` at a time, fatfs doesn't coordinate between filesystem instances.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use unftp_sbe_fatfs::{Mode, Vfs};
+    ///
+    /// let vfs = Vfs::new_rw("path/to/fat/image.img");
+    /// assert_eq!(vfs.mode(), Mode::ReadWrite);
+    /// ```
+    pub fn new_rw<P: AsRef<Path>>(img_path: P) -> Self {
+        Self::with_image(
+            Image::File(img_path.as_ref().to_path_buf()),
+            Mode::ReadWrite,
+        )
+    }
+
+    /// Creates a new virtual file system that provides read-only access to a FAT filesystem in
+    /// a partition of the disk image at the given path.
+    ///
+    /// This is what `dd` dumps of USB sticks and SD cards look like: the image starts with an
+    /// MBR partition table rather than with the filesystem.
+    ///
+    /// # Arguments
+    ///
+    /// * `img_path` - The path to the disk image file
+    /// * `index` - The zero-based index of the partition in the partition table
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use unftp_sbe_fatfs::Vfs;
+    ///
+    /// // Serve the first partition
+    /// let vfs = Vfs::new_partition("path/to/sdcard.img", 0);
+    /// ```
+    pub fn new_partition<P: AsRef<Path>>(img_path: P, index: usize) -> Self {
+        Self::builder(img_path)
+            .partition(PartitionSelect::Index(index))
+            .build()
+    }
+
+    /// Creates a new virtual file system that provides read-only access to a FAT filesystem
+    /// embedded in the image at the given path, at a fixed offset.
+    ///
+    /// This is how firmware images are often laid out: a bootloader or header comes first and the
+    /// filesystem follows at an offset that's known up front, without a partition table.
+    ///
+    /// # Arguments
+    ///
+    /// * `img_path` - The path to the image file
+    /// * `offset` - The offset of the filesystem in the image, in bytes
+    /// * `len` - The size of the filesystem, in bytes
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use unftp_sbe_fatfs::Vfs;
+    ///
+    /// // Serve the 8 MiB filesystem that follows a 1 MiB bootloader
+    /// let vfs = Vfs::new_with_offset("path/to/firmware.bin", 1024 * 1024, 8 * 1024 * 1024);
+    /// ```
+    pub fn new_with_offset<P: AsRef<Path>>(img_path: P, offset: u64, len: u64) -> Self {
+        Self::builder(img_path)
+            .partition(PartitionSelect::Range { offset, len })
+            .build()
+    }
+
+    /// Creates a new virtual file system that provides read-only access to a FAT image split
+    /// into several files, which are read one after the other as if they were a single file.
+    ///
+    /// Passing just the first part of an image split into numbered parts, like `disk.img.001`,
+    /// serves it along with the consecutively numbered parts next to it, `disk.img.002` and so
+    /// on. Use [`VfsBuilder::new_split`] to make the image writable; writes stay within the
+    /// existing parts.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use unftp_sbe_fatfs::Vfs;
+    ///
+    /// let vfs = Vfs::new_split(["path/to/sdcard.img.001"]);
+    /// let vfs = Vfs::new_split(["path/to/part-a.img", "path/to/part-b.img"]);
+    /// ```
+    pub fn new_split<I, P>(parts: I) -> Self
+    where
+        I: IntoIterator<Item = P>,
+        P: AsRef<Path>,
+    {
+        Self::with_image(Image::split(parts), Mode::ReadOnly)
+    }
+
+    /// Creates a new virtual file system that memory-maps the FAT image file at the given path
+    /// instead of reading it with system calls.
+    ///
+    /// This is considerably faster for local images since fatfs' many small reads turn into
+    /// plain memory accesses. The image is mapped when it is first accessed, not here.
+    ///
+    /// # Safety
+    ///
+    /// The image file must not be modified, truncated or replaced in place, by this or any other
+    /// process, while it is being served. Changing the underlying file of a memory map is
+    /// undefined behaviour and can, among other things, crash the server with `SIGBUS`.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use unftp_sbe_fatfs::Vfs;
+    ///
+    /// // SAFETY: Nothing modifies the image while the server runs.
+    /// let vfs = unsafe { Vfs::new_mmap("path/to/fat/image.img") };
+    /// ```
+    #[cfg(feature = "mmap")]
+    pub unsafe fn new_mmap<P: AsRef<Path>>(img_path: P) -> Self {
+        Self::with_image(Image::Mmap(img_path.as_ref().to_path_buf()), Mode::ReadOnly)
+    }
+
+    /// Creates a new virtual file system that provides read-only access to a FAT image held in
+    /// memory, without touching the local filesystem.
+    ///
+    /// Use [`VfsBuilder::from_bytes`] to make the image writable. Writes then modify the bytes
+    /// in memory only.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use unftp_sbe_fatfs::Vfs;
+    ///
+    /// let bytes = std::fs::read("examples/my.img").unwrap();
+    /// let vfs = Vfs::from_bytes(bytes);
+    /// ```
+    pub fn from_bytes(bytes: Vec<u8>) -> Self {
+        Self::with_image(Image::Memory(Memory::new(bytes)), Mode::ReadOnly)
+    }
+
+    /// Creates a new virtual file system that serves a FAT image compiled into the binary, so
+    /// that serving it doesn't depend on any files at runtime.
+    ///
+    /// The image is read in place without being copied. It can't be modified, so it stays
+    /// read-only even when built with [`Mode::ReadWrite`].
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use unftp_sbe_fatfs::Vfs;
+    ///
+    /// let vfs = Vfs::from_static(include_bytes!("../examples/my.img"));
+    /// ```
+    pub fn from_static(bytes: &'static [u8]) -> Self {
+        Self::with_image(Image::Static(Static(bytes)), Mode::ReadOnly)
+    }
+
+    /// Creates a new virtual file system that provides read-only access to a FAT image read from
+    /// a custom [`ImageSource`], such as a storage system this crate doesn't support itself.
+    ///
+    /// Use [`VfsBuilder::from_source`] to make the image writable, for sources that implement
+    /// [`ImageSource::write_at`].
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use unftp_sbe_fatfs::Vfs;
+    ///
+    /// let file = std::fs::File::open("examples/my.img").unwrap();
+    /// let vfs = Vfs::from_source(file);
+    /// ```
+    pub fn from_source(source: impl ImageSource + 'static) -> Self {
+        Self::with_image(Image::Source(Arc::new(source)), Mode::ReadOnly)
+    }
+
+    /// Creates a new virtual file system that provides read-only access to a FAT image on a web
+    /// server.
+    ///
+    /// Rather than downloading the image, only the byte ranges that are needed are fetched with
+    /// HTTP range requests, so the server must support them. The image is always read-only.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use unftp_sbe_fatfs::Vfs;
+    ///
+    /// let vfs = Vfs::from_url("https://example.com/images/fat.img");
+    /// ```
+    #[cfg(feature = "http")]
+    pub fn from_url<S: Into<String>>(url: S) -> Self {
+        Self::with_image(Image::http(url.into()), Mode::ReadOnly)
+    }
+
+    /// Creates a new virtual file system that provides read-only access to a FAT image stored in
+    /// S3 or an S3-compatible store.
+    ///
+    /// Like with [`Vfs::from_url`], only the byte ranges that are needed are fetched.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use unftp_sbe_fatfs::{S3Object, Vfs};
+    ///
+    /// let vfs = Vfs::from_s3("s3://build-artifacts/firmware/fat.img".parse::<S3Object>().unwrap());
+    /// ```
+    #[cfg(feature = "s3")]
+    pub fn from_s3(object: S3Object) -> Self {
+        Self::with_image(Image::s3(object), Mode::ReadOnly)
+    }
+
+    /// Creates a new virtual file system that provides read-only access to a FAT image stored in
+    /// Azure Blob Storage.
+    ///
+    /// Like with [`Vfs::from_url`], only the byte ranges that are needed are fetched.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use unftp_sbe_fatfs::{AzureBlob, Vfs};
+    ///
+    /// let vfs = Vfs::from_azure(
+    ///     AzureBlob::new("mystorageaccount", "images", "fat.img").access_key("bXlrZXk="),
+    /// );
+    /// ```
+    #[cfg(feature = "azure")]
+    pub fn from_azure(blob: AzureBlob) -> Self {
+        Self::with_image(Image::azure(blob), Mode::ReadOnly)
+    }
+
+    /// Creates a new virtual file system that provides read-only access to a FAT image stored in
+    /// Google Cloud Storage.
+    ///
+    /// Like with [`Vfs::from_url`], only the byte ranges that are needed are fetched.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use unftp_sbe_fatfs::{GcsObject, Vfs};
+    ///
+    /// let vfs = Vfs::from_gcs(GcsObject::new("build-artifacts", "fat.img"));
+    /// ```
+    #[cfg(feature = "gcs")]
+    pub fn from_gcs(object: GcsObject) -> Self {
+        Self::with_image(Image::gcs(object), Mode::ReadOnly)
+    }
+
+    /// Starts building a virtual file system for the FAT image file at the given path, for
+    /// when the defaults of the constructors don't fit.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use unftp_sbe_fatfs::Vfs;
+    ///
+    /// let vfs = Vfs::builder("path/to/fat/image.img")
+    ///     .update_accessed_date(true)
+    ///     .build();
+    /// ```
+    pub fn builder<P: AsRef<Path>>(img_path: P) -> VfsBuilder {
+        VfsBuilder::new(img_path)
+    }
+
+    fn with_image(image: Image, mode: Mode) -> Self {
+        Self::with_config(image, PartitionSelect::default(), mode, FsConfig::default())
+    }
+
+    pub(crate) fn with_config(
+        image: Image,
+        partition: PartitionSelect,
+        mode: Mode,
+        fs_options: FsConfig,
+    ) -> Self {
+        Self {
+            shared: registry::shared(image, partition, mode, fs_options),
+            policy: None,
+            hooks: None,
+            audit: None,
+        }
+    }
+
+    /// Returns whether this file system allows modifications.
+    pub fn mode(&self) -> Mode {
+        self.shared.mode
+    }
+
+    /// Returns how often each file was downloaded, how many bytes were served and when, since the
+    /// file system was created.
+    ///
+    /// Downloads count once they found their file, whether or not they are read to the end.
+    /// Clones of a `Vfs` share their counts, as do file systems that share their handle on the
+    /// image. In [`PartitionSelect::All`] mode, files are counted by their path including the
+    /// partition's directory.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use unftp_sbe_fatfs::Vfs;
+    ///
+    /// let vfs = Vfs::new("path/to/fat/image.img");
+    /// let stats = vfs.stats();
+    /// println!("{} downloads, {} bytes", stats.total.downloads, stats.total.bytes_served);
+    /// for (path, file) in &stats.files {
+    ///     println!("{}: {} downloads", path.display(), file.downloads);
+    /// }
+    /// ```
+    pub fn stats(&self) -> Stats {
+        self.shared.stats.snapshot()
+    }
+
+    /// Returns how long opening the image, listings, metadata lookups and downloads took since the
+    /// file system was created, to spot storage that slows down, such as a failing SD card or an
+    /// overloaded network mount.
+    ///
+    /// Operations are timed whether or not they succeed. Downloads include waiting for memory
+    /// from the [`MemoryBudget`], if there is one. Clones of a `Vfs` share their latencies, as do
+    /// file systems that share their handle on the image.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use std::time::Duration;
+    /// use unftp_sbe_fatfs::Vfs;
+    ///
+    /// let vfs = Vfs::new("path/to/fat/image.img");
+    /// let latencies = vfs.latencies();
+    /// if latencies.list.quantile(0.99) > Some(Duration::from_secs(1)) {
+    ///     eprintln!("listings got slow, the storage may be failing");
+    /// }
+    /// ```
+    pub fn latencies(&self) -> Latencies {
+        self.shared.stats.latencies()
+    }
+
+    /// Reads the boot sector, the FAT and the root directory of the image ahead of time, so that
+    /// the first client doesn't have to wait for them.
+    ///
+    /// This matters for remote images, which are otherwise fetched piecemeal as operations need
+    /// them. The filesystem is reopened even if it was in use already. How much of what was read
+    /// stays around depends on the image: remote images keep a limited number of blocks in memory,
+    /// so with large FATs it pays to also configure a [`DiskCache`]. Images configured with
+    /// [`VfsBuilder::load_into_memory`] are copied into memory here.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use unftp_sbe_fatfs::Vfs;
+    ///
+    /// # async fn run() -> unftp_core::storage::Result<()> {
+    /// let vfs = Vfs::new("path/to/fat/image.img");
+    /// vfs.warm_up().await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn warm_up(&self) -> Result<()> {
+        if self.shared.partition == PartitionSelect::All {
+            // Warm up the other partitions even if one of them can't be mounted
+            let mut result = Ok(());
+            for volume in self.volumes().await? {
+                let warmed = Box::pin(volume.vfs.warm_up()).await;
+                result = result.and(warmed);
+            }
+            return result;
+        }
+
+        let vfs = self.clone();
+        tokio::task::spawn_blocking(move || {
+            let _access = vfs.lock_access(Access::Read);
+            let mut guard = vfs.lock_fs();
+            let generation = vfs.shared.image.generation();
+            let permit = vfs.shared.opens.acquire();
+            let mut disk = vfs.open_disk()?;
+            warm_up::prefetch_metadata(&mut *disk).map_err(Error::from)?;
+            let handle = vfs.mount(disk, vfs.shared.fs_options)?;
+            drop(permit);
+            match &handle {
+                FsHandle::Fat(fs) => {
+                    for entry in fs.read_dir(Path::new("/"), None, CorruptionPolicy::Strict)? {
+                        entry?;
+                    }
+                }
+                // exFAT volumes read their root directory when mounted
+                #[cfg(feature = "exfat")]
+                FsHandle::ExFat(_) => {}
+            }
+            *guard = Some(handle);
+            vfs.shared
+                .fs_generation
+                .store(generation, Ordering::Release);
+            Ok(())
+        })
+        .await
+        .map_err(|e| Error::new(ErrorKind::LocalError, e))?
+    }
+
+    /// Checks that the image can be opened and its root directory listed, so that deployments
+    /// such as those on Kubernetes can gate readiness on the file system actually working.
+    ///
+    /// The image is opened anew rather than through the filesystem handle operations share, so
+    /// that an image that went away since is noticed. In [`PartitionSelect::All`] mode, the
+    /// partition table is read and every partition is checked, with the root directory being
+    /// the partitions.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use unftp_sbe_fatfs::Vfs;
+    ///
+    /// # async fn run() {
+    /// let vfs = Vfs::new("path/to/fat/image.img");
+    /// let health = vfs.health_check().await;
+    /// match health.error() {
+    ///     None => println!("ready, checked in {:?}", health.duration),
+    ///     Some(e) => eprintln!("not ready: {e}"),
+    /// }
+    /// # }
+    /// ```
+    pub async fn health_check(&self) -> Health {
+        let started = Instant::now();
+        let (image, root) = if self.shared.partition == PartitionSelect::All {
+            match self.volumes().await {
+                Ok(volumes) => {
+                    let mut checked = (Ok(()), Some(Ok(volumes.len())));
+                    for volume in volumes {
+                        let health = Box::pin(volume.vfs.health_check()).await;
+                        if !health.is_healthy() {
+                            checked = (health.image, health.root);
+                            break;
+                        }
+                    }
+                    checked
+                }
+                Err(e) => (Err(e), None),
+            }
+        } else {
+            let checked = self.spawn(|vfs| {
+                let _access = vfs.lock_access(Access::Read);
+                let mut handle = match vfs.open_fs() {
+                    Ok(handle) => handle,
+                    Err(e) => return Ok((Err(vfs.media_error(e)), None)),
+                };
+                let root = match &mut handle {
+                    FsHandle::Fat(fs) => fs
+                        .read_dir(Path::new("/"), None, CorruptionPolicy::Strict)
+                        .and_then(|mut root| {
+                            root.try_fold(0, |entries, entry| entry.map(|_| entries + 1))
+                        }),
+                    #[cfg(feature = "exfat")]
+                    FsHandle::ExFat(volume) => volume.list(Path::new("/")).map(|list| list.len()),
+                };
+                Ok((Ok(()), Some(root)))
+            });
+            checked.await.unwrap_or_else(|e| (Err(e), None))
+        };
+        Health {
+            image,
+            root,
+            duration: started.elapsed(),
+        }
+    }
+
+    /// Returns the volume label of the filesystem, so that clients can be shown which card or
+    /// disk they connected to, such as in the greeting of the server. That's the label kept in
+    /// the root directory, as Windows shows it, or the one in the boot sector if there is none
+    /// there. `None` if the filesystem has no label.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the image can't be opened or read, and with
+    /// [`ErrorKind::CommandNotImplemented`] in [`PartitionSelect::All`] mode, where each
+    /// partition has a label of its own, see [`VfsBuilder::name_partitions_by_label`].
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use libunftp::ServerBuilder;
+    /// use unftp_sbe_fatfs::Vfs;
+    ///
+    /// # async fn run() -> Result<(), Box<dyn std::error::Error>> {
+    /// let label = Vfs::new("path/to/fat/image.img").volume_label().await?;
+    /// let greeting = format!("Serving {}", label.as_deref().unwrap_or("an unlabeled card"));
+    /// let server = ServerBuilder::new(Box::new(|| Vfs::new("path/to/fat/image.img")))
+    ///     .greeting(greeting.leak())
+    ///     .build()?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn volume_label(&self) -> Result<Option<String>> {
+        if self.shared.partition == PartitionSelect::All {
+            return Err(ErrorKind::CommandNotImplemented.into());
+        }
+        self.spawn_with_handle(Access::Read, |_, handle| match handle {
+            FsHandle::Fat(fs) => fs.volume_label(),
+            #[cfg(feature = "exfat")]
+            FsHandle::ExFat(volume) => Ok(volume.volume_label()),
+        })
+        .await
+    }
+
+    /// Returns the FAT variant the filesystem is formatted with, FAT12, FAT16, FAT32 or exFAT.
+    ///
+    /// # Errors
+    ///
+    /// Fails like [`Vfs::boot_sector_info`].
+    pub async fn fat_type(&self) -> Result<FatVariant> {
+        self.boot_sector_info().await.map(|info| info.variant)
+    }
+
+    /// Returns the variant and capacity of the FAT12, FAT16 or FAT32 filesystem: the size of its
+    /// clusters, how many of them are used, free or bad, and how many entries its root directory
+    /// holds, so that applications can show how full the image is without parsing it themselves.
+    ///
+    /// The clusters are counted by reading the whole FAT.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the image can't be opened or read, with [`FatError::UnsupportedVariant`] for
+    /// exFAT filesystems, and with [`ErrorKind::CommandNotImplemented`] in
+    /// [`PartitionSelect::All`] mode, where each partition has a capacity of its own.
+    pub async fn image_info(&self) -> Result<ImageInfo> {
+        if self.shared.partition == PartitionSelect::All {
+            return Err(ErrorKind::CommandNotImplemented.into());
+        }
+        self.spawn_with_handle(Access::Read, |_, handle| match handle {
+            FsHandle::Fat(fs) => ImageInfo::read(fs).map_err(Error::from),
+            #[cfg(feature = "exfat")]
+            FsHandle::ExFat(_) => Err(FatError::UnsupportedVariant(
+                "exFAT, whose clusters aren't counted".into(),
+            )
+            .into()),
+        })
+        .await
+    }
+
+    /// Returns how many bytes are free on the FAT12, FAT16 or FAT32 filesystem, so that uploads
+    /// to writable images can be checked against it beforehand. libunftp doesn't answer `AVBL`
+    /// or ask storage back-ends for free space, so servers pass it on themselves, such as in the
+    /// greeting or a `SITE` reply. Uploads that run out of space fail with
+    /// [`ErrorKind::InsufficientStorageSpaceError`], which clients are told as a `452` reply.
+    ///
+    /// On FAT32 that's the count of free clusters the FSInfo sector keeps, unless the volume
+    /// wasn't cleanly unmounted, which answers without reading the whole FAT, however large the
+    /// volume. Other variants, and volumes without a count, have their free clusters counted
+    /// once per mount. fatfs keeps count as files are written from then on, and writes the count
+    /// back to the FSInfo sector when unmounted. [`Vfs::repair`] corrects counts that are wrong.
+    ///
+    /// # Errors
+    ///
+    /// Fails like [`Vfs::image_info`].
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use unftp_sbe_fatfs::{Mode, VfsBuilder};
+    ///
+    /// # async fn run() -> Result<(), Box<dyn std::error::Error>> {
+    /// let vfs = VfsBuilder::new("path/to/fat/image.img")
+    ///     .mode(Mode::ReadWrite)
+    ///     .build();
+    /// let free = vfs.free_space().await?;
+    /// println!("{} MiB free for uploads", free >> 20);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn free_space(&self) -> Result<u64> {
+        if self.shared.partition == PartitionSelect::All {
+            return Err(ErrorKind::CommandNotImplemented.into());
+        }
+        self.spawn_with_handle(Access::Read, |_, handle| match handle {
+            FsHandle::Fat(fs) => {
+                let stats = fs.stats()?;
+                Ok(stats.free_clusters() as u64 * stats.cluster_size() as u64)
+            }
+            #[cfg(feature = "exfat")]
+            FsHandle::ExFat(_) => Err(FatError::UnsupportedVariant(
+                "exFAT, whose free space isn't counted".into(),
+            )
+            .into()),
+        })
+        .await
+    }
+
+    /// Returns how much the directory at `path` holds, adding up the sizes of the files in it and
+    /// in the directories below it, like `du`, so that dashboards can show how much each folder
+    /// of the image takes. The size of a file is that of the file alone. In
+    /// [`PartitionSelect::All`] mode, the root directory holds what all the partitions hold.
+    ///
+    /// The whole tree below the directory is read, unless the size is kept from before with
+    /// [`VfsBuilder::cache_dir_sizes`]. Directories that a damaged image leads to more than once
+    /// are counted once.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the path doesn't lead to a file or directory, if the image can't be opened or
+    /// read, with [`FatError::CorruptFat`] if a directory leads back to one counted already,
+    /// unless [`CorruptionPolicy::Lenient`], which leaves it out, and with
+    /// [`FatError::UnsupportedVariant`] for exFAT filesystems.
+    pub async fn dir_size<P: AsRef<Path>>(&self, path: P) -> Result<DirSize> {
+        let path = match self.route(path.as_ref()).await? {
+            Route::Local(path) => path,
+            Route::Partitions => {
+                let mut size = DirSize::default();
+                for volume in self.volumes().await? {
+                    size += Box::pin(volume.vfs.dir_size("/")).await?;
+                    size.directories += 1;
+                }
+                return Ok(size);
+            }
+            Route::Partition(vfs, path) => return Box::pin(vfs.dir_size(path)).await,
+        };
+        self.spawn_with_handle(Access::Read, move |vfs, handle| match handle {
+            FsHandle::Fat(fs) => vfs.measure(fs, &vfs.absolute_path(&path), None),
+            #[cfg(feature = "exfat")]
+            FsHandle::ExFat(_) => Err(FatError::UnsupportedVariant(
+                "exFAT, whose directories aren't measured".into(),
+            )
+            .into()),
+        })
+        .await
+    }
+
+    /// Returns the checksum of the file at `path` computed with `algorithm`, in lowercase
+    /// hexadecimal, as `md5sum` and `sha256sum` tell it, so that integrity checks such as replies
+    /// to `HASH` or `XCRC` can be built on this back-end. The file is streamed from the image as a
+    /// download would, without being counted as one, unless its checksum is kept from before
+    /// with [`VfsBuilder::cache_checksums`].
+    ///
+    /// # Errors
+    ///
+    /// Fails like downloads do, such as if the path doesn't lead to a file or the image can't be
+    /// read.
+    pub async fn checksum<P: AsRef<Path>>(
+        &self,
+        path: P,
+        algorithm: ChecksumAlgorithm,
+    ) -> Result<String> {
+        let max = self.shared.fs_options.cache_checksums;
+        if max == 0 {
+            return self.compute_checksum(path.as_ref(), algorithm).await;
+        }
+        let path = match self.route(path.as_ref()).await? {
+            Route::Local(path) => path,
+            Route::Partitions => return Err(ErrorKind::FileNameNotAllowedError.into()),
+            Route::Partition(vfs, path) => return Box::pin(vfs.checksum(path, algorithm)).await,
+        };
+        let file = path.clone();
+        let meta = self.spawn_with_handle(Access::Read, move |vfs, handle| match handle {
+            FsHandle::Fat(fs) => {
+                let entry = vfs.find(fs, &file)?;
+                Ok(Meta {
+                    is_dir: entry.is_dir(),
+                    len: entry.len(),
+                    modified: entry.modified(),
+                })
+            }
+            #[cfg(feature = "exfat")]
+            FsHandle::ExFat(volume) => volume.metadata(&vfs.normalize_path(&file)),
+        });
+        let meta = meta.await?;
+        let key = checksum::Key {
+            path: self.absolute_path(&path),
+            len: meta.len,
+            modified: checksum::Key::modified(&meta.modified),
+            generation: self.shared.image.generation(),
+            algorithm,
+        };
+        let checksums = || (self.shared.checksums.lock()).unwrap_or_else(PoisonError::into_inner);
+        if let Some(checksum) = checksums().get(&key) {
+            return Ok(checksum);
+        }
+        let checksum = self.compute_checksum(&path, algorithm).await?;
+        checksums().insert(key, checksum.clone(), max);
+        Ok(checksum)
+    }
+
+    /// Computes the checksum of the file at `path` with `algorithm`, streaming it from the image.
+    async fn compute_checksum(&self, path: &Path, algorithm: ChecksumAlgorithm) -> Result<String> {
+        let mut hasher = Hasher::new(algorithm);
+        self.read_through(path, |chunk| hasher.update(chunk))
+            .await?;
+        Ok(hasher.finish())
+    }
+
+    /// Returns which clusters of the FAT12, FAT16 or FAT32 filesystem are used, free or bad, as
+    /// the FAT marks them, so that tools can show how fragmented the image is or how much of it
+    /// is actually used, such as before shrinking it for distribution.
+    ///
+    /// The map is read from the whole FAT, and takes a bit per cluster.
+    ///
+    /// # Errors
+    ///
+    /// Fails like [`Vfs::image_info`].
+    pub async fn cluster_map(&self) -> Result<ClusterMap> {
+        if self.shared.partition == PartitionSelect::All {
+            return Err(ErrorKind::CommandNotImplemented.into());
+        }
+        self.spawn_with_handle(Access::Read, |_, handle| match handle {
+            FsHandle::Fat(fs) => ClusterMap::read(fs).map_err(Error::from),
+            #[cfg(feature = "exfat")]
+            FsHandle::ExFat(_) => Err(FatError::UnsupportedVariant(
+                "exFAT, whose clusters aren't mapped".into(),
+            )
+            .into()),
+        })
+        .await
+    }
+
+    /// Returns the geometry of the filesystem as its boot sector tells it, such as the size of its
+    /// sectors and clusters, so that tools built on this crate can show it without parsing the
+    /// image themselves.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the image can't be opened or read, and with
+    /// [`ErrorKind::CommandNotImplemented`] in [`PartitionSelect::All`] mode, where each
+    /// partition has a boot sector of its own.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use unftp_sbe_fatfs::{FatVariant, Vfs};
+    ///
+    /// # async fn run() -> Result<(), Box<dyn std::error::Error>> {
+    /// let info = Vfs::new("path/to/fat/image.img").boot_sector_info().await?;
+    /// if info.variant == FatVariant::Fat12 {
+    ///     println!("a floppy disk image");
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn boot_sector_info(&self) -> Result<BootSectorInfo> {
+        if self.shared.partition == PartitionSelect::All {
+            return Err(ErrorKind::CommandNotImplemented.into());
+        }
+        self.spawn_with_handle(Access::Read, |_, handle| match handle {
+            FsHandle::Fat(fs) => fs.boot_sector_info().map_err(Error::from),
+            #[cfg(feature = "exfat")]
+            FsHandle::ExFat(volume) => Ok(volume.boot_sector_info()),
+        })
+        .await
+    }
+
+    /// Checks the FAT12, FAT16 or FAT32 filesystem the way fsck.fat does, walking its whole
+    /// directory tree and its FAT, so that images can be checked before they're served, such as
+    /// in CI. The report tells of orphaned clusters, cross-linked files, names FAT doesn't allow,
+    /// dates and times that don't exist, and cluster chains that loop or end wrong.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the image can't be opened or read, with [`FatError::UnsupportedVariant`] for
+    /// exFAT filesystems, and with [`ErrorKind::CommandNotImplemented`] in
+    /// [`PartitionSelect::All`] mode, where each partition is validated through a [`Vfs`] of its
+    /// own.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use unftp_sbe_fatfs::Vfs;
+    ///
+    /// # async fn run() -> Result<(), Box<dyn std::error::Error>> {
+    /// let report = Vfs::new("path/to/fat/image.img").validate().await?;
+    /// for clusters in &report.orphaned_clusters {
+    ///     println!("{} orphaned clusters from cluster {} on", clusters.len(), clusters.start);
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn validate(&self) -> Result<ValidationReport> {
+        if self.shared.partition == PartitionSelect::All {
+            return Err(ErrorKind::CommandNotImplemented.into());
+        }
+        self.spawn_with_handle(Access::Read, |_, handle| match handle {
+            FsHandle::Fat(fs) => validate::validate(fs),
+            #[cfg(feature = "exfat")]
+            FsHandle::ExFat(_) => {
+                Err(FatError::UnsupportedVariant("exFAT, which isn't validated".into()).into())
+            }
+        })
+        .await
+    }
+
+    /// Repairs the damage to the FAT12, FAT16 or FAT32 filesystem that [`Vfs::validate`] finds and
+    /// that can be repaired without guessing, like a minimal chkdsk: cluster chains that loop or
+    /// end wrong are ended where they went wrong, files are cut down to what their chains hold and
+    /// chains to what their files take, orphaned clusters are freed, and dates and times that don't
+    /// exist are set to 1980-01-01 00:00:00. Orphaned clusters are left alone if a directory can't
+    /// be read to the end, as the entries that weren't read may lead to them. Cross-linked files
+    /// and directories and invalid names are left as they are, as are the chains of cross-linked
+    /// files, whose repair could cut the other file short. The count of free clusters the FSInfo
+    /// sector of FAT32 keeps is corrected too.
+    ///
+    /// In [`Mode::ReadWrite`] the fixes are written to the image. Otherwise they are kept in
+    /// memory on top of the image, which is served repaired from then on while left as it is,
+    /// until it is replaced.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the image can't be opened, read or written, with
+    /// [`FatError::UnsupportedVariant`] for exFAT filesystems, and with
+    /// [`ErrorKind::CommandNotImplemented`] in [`PartitionSelect::All`] mode, where each
+    /// partition is repaired through a [`Vfs`] of its own.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use unftp_sbe_fatfs::Vfs;
+    ///
+    /// # async fn run() -> Result<(), Box<dyn std::error::Error>> {
+    /// // Serve a damaged image repaired, leaving the image itself alone
+    /// let vfs = Vfs::new("path/to/fat/image.img");
+    /// let report = vfs.repair().await?;
+    /// println!("{}", report.remaining);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn repair(&self) -> Result<RepairReport> {
+        if self.shared.partition == PartitionSelect::All {
+            return Err(ErrorKind::CommandNotImplemented.into());
+        }
+        self.spawn(|vfs| {
+            vfs.with_cached(Access::Write, |cached| {
+                let report = match cached.as_mut() {
+                    Some(FsHandle::Fat(fs)) => repair::repair(fs)?,
+                    #[cfg(feature = "exfat")]
+                    Some(FsHandle::ExFat(_)) => {
+                        return Err(FatError::UnsupportedVariant(
+                            "exFAT, which isn't repaired".into(),
+                        )
+                        .into());
+                    }
+                    None => return Err(ErrorKind::LocalError.into()),
+                };
+                // fatfs keeps the count of free clusters from before, and writes it to the
+                // FSInfo sector when unmounted, so it is counted anew after that, before anything
+                // else can open the filesystem
+                if let Some(FsHandle::Fat(volume)) = cached.take() {
+                    repair::recount_free(&volume.unmount()?)?;
+                }
+                Ok(report)
+            })
+        })
+        .await
+    }
+
+    /// Returns the partitions served as top-level directories in [`PartitionSelect::All`] mode,
+    /// reading the partition table on first use.
+    async fn volumes(&self) -> Result<&[Volume]> {
+        let volumes = self
+            .shared
+            .volumes
+            .get_or_try_init(|| async {
+                let image = self.shared.image.clone();
+                let decoder = Arc::clone(&self.shared.decoder);
+                let partitions = tokio::task::spawn_blocking(move || {
+                    let mut disk = decoder.open(&image, false)?;
+                    partition::partitions(&mut disk)
+                })
+                .await
+                .map_err(|e| Error::new(ErrorKind::LocalError, e))?
+                .map_err(Error::from)?;
+
+                let mut volumes: Vec<Volume> = partitions
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, partition)| partition.is_some())
+                    .map(|(index, _)| Volume {
+                        name: format!("p{index}"),
+                        vfs: Vfs {
+                            shared: Arc::new(Shared {
+                                decoder: Arc::clone(&self.shared.decoder),
+                                buffers: Arc::clone(&self.shared.buffers),
+                                preload: Arc::clone(&self.shared.preload),
+                                opens: Arc::clone(&self.shared.opens),
+                                transfers: self.shared.transfers.clone(),
+                                stats: Arc::clone(&self.shared.stats),
+                                #[cfg(feature = "metrics")]
+                                volume: true,
+                                ..Shared::new(
+                                    self.shared.image.clone(),
+                                    PartitionSelect::Index(index),
+                                    self.shared.mode,
+                                    self.shared.fs_options,
+                                )
+                            }),
+                            policy: None,
+                            hooks: None,
+                            audit: None,
+                        },
+                    })
+                    .collect();
+                if self.shared.fs_options.name_partitions_by_label {
+                    name_by_label(&mut volumes).await;
+                }
+                Ok::<_, Error>(volumes)
+            })
+            .await?;
+        Ok(volumes)
+    }
+
+    /// Works out which filesystem serves the given FTP path, decoding it first if it isn't valid
+    /// UTF-8 and sanitizing it, so that every operation looks up the same absolute path.
+    async fn route(&self, path: &Path) -> Result<Route> {
+        let path = (self.shared.fs_options.path_decoding)
+            .decode(path)
+            .ok_or(ErrorKind::FileNameNotAllowedError)?;
+        let path = sanitize::sanitize(&path)?;
+        if self.shared.partition != PartitionSelect::All {
+            return Ok(Route::Local(path));
+        }
+
+        let path = self.normalize_path(&path);
+        let mut components = path.components();
+        let Some(name) = components.next() else {
+            return Ok(Route::Partitions);
+        };
+        let name = name.as_os_str().to_string_lossy();
+        let volume = self
+            .volumes()
+            .await?
+            .iter()
+            .find(|v| v.name.eq_ignore_ascii_case(&name))
+            .ok_or(ErrorKind::PermanentFileNotAvailable)?;
+        Ok(Route::Partition(
+            Box::new(volume.vfs.clone()),
+            Path::new("/").join(components.as_path()),
+        ))
+    }
+
+    /// Asks the user policy, if there is one, about `operation`, which `user` asked for. Returns
+    /// the bytes per second the policy allows if it throttles the operation.
+    fn decide(&self, user: &dyn UserDetail, operation: Operation<'_>) -> Result<Option<u64>> {
+        let Some(policy) = &self.policy else {
+            return Ok(None);
+        };
+        match policy.decide(user, operation) {
+            Decision::Allow => Ok(None),
+            Decision::Throttle(bytes_per_second) => Ok(Some(bytes_per_second)),
+            Decision::Reject(kind) => Err(kind.into()),
+        }
+    }
+
+    /// Runs `operation`, which `user` asked for, as `run` within a span of its own, and reports
+    /// its result to the audit sink and the hooks, if there are any.
+    async fn observe<T: Outcome>(
+        &self,
+        user: &dyn UserDetail,
+        operation: Operation<'_>,
+        run: impl Future<Output = Result<T>>,
+    ) -> Result<T> {
+        let started = audit::Started::now();
+        let span = OperationSpan::start(&self.shared, user, operation);
+        let result = span.instrument(run).await;
+        let bytes = result.as_ref().ok().and_then(Outcome::bytes);
+        if let Some(bytes) = bytes {
+            span.transferred(bytes);
+        }
+        span.end(result.as_ref().err());
+        if let Some(audit) = &self.audit {
+            let user = user.to_string();
+            audit.record(&started.event(&user, operation, result.as_ref().map(|_| ()), bytes));
+        }
+        self.report(user, operation, result)
+    }
+
+    /// Tells the hooks, if there are any, about `result` of `operation`, which `user` asked for,
+    /// if it is an error, and counts it in the metrics with the `metrics` feature. Returns
+    /// `result`.
+    fn report<T>(
+        &self,
+        user: &dyn UserDetail,
+        operation: Operation<'_>,
+        result: Result<T>,
+    ) -> Result<T> {
+        if let (Some(hooks), Err(e)) = (&self.hooks, &result) {
+            hooks.on_error(&user.to_string(), operation, e);
+        }
+        #[cfg(feature = "metrics")]
+        if let (false, Err(e)) = (self.shared.volume, &result) {
+            metrics::failed(operation, e.kind());
+        }
+        result
+    }
+
+    /// Returns the rate limits of a new transfer: the lower of the rate a user policy allows and
+    /// the configured rate per transfer, and the total rate of all transfers.
+    fn transfer_limits(&self, allowed: Option<u64>) -> Vec<Arc<RateLimit>> {
+        let per_transfer = allowed
+            .into_iter()
+            .chain(self.shared.fs_options.transfer_rate)
+            .min()
+            .map(|bytes_per_second| Arc::new(RateLimit::new(bytes_per_second)));
+        per_transfer
+            .into_iter()
+            .chain(self.shared.total_rate.clone())
+            .collect()
+    }
+
+    /// Refuses modifications unless this file system was created in [`Mode::ReadWrite`].
+    fn ensure_writable(&self) -> Result<()> {
+        match self.shared.mode {
+            Mode::ReadWrite => Ok(()),
+            Mode::ReadOnly => Err(ErrorKind::PermissionDenied.into()),
+        }
+    }
+
+    /// Opens the filesystem image and returns a handle to the filesystem in it.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the image file cannot be opened or if it's not a valid
+    /// FAT filesystem image.
+    fn open_fs(&self) -> Result<FsHandle> {
+        self.open_with(self.shared.fs_options)
+    }
+
+    /// Opens the filesystem like [`Vfs::open_fs`], for reading files without writing their
+    /// accessed dates, see [`Vfs::take_reader`].
+    fn open_reader(&self) -> Result<FsHandle> {
+        let mut config = self.shared.fs_options;
+        config.update_accessed_date = false;
+        self.open_with(config)
+    }
+
+    /// Opens the filesystem like [`Vfs::open_fs`], mounting it with the options `options`
+    /// returns.
+    fn open_with(&self, config: FsConfig) -> Result<FsHandle> {
+        let _permit = self.shared.opens.acquire();
+        let started = Instant::now();
+        let fs = self.open_disk().and_then(|disk| self.mount(disk, config));
+        self.shared.stats.timed(Timed::Open, started);
+        if let Ok(FsHandle::Fat(volume)) = &fs {
+            self.check_opened(volume)?;
+        }
+        fs
+    }
+
+    /// Checks the filesystem of `volume` as asked to, if it wasn't checked since the image was
+    /// last replaced: whether it was cleanly unmounted, whether the copies of its FAT agree, and
+    /// which of its files are cross-linked.
+    fn check_opened(&self, volume: &FatVolume) -> Result<()> {
+        let generation = self.shared.image.generation();
+        if self.shared.checked.load(Ordering::Acquire) == generation {
+            return Ok(());
+        }
+        self.check_dirty(volume)?;
+        if self.shared.fs_options.verify_fat_copies {
+            self.verify_fats(volume)?;
+        }
+        if self.shared.fs_options.warn_cross_links {
+            let cross_linked = validate::check(volume)?.cross_linked;
+            *self
+                .shared
+                .cross_linked
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner) = cross_linked;
+        }
+        self.shared.checked.store(generation, Ordering::Release);
+        Ok(())
+    }
+
+    /// Deals with `volume` being marked dirty as [`DirtyVolume`] is set to.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`FatError::DirtyVolume`] if it is marked and such volumes are refused.
+    fn check_dirty(&self, volume: &FatVolume) -> Result<()> {
+        let handling = self.shared.fs_options.dirty_volume;
+        if handling == DirtyVolume::Proceed || !volume.read_status_flags()?.dirty() {
+            return Ok(());
+        }
+        match handling {
+            DirtyVolume::Refuse => Err(FatError::DirtyVolume.into()),
+            _ => {
+                tracing::warn!(
+                    image = %self.shared.image.name(),
+                    "the volume wasn't cleanly unmounted, its directories and files may be \
+                     inconsistent"
+                );
+                Ok(())
+            }
+        }
+    }
+
+    /// Compares the FAT of `volume` with its copies, logging a warning if they differ.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`FatError::CorruptFat`] if they differ with [`CorruptionPolicy::Paranoid`].
+    fn verify_fats(&self, volume: &FatVolume) -> Result<()> {
+        let (count, mismatches) = volume.fat_mismatches(MISMATCHES_REPORTED)?;
+        if count > 0 {
+            let listed = mismatches
+                .iter()
+                .map(|m| {
+                    format!(
+                        "cluster {} ({:#x} in FAT 1, {:#x} in FAT {})",
+                        m.cluster, m.expected, m.found, m.copy
+                    )
+                })
+                .collect::<Vec<_>>()
+                .join(", ");
+            let e = FatError::CorruptFat(match count {
+                1 => format!("the entry of {listed} differs between the copies of the FAT"),
+                _ => format!(
+                    "{count} entries differ between the copies of the FAT, among them {listed}"
+                ),
+            });
+            if self.shared.fs_options.corruption_policy == CorruptionPolicy::Paranoid {
+                return Err(e.into());
+            }
+            tracing::warn!(image = %self.shared.image.name(), error = %e, "the FATs differ");
+        }
+        Ok(())
+    }
+
+    /// Opens the image and narrows it down to the selected partition, with what [`Vfs::repair`]
+    /// wrote on top of it if it is served read-only.
+    fn open_disk(&self) -> Result<Box<dyn Disk>> {
+        let writable = self.shared.mode == Mode::ReadWrite;
+        let f = self
+            .shared
+            .preload
+            .open(&self.shared.image, writable, || {
+                self.shared.decoder.open(&self.shared.image, writable)
+            })
+            .map_err(Error::from)?;
+        let mut disk = partition::select(f, &self.shared.partition).map_err(Error::from)?;
+        if !writable {
+            disk = self
+                .shared
+                .overlay
+                .wrap(disk, self.shared.image.generation());
+        }
+        let memory = self.shared.fs_options.memory;
+        if let Some(config) = self.shared.fs_options.block_cache
+            && let Some(reservation) =
+                memory::try_reserve(memory, config.block_size * config.blocks as u64)
+        {
+            disk = Box::new(
+                block_cache::BlockCache::new(disk, config, reservation).map_err(Error::from)?,
+            );
+        }
+        disk = Box::new(batch::BatchedReads::new(disk).map_err(Error::from)?);
+        fat_cache::FatCache::wrap(disk, self.shared.fs_options.cache_fat, memory)
+            .map_err(Error::from)
+    }
+
+    /// Mounts the filesystem on `f`, a disk returned by [`Vfs::open_disk`].
+    #[allow(unused_mut)]
+    fn mount(&self, mut f: Box<dyn Disk>, config: FsConfig) -> Result<FsHandle> {
+        #[cfg(feature = "exfat")]
+        if exfat::is_exfat(&mut f).map_err(Error::from)? {
+            return Ok(FsHandle::ExFat(exfat::ExFatVolume::open(f)?));
+        }
+
+        match FatVolume::mount(f, config.to_fs_options()) {
+            Ok(volume) => Ok(FsHandle::Fat(volume)),
+            Err(e) => match self.mount_backup(config) {
+                Some(volume) => Ok(FsHandle::Fat(volume)),
+                None => Err(self.explain(e)),
+            },
+        }
+    }
+
+    /// Mounts the filesystem from the backup of its boot sector that FAT32 keeps, once it
+    /// couldn't be mounted from the boot sector itself, which it reopens the image for. Returns
+    /// `None` if there is no backup, or the filesystem can't be mounted from it either.
+    fn mount_backup(&self, config: FsConfig) -> Option<FatVolume> {
+        let disk = backup_boot::BackupBoot::open(self.open_disk().ok()?).ok()??;
+        let volume = FatVolume::mount(Box::new(disk), config.to_fs_options()).ok()?;
+        tracing::warn!(
+            image = %self.shared.image.name(),
+            "mounted the filesystem from the backup of its boot sector, which is damaged"
+        );
+        Some(volume)
+    }
+
+    /// Fails if the filesystem takes up more than the volume it is on, which mounting doesn't
+    /// notice.
+    fn check_len(&self) -> Result<()> {
+        let mut disk = self.open_disk()?;
+        let len = disk.seek(SeekFrom::End(0))?;
+        match diagnose::check_len(&mut disk, len)? {
+            Some(diagnosis) => {
+                Err(io::Error::new(io::ErrorKind::InvalidData, diagnosis.to_string()).into())
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Adds to `e`, the error mounting the filesystem failed with, what the volume holds
+    /// instead, which it reopens to find out.
+    fn explain(&self, e: io::Error) -> Error {
+        let diagnosis = self.open_disk().ok().and_then(|mut disk| {
+            let len = disk.seek(SeekFrom::End(0)).ok()?;
+            diagnose::diagnose(&mut disk, 0, len).ok().flatten()
+        });
+        match diagnosis {
+            #[cfg(not(feature = "exfat"))]
+            Some(diagnose::Diagnosis::ExFat) => FatError::UnsupportedVariant(
+                "exFAT, served with the `exfat` feature only".to_string(),
+            )
+            .into(),
+            Some(diagnosis) => diagnosis.explain(e).into(),
+            None => e.into(),
+        }
+    }
+
+    /// Runs `f` against the cached filesystem handle, opening the image first if that hasn't
+    /// happened yet.
+    ///
+    /// The handle is held locked for the duration of `f` since fatfs keeps a single seek
+    /// position on the underlying image. `access` is held for that long too: operations that
+    /// write wait for all reads in progress to finish, and keep new ones waiting until done.
+    ///
+    /// When the media of the image goes away, for instance because an SD card was pulled from
+    /// its reader, the handle is dropped and operations fail with a transient error until the
+    /// image can be opened again.
+    fn with_handle<R>(
+        &self,
+        access: Access,
+        f: impl FnOnce(&mut FsHandle) -> Result<R>,
+    ) -> Result<R> {
+        self.with_cached(access, |cached| match cached.as_mut() {
+            Some(handle) => f(handle),
+            None => Err(ErrorKind::LocalError.into()),
+        })
+    }
+
+    /// Runs `f` against where the filesystem handle is cached, like [`Vfs::with_handle`], for
+    /// operations that take the handle out of it, which is reopened on next use.
+    fn with_cached<R>(
+        &self,
+        access: Access,
+        f: impl FnOnce(&mut Option<FsHandle>) -> Result<R>,
+    ) -> Result<R> {
+        let _access = self.lock_access(access);
+        if access == Access::Write
+            && let Ok(mut readers) = self.shared.readers.lock()
+        {
+            // They would keep serving what is about to change from their caches
+            readers.clear();
+        }
+        if access == Access::Write {
+            self.shared.writes.fetch_add(1, Ordering::AcqRel);
+            self.forget_dir_sizes();
+        }
+        let mut guard = self.lock_fs();
+        // Reopen an image that was replaced, rather than serving a mix of both versions
+        self.shared.image.revalidate().map_err(Error::from)?;
+        let generation = self.shared.image.generation();
+        if generation != self.shared.fs_generation.load(Ordering::Acquire) {
+            *guard = None;
+            self.forget_dir_sizes();
+        }
+        if guard.is_none() {
+            let handle = self.open_fs().map_err(|e| self.media_error(e))?;
+            *guard = Some(handle);
+            self.shared
+                .fs_generation
+                .store(generation, Ordering::Release);
+            self.shared.media_lost.store(false, Ordering::Release);
+        }
+        f(&mut guard).map_err(|e| {
+            let e = self.media_error(e);
+            if e.kind() == ErrorKind::TransientFileNotAvailable {
+                *guard = None;
+            }
+            e
+        })
+    }
+
+    /// Turns an error that means the media of the image went away into a transient one, so that
+    /// clients retry rather than give up.
+    ///
+    /// Once the media went away, its device node may be gone too until it is back, so the image
+    /// not being found counts as well until it could be opened again.
+    fn media_error(&self, e: Error) -> Error {
+        let lost = e.get_io_error().is_some_and(|io| {
+            media::is_lost(io)
+                || (io.kind() == io::ErrorKind::NotFound
+                    && self.shared.media_lost.load(Ordering::Acquire))
+        });
+        if !lost {
+            return e;
+        }
+        self.shared.media_lost.store(true, Ordering::Release);
+        Error::new(ErrorKind::TransientFileNotAvailable, e)
+    }
+
+    /// Takes a filesystem of its own that a previous call to [`Vfs::put_reader`] left, or opens
+    /// one, along with the generation of the image it was opened at, so that a download can read
+    /// alongside other operations rather than wait for the cached handle. Read access must be
+    /// held.
+    ///
+    /// These filesystems don't write the accessed dates of the files they read, which only the
+    /// cached handle may do.
+    fn take_reader(&self) -> Result<(u64, FsHandle)> {
+        self.shared.image.revalidate().map_err(Error::from)?;
+        let generation = self.shared.image.generation();
+        let idle = match self.shared.readers.lock() {
+            Ok(mut readers) => {
+                // Those opened before the image was replaced are of no use anymore
+                readers.retain(|(opened_at, _)| *opened_at == generation);
+                readers.pop()
+            }
+            Err(_) => None,
+        };
+        let handle = match idle {
+            Some((_, handle)) => handle,
+            None => self.open_reader().map_err(|e| self.media_error(e))?,
+        };
+        Ok((generation, handle))
+    }
+
+    /// Leaves `handle`, a filesystem taken with [`Vfs::take_reader`] at `generation`, for later
+    /// calls to take, unless as many are left already.
+    fn put_reader(&self, generation: u64, handle: FsHandle) {
+        if let Ok(mut readers) = self.shared.readers.lock()
+            && readers.len() < IDLE_READERS
+        {
+            readers.push((generation, handle));
+        }
+    }
+
+    /// Runs `operation`, adding how long it took to the latencies of `timed`.
+    async fn timed<T>(&self, timed: Timed, operation: impl Future<Output = T>) -> T {
+        let started = Instant::now();
+        let output = operation.await;
+        self.shared.stats.timed(timed, started);
+        output
+    }
+
+    /// Runs `f` on tokio's blocking thread pool so that image I/O doesn't stall the async
+    /// executor.
     ///
-    /// # Arguments
+    /// The work is spawned immediately; the returned future only waits for its result. Dropping
+    /// the future, for instance because the client went away, cancels the work unless it
+    /// started already, since blocking work can't be interrupted.
+    fn spawn<R, F>(&self, f: F) -> impl Future<Output = Result<R>> + use<R, F>
+    where
+        R: Send + 'static,
+        F: FnOnce(&Vfs) -> Result<R> + Send + 'static,
+    {
+        let vfs = self.clone();
+        let handle = tokio::task::spawn_blocking(move || f(&vfs));
+        let cancel = CancelOnDrop(handle.abort_handle());
+        async move {
+            let _cancel = cancel;
+            handle
+                .await
+                .map_err(|e| Error::new(ErrorKind::LocalError, e))?
+        }
+    }
+
+    /// Runs `f` against the cached filesystem handle on tokio's blocking thread pool, like
+    /// [`Vfs::spawn`].
+    fn spawn_with_handle<R, F>(
+        &self,
+        access: Access,
+        f: F,
+    ) -> impl Future<Output = Result<R>> + use<R, F>
+    where
+        R: Send + 'static,
+        F: FnOnce(&Vfs, &mut FsHandle) -> Result<R> + Send + 'static,
+    {
+        self.spawn(move |vfs| vfs.with_handle(access, |h| f(vfs, h)))
+    }
+
+    /// Runs `f`, which modifies the cached FAT filesystem, on tokio's blocking thread pool, like
+    /// [`Vfs::spawn_with_handle`].
     ///
-    /// * `img_path` - The path to the FAT filesystem image file
+    /// Filesystems not handled by fatfs are read-only, so this refuses them with a permission
+    /// error.
+    fn spawn_with_fs<R, F>(&self, f: F) -> impl Future<Output = Result<R>> + use<R, F>
+    where
+        R: Send + 'static,
+        F: FnOnce(&Vfs, &FatVolume) -> Result<R> + Send + 'static,
+    {
+        self.spawn(move |vfs| vfs.with_fs(|fs| f(vfs, fs)))
+    }
+
+    /// Runs `f`, which modifies the cached FAT filesystem, with write access, like
+    /// [`Vfs::spawn_with_fs`] but on the calling thread.
+    fn with_fs<R>(&self, f: impl FnOnce(&FatVolume) -> Result<R>) -> Result<R> {
+        self.with_handle(Access::Write, |handle| match handle {
+            FsHandle::Fat(fs) => f(fs),
+            #[cfg(feature = "exfat")]
+            FsHandle::ExFat(_) => Err(ErrorKind::PermissionDenied.into()),
+        })
+    }
+
+    /// Starts downloading the file at `path`, a path on this volume, from `start_pos` on.
     ///
-    /// # Example
+    /// fatfs reads are blocking so the file is read chunk by chunk by a blocking task that holds
+    /// it open for the duration of the transfer, staying a few chunks ahead of the client so that
+    /// reading the image and sending to the client overlap. The task reads through a filesystem
+    /// of its own so that concurrent downloads don't take turns, and lets changes through
+    /// between chunks, see [`Vfs::stream`].
+    async fn download(&self, path: PathBuf, start_pos: u64) -> Result<download::Download> {
+        let transfer = self.start_transfer()?;
+        let chunks = download::CHUNKS_IN_FLIGHT * self.shared.buffers.size();
+        let memory = memory::reserve(self.shared.fs_options.memory, chunks as u64).await;
+        let (tx, mut rx) = mpsc::channel(download::READ_AHEAD_CHUNKS);
+        let reader = self.spawn(move |vfs| {
+            // A download dropped while the task waited for its turn needs no filesystem opened
+            if tx.is_closed() {
+                return Ok(());
+            }
+            vfs.stream(&path, start_pos, &tx)
+        });
+
+        // Nothing is sent when the file can't be opened, or when there's nothing to send
+        match rx.recv().await {
+            Some(Ok(first)) => Ok(download::Download::new(
+                first,
+                rx,
+                Arc::clone(&self.shared.buffers),
+                transfer,
+                memory,
+            )),
+            // The task's own error tells whether the image went away
+            Some(Err(e)) => Err(reader
+                .await
+                .err()
+                .unwrap_or_else(|| Error::new(ErrorKind::PermanentFileNotAvailable, e))),
+            None => {
+                reader.await?;
+                Ok(download::Download::new(
+                    Default::default(),
+                    rx,
+                    Arc::clone(&self.shared.buffers),
+                    transfer,
+                    memory,
+                ))
+            }
+        }
+    }
+
+    /// Reads the file at `path`, a path on this volume, from `start_pos` on and sends it to the
+    /// download `chunks` belongs to, see [`Vfs::download`].
     ///
-    /// ```rust
-    /// use unftp_sbe_fatfs::Vfs;
+    /// The file is read through a filesystem of its own, see [`Vfs::take_reader`], taking read
+    /// access a chunk at a time rather than while the client takes its time receiving them, see
+    /// [`download::Gate`]. Once the filesystem was written to in between, which the caches of
+    /// that filesystem don't know of, the file is opened anew where the download got to, as long
+    /// as it is still the same file.
+    fn stream(&self, path: &Path, start_pos: u64, chunks: &download::ChunkSender) -> Result<()> {
+        if self.shared.mode == Mode::ReadWrite && self.shared.fs_options.update_accessed_date {
+            self.touch(path, start_pos)?;
+        }
+        let mut pos = start_pos;
+        // The first cluster, size and modification date of the file when first opened
+        let mut opened = None;
+        loop {
+            let (generation, mut handle, gate) = {
+                let _access = self.lock_access(Access::Read);
+                let gate = download::Gate::new(self);
+                let (generation, handle) = self.take_reader()?;
+                (generation, handle, gate)
+            };
+            let sent = match &mut handle {
+                FsHandle::Fat(fs) => self.stream_fat(fs, path, pos, &mut opened, &gate, chunks),
+                #[cfg(feature = "exfat")]
+                FsHandle::ExFat(volume) => {
+                    let path = self.normalize_path(path);
+                    volume.read(&path, pos, &self.shared.buffers, chunks, &gate)
+                }
+            }
+            .map_err(|e| self.media_error(e));
+            if let Ok(Sent::Interrupted(sent)) = sent {
+                // The filesystem of the download may serve what changed from its caches
+                pos += sent;
+                continue;
+            }
+            if !is_lost(&sent)
+                && let Some(_access) = gate.enter()
+            {
+                self.put_reader(generation, handle);
+            }
+            return sent.map(drop);
+        }
+    }
+
+    /// Reads the file at `path` from `start_pos` on like [`Vfs::stream`] through `fs`, whose
+    /// first cluster, size and modification date are `opened` once it was opened.
+    fn stream_fat(
+        &self,
+        fs: &FatVolume,
+        path: &Path,
+        start_pos: u64,
+        opened: &mut Option<(Option<u32>, u64, DateTime)>,
+        gate: &download::Gate<'_>,
+        chunks: &download::ChunkSender,
+    ) -> Result<Sent> {
+        let Some(access) = gate.enter() else {
+            return Ok(Sent::Interrupted(0));
+        };
+        let reopened = opened.is_some();
+        let mut file = self
+            .open_download(fs, path, start_pos, opened)
+            .inspect_err(|e| {
+                // The client was sent part of the file already, which mustn't look complete
+                if reopened {
+                    let _ = chunks.blocking_send(Err(io::Error::other(e.to_string())));
+                }
+            })?;
+        drop(access);
+        download::send_chunks(&mut file, start_pos, &self.shared.buffers, chunks, gate)
+            .map_err(|e| Error::new(ErrorKind::PermanentFileNotAvailable, e))
+    }
+
+    /// Opens the file at `path` through `fs` for [`Vfs::stream_fat`], positioned at `start_pos`
+    /// and ending where the file can be read to.
     ///
-    /// let vfs = Vfs::new("path/to/fat/image.img");
-    /// ```
-    pub fn new<P: AsRef<Path>>(img_path: P) -> Self {
-        Self {
-            img_path: img_path.as_ref().to_path_buf(),
+    /// The file is checked the first time it is opened, when `opened` is `None`, and has to be
+    /// the same file the times after, since the client was sent part of it already.
+    fn open_download<'a>(
+        &self,
+        fs: &'a FatVolume,
+        path: &Path,
+        start_pos: u64,
+        opened: &mut Option<(Option<u32>, u64, DateTime)>,
+    ) -> Result<io::Take<fatfs::File<'a, Box<dyn Disk>>>> {
+        let entry = self.find(fs, path)?;
+        let identity = (entry.cluster(), entry.len(), entry.modified());
+        let absolute = self.absolute_path(path);
+        match opened {
+            Some(opened) if *opened != identity => {
+                return Err(Error::new(
+                    ErrorKind::PermanentFileNotAvailable,
+                    format!("{} changed while it was downloaded", absolute.display()),
+                ));
+            }
+            Some(_) => {}
+            None => {
+                if entry.is_dir() {
+                    return Err(ErrorKind::FileNameNotAllowedError.into());
+                }
+                download::check_start_pos(start_pos, entry.len())?;
+                self.warn_if_cross_linked(&absolute, &entry);
+            }
+        }
+        let policy = self.shared.fs_options.corruption_policy;
+        let len = fs.check_file(&absolute, &entry, policy)?;
+        if len < entry.len() && opened.is_none() {
+            let e = FatError::CorruptFat(format!(
+                "{} claims to be {} bytes long but only {len} can be read",
+                absolute.display(),
+                entry.len()
+            ));
+            self.damaged(&absolute, &e.into(), "cut the download short");
+        }
+        *opened = Some(identity);
+
+        let mut file = entry.to_file();
+        file.seek(SeekFrom::Start(start_pos))
+            .map_err(|e| Error::new(ErrorKind::PermanentFileNotAvailable, e))?;
+        // Never read past the size the entry tells, or what the chain holds if lenient
+        Ok(file.take(len.saturating_sub(start_pos)))
+    }
+
+    /// Sets the accessed date of the file at `path` as reading it from `start_pos` on would have
+    /// fatfs do, through the cached handle, as downloads read through filesystems of their own
+    /// that don't. Leaves it to the download to fail if the file can't be read.
+    fn touch(&self, path: &Path, start_pos: u64) -> Result<()> {
+        self.with_handle(Access::Read, |handle| match handle {
+            FsHandle::Fat(fs) => {
+                let Ok(entry) = self.find(fs, path) else {
+                    return Ok(());
+                };
+                if entry.is_dir() {
+                    return Ok(());
+                }
+                let mut file = entry.to_file();
+                // fatfs sets the date once it read a byte, and writes it when the file is dropped
+                if file.seek(SeekFrom::Start(start_pos)).is_ok() {
+                    let _ = file.read(&mut [0; 1]);
+                }
+                Ok(())
+            }
+            #[cfg(feature = "exfat")]
+            FsHandle::ExFat(_) => Ok(()),
+        })
+    }
+
+    /// Uploads `input` to the file at `path`, a path on this volume, from `start_pos` on.
+    async fn upload(
+        &self,
+        mut input: impl tokio::io::AsyncRead + Unpin,
+        path: PathBuf,
+        start_pos: u64,
+    ) -> Result<u64> {
+        let _transfer = self.start_transfer()?;
+        let chunks = (UPLOAD_CHUNKS + 2) * WRITE_CHUNK_SIZE;
+        let _memory = memory::reserve(self.shared.fs_options.memory, chunks as u64).await;
+
+        // The upload is handed chunk by chunk to a blocking task that spools it to a temporary
+        // file, and only then takes write access to write it to the image, so that clients that
+        // send slowly don't keep others from the filesystem.
+        let (tx, mut rx) = mpsc::channel::<Vec<u8>>(UPLOAD_CHUNKS);
+        let writer = self.spawn(move |vfs| {
+            // Resuming beyond the end would leave a hole which FAT can't represent, which is
+            // refused before the client sends, and checked again once it did
+            let resumable = |len: u64| {
+                if start_pos > len {
+                    return Err(Error::from(ErrorKind::PermanentFileNotAvailable));
+                }
+                Ok(())
+            };
+            if start_pos > 0 {
+                let len = vfs.with_handle(Access::Read, |handle| match handle {
+                    FsHandle::Fat(fs) => Ok(vfs.find(fs, &path).map_or(0, |entry| entry.len())),
+                    #[cfg(feature = "exfat")]
+                    FsHandle::ExFat(_) => Err(ErrorKind::PermissionDenied.into()),
+                })?;
+                resumable(len)?;
+            }
+
+            let mut spool = Spool::create().map_err(Error::from)?;
+            let mut written = 0u64;
+            while let Some(chunk) = rx.blocking_recv() {
+                spool.write_all(&chunk).map_err(Error::from)?;
+                written += chunk.len() as u64;
+            }
+            spool.rewind().map_err(Error::from)?;
+
+            vfs.with_fs(|fs| {
+                let path = vfs.fat_path(&path)?;
+                let mut file = fs.root_dir().create_file(&path).map_err(Error::from)?;
+                resumable(file.seek(SeekFrom::End(0)).map_err(Error::from)?)?;
+                file.seek(SeekFrom::Start(start_pos)).map_err(Error::from)?;
+                file.truncate().map_err(Error::from)?;
+                let mut buf = vec![0u8; WRITE_CHUNK_SIZE];
+                loop {
+                    let n = spool.read(&mut buf).map_err(Error::from)?;
+                    if n == 0 {
+                        break;
+                    }
+                    file.write_all(&buf[..n])
+                        .map_err(|e| error::write_error(fs, e))?;
+                }
+                file.flush().map_err(|e| error::write_error(fs, e))?;
+                Ok(written)
+            })
+        });
+
+        let mut buf = vec![0u8; WRITE_CHUNK_SIZE];
+        loop {
+            let n = input.read(&mut buf).await.map_err(Error::from)?;
+            // A closed channel means the writer gave up; its error is reported below
+            if n == 0 || tx.send(buf[..n].to_vec()).await.is_err() {
+                break;
+            }
+        }
+        drop(tx);
+
+        writer.await
+    }
+
+    /// Reads the file at `path` from start to end, as a download would, without being counted as
+    /// one, handing what is read to `f` a chunk at a time.
+    async fn read_through(&self, path: &Path, mut f: impl FnMut(&[u8])) -> Result<()> {
+        let mut download = match self.route(path).await? {
+            Route::Local(path) => self.download(path, 0).await?,
+            Route::Partitions => return Err(ErrorKind::FileNameNotAllowedError.into()),
+            Route::Partition(vfs, path) => vfs.download(path, 0).await?,
+        };
+        loop {
+            let chunk = download.fill_buf().await?;
+            if chunk.is_empty() {
+                return Ok(());
+            }
+            f(chunk);
+            let len = chunk.len();
+            download.consume(len);
+        }
+    }
+
+    /// Counts a transfer among those in progress until the returned permit is dropped, refusing
+    /// it with a transient error if as many as allowed are in progress already.
+    fn start_transfer(&self) -> Result<Option<OwnedSemaphorePermit>> {
+        let Some(transfers) = &self.shared.transfers else {
+            return Ok(None);
+        };
+        match Arc::clone(transfers).try_acquire_owned() {
+            Ok(permit) => Ok(Some(permit)),
+            Err(_) => Err(Error::new(
+                ErrorKind::TransientFileNotAvailable,
+                "too many transfers in progress",
+            )),
         }
     }
 
-    /// Opens the FAT filesystem image and returns a `FileSystem` instance.
+    /// Takes `access` to the filesystem, for operations to hold while they use it.
     ///
-    /// # Errors
+    /// The lock guards no data, so a panic while it was held leaves nothing to clean up.
+    fn lock_access(&self, access: Access) -> AccessGuard<'_> {
+        let lock = &self.shared.access;
+        match access {
+            Access::Read => AccessGuard::Read(lock.read().unwrap_or_else(PoisonError::into_inner)),
+            Access::Write => {
+                AccessGuard::Write(lock.write().unwrap_or_else(PoisonError::into_inner))
+            }
+        }
+    }
+
+    /// Locks the cached filesystem handle.
     ///
-    /// Returns an error if the image file cannot be opened or if it's not a valid
-    /// FAT filesystem image.
-    fn open_fs(&self) -> Result<FileSystem<File>> {
-        let f = File::open(&self.img_path).map_err(Error::from)?;
-        let fs = FileSystem::new(f, FsOptions::new()).map_err(Error::from)?;
-        Ok(fs)
+    /// A poisoned lock means a previous operation panicked half way through, so the handle is
+    /// dropped and will be reopened on next use.
+    fn lock_fs(&self) -> MutexGuard<'_, Option<FsHandle>> {
+        self.shared.fs.lock().unwrap_or_else(|poisoned| {
+            let mut guard = poisoned.into_inner();
+            *guard = None;
+            self.shared.fs.clear_poison();
+            guard
+        })
     }
 
     /// Finds a file or directory entry in the FAT filesystem.
@@ -129,16 +2297,9 @@ impl Vfs {
     ///
     /// Returns an error if the path doesn't exist or if there's an error accessing
     /// the filesystem.
-    fn find<'a, P: AsRef<Path>>(
-        &self,
-        fs: &'a FileSystem<File>,
-        ftp_path: P,
-    ) -> Result<DirEntry<'a, File>> {
+    fn find<'a, P: AsRef<Path>>(&self, fs: &'a FatVolume, ftp_path: P) -> Result<FatEntry<'a>> {
         let path = self.normalize_path(ftp_path.as_ref());
 
-        // Start from the root directory
-        let root_dir = fs.root_dir();
-
         // If path is just the root, handle specially
         if path == Path::new("/") || path.as_os_str().is_empty() {
             // Return a special case for root, or error depending on your needs
@@ -146,6 +2307,9 @@ impl Vfs {
             // Alternatively, you might have a way to represent root as a DirEntry
         }
 
+        // Errors tell the path as the client would know it
+        let requested = Path::new("/").join(&path);
+
         // Strip leading slash if present
         let path_str = path.to_string_lossy();
         let path_str = path_str.trim_start_matches('/');
@@ -153,9 +2317,13 @@ impl Vfs {
         // Split the path into components
         let components: Vec<&str> = path_str.split('/').collect();
 
-        // Navigate through each component
-        let mut current_dir = root_dir;
-        let mut current_entry: Option<DirEntry<File>> = None;
+        // Navigate through each component, starting from the root directory
+        let mut current_dir: Option<FatEntry> = None;
+        let mut current_path = PathBuf::from("/");
+        let mut current_entry: Option<FatEntry> = None;
+
+        // The directories passed through, which a damaged image may lead back to
+        let mut visited = HashSet::from([fs.dir_cluster(None)]);
 
         // Handle all components except the last one (which may be a file)
         for (i, component) in components.iter().enumerate() {
@@ -165,11 +2333,18 @@ impl Vfs {
 
             // Iterate through directory entries to find the component
             let mut found = false;
-            for entry_result in current_dir.iter() {
-                let entry = entry_result.map_err(|_| {
-                    let e: Error = ErrorKind::PermanentFileNotAvailable.into();
-                    e
-                })?;
+            let policy = self.shared.fs_options.corruption_policy;
+            for entry_result in fs.read_dir(&current_path, current_dir.as_ref(), policy)? {
+                let entry = match entry_result {
+                    Ok(entry) => entry,
+                    Err(e) if policy == CorruptionPolicy::Lenient => {
+                        let action =
+                            "looked up the path as if the rest of the directory were empty";
+                        self.damaged(&current_path, &e, action);
+                        break;
+                    }
+                    Err(e) => return Err(e),
+                };
 
                 // Compare the entry name with the current component (case-insensitive for FAT)
                 if entry.file_name().eq_ignore_ascii_case(component) {
@@ -182,48 +2357,240 @@ impl Vfs {
 
                     // Otherwise, ensure this component is a directory and continue navigating
                     if entry.is_dir() {
-                        current_dir = entry.to_dir();
+                        if !visited.insert(fs.dir_cluster(Some(&entry))) {
+                            return Err(FatError::CorruptFat(format!(
+                                "{} leads back to a directory it is in",
+                                current_path.join(component).display()
+                            ))
+                            .into());
+                        }
+                        current_dir = Some(entry);
+                        current_path.push(component);
                         found = true;
                         break;
                     } else {
                         // Found a file but expected a directory
-                        return Err(ErrorKind::FileNameNotAllowedError.into());
+                        return Err(FatError::not_a_directory(&requested, component).into());
                     }
                 }
             }
 
             if !found {
-                return Err(ErrorKind::PermanentFileNotAvailable.into());
+                return Err(FatError::not_found(&requested, component).into());
             }
         }
 
-        current_entry.ok_or(ErrorKind::PermanentFileNotAvailable.into())
+        current_entry.ok_or_else(|| FatError::not_found(&requested, path_str).into())
     }
 
-    /// Normalizes an FTP path to a consistent format.
-    ///
-    /// This function handles path components like '..' and '.' to produce a
-    /// canonical path representation.
-    fn normalize_path(&self, path: &Path) -> PathBuf {
-        // Convert to a canonical form, resolving '..' and '.'
-        // This is a simplified version - you might need more robust handling
-        let mut result = PathBuf::new();
-
-        for component in path.components() {
-            match component {
-                std::path::Component::ParentDir => {
-                    // Go up one level if possible
-                    if !result.as_os_str().is_empty() {
-                        result.pop();
+    /// Returns how much the file or directory at `path`, an absolute path, holds, counting the
+    /// files down to `max_depth` directories below it, if limited.
+    fn measure(&self, fs: &FatVolume, path: &Path, max_depth: Option<u32>) -> Result<DirSize> {
+        self.kept_size(path, max_depth, || {
+            let dir = match path == Path::new("/") {
+                true => None,
+                false => {
+                    let entry = self.find(fs, path)?;
+                    if !entry.is_dir() {
+                        return Ok(DirSize::file(entry.len()));
                     }
+                    Some(entry)
                 }
-                std::path::Component::Normal(name) => result.push(name),
-                std::path::Component::CurDir => {} // Skip '.' components
-                _ => {}                            // Skip other components
+            };
+            self.walk_size(fs, path, dir, max_depth)
+        })
+    }
+
+    /// Returns the length to list the directory `dir` at `path`, an absolute path, or the root
+    /// directory if `None`, with when [`VfsBuilder::dir_sizes_in_listings`] is set: the size of
+    /// what it holds down to `max_depth` directories below it, or `len`, the length it has of
+    /// its own, if that can't be told.
+    fn listed_len(
+        &self,
+        fs: &FatVolume,
+        path: &Path,
+        dir: Option<FatEntry<'_>>,
+        len: u64,
+        max_depth: u32,
+    ) -> u64 {
+        let size = self.kept_size(path, Some(max_depth), || {
+            self.walk_size(fs, path, dir, Some(max_depth))
+        });
+        match size {
+            Ok(size) => size.bytes,
+            Err(e) => {
+                let action = "listed the directory without the size of what it holds";
+                self.damaged(path, &e, action);
+                len
             }
         }
+    }
 
-        result
+    /// Returns the size of what is at `path`, an absolute path, down to `max_depth`, as kept from
+    /// before with [`VfsBuilder::cache_dir_sizes`] or [`VfsBuilder::dir_sizes_in_listings`], or
+    /// as `measure` finds it otherwise, keeping it for later if set to.
+    fn kept_size(
+        &self,
+        path: &Path,
+        max_depth: Option<u32>,
+        measure: impl FnOnce() -> Result<DirSize>,
+    ) -> Result<DirSize> {
+        let options = &self.shared.fs_options;
+        let keep = options.cache_dir_sizes || options.dir_sizes_in_listings.is_some();
+        let lock = || (self.shared.dir_sizes.lock()).unwrap_or_else(PoisonError::into_inner);
+        let key = (path.to_path_buf(), max_depth);
+        if keep && let Some(size) = lock().get(&key) {
+            return Ok(*size);
+        }
+        let size = measure()?;
+        if keep {
+            lock().insert(key, size);
+        }
+        Ok(size)
+    }
+
+    /// Adds up the sizes of the files in the directory `dir` at `path`, an absolute path, or the
+    /// root directory if `None`, and in the directories down to `max_depth` below it, if
+    /// limited.
+    fn walk_size(
+        &self,
+        fs: &FatVolume,
+        path: &Path,
+        dir: Option<FatEntry<'_>>,
+        max_depth: Option<u32>,
+    ) -> Result<DirSize> {
+        let policy = self.shared.fs_options.corruption_policy;
+        let mut size = DirSize::default();
+        // The directories counted, which a damaged image may lead to again
+        let mut visited = HashSet::from([fs.dir_cluster(dir.as_ref())]);
+        let mut dirs = vec![(path.to_path_buf(), dir, 0)];
+        while let Some((path, dir, depth)) = dirs.pop() {
+            for entry in fs.read_dir(&path, dir.as_ref(), policy)? {
+                let entry = match entry {
+                    Ok(entry) => entry,
+                    Err(e) if policy == CorruptionPolicy::Lenient => {
+                        let action = "counted the directory as if the rest of it were empty";
+                        self.damaged(&path, &e, action);
+                        break;
+                    }
+                    Err(e) => return Err(e),
+                };
+                if matches!(entry.short_file_name_as_bytes(), b"." | b"..") {
+                    continue;
+                }
+                if !entry.is_dir() {
+                    size += DirSize::file(entry.len());
+                    continue;
+                }
+                let entry_path = path.join(entry.file_name());
+                if !visited.insert(fs.dir_cluster(Some(&entry))) {
+                    let e = Error::from(FatError::CorruptFat(format!(
+                        "{} leads to a directory that was counted already",
+                        entry_path.display()
+                    )));
+                    if policy != CorruptionPolicy::Lenient {
+                        return Err(e);
+                    }
+                    self.damaged(&entry_path, &e, "left the directory out of the size");
+                    continue;
+                }
+                size.directories += 1;
+                if max_depth.is_none_or(|max| depth < max) {
+                    dirs.push((entry_path, Some(entry), depth + 1));
+                }
+            }
+        }
+        Ok(size)
+    }
+
+    /// Drops the sizes kept by [`Vfs::kept_size`], once the filesystem is written or reopened.
+    fn forget_dir_sizes(&self) {
+        (self.shared.dir_sizes.lock())
+            .unwrap_or_else(PoisonError::into_inner)
+            .clear();
+    }
+
+    /// Returns `path` made absolute, with `.` and `..` resolved, as downloads are counted under
+    /// in the stats and errors tell it.
+    fn absolute_path(&self, path: &Path) -> PathBuf {
+        Path::new("/").join(self.normalize_path(path))
+    }
+
+    /// Normalizes an FTP path to a consistent format, relative to the root directory.
+    ///
+    /// See [`sanitize::normalize`].
+    fn normalize_path(&self, path: &Path) -> PathBuf {
+        sanitize::normalize(path)
+    }
+
+    /// Normalizes an FTP path into the '/' separated form fatfs' `Dir` methods expect,
+    /// relative to the root directory.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the path refers to the root directory itself.
+    fn fat_path(&self, path: &Path) -> Result<String> {
+        let path = self.normalize_path(path);
+        if path.as_os_str().is_empty() {
+            return Err(ErrorKind::FileNameNotAllowedError.into());
+        }
+        Ok(path.to_string_lossy().into_owned())
+    }
+
+    /// Logs a warning if `entry`, the file at `path`, was found to be cross-linked when the image
+    /// was opened, see [`VfsBuilder::warn_cross_links`].
+    fn warn_if_cross_linked(&self, path: &Path, entry: &FatEntry<'_>) {
+        let Some(offset) = entry.offset() else {
+            return;
+        };
+        let cross_linked = self.shared.cross_linked.lock();
+        let cross_linked = cross_linked.unwrap_or_else(PoisonError::into_inner);
+        if let Some(other) = cross_linked.get(&offset) {
+            let redaction = self.shared.fs_options.redact_paths;
+            tracing::warn!(
+                path = %Redacted(path, redaction),
+                other = %Redacted(other, redaction),
+                "the file is cross-linked with another, so one of them holds what was written to \
+                 the other"
+            );
+        }
+    }
+
+    /// Logs that damage found at `path`, which `error` tells of, was dealt with by `action`, as
+    /// [`CorruptionPolicy::Lenient`] has it.
+    fn damaged(&self, path: &Path, error: &Error, action: &str) {
+        let redaction = self.shared.fs_options.redact_paths;
+        let cause = std::error::Error::source(error)
+            .map(|cause| RedactedCause(cause, redaction).to_string());
+        tracing::warn!(
+            path = %Redacted(path, redaction),
+            error = %error,
+            cause,
+            "{action}"
+        );
+    }
+}
+
+/// What storage operations return, which tells how many bytes they transferred.
+trait Outcome {
+    /// Returns the bytes transferred, or `None` for operations other than transfers.
+    fn bytes(&self) -> Option<u64> {
+        None
+    }
+}
+
+impl Outcome for () {}
+
+impl Outcome for Meta {}
+
+impl Outcome for String {}
+
+impl Outcome for Vec<Fileinfo<PathBuf, Meta>> {}
+
+/// The bytes downloads and uploads transferred.
+impl Outcome for u64 {
+    fn bytes(&self) -> Option<u64> {
+        Some(*self)
     }
 }
 
@@ -231,91 +2598,261 @@ impl Vfs {
 impl<User: UserDetail> StorageBackend<User> for Vfs {
     type Metadata = Meta;
 
+    fn supported_features(&self) -> u32 {
+        FEATURE_SITEMD5
+    }
+
     async fn metadata<P: AsRef<Path> + Send + Debug>(
         &self,
-        _user: &User,
+        user: &User,
         path: P,
     ) -> Result<Self::Metadata> {
-        let fs = self.open_fs()?;
+        let path = path.as_ref();
+        self.observe(user, Operation::Metadata(path), async {
+            self.decide(user, Operation::Metadata(path))?;
+            let path = match self.route(path).await? {
+                Route::Local(path) => path,
+                Route::Partitions => return Ok(Meta::virtual_dir()),
+                Route::Partition(vfs, path) => return vfs.metadata(user, path).await,
+            };
+            let metadata = self.spawn_with_handle(Access::Read, move |vfs, handle| match handle {
+                FsHandle::Fat(fs) => {
+                    let depth = vfs.shared.fs_options.dir_sizes_in_listings;
+                    let absolute = vfs.absolute_path(&path);
+                    if vfs.normalize_path(&path).as_os_str().is_empty() {
+                        let mut meta = Meta::root(vfs, fs)?;
+                        if let Some(depth) = depth {
+                            meta.len = vfs.listed_len(fs, &absolute, None, meta.len, depth);
+                        }
+                        return Ok(meta);
+                    }
+                    let e = vfs.find(fs, &path)?;
+                    let policy = vfs.shared.fs_options.corruption_policy;
+                    if policy == CorruptionPolicy::Paranoid && e.is_file() {
+                        fs.check_file(&absolute, &e, policy)?;
+                    }
 
-        let e = self.find(&fs, path)?;
+                    let (is_dir, len, modified) = (e.is_dir(), e.len(), e.modified());
+                    let len = match depth {
+                        Some(depth) if is_dir => vfs.listed_len(fs, &absolute, Some(e), len, depth),
+                        _ => len,
+                    };
+                    Ok(Meta {
+                        is_dir,
+                        len,
+                        modified,
+                    })
+                }
+                #[cfg(feature = "exfat")]
+                FsHandle::ExFat(volume) => volume.metadata(&vfs.normalize_path(&path)),
+            });
+            self.timed(Timed::Metadata, metadata).await
+        })
+        .await
+    }
 
-        Ok(Meta {
-            is_dir: e.is_dir(),
-            len: e.len(),
-            modified: e.modified(),
+    /// Streams the file from the image to hash it, like a download without sending it, rather
+    /// than through [`StorageBackend::get`], so that it isn't throttled or counted as a download.
+    async fn md5<P: AsRef<Path> + Send + Debug>(&self, user: &User, path: P) -> Result<String> {
+        let path = path.as_ref();
+        self.observe(user, Operation::Checksum(path), async {
+            self.decide(user, Operation::Checksum(path))?;
+            self.checksum(path, ChecksumAlgorithm::Md5).await
         })
+        .await
     }
 
     async fn list<P: AsRef<Path> + Send + Debug>(
         &self,
-        _user: &User,
+        user: &User,
         path: P,
     ) -> Result<Vec<Fileinfo<PathBuf, Self::Metadata>>>
     where
         <Self as StorageBackend<User>>::Metadata: Metadata,
     {
-        let mut entries = Vec::new();
-        let fs = self.open_fs()?;
-        let dir = if path.as_ref().to_str().unwrap().eq("/") {
-            fs.root_dir()
-        } else {
-            let entry = self.find(&fs, path)?;
-            if entry.is_file() {
-                return Err(Error::from(ErrorKind::FileNameNotAllowedError));
-            }
-            entry.to_dir()
-        };
+        let path = path.as_ref();
+        self.observe(user, Operation::List(path), async {
+            self.decide(user, Operation::List(path))?;
+            let path = match self.route(path).await? {
+                Route::Local(path) => path,
+                Route::Partitions => {
+                    let volumes = self.timed(Timed::List, self.volumes()).await?;
+                    return Ok(volumes
+                        .iter()
+                        .map(|v| Fileinfo {
+                            path: v.name.clone().into(),
+                            metadata: Meta::virtual_dir(),
+                        })
+                        .collect());
+                }
+                Route::Partition(vfs, path) => return vfs.list(user, path).await,
+            };
+            let list = self.spawn_with_handle(Access::Read, move |vfs, handle| {
+                let fs = match handle {
+                    FsHandle::Fat(fs) => &*fs,
+                    #[cfg(feature = "exfat")]
+                    FsHandle::ExFat(volume) => return volume.list(&vfs.normalize_path(&path)),
+                };
+                let mut entries = Vec::new();
+                let dir = if path == Path::new("/") {
+                    None
+                } else {
+                    let entry = vfs.find(fs, &path)?;
+                    if entry.is_file() {
+                        let path = vfs.absolute_path(&path);
+                        return Err(FatError::not_a_directory(&path, &entry.file_name()).into());
+                    }
+                    Some(entry)
+                };
 
-        for sub_result in dir.iter() {
-            let sub = sub_result.map_err(|_| {
-                let e: Error = ErrorKind::PermanentFileNotAvailable.into();
-                e
-            })?;
-            entries.push(Fileinfo {
-                path: sub.file_name().into(),
-                metadata: Meta {
-                    is_dir: sub.is_dir(),
-                    len: sub.len(),
-                    modified: sub.modified(),
-                },
-            })
-        }
+                let policy = vfs.shared.fs_options.corruption_policy;
+                let dir_path = vfs.absolute_path(&path);
+                for sub_result in fs.read_dir(&dir_path, dir.as_ref(), policy)? {
+                    let sub = match sub_result {
+                        Ok(sub) => sub,
+                        Err(e) if policy == CorruptionPolicy::Lenient => {
+                            // fatfs lists nothing after an entry it couldn't read
+                            let action = "left the rest of the directory out of the listing";
+                            vfs.damaged(&dir_path, &e, action);
+                            break;
+                        }
+                        Err(e) => return Err(e),
+                    };
+                    match policy {
+                        CorruptionPolicy::Lenient if sub.file_name().is_empty() => {
+                            let e = FatError::CorruptFat(format!(
+                                "an entry of {} has no name",
+                                dir_path.display()
+                            ));
+                            let action = "left the entry out of the listing";
+                            vfs.damaged(&dir_path, &e.into(), action);
+                            continue;
+                        }
+                        CorruptionPolicy::Paranoid if sub.is_file() => {
+                            fs.check_file(&dir_path.join(sub.file_name()), &sub, policy)?;
+                        }
+                        _ => {}
+                    }
+                    let (name, is_dir, modified) = (sub.file_name(), sub.is_dir(), sub.modified());
+                    let len = match vfs.shared.fs_options.dir_sizes_in_listings {
+                        Some(depth) if is_dir => {
+                            let path = dir_path.join(&name);
+                            vfs.listed_len(fs, &path, Some(sub), 0, depth)
+                        }
+                        _ => sub.len(),
+                    };
+                    entries.push(Fileinfo {
+                        path: name.into(),
+                        metadata: Meta {
+                            is_dir,
+                            len,
+                            modified,
+                        },
+                    })
+                }
 
-        Ok(entries)
+                Ok(entries)
+            });
+            self.timed(Timed::List, list).await
+        })
+        .await
     }
 
     async fn get<P: AsRef<Path> + Send + Debug>(
         &self,
-        _user: &User,
+        user: &User,
         path: P,
         start_pos: u64,
     ) -> Result<Box<dyn tokio::io::AsyncRead + Send + Sync + Unpin>> {
-        let fs = self.open_fs()?;
-        let entry = self.find(&fs, path)?;
-
-        if entry.is_dir() {
-            return Err(ErrorKind::FileNameNotAllowedError.into());
-        }
-
-        let mut file = entry.to_file();
-
-        // Seek to the starting position
-        file.seek(SeekFrom::Start(start_pos))
-            .map_err(|_| ErrorKind::PermanentFileNotAvailable)?;
-
-        // Read entire contents into a Vec<u8>
-        let mut buf = Vec::new();
-        file.read_to_end(&mut buf).map_err(|e| {
-            Error::new(
-                ErrorKind::PermanentFileNotAvailable,
-                format!("read error: {e}"),
-            )
-        })?;
+        let path = path.as_ref();
+        let started = audit::Started::now();
+        let span = OperationSpan::start(&self.shared, user, Operation::Download(path));
+        let result = span
+            .instrument(async {
+                let limits = self.transfer_limits(self.decide(user, Operation::Download(path))?);
+                let download = match self.route(path).await? {
+                    Route::Local(path) => {
+                        self.timed(Timed::Get, self.download(path, start_pos))
+                            .await?
+                    }
+                    Route::Partitions => return Err(ErrorKind::FileNameNotAllowedError.into()),
+                    Route::Partition(vfs, path) => {
+                        self.timed(Timed::Get, vfs.download(path, start_pos))
+                            .await?
+                    }
+                };
+                Ok(Throttled::new(download, limits))
+            })
+            .await;
+        // The span of a download that started ends with the download
+        let download = match result {
+            Ok(download) => download,
+            Err(e) => {
+                span.end(Some(&e));
+                if let Some(audit) = &self.audit {
+                    let user = user.to_string();
+                    let operation = Operation::Download(path);
+                    audit.record(&started.event(&user, operation, Err(&e), None));
+                }
+                return self.report(user, Operation::Download(path), Err(e));
+            }
+        };
+        Ok(Box::new(Tracked::start(
+            download,
+            self,
+            user.to_string(),
+            path.to_path_buf(),
+            start_pos,
+            (started, span),
+        )))
+    }
 
-        // Return a cursor over the buffer to provide async access
-        let cursor = Cursor::new(buf);
-        Ok(Box::new(cursor))
+    async fn get_into<'a, P, W: ?Sized>(
+        &self,
+        user: &User,
+        path: P,
+        start_pos: u64,
+        output: &'a mut W,
+    ) -> Result<u64>
+    where
+        W: tokio::io::AsyncWrite + Unpin + Sync + Send,
+        P: AsRef<Path> + Send + Debug,
+    {
+        let path = path.as_ref();
+        self.observe(user, Operation::Download(path), async {
+            let limits = self.transfer_limits(self.decide(user, Operation::Download(path))?);
+            let download = match self.route(path).await? {
+                Route::Local(path) => {
+                    self.timed(Timed::Get, self.download(path, start_pos))
+                        .await?
+                }
+                Route::Partitions => return Err(ErrorKind::FileNameNotAllowedError.into()),
+                Route::Partition(vfs, path) => {
+                    self.timed(Timed::Get, vfs.download(path, start_pos))
+                        .await?
+                }
+            };
+            let started = Instant::now();
+            let key = self.absolute_path(path);
+            self.shared.stats.started(&key);
+            if let Some(hooks) = &self.hooks {
+                hooks.on_get_start(&user.to_string(), path, start_pos);
+            }
+            let written = if limits.is_empty() {
+                // Write the chunks out as they were read rather than copying them through
+                // another buffer
+                download.write_to(output).await?
+            } else {
+                let mut throttled = Throttled::new(download, limits);
+                tokio::io::copy(&mut throttled, output).await?
+            };
+            self.shared.stats.served(&key, written);
+            if let Some(hooks) = &self.hooks {
+                hooks.on_get_complete(&user.to_string(), path, written, started.elapsed());
+            }
+            Ok(written)
+        })
+        .await
     }
 
     async fn put<
@@ -323,46 +2860,181 @@ impl<User: UserDetail> StorageBackend<User> for Vfs {
         R: tokio::io::AsyncRead + Send + Sync + Unpin + 'static,
     >(
         &self,
-        _user: &User,
-        _input: R,
-        _path: P,
-        _start_pos: u64,
+        user: &User,
+        input: R,
+        path: P,
+        start_pos: u64,
     ) -> Result<u64> {
-        Err(Error::from(ErrorKind::PermissionDenied))
+        let path = path.as_ref();
+        self.observe(user, Operation::Upload(path), async {
+            let limits = self.transfer_limits(self.decide(user, Operation::Upload(path))?);
+            self.ensure_writable()?;
+            let input = Throttled::new(input, limits);
+            let started = Instant::now();
+            if let Some(hooks) = &self.hooks {
+                hooks.on_put_start(&user.to_string(), path, start_pos);
+            }
+            let written = match self.route(path).await? {
+                Route::Local(path) => self.upload(input, path, start_pos).await?,
+                Route::Partitions => return Err(ErrorKind::FileNameNotAllowedError.into()),
+                Route::Partition(vfs, path) => vfs.upload(input, path, start_pos).await?,
+            };
+            if let Some(hooks) = &self.hooks {
+                hooks.on_put_complete(&user.to_string(), path, written, started.elapsed());
+            }
+            Ok(written)
+        })
+        .await
     }
 
-    async fn del<P: AsRef<Path> + Send + Debug>(&self, _user: &User, _path: P) -> Result<()> {
-        Err(Error::from(ErrorKind::PermissionDenied))
+    async fn del<P: AsRef<Path> + Send + Debug>(&self, user: &User, path: P) -> Result<()> {
+        let path = path.as_ref();
+        self.observe(user, Operation::Delete(path), async {
+            self.decide(user, Operation::Delete(path))?;
+            Err(Error::from(ErrorKind::PermissionDenied))
+        })
+        .await
     }
 
-    async fn mkd<P: AsRef<Path> + Send + Debug>(&self, _user: &User, _path: P) -> Result<()> {
-        Err(Error::from(ErrorKind::PermissionDenied))
+    async fn mkd<P: AsRef<Path> + Send + Debug>(&self, user: &User, path: P) -> Result<()> {
+        let path = path.as_ref();
+        self.observe(user, Operation::CreateDir(path), async {
+            self.decide(user, Operation::CreateDir(path))?;
+            self.ensure_writable()?;
+            let path = match self.route(path).await? {
+                Route::Local(path) => path,
+                Route::Partitions => return Err(ErrorKind::PermanentFileNotAvailable.into()),
+                Route::Partition(vfs, path) => return vfs.mkd(user, path).await,
+            };
+            self.spawn_with_fs(move |vfs, fs| {
+                // fatfs happily opens an existing directory but MKD should fail
+                if vfs.find(fs, &path).is_ok() {
+                    return Err(ErrorKind::PermanentFileNotAvailable.into());
+                }
+
+                let path = vfs.fat_path(&path)?;
+                fs.root_dir().create_dir(&path).map_err(Error::from)?;
+                Ok(())
+            })
+            .await
+        })
+        .await
     }
 
     async fn rename<P: AsRef<Path> + Send + Debug>(
         &self,
-        _user: &User,
-        _from: P,
-        _to: P,
+        user: &User,
+        from: P,
+        to: P,
     ) -> Result<()> {
-        Err(Error::from(ErrorKind::PermissionDenied))
+        let (from, to) = (from.as_ref(), to.as_ref());
+        let operation = Operation::Rename { from, to };
+        self.observe(user, operation, async {
+            self.decide(user, operation)?;
+            self.ensure_writable()?;
+            let (from, to) = match (self.route(from).await?, self.route(to).await?) {
+                (Route::Local(from), Route::Local(to)) => (from, to),
+                (Route::Partition(vfs, from), Route::Partition(to_vfs, to))
+                    if Arc::ptr_eq(&vfs.shared, &to_vfs.shared) =>
+                {
+                    return vfs.rename(user, from, to).await;
+                }
+                // Partitions themselves can't be renamed and entries can't move between them
+                _ => return Err(ErrorKind::PermissionDenied.into()),
+            };
+            self.spawn_with_fs(move |vfs, fs| {
+                let entry = vfs.find(fs, &from)?;
+                let from = vfs.fat_path(&from)?;
+                let to = vfs.fat_path(&to)?;
+
+                // fatfs doesn't rewrite the '..' entry of a directory that moves to another parent,
+                // so only allow directories to be renamed in place.
+                if entry.is_dir() {
+                    let from_parent = Path::new(&from).parent().map(Path::to_string_lossy);
+                    let to_parent = Path::new(&to).parent().map(Path::to_string_lossy);
+                    let same_parent = match (from_parent, to_parent) {
+                        (Some(a), Some(b)) => a.eq_ignore_ascii_case(&b),
+                        _ => false,
+                    };
+                    if !same_parent {
+                        return Err(ErrorKind::PermissionDenied.into());
+                    }
+                }
+
+                let root_dir = fs.root_dir();
+                root_dir.rename(&from, &root_dir, &to).map_err(Error::from)
+            })
+            .await
+        })
+        .await
     }
 
-    async fn rmd<P: AsRef<Path> + Send + Debug>(&self, _user: &User, _path: P) -> Result<()> {
-        Err(Error::from(ErrorKind::PermissionDenied))
+    async fn rmd<P: AsRef<Path> + Send + Debug>(&self, user: &User, path: P) -> Result<()> {
+        let path = path.as_ref();
+        self.observe(user, Operation::RemoveDir(path), async {
+            self.decide(user, Operation::RemoveDir(path))?;
+            self.ensure_writable()?;
+            let path = match self.route(path).await? {
+                Route::Local(path) => path,
+                Route::Partitions => return Err(ErrorKind::PermissionDenied.into()),
+                Route::Partition(vfs, path) => return vfs.rmd(user, path).await,
+            };
+            self.spawn_with_fs(move |vfs, fs| {
+                let entry = vfs.find(fs, &path)?;
+                if !entry.is_dir() {
+                    let path = vfs.absolute_path(&path);
+                    return Err(FatError::not_a_directory(&path, &entry.file_name()).into());
+                }
+
+                // Check ourselves since fatfs reports a non-empty directory as a generic I/O error
+                for sub_result in fs.read_dir(
+                    &vfs.absolute_path(&path),
+                    Some(&entry),
+                    CorruptionPolicy::Strict,
+                )? {
+                    let sub = sub_result?;
+                    let name = sub.short_file_name_as_bytes();
+                    if name != b"." && name != b".." {
+                        return Err(ErrorKind::PermanentDirectoryNotEmpty.into());
+                    }
+                }
+
+                let path = vfs.fat_path(&path)?;
+                fs.root_dir().remove(&path).map_err(Error::from)
+            })
+            .await
+        })
+        .await
     }
 
-    async fn cwd<P: AsRef<Path> + Send + Debug>(&self, _user: &User, path: P) -> Result<()> {
-        let fs = self.open_fs()?;
-        if path.as_ref().to_str().unwrap().eq("/") {
-            return Ok(());
-        }
+    async fn cwd<P: AsRef<Path> + Send + Debug>(&self, user: &User, path: P) -> Result<()> {
+        let path = path.as_ref();
+        self.observe(user, Operation::ChangeDir(path), async {
+            self.decide(user, Operation::ChangeDir(path))?;
+            let path = match self.route(path).await? {
+                Route::Local(path) => path,
+                Route::Partitions => return Ok(()),
+                Route::Partition(vfs, path) => return vfs.cwd(user, path).await,
+            };
+            if path == Path::new("/") {
+                return Ok(());
+            }
 
-        let entry = self.find(&fs, path)?;
-        if entry.is_file() {
-            return Err(Error::from(ErrorKind::FileNameNotAllowedError));
-        }
-        Ok(())
+            self.spawn_with_handle(Access::Read, move |vfs, handle| match handle {
+                FsHandle::Fat(fs) => {
+                    let entry = vfs.find(fs, &path)?;
+                    if entry.is_file() {
+                        let path = vfs.absolute_path(&path);
+                        return Err(FatError::not_a_directory(&path, &entry.file_name()).into());
+                    }
+                    Ok(())
+                }
+                #[cfg(feature = "exfat")]
+                FsHandle::ExFat(volume) => volume.cwd(&vfs.normalize_path(&path)),
+            })
+            .await
+        })
+        .await
     }
 }
 
@@ -377,6 +3049,72 @@ pub struct Meta {
     modified: DateTime,
 }
 
+impl Meta {
+    /// Metadata for directories that don't exist in a FAT filesystem, such as the directories
+    /// partitions are served under, dated at the FAT epoch.
+    fn virtual_dir() -> Self {
+        Self {
+            is_dir: true,
+            len: 0,
+            modified: DateTime {
+                date: Date {
+                    year: 1980,
+                    month: 1,
+                    day: 1,
+                },
+                time: Time {
+                    hour: 0,
+                    min: 0,
+                    sec: 0,
+                    millis: 0,
+                },
+            },
+        }
+    }
+
+    /// Metadata for the root directory, which has no entry of its own to tell when it was
+    /// modified, so it is dated with the entry in it that was modified last, or at the FAT epoch
+    /// if it is empty.
+    fn root(vfs: &Vfs, fs: &FatVolume) -> Result<Self> {
+        let mut modified = None;
+        let policy = vfs.shared.fs_options.corruption_policy;
+        for entry in fs.read_dir(Path::new("/"), None, policy)? {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) if policy == CorruptionPolicy::Lenient => {
+                    let action = "dated the root directory as if the rest of it were empty";
+                    vfs.damaged(Path::new("/"), &e, action);
+                    break;
+                }
+                Err(e) => return Err(e),
+            };
+            let dt = entry.modified();
+            let key = |dt: &DateTime| {
+                let (date, time) = (dt.date, dt.time);
+                (
+                    date.year,
+                    date.month,
+                    date.day,
+                    time.hour,
+                    time.min,
+                    time.sec,
+                    time.millis,
+                )
+            };
+            if modified.is_none_or(|latest| key(&dt) > key(&latest)) {
+                modified = Some(dt);
+            }
+        }
+        Ok(match modified {
+            Some(modified) => Self {
+                modified,
+                ..Self::virtual_dir()
+            },
+            None => Self::virtual_dir(),
+        })
+    }
+}
+
 impl Metadata for Meta {
     fn len(&self) -> u64 {
         self.len
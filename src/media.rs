@@ -0,0 +1,34 @@
+//! Recognizes the errors of images whose media went away, such as an SD card pulled from its
+//! reader, so that the image can be reopened once the media is back.
+
+use std::io;
+
+/// The OS errors that mean the device or its media is gone, or its handle went stale.
+#[cfg(target_os = "linux")]
+const LOST: &[i32] = &[
+    libc::EIO,
+    libc::ENXIO,
+    libc::ENODEV,
+    libc::ESTALE,
+    libc::ENOMEDIUM,
+];
+
+#[cfg(all(unix, not(target_os = "linux")))]
+const LOST: &[i32] = &[libc::EIO, libc::ENXIO, libc::ENODEV, libc::ESTALE];
+
+#[cfg(windows)]
+const LOST: &[i32] = &[
+    windows_sys::Win32::Foundation::ERROR_NOT_READY as i32,
+    windows_sys::Win32::Foundation::ERROR_DEV_NOT_EXIST as i32,
+    windows_sys::Win32::Foundation::ERROR_MEDIA_CHANGED as i32,
+    windows_sys::Win32::Foundation::ERROR_NO_MEDIA_IN_DRIVE as i32,
+    windows_sys::Win32::Foundation::ERROR_DEVICE_NOT_CONNECTED as i32,
+];
+
+#[cfg(not(any(unix, windows)))]
+const LOST: &[i32] = &[];
+
+/// Tells whether `e` means the media of the image went away.
+pub(crate) fn is_lost(e: &io::Error) -> bool {
+    e.raw_os_error().is_some_and(|code| LOST.contains(&code))
+}
@@ -0,0 +1,157 @@
+//! The public memory budget that bounds what file systems buffer in memory.
+//!
+//! Caches reserve their memory from the budget when they are created and go without when it is
+//! spent, reading from the image instead. Transfers reserve the memory of the chunks they keep in
+//! flight before they start, waiting for other transfers to finish when it is spent, so that a
+//! flood of clients slows down rather than running the server out of memory.
+//!
+//! Caches hold their memory for as long as the image is open, so they may only take half of the
+//! budget. The other half is left to transfers, which would otherwise wait for good.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::sync::Semaphore;
+
+/// The granularity memory is reserved with, so that budgets of terabytes fit the semaphore.
+const UNIT: u64 = 4096;
+
+/// A limit on the memory that file systems buffer images and transfers in, shared by all the
+/// file systems it is given to.
+///
+/// Counted against the budget are:
+///
+/// - the copy of images loaded into memory with
+///   [`VfsBuilder::load_into_memory`](crate::VfsBuilder::load_into_memory), which reads the
+///   image from where it is instead when the budget doesn't allow a copy,
+/// - block caches and FAT caches, set up with
+///   [`VfsBuilder::block_cache`](crate::VfsBuilder::block_cache) and
+///   [`VfsBuilder::cache_fat`](crate::VfsBuilder::cache_fat), which aren't used when the budget
+///   doesn't allow them,
+/// - the frames of seekable zstd images kept decompressed, which are decompressed into a
+///   temporary file instead when the budget doesn't allow them,
+/// - the chunks downloads read ahead and uploads buffer, which wait for memory to become
+///   available before the transfer starts.
+///
+/// Caches take at most half of the budget, leaving the rest to transfers. The small buffers in
+/// front of image files and of the FAT, the structures of the filesystem, and images given as
+/// bytes aren't counted. Memory is counted in units of 4 KiB.
+///
+/// Budgets are usually kept in a `static`, or leaked with [`Box::leak`] when their size is only
+/// known at runtime.
+///
+/// # Example
+///
+/// ```rust
+/// use unftp_sbe_fatfs::{MemoryBudget, VfsBuilder};
+///
+/// static MEMORY: MemoryBudget = MemoryBudget::new(1024 * 1024 * 1024);
+///
+/// // Both images are copied into the 512 MiB the budget leaves to caches, if they fit
+/// let images = VfsBuilder::new("path/to/fat/image.img")
+///     .load_into_memory(256 * 1024 * 1024)
+///     .memory_budget(&MEMORY)
+///     .build();
+/// let esp = VfsBuilder::new("path/to/esp.img")
+///     .load_into_memory(256 * 1024 * 1024)
+///     .memory_budget(&MEMORY)
+///     .build();
+/// ```
+#[derive(Debug)]
+pub struct MemoryBudget {
+    /// The units not reserved.
+    units: Semaphore,
+    /// The number of units in the budget.
+    total: u64,
+    /// The number of units caches reserved.
+    cached: AtomicU64,
+}
+
+/// Memory reserved from a [`MemoryBudget`], given back when dropped. Reservations made without
+/// a budget hold nothing.
+#[derive(Debug, Default)]
+pub(crate) struct Reservation {
+    budget: Option<&'static MemoryBudget>,
+    units: u32,
+    /// Whether a cache reserved the memory.
+    cached: bool,
+}
+
+impl MemoryBudget {
+    /// Creates a budget of `bytes`.
+    pub const fn new(bytes: usize) -> Self {
+        let total = bytes as u64 / UNIT;
+        Self {
+            units: Semaphore::const_new(total as usize),
+            total,
+            cached: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns how many bytes of the budget aren't reserved at the moment.
+    pub fn available(&self) -> usize {
+        self.units.available_permits() * UNIT as usize
+    }
+}
+
+/// Reserves `bytes` for a cache from `budget`, if there is one, or returns `None` if that much
+/// memory isn't available right now, or would leave less than half of the budget to transfers.
+pub(crate) fn try_reserve(
+    budget: Option<&'static MemoryBudget>,
+    bytes: u64,
+) -> Option<Reservation> {
+    let Some(budget) = budget else {
+        return Some(Reservation::default());
+    };
+    let units = u32::try_from(bytes.div_ceil(UNIT)).ok()?;
+    budget
+        .cached
+        .fetch_update(Ordering::AcqRel, Ordering::Acquire, |cached| {
+            Some(cached + u64::from(units)).filter(|&cached| cached <= budget.total / 2)
+        })
+        .ok()?;
+    match budget.units.try_acquire_many(units) {
+        Ok(permit) => permit.forget(),
+        Err(_) => {
+            budget.cached.fetch_sub(u64::from(units), Ordering::AcqRel);
+            return None;
+        }
+    }
+    Some(Reservation {
+        budget: Some(budget),
+        units,
+        cached: true,
+    })
+}
+
+/// Reserves `bytes` for a transfer from `budget`, if there is one, waiting until that much memory
+/// is available. Reservations larger than the half of the budget left to transfers take all of
+/// that.
+pub(crate) async fn reserve(budget: Option<&'static MemoryBudget>, bytes: u64) -> Reservation {
+    let Some(budget) = budget else {
+        return Reservation::default();
+    };
+    let most = budget.total - budget.total / 2;
+    let units = bytes.div_ceil(UNIT).min(most).min(u64::from(u32::MAX)) as u32;
+    // The semaphore is never closed, so this only returns once the units were taken
+    if let Ok(permit) = budget.units.acquire_many(units).await {
+        permit.forget();
+    }
+    Reservation {
+        budget: Some(budget),
+        units,
+        cached: false,
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        let Some(budget) = self.budget else {
+            return;
+        };
+        budget.units.add_permits(self.units as usize);
+        if self.cached {
+            budget
+                .cached
+                .fetch_sub(u64::from(self.units), Ordering::AcqRel);
+        }
+    }
+}
@@ -0,0 +1,106 @@
+//! Exports what file systems do as Prometheus metrics, registered with the default registry that
+//! libunftp registers its own metrics with, so that they are served along with those.
+
+use crate::{Operation, stats::Timed};
+use prometheus::{
+    HistogramVec, IntCounter, IntCounterVec, register_histogram_vec, register_int_counter,
+    register_int_counter_vec,
+};
+use std::{sync::LazyLock, time::Duration};
+use unftp_core::storage::ErrorKind;
+
+static OPENS: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "fatfs_image_opens_total",
+        "Total number of times images were opened and their filesystem mounted."
+    )
+    .unwrap()
+});
+static LISTINGS: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "fatfs_listings_total",
+        "Total number of directories listed."
+    )
+    .unwrap()
+});
+static DOWNLOADS: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "fatfs_downloads_total",
+        "Total number of downloads started."
+    )
+    .unwrap()
+});
+static BYTES_SERVED: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "fatfs_bytes_served_total",
+        "Total number of bytes sent to clients by downloads."
+    )
+    .unwrap()
+});
+static ERRORS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "fatfs_errors_total",
+        "Total number of operations that failed, by operation and kind of error.",
+        &["operation", "kind"]
+    )
+    .unwrap()
+});
+static DURATIONS: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "fatfs_operation_duration_seconds",
+        "How long opening images, listings, metadata lookups and downloads until their first \
+         byte took.",
+        &["operation"],
+        crate::stats::BOUNDS
+            .iter()
+            .map(|&micros| Duration::from_micros(micros).as_secs_f64())
+            .collect()
+    )
+    .unwrap()
+});
+
+/// Registers the metrics, so that they are exported before anything happened.
+pub(crate) fn register() {
+    LazyLock::force(&OPENS);
+    LazyLock::force(&LISTINGS);
+    LazyLock::force(&DOWNLOADS);
+    LazyLock::force(&BYTES_SERVED);
+    LazyLock::force(&ERRORS);
+    LazyLock::force(&DURATIONS);
+}
+
+/// Counts a download that started.
+pub(crate) fn download_started() {
+    DOWNLOADS.inc();
+}
+
+/// Counts `bytes` sent to a client.
+pub(crate) fn served(bytes: u64) {
+    BYTES_SERVED.inc_by(bytes);
+}
+
+/// Counts `operation`, which took `duration`.
+pub(crate) fn timed(operation: Timed, duration: Duration) {
+    let name = match operation {
+        Timed::Open => {
+            OPENS.inc();
+            "open"
+        }
+        Timed::List => {
+            LISTINGS.inc();
+            "list"
+        }
+        Timed::Metadata => "metadata",
+        Timed::Get => "get",
+    };
+    DURATIONS
+        .with_label_values(&[name])
+        .observe(duration.as_secs_f64());
+}
+
+/// Counts a failure of `operation` with an error of `kind`.
+pub(crate) fn failed(operation: Operation<'_>, kind: ErrorKind) {
+    ERRORS
+        .with_label_values(&[operation.name(), &format!("{kind:?}")])
+        .inc();
+}
@@ -0,0 +1,58 @@
+//! The error returned when an image turns out not to be servable up front.
+
+use std::{
+    error::Error as _,
+    fmt,
+    path::{Path, PathBuf},
+};
+use unftp_core::storage::{Error, ErrorKind};
+
+/// The error returned by [`Vfs::try_new`](crate::Vfs::try_new) when the image can't be opened,
+/// or holds no filesystem that can be mounted.
+#[derive(Debug)]
+pub struct OpenError {
+    path: PathBuf,
+    error: Error,
+}
+
+impl OpenError {
+    pub(crate) fn new(path: &Path, error: Error) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            error,
+        }
+    }
+
+    /// Returns the path of the image.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns the kind of error operations on the image would have failed with.
+    pub fn kind(&self) -> ErrorKind {
+        self.error.kind()
+    }
+}
+
+impl fmt::Display for OpenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "cannot serve the image {}: ", self.path.display())?;
+        // The storage error itself only tells the FTP reply clients would get
+        match self.error.source() {
+            Some(cause) => write!(f, "{cause}"),
+            None => write!(f, "{}", self.error),
+        }
+    }
+}
+
+impl std::error::Error for OpenError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+impl From<OpenError> for Error {
+    fn from(e: OpenError) -> Self {
+        e.error
+    }
+}
@@ -0,0 +1,57 @@
+//! Limits how many times an image is opened at once.
+//!
+//! Every download that can't reuse a filesystem opened earlier opens the image and mounts the
+//! filesystem in it, reading the partition table, the boot sector and more. A client flooding the
+//! server with connections would have the image opened hundreds of times at once, so opens beyond
+//! the limit wait for earlier ones to finish instead.
+
+use std::sync::{Condvar, Mutex, MutexGuard, PoisonError};
+
+/// How many times an image is opened at once unless configured otherwise.
+pub(crate) const DEFAULT_MAX_OPENS: usize = 8;
+
+/// Counts the opens in progress, making further ones wait once there are as many as allowed.
+#[derive(Debug)]
+pub(crate) struct OpenLimit {
+    max: usize,
+    opening: Mutex<usize>,
+    finished: Condvar,
+}
+
+/// Lets one open proceed until dropped.
+pub(crate) struct OpenPermit<'a>(&'a OpenLimit);
+
+impl OpenLimit {
+    pub(crate) fn new(max: usize) -> Self {
+        Self {
+            max,
+            opening: Mutex::new(0),
+            finished: Condvar::new(),
+        }
+    }
+
+    /// Waits until fewer opens than allowed are in progress and returns the permit for another.
+    pub(crate) fn acquire(&self) -> OpenPermit<'_> {
+        let mut opening = self.lock();
+        while *opening >= self.max {
+            opening = self
+                .finished
+                .wait(opening)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+        *opening += 1;
+        OpenPermit(self)
+    }
+
+    /// Locks the count, which is never left half updated.
+    fn lock(&self) -> MutexGuard<'_, usize> {
+        self.opening.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl Drop for OpenPermit<'_> {
+    fn drop(&mut self) {
+        *self.0.lock() -= 1;
+        self.0.finished.notify_one();
+    }
+}
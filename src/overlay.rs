@@ -0,0 +1,125 @@
+//! Keeps what [`Vfs::repair`](crate::Vfs::repair) writes to read-only images in memory, on top
+//! of the image.
+//!
+//! Read-only images are served through an [`OverlayDisk`], which takes the blocks written to it
+//! into the [`Overlay`] shared by every stream over the image, and reads them from there from
+//! then on. The image itself is left as it is.
+
+use crate::{format::read_up_to, image::Disk};
+use std::{
+    collections::{BTreeMap, btree_map::Entry},
+    io::{self, Read, Seek, SeekFrom, Write},
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+};
+
+/// The size of the blocks taken into the overlay, a sector.
+const BLOCK_LEN: u64 = 512;
+
+/// The blocks written to a read-only image, shared by the streams over it.
+#[derive(Clone, Default)]
+pub(crate) struct Overlay(Arc<Mutex<Blocks>>);
+
+#[derive(Default)]
+struct Blocks {
+    /// The generation of the image the blocks were written to.
+    generation: u64,
+    /// The blocks by their index.
+    blocks: BTreeMap<u64, Box<[u8]>>,
+}
+
+impl Overlay {
+    fn lock(&self) -> MutexGuard<'_, Blocks> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Returns `inner`, a stream over generation `generation` of the image, with the overlay on
+    /// top of it. Blocks written to an earlier generation are dropped, as the image they were
+    /// written to was replaced.
+    pub(crate) fn wrap(&self, inner: Box<dyn Disk>, generation: u64) -> Box<dyn Disk> {
+        let mut blocks = self.lock();
+        if blocks.generation != generation {
+            blocks.blocks.clear();
+            blocks.generation = generation;
+        }
+        Box::new(OverlayDisk {
+            inner,
+            overlay: self.clone(),
+            pos: 0,
+        })
+    }
+}
+
+/// A stream over an image that writes to an [`Overlay`] instead of the image.
+struct OverlayDisk {
+    inner: Box<dyn Disk>,
+    overlay: Overlay,
+    pos: u64,
+}
+
+impl Read for OverlayDisk {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let index = self.pos / BLOCK_LEN;
+        let within = (self.pos % BLOCK_LEN) as usize;
+        let len = {
+            let blocks = self.overlay.lock();
+            if let Some(block) = blocks.blocks.get(&index) {
+                let rest = block.get(within..).unwrap_or_default();
+                let n = rest.len().min(buf.len());
+                buf[..n].copy_from_slice(&rest[..n]);
+                self.pos += n as u64;
+                return Ok(n);
+            }
+            // Reads of the image stop at the next block of the overlay
+            match blocks.blocks.range(index..).next() {
+                Some((&next, _)) => ((next * BLOCK_LEN - self.pos) as usize).min(buf.len()),
+                None => buf.len(),
+            }
+        };
+        self.inner.seek(SeekFrom::Start(self.pos))?;
+        let n = self.inner.read(&mut buf[..len])?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Write for OverlayDisk {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let index = self.pos / BLOCK_LEN;
+        let within = (self.pos % BLOCK_LEN) as usize;
+        let mut blocks = self.overlay.lock();
+        let block = match blocks.blocks.entry(index) {
+            Entry::Occupied(block) => block.into_mut(),
+            Entry::Vacant(vacant) => {
+                let mut block = vec![0; BLOCK_LEN as usize];
+                self.inner.seek(SeekFrom::Start(index * BLOCK_LEN))?;
+                let n = read_up_to(&mut *self.inner, &mut block)?;
+                block.truncate(n);
+                vacant.insert(block.into_boxed_slice())
+            }
+        };
+        let rest = block.get_mut(within..).unwrap_or_default();
+        let n = rest.len().min(buf.len());
+        rest[..n].copy_from_slice(&buf[..n]);
+        self.pos += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for OverlayDisk {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.pos = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => {
+                let len = self.inner.seek(SeekFrom::End(0))?;
+                len.checked_add_signed(offset)
+            }
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+        }
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "seek to a negative offset"))?;
+        Ok(self.pos)
+    }
+}
@@ -0,0 +1,598 @@
+//! Locates FAT filesystems inside partitioned disk images.
+//!
+//! Sector sizes are assumed to be 512 bytes, which holds for practically all disk images.
+
+use crate::{
+    backup_boot,
+    diagnose::{self, Diagnosis},
+    format::invalid_data,
+    image::{Disk, Slice},
+};
+use std::{
+    collections::HashSet,
+    fmt,
+    io::{self, Read, Seek, SeekFrom},
+    str::FromStr,
+};
+
+/// The sector size partition tables are expressed in.
+pub(crate) const SECTOR_SIZE: u64 = 512;
+
+/// Offset of the first of the four primary partition entries in the MBR.
+const MBR_ENTRIES_OFFSET: usize = 446;
+
+/// The MBR partition type of the protective partition that precedes a GPT.
+const MBR_TYPE_GPT_PROTECTIVE: u8 = 0xEE;
+
+/// The MBR partition type of an EFI System Partition.
+const MBR_TYPE_ESP: u8 = 0xEF;
+
+/// The GPT partition type GUID of an EFI System Partition, C12A7328-F81F-11D2-BA4B-00A0C93EC93B.
+const GPT_TYPE_ESP: Guid = Guid([
+    0x28, 0x73, 0x2A, 0xC1, 0x1F, 0xF8, 0xD2, 0x11, 0xBA, 0x4B, 0x00, 0xA0, 0xC9, 0x3E, 0xC9, 0x3B,
+]);
+
+/// The MBR partition types of extended partitions, which hold a chain of logical partitions.
+const MBR_TYPES_EXTENDED: [u8; 3] = [0x05, 0x0F, 0x85];
+
+/// The sector size of ISO 9660 images, which El Torito boot catalogs are expressed in as well.
+const ISO_SECTOR_SIZE: u64 = 2048;
+
+/// The offset of the El Torito boot record volume descriptor in an ISO 9660 image.
+const EL_TORITO_RECORD_OFFSET: u64 = 17 * ISO_SECTOR_SIZE;
+
+/// The El Torito platform ID of EFI boot images.
+const EL_TORITO_PLATFORM_EFI: u8 = 0xEF;
+
+/// Upper bound on the number of extended boot records we follow the chain of, and so on the
+/// number of logical partitions, which guards against chains that loop.
+const MAX_LOGICAL_PARTITIONS: usize = 128;
+
+/// Upper bound on the size of the GPT partition entry array we're willing to read.
+const GPT_MAX_ENTRIES_SIZE: u64 = 1024 * 1024;
+
+/// Selects the part of a disk image that holds the FAT filesystem to serve.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum PartitionSelect {
+    /// Probes the image: a bare filesystem is served whole, otherwise the first partition that
+    /// starts with a FAT or exFAT boot sector is served.
+    ///
+    /// Compressed images and virtual disk formats are decoded before probing, so this finds the
+    /// filesystem of most disk dumps without further configuration. Installer ISOs, whether
+    /// hybrids with a partition table or not, are served the FAT image of their EFI boot entry.
+    #[default]
+    Auto,
+    /// The image is a bare FAT filesystem without a partition table, as created by `mkfs.fat`
+    /// on a file.
+    Whole,
+    /// The image starts with a partition table and the filesystem is in the partition with the
+    /// given zero-based index.
+    ///
+    /// For DOS (MBR) partition tables this is 0 to 3 for the primary partitions, followed by the
+    /// logical partitions in an extended partition from 4 on, in the order of their chain. Index 4
+    /// is what Linux calls partition 5. For GPT it is the index in the partition entry array.
+    Index(usize),
+    /// The image has a GPT and the filesystem is in the partition with the given unique
+    /// partition GUID.
+    Guid(Guid),
+    /// The image has a GPT and the filesystem is in the first partition with the given name.
+    Name(String),
+    /// The filesystem is in the EFI System Partition, found by its partition type in either an
+    /// MBR or a GPT. This serves the boot loaders and firmware updates on dumps of UEFI disks.
+    ///
+    /// ISO 9660 images without such a partition are served the FAT image of the EFI entry in
+    /// their El Torito boot catalog.
+    Esp,
+    /// The filesystem takes up `len` bytes of the image, starting `offset` bytes in.
+    ///
+    /// This suits firmware images in which a bootloader or header precedes the filesystem at a
+    /// fixed offset, without a partition table pointing to it.
+    Range {
+        /// The offset of the filesystem in bytes.
+        offset: u64,
+        /// The size of the filesystem in bytes.
+        len: u64,
+    },
+    /// The image starts with a partition table and every partition is served, each as a
+    /// top-level directory named after its index: `p0`, `p1` and so on.
+    ///
+    /// Partitions that don't hold a FAT filesystem are listed too but can't be entered.
+    All,
+}
+
+/// A GUID as used in GPT partition tables.
+///
+/// Parses from and displays as the usual hyphenated form, e.g.
+/// `C12A7328-F81F-11D2-BA4B-00A0C93EC93B`.
+///
+/// # Example
+///
+/// ```rust
+/// use unftp_sbe_fatfs::{Guid, PartitionSelect, Vfs};
+///
+/// let guid: Guid = "0FC63DAF-8483-4772-8E79-3D69D8477DE4".parse().unwrap();
+/// let vfs = Vfs::builder("path/to/disk.img")
+///     .partition(PartitionSelect::Guid(guid))
+///     .build();
+/// ```
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Guid([u8; 16]);
+
+impl Guid {
+    /// Creates a GUID from its 16 byte on-disk representation, in which the first three fields
+    /// are little endian.
+    pub const fn from_bytes(bytes: [u8; 16]) -> Self {
+        Self(bytes)
+    }
+
+    /// Returns the 16 byte on-disk representation.
+    pub const fn to_bytes(self) -> [u8; 16] {
+        self.0
+    }
+}
+
+/// Maps positions in the textual form of a GUID (as hex digit pairs) to its on-disk byte order.
+const GUID_BYTE_ORDER: [usize; 16] = [3, 2, 1, 0, 5, 4, 7, 6, 8, 9, 10, 11, 12, 13, 14, 15];
+
+impl fmt::Display for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, &pos) in GUID_BYTE_ORDER.iter().enumerate() {
+            if matches!(i, 4 | 6 | 8 | 10) {
+                f.write_str("-")?;
+            }
+            write!(f, "{:02X}", self.0[pos])?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Guid({self})")
+    }
+}
+
+/// The error returned when parsing a malformed [`Guid`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseGuidError;
+
+impl fmt::Display for ParseGuidError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid GUID, expected the form XXXXXXXX-XXXX-XXXX-XXXX-XXXXXXXXXXXX")
+    }
+}
+
+impl std::error::Error for ParseGuidError {}
+
+impl FromStr for Guid {
+    type Err = ParseGuidError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim_start_matches('{').trim_end_matches('}');
+        let groups: Vec<&str> = s.split('-').collect();
+        if groups.iter().map(|g| g.len()).ne([8, 4, 4, 4, 12]) {
+            return Err(ParseGuidError);
+        }
+
+        let hex = groups.concat();
+        let mut bytes = [0u8; 16];
+        for (i, &pos) in GUID_BYTE_ORDER.iter().enumerate() {
+            bytes[pos] = hex
+                .get(i * 2..i * 2 + 2)
+                .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+                .ok_or(ParseGuidError)?;
+        }
+        Ok(Self(bytes))
+    }
+}
+
+/// An entry of a partition table.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Partition {
+    /// The offset of the partition in bytes.
+    start: u64,
+    /// The size of the partition in bytes.
+    len: u64,
+    /// The unique partition GUID, for GPT partitions.
+    guid: Option<Guid>,
+    /// The partition name, for GPT partitions.
+    name: Option<String>,
+    /// Whether this is an EFI System Partition according to its partition type.
+    esp: bool,
+}
+
+/// Narrows `disk` down to the selected partition.
+pub(crate) fn select(
+    mut disk: Box<dyn Disk>,
+    select: &PartitionSelect,
+) -> io::Result<Box<dyn Disk>> {
+    let partition = match select {
+        PartitionSelect::Whole => return Ok(disk),
+        PartitionSelect::Auto => match probe(&mut disk)? {
+            Some(partition) => Some(partition),
+            None => return Ok(disk),
+        },
+        // Each partition is served through a `Vfs` of its own that selects it by index
+        PartitionSelect::All => {
+            return Err(io::Error::other("all partitions can't be opened at once"));
+        }
+        PartitionSelect::Range { offset, len } => {
+            let size = disk.seek(SeekFrom::End(0))?;
+            if offset.checked_add(*len).is_none_or(|end| end > size) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{select:?} extends past the end of the {size} byte image"),
+                ));
+            }
+            return Ok(Box::new(Slice::new(disk, *offset, *len)?));
+        }
+        PartitionSelect::Index(index) => partitions(&mut disk)?.into_iter().nth(*index).flatten(),
+        PartitionSelect::Guid(guid) => partitions(&mut disk)?
+            .into_iter()
+            .flatten()
+            .find(|p| p.guid == Some(*guid)),
+        PartitionSelect::Name(name) => partitions(&mut disk)?
+            .into_iter()
+            .flatten()
+            .find(|p| p.name.as_ref() == Some(name)),
+        PartitionSelect::Esp => match partitions(&mut disk) {
+            Ok(partitions) => partitions.into_iter().flatten().find(|p| p.esp),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e),
+        }
+        .map_or_else(|| el_torito_esp(&mut disk), |p| Ok(Some(p)))?,
+    }
+    .ok_or_else(|| not_found(format!("no partition matches {select:?}")))?;
+
+    Ok(Box::new(Slice::new(disk, partition.start, partition.len)?))
+}
+
+/// Finds the partition that holds the filesystem of a disk, or `None` when the disk should be
+/// served whole.
+///
+/// Disks that start with a boot sector, keep a backup of a damaged one, or have no recognizable
+/// partition table, are served whole. In the latter case mounting reports what's wrong with the
+/// image.
+///
+/// Installer ISOs are often hybrids whose MBR or GPT points into the ISO 9660 filesystem as well
+/// as to the FAT image of the EFI System Partition. The partition that covers the ISO itself
+/// doesn't start with a boot sector and is skipped. ISOs that only announce their EFI image in
+/// their El Torito boot catalog are served that image.
+fn probe<T: Read + Seek>(disk: &mut T) -> io::Result<Option<Partition>> {
+    if read_sector(disk, 0)?.is_none_or(|sector| is_boot_sector(&sector)) {
+        return Ok(None);
+    }
+    // A damaged boot sector may well look like an MBR, while the backup of it tells otherwise
+    if backup_boot::find(disk)?.is_some() {
+        return Ok(None);
+    }
+    let partitions = match partitions(disk) {
+        Ok(partitions) => Some(partitions),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e),
+    };
+
+    for partition in partitions.iter().flatten().flatten() {
+        if read_sector(disk, partition.start)?.is_some_and(|sector| is_boot_sector(&sector)) {
+            return Ok(Some(partition.clone()));
+        }
+    }
+    match (el_torito_esp(disk)?, partitions) {
+        (Some(esp), _) => Ok(Some(esp)),
+        (None, None) => Ok(None),
+        (None, Some(partitions)) => {
+            // Tell what they hold instead, where that's recognizable
+            let mut held = Vec::new();
+            for (index, partition) in partitions.iter().enumerate() {
+                if let Some(partition) = partition
+                    && let Some(Diagnosis::Foreign(name)) =
+                        diagnose::diagnose(disk, partition.start, partition.len)?
+                {
+                    held.push(format!("partition {index} holds {name}"));
+                }
+            }
+            let mut msg = "no partition holds a FAT filesystem".to_string();
+            if !held.is_empty() {
+                msg = format!("{msg}, {}", held.join(", "));
+            }
+            Err(not_found(msg))
+        }
+    }
+}
+
+/// Finds the EFI boot image in the El Torito boot catalog of an ISO 9660 image, if it is a FAT
+/// filesystem.
+///
+/// Only the first sector of the catalog is read, which has room for 63 boot entries.
+fn el_torito_esp<T: Read + Seek>(disk: &mut T) -> io::Result<Option<Partition>> {
+    let mut record = [0u8; 0x4B];
+    disk.seek(SeekFrom::Start(EL_TORITO_RECORD_OFFSET))?;
+    match disk.read_exact(&mut record) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    if record[0..7] != *b"\0CD001\x01" || !record[7..39].starts_with(b"EL TORITO SPECIFICATION") {
+        return Ok(None);
+    }
+
+    let mut catalog = [0u8; ISO_SECTOR_SIZE as usize];
+    disk.seek(SeekFrom::Start(
+        le_u32(&record[0x47..0x4B]) as u64 * ISO_SECTOR_SIZE,
+    ))?;
+    disk.read_exact(&mut catalog)?;
+    let mut entries = catalog.chunks_exact(32);
+    let validation = entries.next().expect("catalog holds entries");
+    if validation[0] != 0x01 || validation[30..32] != [0x55, 0xAA] {
+        return Ok(None);
+    }
+
+    // The default entry follows the validation entry, then come sections of further entries
+    // headed by the platform they're for
+    let mut candidates = Vec::new();
+    let mut platform = validation[1];
+    for entry in entries {
+        match entry[0] {
+            // A section header, the last one being 0x91
+            0x90 | 0x91 => platform = entry[1],
+            // A boot entry, bootable or not
+            0x88 | 0x00 if platform == EL_TORITO_PLATFORM_EFI => candidates.push((
+                le_u32(&entry[8..12]) as u64 * ISO_SECTOR_SIZE,
+                u16::from_le_bytes([entry[6], entry[7]]) as u64 * SECTOR_SIZE,
+            )),
+            // Section entry extensions and unused space
+            _ => {}
+        }
+    }
+
+    for (start, catalog_len) in candidates {
+        if let Some(sector) = read_sector(disk, start)?.filter(is_boot_sector) {
+            // The catalog's sector count is often just 1 for images too large for its 16 bits,
+            // the boot sector knows better
+            let len = match boot_sector_len(&sector) {
+                0 => catalog_len,
+                len => len,
+            };
+            return Ok(Some(Partition {
+                start,
+                len,
+                guid: None,
+                name: None,
+                esp: true,
+            }));
+        }
+    }
+    Ok(None)
+}
+
+/// Reads the sector at byte offset `start`, or `None` if the disk ends before it does.
+fn read_sector<T: Read + Seek>(
+    disk: &mut T,
+    start: u64,
+) -> io::Result<Option<[u8; SECTOR_SIZE as usize]>> {
+    let mut sector = [0u8; SECTOR_SIZE as usize];
+    disk.seek(SeekFrom::Start(start))?;
+    match disk.read_exact(&mut sector) {
+        Ok(()) => Ok(Some(sector)),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Tells whether `sector` is the boot sector of a FAT or exFAT filesystem.
+///
+/// MBRs end with the same signature as boot sectors and often start with a jump instruction as
+/// well, so the BIOS parameter block is checked for sane values.
+pub(crate) fn is_boot_sector(sector: &[u8; SECTOR_SIZE as usize]) -> bool {
+    if &sector[3..11] == b"EXFAT   " {
+        return true;
+    }
+    let bytes_per_sector = u16::from_le_bytes([sector[11], sector[12]]);
+    let sectors_per_cluster = sector[13];
+    let reserved_sectors = u16::from_le_bytes([sector[14], sector[15]]);
+    let fats = sector[16];
+    let media = sector[21];
+    matches!(sector[0], 0xEB | 0xE9)
+        && matches!(bytes_per_sector, 512 | 1024 | 2048 | 4096)
+        && sectors_per_cluster.is_power_of_two()
+        && reserved_sectors != 0
+        && fats != 0
+        && (media == 0xF0 || media >= 0xF8)
+}
+
+/// The size of the FAT filesystem whose boot sector is `sector`, or 0 if it doesn't say.
+pub(crate) fn boot_sector_len(sector: &[u8; SECTOR_SIZE as usize]) -> u64 {
+    let bytes_per_sector = u16::from_le_bytes([sector[11], sector[12]]) as u64;
+    let sectors = match u16::from_le_bytes([sector[19], sector[20]]) {
+        0 => le_u32(&sector[32..36]) as u64,
+        sectors => sectors as u64,
+    };
+    sectors * bytes_per_sector
+}
+
+/// Reads the partition table of a disk, with `None` for unused slots.
+///
+/// A GPT is used when the MBR contains a protective partition, otherwise the MBR's primary
+/// partitions are returned, followed by the logical partitions of its extended partition.
+pub(crate) fn partitions<T: Read + Seek>(disk: &mut T) -> io::Result<Vec<Option<Partition>>> {
+    let mbr = mbr_entries(disk, 0)?;
+    if mbr
+        .iter()
+        .flatten()
+        .any(|e| e.kind == MBR_TYPE_GPT_PROTECTIVE)
+    {
+        return gpt_partitions(disk);
+    }
+
+    let logical = match mbr
+        .iter()
+        .flatten()
+        .find(|e| MBR_TYPES_EXTENDED.contains(&e.kind))
+    {
+        Some(extended) => logical_entries(disk, extended.start)?,
+        None => Vec::new(),
+    };
+    Ok(mbr
+        .into_iter()
+        .chain(logical.into_iter().map(Some))
+        .map(|e| {
+            e.map(|e| Partition {
+                start: e.start,
+                len: e.len,
+                guid: None,
+                name: None,
+                esp: e.kind == MBR_TYPE_ESP,
+            })
+        })
+        .collect())
+}
+
+/// A primary partition entry of an MBR.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct MbrEntry {
+    /// The partition type.
+    kind: u8,
+    /// The offset of the partition in bytes.
+    start: u64,
+    /// The size of the partition in bytes.
+    len: u64,
+}
+
+/// Reads the four partition entries of the MBR or extended boot record at byte offset `at`,
+/// with `None` for unused slots.
+///
+/// The start of each entry is relative to the start of the disk for the MBR. Callers reading an
+/// extended boot record, whose entries are relative to other offsets, rebase them.
+fn mbr_entries<T: Read + Seek>(disk: &mut T, at: u64) -> io::Result<Vec<Option<MbrEntry>>> {
+    let mut sector = [0u8; SECTOR_SIZE as usize];
+    disk.seek(SeekFrom::Start(at))?;
+    disk.read_exact(&mut sector)?;
+    if sector[510..512] != [0x55, 0xAA] {
+        return Err(not_found("no MBR partition table found"));
+    }
+
+    Ok(sector[MBR_ENTRIES_OFFSET..MBR_ENTRIES_OFFSET + 64]
+        .chunks_exact(16)
+        .map(|entry| {
+            let kind = entry[4];
+            let start = le_u32(&entry[8..12]);
+            let sectors = le_u32(&entry[12..16]);
+            (kind != 0 && sectors != 0).then_some(MbrEntry {
+                kind,
+                start: start as u64 * SECTOR_SIZE,
+                len: sectors as u64 * SECTOR_SIZE,
+            })
+        })
+        .collect())
+}
+
+/// Follows the chain of extended boot records of the extended partition starting at byte offset
+/// `extended`, returning the logical partitions in chain order.
+///
+/// Each extended boot record describes a logical partition relative to itself and links to the
+/// next record relative to the start of the extended partition.
+fn logical_entries<T: Read + Seek>(disk: &mut T, extended: u64) -> io::Result<Vec<MbrEntry>> {
+    let mut logical = Vec::new();
+    let mut ebr = extended;
+    // Bounds the records read rather than the partitions found, as records without a partition
+    // could link back to each other, and ends where a record links back to one read before
+    let mut visited = HashSet::new();
+    while visited.len() < MAX_LOGICAL_PARTITIONS && visited.insert(ebr) {
+        // A damaged chain ends there, the partitions before it are still usable
+        let entries = match mbr_entries(disk, ebr) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => break,
+            Err(e) => return Err(e),
+        };
+        if let Some(entry) = entries[0] {
+            logical.push(MbrEntry {
+                start: ebr + entry.start,
+                ..entry
+            });
+        }
+        match entries[1] {
+            Some(next) if MBR_TYPES_EXTENDED.contains(&next.kind) && next.start != 0 => {
+                ebr = extended + next.start;
+            }
+            _ => break,
+        }
+    }
+    Ok(logical)
+}
+
+/// Reads the partition entry array of a GPT, with `None` for unused entries.
+fn gpt_partitions<T: Read + Seek>(disk: &mut T) -> io::Result<Vec<Option<Partition>>> {
+    let mut header = [0u8; 92];
+    disk.seek(SeekFrom::Start(SECTOR_SIZE))?;
+    disk.read_exact(&mut header)?;
+    if &header[0..8] != b"EFI PART" {
+        return Err(not_found("no GPT header found"));
+    }
+
+    let entries_lba = le_u64(&header[72..80]);
+    let entry_count = le_u32(&header[80..84]) as u64;
+    let entry_size = le_u32(&header[84..88]) as u64;
+    if entry_size < 128 || entry_count * entry_size > GPT_MAX_ENTRIES_SIZE {
+        return Err(invalid_data(
+            "GPT partition entry array has an invalid size",
+        ));
+    }
+    let entries_start = entries_lba
+        .checked_mul(SECTOR_SIZE)
+        .ok_or_else(|| invalid_data("GPT partition entry array lies beyond any disk"))?;
+
+    let mut entries = vec![0u8; (entry_count * entry_size) as usize];
+    disk.seek(SeekFrom::Start(entries_start))?;
+    disk.read_exact(&mut entries)?;
+
+    entries
+        .chunks_exact(entry_size as usize)
+        .map(|entry| {
+            // An all-zero partition type GUID marks an unused entry
+            if entry[0..16].iter().all(|&b| b == 0) {
+                return Ok(None);
+            }
+            let first_lba = le_u64(&entry[32..40]);
+            let last_lba = le_u64(&entry[40..48]);
+            if last_lba < first_lba {
+                return Err(invalid_data(format!(
+                    "GPT partition ends at sector {last_lba}, before it starts at {first_lba}"
+                )));
+            }
+            // Sectors this far out would wrap around, which no disk holds
+            let start = first_lba.checked_mul(SECTOR_SIZE);
+            let sectors = (last_lba - first_lba).checked_add(1);
+            let len = sectors.and_then(|sectors| sectors.checked_mul(SECTOR_SIZE));
+            let (Some(start), Some(len)) = (start, len) else {
+                return Err(invalid_data("GPT partition lies beyond any disk"));
+            };
+            if start.checked_add(len).is_none() {
+                return Err(invalid_data("GPT partition lies beyond any disk"));
+            }
+            let name: Vec<u16> = entry[56..128]
+                .chunks_exact(2)
+                .map(|c| u16::from_le_bytes([c[0], c[1]]))
+                .take_while(|&c| c != 0)
+                .collect();
+            Ok(Some(Partition {
+                start,
+                len,
+                guid: Some(Guid(entry[16..32].try_into().unwrap())),
+                name: Some(String::from_utf16_lossy(&name)),
+                esp: entry[0..16] == GPT_TYPE_ESP.0,
+            }))
+        })
+        .collect()
+}
+
+fn le_u32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes(bytes.try_into().unwrap())
+}
+
+fn le_u64(bytes: &[u8]) -> u64 {
+    u64::from_le_bytes(bytes.try_into().unwrap())
+}
+
+fn not_found<E: Into<Box<dyn std::error::Error + Send + Sync>>>(msg: E) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, msg)
+}
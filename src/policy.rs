@@ -0,0 +1,104 @@
+//! The public extension point for deciding per user what they may do, and how fast.
+
+use std::{fmt::Debug, path::Path};
+use unftp_core::{auth::UserDetail, storage::ErrorKind};
+
+/// An operation a user asked for, as passed to a [`UserPolicy`].
+///
+/// Paths are as the client gave them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Operation<'a> {
+    /// Getting the metadata of a file or directory.
+    Metadata(&'a Path),
+    /// Listing a directory.
+    List(&'a Path),
+    /// Changing to a directory.
+    ChangeDir(&'a Path),
+    /// Downloading a file.
+    Download(&'a Path),
+    /// Computing the checksum of a file, which reads all of it as a download would.
+    Checksum(&'a Path),
+    /// Uploading a file.
+    Upload(&'a Path),
+    /// Deleting a file.
+    Delete(&'a Path),
+    /// Creating a directory.
+    CreateDir(&'a Path),
+    /// Removing a directory.
+    RemoveDir(&'a Path),
+    /// Renaming a file or directory.
+    Rename {
+        /// The path of the file or directory.
+        from: &'a Path,
+        /// Its new path.
+        to: &'a Path,
+    },
+}
+
+impl Operation<'_> {
+    /// Returns the name of the kind of operation, as used in metrics and spans.
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            Operation::Metadata(_) => "metadata",
+            Operation::List(_) => "list",
+            Operation::ChangeDir(_) => "change_dir",
+            Operation::Download(_) => "download",
+            Operation::Checksum(_) => "checksum",
+            Operation::Upload(_) => "upload",
+            Operation::Delete(_) => "delete",
+            Operation::CreateDir(_) => "create_dir",
+            Operation::RemoveDir(_) => "remove_dir",
+            Operation::Rename { .. } => "rename",
+        }
+    }
+}
+
+/// What a [`UserPolicy`] decided about an operation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decision {
+    /// The operation goes ahead as it would without a policy.
+    Allow,
+    /// The operation goes ahead, transferring at most this many bytes per second. Operations
+    /// other than downloads and uploads go ahead as they would without a policy.
+    Throttle(u64),
+    /// The operation is refused with an error of this kind, such as
+    /// [`ErrorKind::PermissionDenied`], or [`ErrorKind::TransientFileNotAvailable`] to have
+    /// clients try again later.
+    Reject(ErrorKind),
+}
+
+/// Decides for each operation whether the user who asked for it may go ahead, and how fast.
+///
+/// Implement this to throttle or refuse users based on who they are, and pass the policy to
+/// [`VfsBuilder::user_policy`](crate::VfsBuilder::user_policy). It is asked before anything
+/// else is done for an operation, including checking whether the file system allows
+/// modifications at all, so it should decide quickly and without blocking.
+///
+/// # Example
+///
+/// ```rust
+/// use unftp_core::auth::UserDetail;
+/// use unftp_sbe_fatfs::{Decision, Operation, UserPolicy, VfsBuilder};
+///
+/// /// Lets anonymous users download at 100 KiB/s and authenticated users at full speed.
+/// #[derive(Debug)]
+/// struct SlowAnonymous;
+///
+/// impl UserPolicy for SlowAnonymous {
+///     fn decide(&self, user: &dyn UserDetail, _operation: Operation<'_>) -> Decision {
+///         match user.to_string().as_str() {
+///             "anonymous" | "DefaultUser" => Decision::Throttle(100 * 1024),
+///             _ => Decision::Allow,
+///         }
+///     }
+/// }
+///
+/// let vfs = VfsBuilder::new("path/to/fat/image.img")
+///     .user_policy(SlowAnonymous)
+///     .build();
+/// ```
+pub trait UserPolicy: Debug + Send + Sync {
+    /// Decides about `operation`, which `user` asked for.
+    fn decide(&self, user: &dyn UserDetail, operation: Operation<'_>) -> Decision;
+}
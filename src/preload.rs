@@ -0,0 +1,92 @@
+//! Keeps a copy of the whole image in memory.
+//!
+//! Small images, such as EFI system partitions, fit in memory easily. Once copied there,
+//! listings and downloads no longer depend on how fast the image can be read.
+
+use crate::{
+    MemoryBudget,
+    image::{Disk, Image, ReadOnly},
+    memory::{self, Reservation},
+};
+use bytes::Bytes;
+use std::{
+    io::{self, Cursor, Read, Seek, SeekFrom},
+    sync::Mutex,
+};
+
+/// Copies images into memory for everything that opens them, so that they are read once.
+#[derive(Debug)]
+pub(crate) struct Preload {
+    /// The size of the largest image copied into memory, if images are copied at all.
+    max_size: Option<u64>,
+    /// The budget copies reserve their memory from, if memory is limited.
+    memory: Option<&'static MemoryBudget>,
+    /// The copy of the image, along with the generation of the image it was copied from.
+    copy: Mutex<Option<(u64, Bytes)>>,
+}
+
+/// A copy of an image, holding its memory until the last stream over it is dropped.
+struct Copied {
+    bytes: Vec<u8>,
+    _memory: Reservation,
+}
+
+impl AsRef<[u8]> for Copied {
+    fn as_ref(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+impl Preload {
+    pub(crate) fn new(max_size: Option<u64>, memory: Option<&'static MemoryBudget>) -> Self {
+        Self {
+            max_size,
+            memory,
+            copy: Mutex::new(None),
+        }
+    }
+
+    /// Returns a stream over the copy of `image` in memory, copying the stream `open` returns
+    /// unless that happened already since the image last changed.
+    ///
+    /// Writable streams, images larger than the maximum size, and images the memory budget
+    /// doesn't leave room for are opened with `open` as they are.
+    pub(crate) fn open(
+        &self,
+        image: &Image,
+        writable: bool,
+        open: impl FnOnce() -> io::Result<Box<dyn Disk>>,
+    ) -> io::Result<Box<dyn Disk>> {
+        let Some(max_size) = self.max_size.filter(|_| !writable) else {
+            return open();
+        };
+        let mut copy = self
+            .copy
+            .lock()
+            .map_err(|_| io::Error::other("preload lock poisoned"))?;
+        let generation = image.generation();
+        let bytes = match &*copy {
+            Some((copied_at, bytes)) if *copied_at == generation => bytes.clone(),
+            _ => {
+                let mut disk = open()?;
+                let len = disk.seek(SeekFrom::End(0))?;
+                disk.seek(SeekFrom::Start(0))?;
+                if len > max_size {
+                    return Ok(disk);
+                }
+                let Some(reservation) = memory::try_reserve(self.memory, len) else {
+                    return Ok(disk);
+                };
+                let mut bytes = Vec::with_capacity(len as usize);
+                disk.read_to_end(&mut bytes)?;
+                let bytes = Bytes::from_owner(Copied {
+                    bytes,
+                    _memory: reservation,
+                });
+                *copy = Some((generation, bytes.clone()));
+                bytes
+            }
+        };
+        Ok(Box::new(ReadOnly(Cursor::new(bytes))))
+    }
+}
@@ -0,0 +1,228 @@
+//! Images in QEMU's qcow2 format, which store the guest disk in clusters that are looked up
+//! through a two-level table.
+//!
+//! Clusters are read through the tables as they are needed, so nothing has to be converted up
+//! front. Clusters that were never written read as zeros. Images that need a backing file or are
+//! encrypted aren't supported.
+
+use crate::{
+    format::{invalid_data, read_up_to, unsupported},
+    image::Disk,
+};
+use flate2::read::DeflateDecoder;
+use std::io::{self, Read, Seek, SeekFrom};
+
+/// The magic bytes qcow2 images start with.
+const MAGIC: &[u8; 4] = b"QFI\xfb";
+
+/// Masks the host offset out of L1 and L2 table entries.
+const OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
+
+/// Marks L2 entries of compressed clusters.
+const COMPRESSED: u64 = 1 << 62;
+
+/// Marks L2 entries of clusters that read as zeros, in version 3 images.
+const ZERO: u64 = 1;
+
+/// The incompatible features that don't affect reading: the dirty and corrupt bits.
+const READABLE_FEATURES: u64 = 0b11;
+
+/// The number of L2 tables kept in memory.
+const CACHED_TABLES: usize = 16;
+
+/// A seekable stream over the guest disk in a qcow2 image.
+pub(crate) struct Qcow2 {
+    inner: Box<dyn Disk>,
+    cluster_bits: u32,
+    /// The size of the guest disk.
+    len: u64,
+    l1: Vec<u64>,
+    /// L2 tables by their offset in the image, most recently used last.
+    tables: Vec<(u64, Vec<u64>)>,
+    /// The last compressed cluster read, by its L2 entry.
+    compressed: Option<(u64, Vec<u8>)>,
+    pos: u64,
+}
+
+impl Qcow2 {
+    /// Reads the header and the L1 table of the qcow2 image on `inner`.
+    pub(crate) fn open(mut inner: Box<dyn Disk>) -> io::Result<Self> {
+        let mut header = [0u8; 105];
+        inner.seek(SeekFrom::Start(0))?;
+        let read = read_up_to(&mut *inner, &mut header)?;
+        if read < 72 || &header[..4] != MAGIC {
+            return Err(invalid_data("not a qcow2 image"));
+        }
+        let u32_at = |offset: usize| {
+            u32::from_be_bytes(header[offset..offset + 4].try_into().expect("4 bytes"))
+        };
+        let u64_at = |offset: usize| {
+            u64::from_be_bytes(header[offset..offset + 8].try_into().expect("8 bytes"))
+        };
+
+        let version = u32_at(4);
+        if version != 2 && version != 3 {
+            return Err(unsupported(format!(
+                "qcow2 version {version} isn't supported"
+            )));
+        }
+        if u64_at(8) != 0 {
+            return Err(unsupported(
+                "qcow2 images with a backing file aren't supported",
+            ));
+        }
+        let cluster_bits = u32_at(20);
+        if !(9..=21).contains(&cluster_bits) {
+            return Err(invalid_data("the qcow2 cluster size is out of range"));
+        }
+        if u32_at(32) != 0 {
+            return Err(unsupported("encrypted qcow2 images aren't supported"));
+        }
+        if version == 3 {
+            if read < 104 {
+                return Err(invalid_data("the qcow2 header is truncated"));
+            }
+            let features = u64_at(72);
+            if features & !READABLE_FEATURES != 0 {
+                return Err(unsupported(format!(
+                    "qcow2 images with incompatible features {features:#x} aren't supported"
+                )));
+            }
+        }
+
+        let len = u64_at(24);
+        let l1_len = u32_at(36) as usize;
+        // Every L1 entry covers an L2 table's worth of clusters, each entry of which is 8 bytes
+        let clusters_per_table = 1u64 << (cluster_bits - 3);
+        if (l1_len as u64) < len.div_ceil(clusters_per_table << cluster_bits) {
+            return Err(invalid_data(
+                "the qcow2 L1 table is too small for the disk size",
+            ));
+        }
+        let l1 = read_table(&mut *inner, u64_at(40), l1_len)?;
+
+        Ok(Self {
+            inner,
+            cluster_bits,
+            len,
+            l1,
+            tables: Vec::new(),
+            compressed: None,
+            pos: 0,
+        })
+    }
+
+    /// Returns the L2 entry that describes the guest cluster with the given index.
+    fn l2_entry(&mut self, cluster: u64) -> io::Result<u64> {
+        let entries_bits = self.cluster_bits - 3;
+        let l1_entry = self.l1[(cluster >> entries_bits) as usize];
+        let table_offset = l1_entry & OFFSET_MASK;
+        if table_offset == 0 {
+            return Ok(0);
+        }
+        let index = (cluster & ((1 << entries_bits) - 1)) as usize;
+
+        let position = match self.tables.iter().position(|(o, _)| *o == table_offset) {
+            Some(position) => {
+                let table = self.tables.remove(position);
+                self.tables.push(table);
+                self.tables.len() - 1
+            }
+            None => {
+                let table = read_table(&mut *self.inner, table_offset, 1 << entries_bits)?;
+                if self.tables.len() == CACHED_TABLES {
+                    self.tables.remove(0);
+                }
+                self.tables.push((table_offset, table));
+                self.tables.len() - 1
+            }
+        };
+        Ok(self.tables[position].1[index])
+    }
+
+    /// Decompresses the compressed cluster with the given L2 entry.
+    fn compressed_cluster(&mut self, entry: u64) -> io::Result<&[u8]> {
+        if self.compressed.as_ref().is_none_or(|(e, _)| *e != entry) {
+            // The entry holds the host offset in its low bits and the number of additional
+            // 512-byte sectors the compressed data spans above them
+            let offset_bits = 62 - (self.cluster_bits - 8);
+            let offset = entry & ((1 << offset_bits) - 1);
+            let sectors = (entry >> offset_bits) & ((1 << (62 - offset_bits)) - 1);
+            let compressed_len = (sectors + 1) * 512 - (offset & 511);
+
+            let mut compressed = vec![0u8; compressed_len as usize];
+            self.inner.seek(SeekFrom::Start(offset))?;
+            // The last compressed cluster may end before the sector it was counted up to
+            let n = read_up_to(&mut *self.inner, &mut compressed)?;
+            compressed.truncate(n);
+
+            let mut cluster = vec![0u8; 1 << self.cluster_bits];
+            DeflateDecoder::new(&compressed[..])
+                .read_exact(&mut cluster)
+                .map_err(|_| invalid_data("a compressed qcow2 cluster is corrupt"))?;
+            self.compressed = Some((entry, cluster));
+        }
+        Ok(&self.compressed.as_ref().expect("just decompressed").1)
+    }
+}
+
+impl Read for Qcow2 {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let cluster_size = 1u64 << self.cluster_bits;
+        let in_cluster = self.pos & (cluster_size - 1);
+        let n = (cluster_size - in_cluster)
+            .min(self.len.saturating_sub(self.pos))
+            .min(buf.len() as u64) as usize;
+        if n == 0 {
+            return Ok(0);
+        }
+
+        let entry = self.l2_entry(self.pos >> self.cluster_bits)?;
+        if entry & COMPRESSED != 0 {
+            let cluster = self.compressed_cluster(entry)?;
+            let start = in_cluster as usize;
+            buf[..n].copy_from_slice(&cluster[start..start + n]);
+        } else if entry & ZERO != 0 || entry & OFFSET_MASK == 0 {
+            buf[..n].fill(0);
+        } else {
+            self.inner
+                .seek(SeekFrom::Start((entry & OFFSET_MASK) + in_cluster))?;
+            self.inner.read_exact(&mut buf[..n])?;
+        }
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for Qcow2 {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.pos = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+        }
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "seek to a negative offset"))?;
+        Ok(self.pos)
+    }
+}
+
+/// Reads a table of `len` big-endian 64-bit entries at `offset`, failing rather than allocating
+/// it if it would end beyond the end of `disk`.
+fn read_table(disk: &mut dyn Disk, offset: u64, len: usize) -> io::Result<Vec<u64>> {
+    let disk_len = disk.seek(SeekFrom::End(0))?;
+    let end = (len as u64)
+        .checked_mul(8)
+        .and_then(|size| offset.checked_add(size));
+    if end.is_none_or(|end| end > disk_len) {
+        return Err(invalid_data(
+            "a qcow2 table lies beyond the end of the image",
+        ));
+    }
+    let mut bytes = vec![0u8; len * 8];
+    disk.seek(SeekFrom::Start(offset))?;
+    disk.read_exact(&mut bytes)?;
+    Ok(bytes
+        .chunks_exact(8)
+        .map(|entry| u64::from_be_bytes(entry.try_into().expect("8 bytes")))
+        .collect())
+}
@@ -0,0 +1,618 @@
+//! Reads what fatfs keeps to itself from the image it mounted: the cluster chains of the FAT and
+//! the first clusters of directory entries.
+//!
+//! fatfs follows cluster chains wherever they lead, so a chain that loops back on itself keeps it
+//! reading the same clusters forever. Chains are followed here first, which notices the loop.
+
+use crate::{CorruptionPolicy, FatError, error, image::Disk};
+use fatfs::FatType;
+use std::{
+    collections::HashSet,
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::Path,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+};
+use unftp_core::storage::Result;
+
+/// The size of directory entries.
+const ENTRY_LEN: usize = 32;
+
+/// The first byte of the name of the entry that ends a directory.
+const END: u8 = 0x00;
+
+/// The first byte of the name of deleted entries.
+const DELETED: u8 = 0xE5;
+
+/// The attribute of volume labels.
+const VOLUME_ID: u8 = 0x08;
+
+/// The attributes of the entries that hold parts of long names, which no other entry has all of.
+const LONG_NAME: u8 = 0x0F;
+
+/// The size of the chunks the FAT is read in as a whole, a whole number of entries of any FAT
+/// type, two of FAT12 taking three bytes.
+const FAT_CHUNK_LEN: u64 = 12 * 4096;
+
+/// A stream over a disk that is shared with the streams cloned from it, each of which keeps a
+/// position of its own. fatfs reads and writes through one of them while the image is read
+/// alongside through another.
+#[derive(Clone)]
+pub(crate) struct SharedDisk {
+    inner: Arc<Mutex<Box<dyn Disk>>>,
+    pos: u64,
+}
+
+impl SharedDisk {
+    pub(crate) fn new(inner: Box<dyn Disk>) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(inner)),
+            pos: 0,
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Box<dyn Disk>> {
+        self.inner.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Fills `buf` with the bytes from `offset` on, leaving the position of the stream as it is.
+    fn read_exact_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        let mut inner = self.lock();
+        inner.seek(SeekFrom::Start(offset))?;
+        inner.read_exact(buf)
+    }
+
+    /// Writes all of `buf` from `offset` on, leaving the position of the stream as it is.
+    fn write_all_at(&self, offset: u64, buf: &[u8]) -> io::Result<()> {
+        let mut inner = self.lock();
+        inner.seek(SeekFrom::Start(offset))?;
+        inner.write_all(buf)?;
+        inner.flush()
+    }
+}
+
+impl Read for SharedDisk {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = {
+            let mut inner = self.lock();
+            inner.seek(SeekFrom::Start(self.pos))?;
+            inner.read(buf)?
+        };
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Write for SharedDisk {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = {
+            let mut inner = self.lock();
+            inner.seek(SeekFrom::Start(self.pos))?;
+            inner.write(buf)?
+        };
+        self.pos += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.lock().flush()
+    }
+}
+
+impl Seek for SharedDisk {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.pos = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => {
+                let len = self.lock().seek(SeekFrom::End(0))?;
+                len.checked_add_signed(offset)
+            }
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+        }
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "seek to a negative offset"))?;
+        Ok(self.pos)
+    }
+}
+
+/// An entry that differs between the first FAT and a copy of it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct FatMismatch {
+    /// The number of the copy, 2 for the first copy.
+    pub(crate) copy: u8,
+    pub(crate) cluster: u32,
+    /// The entry in the first FAT.
+    pub(crate) expected: u32,
+    /// The entry in the copy.
+    pub(crate) found: u32,
+}
+
+/// An entry of a directory as read from the image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct RawEntry {
+    /// The first cluster of the entry, `None` if it has none.
+    pub(crate) cluster: Option<u32>,
+    /// The offset of the entry in the image.
+    pub(crate) offset: u64,
+}
+
+/// Reads the FAT and directories of the filesystem on a disk, where its boot sector puts them.
+pub(crate) struct Raw {
+    disk: SharedDisk,
+    fat_type: FatType,
+    /// The offset of the first FAT.
+    fat_start: u64,
+    /// The size of each FAT.
+    fat_len: u64,
+    /// How many copies of the FAT there are.
+    fats: u64,
+    /// The offset and size of the root directory of FAT12 and FAT16, or `None` on FAT32, which
+    /// chains it like other directories.
+    root_region: Option<(u64, u64)>,
+    /// The first cluster of the root directory of FAT32.
+    root_cluster: u32,
+    /// The offset of the FSInfo sector of FAT32, if there is one.
+    fs_info: Option<u64>,
+    /// The offset of the first cluster.
+    data_start: u64,
+    cluster_len: u64,
+    /// The number of the cluster after the last one.
+    end_cluster: u32,
+}
+
+impl Raw {
+    /// Reads where the structures of the filesystem of `fat_type` on `disk` are from its boot
+    /// sector, which fatfs checked when mounting it.
+    pub(crate) fn new(disk: SharedDisk, fat_type: FatType) -> io::Result<Self> {
+        let mut sector = [0; 512];
+        disk.read_exact_at(0, &mut sector)?;
+        let u16_at = |offset: usize| u16::from_le_bytes([sector[offset], sector[offset + 1]]);
+        let u32_at =
+            |offset: usize| u32::from_le_bytes(sector[offset..offset + 4].try_into().unwrap());
+
+        let bytes_per_sector = u16_at(11) as u64;
+        let sectors_per_cluster = sector[13] as u64;
+        let reserved_sectors = u16_at(14) as u64;
+        let fats = sector[16] as u64;
+        let root_entries = u16_at(17) as u64;
+        let total_sectors = match u16_at(19) {
+            0 => u32_at(32) as u64,
+            sectors => sectors as u64,
+        };
+        let sectors_per_fat = match u16_at(22) {
+            // FAT32 keeps the size in a field of its own
+            0 => u32_at(36) as u64,
+            sectors => sectors as u64,
+        };
+
+        let fat_start = reserved_sectors * bytes_per_sector;
+        let root_start = fat_start + fats * sectors_per_fat * bytes_per_sector;
+        let root_len = (root_entries * ENTRY_LEN as u64).next_multiple_of(bytes_per_sector);
+        let data_start = root_start + root_len;
+        let data_sectors = total_sectors.saturating_sub(data_start / bytes_per_sector);
+        let clusters = data_sectors / sectors_per_cluster.max(1);
+        Ok(Self {
+            disk,
+            fat_type,
+            fat_start,
+            fat_len: sectors_per_fat * bytes_per_sector,
+            fats,
+            root_region: (fat_type != FatType::Fat32)
+                .then_some((root_start, root_entries * ENTRY_LEN as u64)),
+            root_cluster: u32_at(44),
+            fs_info: match u16_at(48) {
+                0 | 0xFFFF => None,
+                _ if fat_type != FatType::Fat32 => None,
+                sector => Some(sector as u64 * bytes_per_sector),
+            },
+            data_start,
+            cluster_len: sectors_per_cluster * bytes_per_sector,
+            end_cluster: (clusters + 2).min(u32::MAX as u64) as u32,
+        })
+    }
+
+    /// Returns the boot sector, or the backup of it if the filesystem was mounted from that.
+    pub(crate) fn boot_sector(&self) -> io::Result<[u8; 512]> {
+        let mut sector = [0; 512];
+        self.disk.read_exact_at(0, &mut sector)?;
+        Ok(sector)
+    }
+
+    /// Returns the number of entries the root directory of FAT12 and FAT16 holds, or `None` on
+    /// FAT32, whose root directory grows like others.
+    pub(crate) fn root_entries(&self) -> Option<u32> {
+        let (_, len) = self.root_region?;
+        Some((len / ENTRY_LEN as u64) as u32)
+    }
+
+    /// Returns the first cluster of the root directory of FAT32, or `None` on FAT12 and FAT16,
+    /// which don't keep it in clusters.
+    pub(crate) fn root_cluster(&self) -> Option<u32> {
+        self.root_region.is_none().then_some(self.root_cluster)
+    }
+
+    /// Returns the cluster that follows `cluster` in its chain, or `None` if the chain ends
+    /// there. Entries that don't lead to a cluster of the filesystem, such as those of free or
+    /// bad clusters, end chains too, as fatfs reads past the filesystem otherwise.
+    fn next(&self, cluster: u32) -> io::Result<Option<u32>> {
+        let next = self.link(cluster)?;
+        Ok(self.is_cluster(next).then_some(next))
+    }
+
+    /// Returns the entry of `cluster` in the FAT, which is the cluster that follows it in its
+    /// chain unless it marks the end of the chain, or a free or bad cluster.
+    pub(crate) fn link(&self, cluster: u32) -> io::Result<u32> {
+        Ok(match self.fat_type {
+            FatType::Fat12 => {
+                let mut entry = [0; 2];
+                let offset = cluster as u64 + cluster as u64 / 2;
+                self.disk
+                    .read_exact_at(self.fat_start + offset, &mut entry)?;
+                let entry = u16::from_le_bytes(entry);
+                if cluster.is_multiple_of(2) {
+                    entry as u32 & 0xFFF
+                } else {
+                    entry as u32 >> 4
+                }
+            }
+            FatType::Fat16 => {
+                let mut entry = [0; 2];
+                let offset = cluster as u64 * 2;
+                self.disk
+                    .read_exact_at(self.fat_start + offset, &mut entry)?;
+                u16::from_le_bytes(entry) as u32
+            }
+            FatType::Fat32 => {
+                let mut entry = [0; 4];
+                let offset = cluster as u64 * 4;
+                self.disk
+                    .read_exact_at(self.fat_start + offset, &mut entry)?;
+                u32::from_le_bytes(entry) & 0x0FFF_FFFF
+            }
+        })
+    }
+
+    /// Sets the entry of `cluster` in every copy of the FAT to `link`, keeping the bits of FAT32
+    /// entries that aren't part of it, and those of the FAT12 entry that shares a byte with it.
+    pub(crate) fn set_link(&self, cluster: u32, link: u32) -> io::Result<()> {
+        for copy in 0..self.fats {
+            let fat_start = self.fat_start + copy * self.fat_len;
+            match self.fat_type {
+                FatType::Fat12 => {
+                    let mut entry = [0; 2];
+                    let offset = fat_start + cluster as u64 + cluster as u64 / 2;
+                    self.disk.read_exact_at(offset, &mut entry)?;
+                    let entry = u16::from_le_bytes(entry);
+                    let link = (link & 0xFFF) as u16;
+                    let entry = if cluster.is_multiple_of(2) {
+                        entry & 0xF000 | link
+                    } else {
+                        entry & 0x000F | link << 4
+                    };
+                    self.disk.write_all_at(offset, &entry.to_le_bytes())?;
+                }
+                FatType::Fat16 => {
+                    let offset = fat_start + cluster as u64 * 2;
+                    (self.disk).write_all_at(offset, &(link as u16).to_le_bytes())?;
+                }
+                FatType::Fat32 => {
+                    let mut entry = [0; 4];
+                    let offset = fat_start + cluster as u64 * 4;
+                    self.disk.read_exact_at(offset, &mut entry)?;
+                    let entry = u32::from_le_bytes(entry) & 0xF000_0000 | link & 0x0FFF_FFFF;
+                    self.disk.write_all_at(offset, &entry.to_le_bytes())?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the entry in the FAT that marks the end of a chain.
+    pub(crate) fn end_link(&self) -> u32 {
+        match self.fat_type {
+            FatType::Fat12 => 0xFFF,
+            FatType::Fat16 => 0xFFFF,
+            FatType::Fat32 => 0x0FFF_FFFF,
+        }
+    }
+
+    /// Writes `buf` to the image at `offset`.
+    pub(crate) fn write_at(&self, offset: u64, buf: &[u8]) -> io::Result<()> {
+        self.disk.write_all_at(offset, buf)
+    }
+
+    /// Whether the filesystem has an FSInfo sector, which only FAT32 has.
+    pub(crate) fn has_fs_info(&self) -> bool {
+        self.fs_info.is_some()
+    }
+
+    /// Returns the count of free clusters the FSInfo sector of FAT32 keeps, or `None` if there is
+    /// no FSInfo sector or the count is marked as unknown.
+    pub(crate) fn free_count(&self) -> io::Result<Option<u32>> {
+        let Some(offset) = self.fs_info else {
+            return Ok(None);
+        };
+        let mut count = [0; 4];
+        self.disk.read_exact_at(offset + 488, &mut count)?;
+        Ok(Some(u32::from_le_bytes(count)).filter(|&count| count != u32::MAX))
+    }
+
+    /// Sets the count of free clusters the FSInfo sector of FAT32 keeps to `count`, or marks it
+    /// as unknown if `None`, so that they are counted anew. Does nothing without an FSInfo
+    /// sector.
+    pub(crate) fn set_free_count(&self, count: Option<u32>) -> io::Result<()> {
+        match self.fs_info {
+            Some(offset) => self
+                .disk
+                .write_all_at(offset + 488, &count.unwrap_or(u32::MAX).to_le_bytes()),
+            None => Ok(()),
+        }
+    }
+
+    /// Counts the clusters the FAT marks as free.
+    pub(crate) fn count_free(&self) -> io::Result<u32> {
+        let mut free = 0;
+        self.for_each_link(|_, link| free += (link == 0) as u32)?;
+        Ok(free)
+    }
+
+    /// Returns the entry of bad clusters in the FAT, which those after it mark the end of chains.
+    fn bad_link(&self) -> u32 {
+        match self.fat_type {
+            FatType::Fat12 => 0xFF7,
+            FatType::Fat16 => 0xFFF7,
+            FatType::Fat32 => 0x0FFF_FFF7,
+        }
+    }
+
+    /// Whether `link`, an entry of the FAT, marks the end of a chain.
+    pub(crate) fn is_end(&self, link: u32) -> bool {
+        link > self.bad_link()
+    }
+
+    /// Whether `link`, an entry of the FAT, marks a bad cluster.
+    pub(crate) fn is_bad(&self, link: u32) -> bool {
+        link == self.bad_link()
+    }
+
+    /// Calls `f` with every cluster of the filesystem and its entry in the first FAT, reading the
+    /// FAT a chunk at a time rather than an entry at a time.
+    pub(crate) fn for_each_link(&self, mut f: impl FnMut(u32, u32)) -> io::Result<()> {
+        let mut chunk = vec![0; FAT_CHUNK_LEN as usize];
+        let mut offset = 0;
+        while offset < self.fat_len {
+            let len = (self.fat_len - offset).min(FAT_CHUNK_LEN) as usize;
+            (self.disk).read_exact_at(self.fat_start + offset, &mut chunk[..len])?;
+            let first = self.entries_in(offset);
+            for i in 0..self.entries_in(len as u64) {
+                if self.is_cluster(first + i) {
+                    f(first + i, self.entry(&chunk, i));
+                }
+            }
+            offset += len as u64;
+        }
+        Ok(())
+    }
+
+    /// Returns the number of the cluster after the last one.
+    pub(crate) fn end_cluster(&self) -> u32 {
+        self.end_cluster
+    }
+
+    /// Whether `cluster` is a cluster of the filesystem.
+    pub(crate) fn is_cluster(&self, cluster: u32) -> bool {
+        (2..self.end_cluster).contains(&cluster)
+    }
+
+    /// Returns the size of clusters.
+    pub(crate) fn cluster_len(&self) -> u64 {
+        self.cluster_len
+    }
+
+    /// Returns the entries that differ between the first FAT and its copies, up to `max` of
+    /// them, along with how many there are in all. The entries of the first two clusters, which
+    /// don't chain clusters and hold flags that may only be kept in the first FAT, are left out.
+    pub(crate) fn fat_mismatches(&self, max: usize) -> io::Result<(u64, Vec<FatMismatch>)> {
+        let mut mismatches = Vec::new();
+        let mut count = 0;
+        let mut primary = vec![0; FAT_CHUNK_LEN as usize];
+        let mut copy = vec![0; FAT_CHUNK_LEN as usize];
+        for index in 1..self.fats {
+            let mut offset = 0;
+            while offset < self.fat_len {
+                let len = (self.fat_len - offset).min(FAT_CHUNK_LEN) as usize;
+                (self.disk).read_exact_at(self.fat_start + offset, &mut primary[..len])?;
+                let copy_start = self.fat_start + index * self.fat_len;
+                (self.disk).read_exact_at(copy_start + offset, &mut copy[..len])?;
+                if primary[..len] != copy[..len] {
+                    let first = self.entries_in(offset);
+                    let entries = self.entries_in(len as u64);
+                    for i in 0..entries {
+                        let cluster = first + i;
+                        if !self.is_cluster(cluster) {
+                            continue;
+                        }
+                        let (expected, found) = (self.entry(&primary, i), self.entry(&copy, i));
+                        if expected != found {
+                            count += 1;
+                            if mismatches.len() < max {
+                                mismatches.push(FatMismatch {
+                                    copy: index as u8 + 1,
+                                    cluster,
+                                    expected,
+                                    found,
+                                });
+                            }
+                        }
+                    }
+                }
+                offset += len as u64;
+            }
+        }
+        Ok((count, mismatches))
+    }
+
+    /// Returns how many entries of the FAT take up `len` bytes.
+    fn entries_in(&self, len: u64) -> u32 {
+        let entries = match self.fat_type {
+            FatType::Fat12 => len * 2 / 3,
+            FatType::Fat16 => len / 2,
+            FatType::Fat32 => len / 4,
+        };
+        entries.min(u32::MAX as u64) as u32
+    }
+
+    /// Returns the entry at `index` of `fat`, a part of a FAT that starts with an entry.
+    fn entry(&self, fat: &[u8], index: u32) -> u32 {
+        let index = index as usize;
+        match self.fat_type {
+            FatType::Fat12 => {
+                let offset = index + index / 2;
+                let entry = u16::from_le_bytes([fat[offset], fat[offset + 1]]) as u32;
+                if index.is_multiple_of(2) {
+                    entry & 0xFFF
+                } else {
+                    entry >> 4
+                }
+            }
+            FatType::Fat16 => u16::from_le_bytes([fat[index * 2], fat[index * 2 + 1]]) as u32,
+            FatType::Fat32 => {
+                let offset = index * 4;
+                u32::from_le_bytes(fat[offset..offset + 4].try_into().unwrap()) & 0x0FFF_FFFF
+            }
+        }
+    }
+
+    /// Returns the clusters of the chain that starts at `first`, the chain of `path`, up to
+    /// `max` of them.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`FatError::CorruptFat`] if the chain loops back on itself within them.
+    pub(crate) fn chain(&self, path: &Path, first: u32, max: u64) -> Result<Vec<u32>> {
+        match self.follow(path, first, max)? {
+            (clusters, None) => Ok(clusters),
+            (_, Some(e)) => Err(e.into()),
+        }
+    }
+
+    /// Returns the clusters of the chain that starts at `first`, the chain of `path`, up to
+    /// `max` of them or up to where it loops back on itself, along with the error of the loop.
+    pub(crate) fn follow(
+        &self,
+        path: &Path,
+        first: u32,
+        max: u64,
+    ) -> Result<(Vec<u32>, Option<FatError>)> {
+        let mut clusters = Vec::new();
+        let mut visited = HashSet::new();
+        let mut next = self.is_cluster(first).then_some(first);
+        while let Some(cluster) = next.filter(|_| (clusters.len() as u64) < max) {
+            if !visited.insert(cluster) {
+                let e = FatError::CorruptFat(format!(
+                    "the cluster chain of {} loops back to cluster {cluster}",
+                    path.display()
+                ));
+                return Ok((clusters, Some(e)));
+            }
+            clusters.push(cluster);
+            next = self.next(cluster).map_err(|e| error::dir_error(path, e))?;
+        }
+        Ok((clusters, None))
+    }
+
+    /// Returns the first clusters and offsets of the entries of the directory at `path`, whose
+    /// first cluster is `cluster`, or of the root directory if `None`. Entries come in the order
+    /// fatfs lists them, without the parts of long names, deleted entries or the volume label.
+    ///
+    /// If the cluster chain of the directory loops back on itself, the entries of the clusters
+    /// before the loop are returned along with the error of the loop with
+    /// [`CorruptionPolicy::Lenient`].
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`FatError::CorruptFat`] if the cluster chain of the directory loops back on
+    /// itself, unless lenient, or with [`CorruptionPolicy::Paranoid`] if a long name doesn't
+    /// belong to the entry it comes with.
+    pub(crate) fn entries(
+        &self,
+        path: &Path,
+        cluster: Option<u32>,
+        policy: CorruptionPolicy,
+    ) -> Result<(Vec<RawEntry>, Option<FatError>)> {
+        let (regions, looped) = match (cluster, self.root_region) {
+            (None, Some(region)) => (vec![region], None),
+            (cluster, _) => {
+                let first = cluster.unwrap_or(self.root_cluster);
+                let (chain, looped) = self.follow(path, first, u64::MAX)?;
+                if policy != CorruptionPolicy::Lenient
+                    && let Some(e) = looped
+                {
+                    return Err(e.into());
+                }
+                let regions = chain
+                    .into_iter()
+                    .map(|cluster| {
+                        let offset = (cluster - 2) as u64 * self.cluster_len;
+                        (self.data_start + offset, self.cluster_len)
+                    })
+                    .collect();
+                (regions, looped)
+            }
+        };
+
+        let mut entries = Vec::new();
+        // The checksum of the short name the long name that came last belongs to
+        let mut long_name = None;
+        for (offset, len) in regions {
+            let mut region = vec![0; len as usize];
+            (self.disk)
+                .read_exact_at(offset, &mut region)
+                .map_err(|e| error::dir_error(path, e))?;
+            for (index, entry) in region.chunks_exact(ENTRY_LEN).enumerate() {
+                let attributes = entry[11];
+                match entry[0] {
+                    END => return Ok((entries, None)),
+                    DELETED => {
+                        long_name = None;
+                        continue;
+                    }
+                    _ if attributes & LONG_NAME == LONG_NAME => {
+                        long_name = Some(entry[13]);
+                        continue;
+                    }
+                    _ if attributes & VOLUME_ID != 0 => {
+                        long_name = None;
+                        continue;
+                    }
+                    _ => {}
+                }
+                if policy == CorruptionPolicy::Paranoid
+                    && let Some(checksum) = long_name.take()
+                    && checksum != name_checksum(&entry[..11])
+                {
+                    return Err(FatError::CorruptFat(format!(
+                        "a long name in {} doesn't belong to the entry it comes with",
+                        path.display()
+                    ))
+                    .into());
+                }
+                long_name = None;
+                let high = match self.fat_type {
+                    FatType::Fat32 => u16::from_le_bytes([entry[20], entry[21]]) as u32,
+                    _ => 0,
+                };
+                let cluster = high << 16 | u16::from_le_bytes([entry[26], entry[27]]) as u32;
+                entries.push(RawEntry {
+                    cluster: (cluster != 0).then_some(cluster),
+                    offset: offset + (index * ENTRY_LEN) as u64,
+                });
+            }
+        }
+        Ok((entries, looped))
+    }
+}
+
+/// Returns the checksum of the short name `name` that the parts of its long name carry.
+fn name_checksum(name: &[u8]) -> u8 {
+    name.iter()
+        .fold(0u8, |sum, &byte| sum.rotate_right(1).wrapping_add(byte))
+}
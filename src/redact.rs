@@ -0,0 +1,89 @@
+//! The public setting that keeps the paths clients give out of logs and traces.
+
+use crate::{FatError, hash::fnv1a};
+use std::{error::Error, fmt, path::Path};
+
+/// How the paths clients give are redacted where they are emitted, as set with
+/// [`VfsBuilder::redact_paths`](crate::VfsBuilder::redact_paths).
+///
+/// Redacted paths end with a hash of the path as the client gave it, so that the operations on a
+/// file can still be told apart from those on others and followed across logs and traces. The
+/// hash is the same across restarts and versions of this crate. It doesn't keep those who can
+/// guess a path from confirming the guess.
+///
+/// # Example
+///
+/// ```rust
+/// use unftp_sbe_fatfs::{PathRedaction, VfsBuilder};
+///
+/// // `/photos/2024/beach.jpg` shows up as `/photos/…#` followed by 16 hex digits
+/// let vfs = VfsBuilder::new("path/to/fat/image.img")
+///     .redact_paths(PathRedaction::Truncate(1))
+///     .build();
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum PathRedaction {
+    /// Replaces paths with their hash, such as `#6b1a0c2e9d3f4a57`.
+    Hash,
+    /// Keeps this many of the leading directories and files of paths and replaces the rest
+    /// with the hash of the whole path, such as `/photos/…#6b1a0c2e9d3f4a57` for one.
+    /// Paths that aren't longer are kept as they are.
+    Truncate(usize),
+}
+
+/// Displays a path as redacted by a [`PathRedaction`], if there is one.
+pub(crate) struct Redacted<'a>(pub(crate) &'a Path, pub(crate) Option<PathRedaction>);
+
+impl fmt::Display for Redacted<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Redacted(path, redaction) = *self;
+        let kept = match redaction {
+            None => return write!(f, "{}", path.display()),
+            Some(PathRedaction::Hash) => 0,
+            Some(PathRedaction::Truncate(kept)) => kept,
+        };
+        let names = path.iter().filter(|name| *name != "/");
+        if kept > 0 && names.clone().count() <= kept {
+            return write!(f, "{}", path.display());
+        }
+        for name in names.take(kept) {
+            write!(f, "/{}", name.to_string_lossy())?;
+        }
+        if kept > 0 {
+            f.write_str("/…")?;
+        }
+        write!(f, "#{:016x}", fnv1a(path.as_os_str().as_encoded_bytes()))
+    }
+}
+
+/// Displays the cause of an error, with the paths it tells of redacted by a [`PathRedaction`], if
+/// there is one. Lookup errors tell their paths redacted, leaving out the name that wasn't found,
+/// which is part of them, while the reasons the FAT is corrupt are left out altogether, as many
+/// tell paths.
+pub(crate) struct RedactedCause<'a>(
+    pub(crate) &'a (dyn Error + 'static),
+    pub(crate) Option<PathRedaction>,
+);
+
+impl fmt::Display for RedactedCause<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let RedactedCause(cause, redaction) = *self;
+        let Some(error) = cause
+            .downcast_ref::<FatError>()
+            .filter(|_| redaction.is_some())
+        else {
+            return write!(f, "{cause}");
+        };
+        match error {
+            FatError::NotFound { path, .. } => {
+                write!(f, "{}: not found", Redacted(path, redaction))
+            }
+            FatError::NotADirectory { path, .. } => {
+                write!(f, "{}: not a directory", Redacted(path, redaction))
+            }
+            FatError::CorruptFat(_) => f.write_str("the FAT is corrupt"),
+            _ => write!(f, "{error}"),
+        }
+    }
+}
@@ -0,0 +1,92 @@
+//! Shares the state of file systems created for the same image files.
+//!
+//! A server listening on several ports, or with several users each given a backend of their own,
+//! may create a [`Vfs`] for the same image many times over. Each would open the image separately
+//! and keep caches of its own, so a file system created for image files another one serves
+//! already, configured the same, shares the state of that one instead, as if it were a clone.
+//!
+//! [`Vfs`]: crate::Vfs
+
+use crate::{Mode, PartitionSelect, Shared, builder::FsConfig, image::Image};
+use std::{
+    mem::Discriminant,
+    path::PathBuf,
+    sync::{Arc, Mutex, Weak},
+};
+
+/// The state of the file systems created for image files, until none of them are left.
+static REGISTRY: Mutex<Vec<(Key, Weak<Shared>)>> = Mutex::new(Vec::new());
+
+/// What makes file systems share their state.
+#[derive(PartialEq)]
+struct Key {
+    /// The kind of image, since the same file accessed differently is read differently.
+    kind: Discriminant<Image>,
+    paths: Vec<PathBuf>,
+    partition: PartitionSelect,
+    mode: Mode,
+    fs_options: FsConfig,
+}
+
+/// Returns the state of a file system for `image`, shared with any other file system created
+/// for the same image files with the same configuration.
+///
+/// Images that aren't files, such as those in memory, aren't shared.
+pub(crate) fn shared(
+    image: Image,
+    partition: PartitionSelect,
+    mode: Mode,
+    fs_options: FsConfig,
+) -> Arc<Shared> {
+    let Some(paths) = paths(&image) else {
+        return Arc::new(Shared::new(image, partition, mode, fs_options));
+    };
+    let key = Key {
+        kind: std::mem::discriminant(&image),
+        paths,
+        partition,
+        mode,
+        fs_options,
+    };
+    let Ok(mut registry) = REGISTRY.lock() else {
+        return Arc::new(Shared::new(image, key.partition, key.mode, key.fs_options));
+    };
+    registry.retain(|(_, shared)| shared.strong_count() > 0);
+    if let Some(shared) = registry
+        .iter()
+        .find(|(registered, _)| *registered == key)
+        .and_then(|(_, shared)| shared.upgrade())
+    {
+        return shared;
+    }
+    let shared = Arc::new(Shared::new(
+        image,
+        key.partition.clone(),
+        key.mode,
+        key.fs_options,
+    ));
+    registry.push((key, Arc::downgrade(&shared)));
+    shared
+}
+
+/// Returns the files of images stored in files, each made absolute so that the different ways
+/// of referring to the same file compare equal. Files that don't exist yet are taken as given.
+fn paths(image: &Image) -> Option<Vec<PathBuf>> {
+    let paths = match image {
+        Image::File(path) => std::slice::from_ref(path),
+        #[cfg(feature = "mmap")]
+        Image::Mmap(path) => std::slice::from_ref(path),
+        #[cfg(all(feature = "direct-io", target_os = "linux"))]
+        Image::Direct(path) => std::slice::from_ref(path),
+        #[cfg(all(feature = "io-uring", target_os = "linux"))]
+        Image::Uring(path) => std::slice::from_ref(path),
+        Image::Split(paths) => paths.as_slice(),
+        _ => return None,
+    };
+    Some(
+        paths
+            .iter()
+            .map(|path| std::fs::canonicalize(path).unwrap_or_else(|_| path.clone()))
+            .collect(),
+    )
+}
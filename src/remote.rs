@@ -0,0 +1,273 @@
+//! Images that live on a remote store and are read with ranged requests.
+//!
+//! Remote stores are read-only as far as this crate is concerned. Instead of downloading the
+//! whole image, only the parts fatfs asks for are fetched, in aligned blocks so that its many
+//! small reads of directory entries and FAT sectors don't each cost a round trip.
+
+use crate::image::ReadOnly;
+use std::{
+    error::Error,
+    fmt::{self, Debug},
+    io::{self, Read, Seek, SeekFrom},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+/// The default size of the blocks remote images are fetched in.
+pub(crate) const DEFAULT_BLOCK_SIZE: u64 = 64 * 1024;
+
+/// The number of blocks each stream over a remote image keeps in memory.
+const BUFFERED_BLOCKS: usize = 32;
+
+/// The most blocks fetched ahead of sequential reads.
+const MAX_READ_AHEAD: u64 = 16;
+
+/// How long an opened image is trusted not to have changed before its version is checked again.
+const REVALIDATE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// A remote image that can be read at arbitrary offsets.
+pub(crate) trait RangeRead: Debug + Send + Sync {
+    /// Looks up the current size and version of the image.
+    fn stat(&self) -> io::Result<RemoteStat>;
+
+    /// Returns a string that identifies the image, without any secrets, to key caches with.
+    fn cache_key(&self) -> String;
+
+    /// Reads the bytes starting at `offset` into `buf`, returning how many were read. That can
+    /// be fewer than requested, but only none at the end of the image.
+    ///
+    /// Fails with [`ImageChanged`] if the image is no longer at the given `version`.
+    fn read_at(&self, offset: u64, buf: &mut [u8], version: Option<&str>) -> io::Result<usize>;
+}
+
+/// The size and version of a remote image.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct RemoteStat {
+    pub(crate) len: u64,
+    /// An opaque string that changes whenever the image is replaced, such as its ETag, if the
+    /// store provides one.
+    pub(crate) version: Option<String>,
+}
+
+/// The error reads fail with when the remote image was replaced since it was opened.
+#[derive(Debug)]
+pub(crate) struct ImageChanged;
+
+impl fmt::Display for ImageChanged {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("the remote image changed while it was being served")
+    }
+}
+
+impl Error for ImageChanged {}
+
+impl From<ImageChanged> for io::Error {
+    fn from(e: ImageChanged) -> Self {
+        io::Error::other(e)
+    }
+}
+
+/// A shareable handle to a remote image.
+#[derive(Debug, Clone)]
+pub(crate) struct Remote {
+    pub(crate) source: Arc<dyn RangeRead>,
+    /// The size of the blocks the image is fetched in.
+    pub(crate) block_size: u64,
+    /// Counts the times the image was found replaced.
+    generation: Arc<AtomicU64>,
+    /// When the image was last checked for changes, and what it looked like then.
+    checked: Arc<Mutex<Option<(Instant, RemoteStat)>>>,
+}
+
+impl Remote {
+    pub(crate) fn new(source: impl RangeRead + 'static) -> Self {
+        Self {
+            source: Arc::new(source),
+            block_size: DEFAULT_BLOCK_SIZE,
+            generation: Arc::new(AtomicU64::new(0)),
+            checked: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Returns a number that changes whenever the image is found replaced since it was opened.
+    /// Streams opened before then should be reopened.
+    pub(crate) fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Acquire)
+    }
+
+    /// Checks whether the image was replaced, unless that was checked recently, and bumps the
+    /// generation if it was.
+    ///
+    /// Streams notice a replaced image by themselves once they fetch from it, but not while they
+    /// only read blocks they have buffered.
+    pub(crate) fn revalidate(&self) -> io::Result<()> {
+        let mut checked = self
+            .checked
+            .lock()
+            .map_err(|_| io::Error::other("remote image lock poisoned"))?;
+        let Some((at, stat)) = &*checked else {
+            return Ok(());
+        };
+        if at.elapsed() < REVALIDATE_INTERVAL {
+            return Ok(());
+        }
+        let current = self.source.stat()?;
+        if current != *stat {
+            self.generation.fetch_add(1, Ordering::AcqRel);
+        }
+        *checked = Some((Instant::now(), current));
+        Ok(())
+    }
+
+    /// Opens a new stream over the image, looking up its current size and version.
+    pub(crate) fn open(&self) -> io::Result<ReadOnly<RemoteDisk>> {
+        let stat = self.source.stat()?;
+        if let Ok(mut checked) = self.checked.lock() {
+            *checked = Some((Instant::now(), stat.clone()));
+        }
+        Ok(ReadOnly(RemoteDisk {
+            source: Arc::clone(&self.source),
+            len: stat.len,
+            version: stat.version,
+            pos: 0,
+            block_size: self.block_size,
+            generation: Arc::clone(&self.generation),
+            blocks: Vec::new(),
+            next_block: 0,
+            read_ahead: 1,
+        }))
+    }
+}
+
+/// Fetches the `count` consecutive blocks starting at block `first` with a single read of
+/// `source`, an image of `len` bytes. Blocks at the end of the image come back shorter, or empty
+/// past its end.
+pub(crate) fn fetch_blocks(
+    source: &dyn RangeRead,
+    block_size: u64,
+    first: u64,
+    count: u64,
+    len: u64,
+    version: Option<&str>,
+) -> io::Result<Vec<Vec<u8>>> {
+    // Stores answer ranges that start past the end with an error rather than nothing
+    let end = ((first + count) * block_size).min(len);
+    let mut data = vec![0; end.saturating_sub(first * block_size) as usize];
+    let mut filled = 0;
+    while filled < data.len() {
+        let offset = first * block_size + filled as u64;
+        match source.read_at(offset, &mut data[filled..], version)? {
+            0 => break,
+            n => filled += n,
+        }
+    }
+    data.truncate(filled);
+
+    let mut blocks: Vec<Vec<u8>> = data
+        .chunks(block_size as usize)
+        .map(<[u8]>::to_vec)
+        .collect();
+    blocks.resize(count as usize, Vec::new());
+    Ok(blocks)
+}
+
+/// A seekable stream over a remote image that reads it in blocks, keeping the most recently used
+/// ones in memory.
+pub(crate) struct RemoteDisk {
+    source: Arc<dyn RangeRead>,
+    len: u64,
+    /// The version of the image when the stream was opened.
+    version: Option<String>,
+    pos: u64,
+    block_size: u64,
+    generation: Arc<AtomicU64>,
+    /// Buffered blocks by index, most recently used last.
+    blocks: Vec<(u64, Vec<u8>)>,
+    /// The block following the ones fetched last, which is where a sequential read continues.
+    next_block: u64,
+    /// The number of blocks to fetch at once, which grows while reads are sequential.
+    read_ahead: u64,
+}
+
+impl RemoteDisk {
+    /// Makes sure the block with index `first` is buffered and returns it.
+    ///
+    /// Missing blocks are fetched along with the following blocks up to `last` that aren't
+    /// buffered either, or more when reading sequentially, in a single request.
+    fn block(&mut self, first: u64, last: u64) -> io::Result<&[u8]> {
+        let position = match self.blocks.iter().position(|(i, _)| *i == first) {
+            Some(position) => {
+                let block = self.blocks.remove(position);
+                self.blocks.push(block);
+                self.blocks.len() - 1
+            }
+            None => {
+                self.read_ahead = if first == self.next_block {
+                    (self.read_ahead * 2).min(MAX_READ_AHEAD)
+                } else {
+                    1
+                };
+                let end_block = self.len.div_ceil(self.block_size);
+                let last = last.max(first + self.read_ahead - 1).min(end_block - 1);
+                let count = (first..=last)
+                    .take(BUFFERED_BLOCKS)
+                    .take_while(|i| !self.blocks.iter().any(|(j, _)| i == j))
+                    .count() as u64;
+                self.next_block = first + count;
+                let fetched = fetch_blocks(
+                    &*self.source,
+                    self.block_size,
+                    first,
+                    count,
+                    self.len,
+                    self.version.as_deref(),
+                )
+                .inspect_err(|e| {
+                    if e.get_ref().is_some_and(|e| e.is::<ImageChanged>()) {
+                        self.generation.fetch_add(1, Ordering::AcqRel);
+                    }
+                })?;
+                // Keep the requested block last so it survives trimming
+                let fetched: Vec<_> = (first..).zip(fetched).collect();
+                self.blocks.extend(fetched.into_iter().rev());
+                let excess = self.blocks.len().saturating_sub(BUFFERED_BLOCKS);
+                self.blocks.drain(..excess);
+                self.blocks.len() - 1
+            }
+        };
+        Ok(&self.blocks[position].1)
+    }
+}
+
+impl Read for RemoteDisk {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let max = self.len.saturating_sub(self.pos).min(buf.len() as u64);
+        if max == 0 {
+            return Ok(0);
+        }
+        let first = self.pos / self.block_size;
+        let last = (self.pos + max - 1) / self.block_size;
+        let start = (self.pos % self.block_size) as usize;
+        let block = self.block(first, last)?;
+
+        let n = block.len().saturating_sub(start).min(max as usize);
+        buf[..n].copy_from_slice(&block[start..start + n]);
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for RemoteDisk {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.pos = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+        }
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "seek to a negative offset"))?;
+        Ok(self.pos)
+    }
+}
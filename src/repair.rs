@@ -0,0 +1,86 @@
+//! Fixes the damage [`Vfs::validate`](crate::Vfs::validate) finds that can be fixed without
+//! guessing, like a minimal chkdsk, as [`Vfs::repair`](crate::Vfs::repair) does.
+
+use crate::{
+    ValidationReport,
+    raw::Raw,
+    validate::{self, Checked, Fix},
+    volume::FatVolume,
+};
+use std::io;
+use unftp_core::storage::Result;
+
+/// What [`Vfs::repair`](crate::Vfs::repair) found wrong with a FAT filesystem, and what is still
+/// wrong with it after the repair.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// use unftp_sbe_fatfs::{Mode, VfsBuilder};
+///
+/// # async fn run() -> Result<(), Box<dyn std::error::Error>> {
+/// let vfs = VfsBuilder::new("path/to/fat/image.img")
+///     .mode(Mode::ReadWrite)
+///     .build();
+/// let report = vfs.repair().await?;
+/// println!("found:\n{}", report.found);
+/// if !report.remaining.is_clean() {
+///     println!("left for fsck.fat or chkdsk:\n{}", report.remaining);
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+#[non_exhaustive]
+pub struct RepairReport {
+    /// What was found wrong before the repair.
+    pub found: ValidationReport,
+    /// What is still wrong after the repair, such as cross-links and invalid names, which
+    /// aren't repaired.
+    pub remaining: ValidationReport,
+}
+
+/// Repairs the filesystem of `volume`, writing the fixes through the disk it was mounted on.
+///
+/// # Errors
+///
+/// Fails if the image can't be read or the fixes can't be written.
+pub(crate) fn repair(volume: &FatVolume) -> Result<RepairReport> {
+    let raw = volume.raw();
+    let Checked {
+        report: found,
+        fixes,
+        incomplete,
+        ..
+    } = validate::check(volume)?;
+    for fix in fixes {
+        match fix {
+            Fix::Link(cluster, link) => raw.set_link(cluster, link)?,
+            Fix::Write(offset, bytes) => raw.write_at(offset, &bytes)?,
+        }
+    }
+    // The entries of a directory that couldn't be read to the end may lead to the clusters
+    // that seem orphaned, which would be freed from under them
+    if !incomplete {
+        for cluster in found.orphaned_clusters.iter().cloned().flatten() {
+            raw.set_link(cluster, 0)?;
+        }
+    }
+    let remaining = validate::validate(volume)?;
+    Ok(RepairReport { found, remaining })
+}
+
+/// Sets the count of free clusters the FSInfo sector of FAT32 keeps to the clusters the FAT marks
+/// as free, if it is wrong, such as after clusters were freed by [`repair`] behind the back of
+/// fatfs, or by systems that didn't keep the count. `raw` is that of a filesystem that was
+/// unmounted, as fatfs writes the count it kept on unmounting.
+pub(crate) fn recount_free(raw: &Raw) -> io::Result<()> {
+    if !raw.has_fs_info() {
+        return Ok(());
+    }
+    let free = raw.count_free()?;
+    match raw.free_count()? {
+        Some(count) if count == free => Ok(()),
+        _ => raw.set_free_count(Some(free)),
+    }
+}
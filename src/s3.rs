@@ -0,0 +1,268 @@
+//! Images stored as S3 objects, read with ranged GET requests signed with AWS Signature
+//! Version 4.
+
+use crate::{
+    cloud::{UtcTime, encode_path, hmac_sha256, host_and_path},
+    http::{HttpSource, SignRequest},
+};
+use sha2::{Digest, Sha256};
+use std::{env, fmt, io, str::FromStr, time::SystemTime};
+
+/// The SHA-256 hash of an empty request body, as sent with every GET request.
+const EMPTY_PAYLOAD_SHA256: &str =
+    "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+/// The location of an image stored in Amazon S3 or an S3-compatible store such as MinIO, and
+/// the credentials to read it with.
+///
+/// Settings that aren't given explicitly are taken from the standard AWS environment variables
+/// when the [`Vfs`](crate::Vfs) is created: `AWS_REGION` or `AWS_DEFAULT_REGION`,
+/// `AWS_ENDPOINT_URL_S3` or `AWS_ENDPOINT_URL`, `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY` and
+/// `AWS_SESSION_TOKEN`. Without any credentials requests are sent unsigned, which works for
+/// public objects.
+///
+/// # Example
+///
+/// ```rust
+/// use unftp_sbe_fatfs::S3Object;
+///
+/// let object: S3Object = "s3://build-artifacts/firmware/fat.img".parse().unwrap();
+/// let object = object.region("eu-west-1");
+/// ```
+#[derive(Clone)]
+pub struct S3Object {
+    bucket: String,
+    key: String,
+    region: Option<String>,
+    endpoint: Option<String>,
+    credentials: Option<Credentials>,
+}
+
+#[derive(Clone)]
+struct Credentials {
+    access_key_id: String,
+    secret_access_key: String,
+    session_token: Option<String>,
+}
+
+impl S3Object {
+    /// Refers to the object with the given key in the given bucket.
+    pub fn new<B: Into<String>, K: Into<String>>(bucket: B, key: K) -> Self {
+        Self {
+            bucket: bucket.into(),
+            key: key.into(),
+            region: None,
+            endpoint: None,
+            credentials: None,
+        }
+    }
+
+    /// Sets the AWS region of the bucket. Defaults to `us-east-1` if the environment doesn't
+    /// name one either.
+    pub fn region<S: Into<String>>(mut self, region: S) -> Self {
+        self.region = Some(region.into());
+        self
+    }
+
+    /// Sets the base URL of an S3-compatible store to use instead of AWS, for instance
+    /// `http://localhost:9000`. Objects are then addressed path-style, with the bucket as the
+    /// first path segment.
+    pub fn endpoint<S: Into<String>>(mut self, endpoint: S) -> Self {
+        self.endpoint = Some(endpoint.into());
+        self
+    }
+
+    /// Sets the access key to sign requests with.
+    pub fn credentials<A: Into<String>, S: Into<String>>(
+        mut self,
+        access_key_id: A,
+        secret_access_key: S,
+    ) -> Self {
+        self.credentials = Some(Credentials {
+            access_key_id: access_key_id.into(),
+            secret_access_key: secret_access_key.into(),
+            session_token: None,
+        });
+        self
+    }
+
+    /// Sets the session token that goes with temporary [credentials](S3Object::credentials).
+    pub fn session_token<S: Into<String>>(mut self, session_token: S) -> Self {
+        if let Some(credentials) = &mut self.credentials {
+            credentials.session_token = Some(session_token.into());
+        }
+        self
+    }
+
+    /// Fills in the settings that weren't given explicitly from the environment.
+    fn with_env_defaults(mut self) -> Self {
+        let var = |names: &[&str]| names.iter().find_map(|name| env::var(name).ok());
+        if self.region.is_none() {
+            self.region = var(&["AWS_REGION", "AWS_DEFAULT_REGION"]);
+        }
+        if self.endpoint.is_none() {
+            self.endpoint = var(&["AWS_ENDPOINT_URL_S3", "AWS_ENDPOINT_URL"]);
+        }
+        if self.credentials.is_none()
+            && let (Some(access_key_id), Some(secret_access_key)) =
+                (var(&["AWS_ACCESS_KEY_ID"]), var(&["AWS_SECRET_ACCESS_KEY"]))
+        {
+            self.credentials = Some(Credentials {
+                access_key_id,
+                secret_access_key,
+                session_token: var(&["AWS_SESSION_TOKEN"]),
+            });
+        }
+        self
+    }
+
+    /// Returns the HTTP(S) URL of the object.
+    fn url(&self, region: &str) -> String {
+        let key = encode_path(&self.key);
+        match &self.endpoint {
+            Some(endpoint) => format!("{}/{}/{key}", endpoint.trim_end_matches('/'), self.bucket),
+            None => format!("https://{}.s3.{region}.amazonaws.com/{key}", self.bucket),
+        }
+    }
+}
+
+impl fmt::Debug for S3Object {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("S3Object")
+            .field("bucket", &self.bucket)
+            .field("key", &self.key)
+            .field("region", &self.region)
+            .field("endpoint", &self.endpoint)
+            .finish_non_exhaustive()
+    }
+}
+
+/// The error returned when parsing a malformed `s3://` URL into an [`S3Object`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseS3UrlError;
+
+impl fmt::Display for ParseS3UrlError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid S3 URL, expected the form s3://bucket/key")
+    }
+}
+
+impl std::error::Error for ParseS3UrlError {}
+
+impl FromStr for S3Object {
+    type Err = ParseS3UrlError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (bucket, key) = s
+            .strip_prefix("s3://")
+            .and_then(|s| s.split_once('/'))
+            .ok_or(ParseS3UrlError)?;
+        if bucket.is_empty() || key.is_empty() {
+            return Err(ParseS3UrlError);
+        }
+        Ok(Self::new(bucket, key))
+    }
+}
+
+/// Creates the source that reads `object` with ranged GET requests.
+pub(crate) fn source(object: S3Object) -> HttpSource {
+    let object = object.with_env_defaults();
+    let region = object
+        .region
+        .clone()
+        .unwrap_or_else(|| "us-east-1".to_string());
+    let url = object.url(&region);
+    match object.credentials {
+        Some(credentials) => HttpSource::with_signer(
+            url,
+            Signer {
+                region,
+                credentials,
+            },
+        ),
+        None => HttpSource::new(url),
+    }
+}
+
+/// Signs requests with AWS Signature Version 4.
+struct Signer {
+    region: String,
+    credentials: Credentials,
+}
+
+impl Signer {
+    /// Returns the headers that authenticate a GET request for `range` of the object at `path`
+    /// on `host`, made at `now`.
+    fn headers(
+        &self,
+        host: &str,
+        path: &str,
+        range: &str,
+        now: SystemTime,
+    ) -> Vec<(&'static str, String)> {
+        let (date, timestamp) = utc_timestamp(now);
+
+        let mut headers = vec![
+            ("host", host.to_string()),
+            ("range", range.to_string()),
+            ("x-amz-content-sha256", EMPTY_PAYLOAD_SHA256.to_string()),
+            ("x-amz-date", timestamp.clone()),
+        ];
+        if let Some(token) = &self.credentials.session_token {
+            headers.push(("x-amz-security-token", token.clone()));
+        }
+        let signed_headers = headers
+            .iter()
+            .map(|(name, _)| *name)
+            .collect::<Vec<_>>()
+            .join(";");
+        let canonical_headers: String = headers
+            .iter()
+            .map(|(name, value)| format!("{name}:{}\n", value.trim()))
+            .collect();
+        let canonical_request =
+            format!("GET\n{path}\n\n{canonical_headers}\n{signed_headers}\n{EMPTY_PAYLOAD_SHA256}");
+
+        let scope = format!("{date}/{}/s3/aws4_request", self.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{timestamp}\n{scope}\n{}",
+            hex(&Sha256::digest(canonical_request.as_bytes()))
+        );
+        let secret = format!("AWS4{}", self.credentials.secret_access_key);
+        let key = [date.as_str(), &self.region, "s3", "aws4_request"]
+            .iter()
+            .fold(secret.into_bytes(), |key, part| hmac_sha256(&key, part));
+        let signature = hex(&hmac_sha256(&key, &string_to_sign));
+
+        // The HTTP client sends the host itself and the range is already on the request
+        headers.retain(|(name, _)| *name != "host" && *name != "range");
+        headers.push((
+            "authorization",
+            format!(
+                "AWS4-HMAC-SHA256 Credential={}/{scope}, SignedHeaders={signed_headers}, Signature={signature}",
+                self.credentials.access_key_id
+            ),
+        ));
+        headers
+    }
+}
+
+impl SignRequest for Signer {
+    fn sign(&self, url: &str, range: &str) -> io::Result<Vec<(&'static str, String)>> {
+        let (host, path) = host_and_path(url);
+        Ok(self.headers(host, path, range, SystemTime::now()))
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Formats `time` as the date (`YYYYMMDD`) and timestamp (`YYYYMMDDTHHMMSSZ`) that Signature
+/// Version 4 expects.
+fn utc_timestamp(time: SystemTime) -> (String, String) {
+    let t = UtcTime::from(time);
+    let date = format!("{:04}{:02}{:02}", t.year, t.month, t.day);
+    let timestamp = format!("{date}T{:02}{:02}{:02}Z", t.hour, t.min, t.sec);
+    (date, timestamp)
+}
@@ -0,0 +1,133 @@
+//! Turns the paths clients give into the paths looked up in the filesystem, the same way for
+//! every operation.
+
+use std::path::{Component, Path, PathBuf};
+use unftp_core::storage::{ErrorKind, Result};
+
+/// Returns `path` made absolute, with repeated slashes and `.` segments dropped and `..`
+/// segments resolved, as operations look it up.
+///
+/// # Errors
+///
+/// Returns `FileNameNotAllowedError` if `path` leads above the root directory, or a name in it
+/// holds a NUL, which no name on a FAT filesystem can.
+pub(crate) fn sanitize(path: &Path) -> Result<PathBuf> {
+    let mut depth = 0usize;
+    for component in path.components() {
+        match component {
+            Component::Normal(name) if name.as_encoded_bytes().contains(&0) => {
+                return Err(ErrorKind::FileNameNotAllowedError.into());
+            }
+            Component::Normal(_) => depth += 1,
+            Component::ParentDir => {
+                depth = depth
+                    .checked_sub(1)
+                    .ok_or(ErrorKind::FileNameNotAllowedError)?;
+            }
+            // Paths of Windows drives or shares lead outside the filesystem as well
+            Component::Prefix(_) => return Err(ErrorKind::FileNameNotAllowedError.into()),
+            Component::RootDir | Component::CurDir => {}
+        }
+    }
+    Ok(Path::new("/").join(normalize(path)))
+}
+
+/// Returns `path` relative to the root directory, with `.` and `..` segments resolved. `..`
+/// segments that would lead above the root directory are dropped, as [`sanitize`] refused them
+/// in the paths clients give.
+pub(crate) fn normalize(path: &Path) -> PathBuf {
+    let mut result = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::ParentDir => {
+                result.pop();
+            }
+            Component::Normal(name) => result.push(name),
+            Component::Prefix(_) | Component::RootDir | Component::CurDir => {}
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{normalize, sanitize};
+    use std::path::{Path, PathBuf};
+    use unftp_core::storage::ErrorKind;
+
+    fn sanitized(path: &str) -> Result<PathBuf, ErrorKind> {
+        sanitize(Path::new(path)).map_err(|e| e.kind())
+    }
+
+    #[test]
+    fn refuses_paths_above_the_root() {
+        for path in ["..", "/..", "/../etc/passwd", "a/../..", "/a/b/../../../c"] {
+            assert_eq!(
+                sanitized(path),
+                Err(ErrorKind::FileNameNotAllowedError),
+                "{path}"
+            );
+        }
+    }
+
+    #[test]
+    fn drops_repeated_slashes() {
+        assert_eq!(sanitized("//a///b//"), Ok(PathBuf::from("/a/b")));
+        assert_eq!(sanitized("///"), Ok(PathBuf::from("/")));
+    }
+
+    #[test]
+    fn refuses_nul_bytes() {
+        for path in ["a\0b", "/a/\0", "/a\0/b"] {
+            assert_eq!(
+                sanitized(path),
+                Err(ErrorKind::FileNameNotAllowedError),
+                "{path:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn resolves_dot_segments() {
+        assert_eq!(sanitized("./a/./b/."), Ok(PathBuf::from("/a/b")));
+        assert_eq!(sanitized("/a/b/../c"), Ok(PathBuf::from("/a/c")));
+        assert_eq!(sanitized("a/.."), Ok(PathBuf::from("/")));
+        assert_eq!(sanitized(""), Ok(PathBuf::from("/")));
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn refuses_windows_prefixes() {
+        for path in [
+            r"C:\Windows",
+            "C:/Windows",
+            r"\\server\share\a",
+            r"\\?\C:\a",
+        ] {
+            assert_eq!(
+                sanitized(path),
+                Err(ErrorKind::FileNameNotAllowedError),
+                "{path}"
+            );
+        }
+    }
+
+    // Elsewhere they are names like any other, below the root
+    #[cfg(not(windows))]
+    #[test]
+    fn keeps_windows_prefixes_below_the_root() {
+        assert_eq!(sanitized("C:/Windows"), Ok(PathBuf::from("/C:/Windows")));
+        assert_eq!(
+            sanitized(r"\\server\share"),
+            Ok(PathBuf::from(r"/\\server\share"))
+        );
+    }
+
+    #[test]
+    fn normalizes_relative_to_the_root() {
+        assert_eq!(normalize(Path::new("/a//b/./c")), PathBuf::from("a/b/c"));
+        assert_eq!(normalize(Path::new("/a/b/../c")), PathBuf::from("a/c"));
+        assert_eq!(normalize(Path::new("/")), PathBuf::new());
+        assert_eq!(normalize(Path::new("../../a")), PathBuf::from("a"));
+    }
+}
@@ -0,0 +1,216 @@
+//! The public extension point for storage systems that images can be read from.
+
+use std::{
+    fmt::Debug,
+    fs::File,
+    io::{self, Read, Seek, SeekFrom, Write},
+    sync::Arc,
+};
+
+/// A store that a FAT image can be read from at arbitrary offsets, and optionally written to.
+///
+/// Implement this to serve images from storage systems this crate doesn't support out of the
+/// box, and pass the source to [`Vfs::from_source`](crate::Vfs::from_source). Everything that
+/// applies to images opened from a path applies to sources too: compressed images and virtual
+/// disks are decoded, and partitions are looked up.
+///
+/// Files, memory maps and byte buffers implement this trait already, as does an `Arc` of any
+/// source.
+///
+/// # Example
+///
+/// ```rust
+/// use std::io;
+/// use unftp_sbe_fatfs::{ImageSource, Vfs};
+///
+/// /// An image that is all zeros, standing in for some proprietary store.
+/// #[derive(Debug)]
+/// struct Zeros(u64);
+///
+/// impl ImageSource for Zeros {
+///     fn len(&self) -> io::Result<u64> {
+///         Ok(self.0)
+///     }
+///
+///     fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+///         let n = self.0.saturating_sub(offset).min(buf.len() as u64) as usize;
+///         buf[..n].fill(0);
+///         Ok(n)
+///     }
+/// }
+///
+/// let vfs = Vfs::from_source(Zeros(1024 * 1024));
+/// ```
+#[allow(clippy::len_without_is_empty)]
+pub trait ImageSource: Debug + Send + Sync {
+    /// Prepares the source for reading. This is called each time the image is opened, which
+    /// includes reopening it after it was replaced or its media came back, so it's the place to
+    /// connect or to look up a new version. Does nothing by default.
+    fn open(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Returns the size of the image in bytes.
+    fn len(&self) -> io::Result<u64>;
+
+    /// Reads the bytes starting at `offset` into `buf`, returning how many were read. That can
+    /// be fewer than requested, but only none at the end of the image.
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<usize>;
+
+    /// Writes `buf` to the image starting at `offset`, returning how many bytes were written.
+    ///
+    /// Only called when the image is served in [`Mode::ReadWrite`](crate::Mode::ReadWrite).
+    /// Sources are read-only by default, failing with [`io::ErrorKind::Unsupported`].
+    fn write_at(&self, offset: u64, buf: &[u8]) -> io::Result<usize> {
+        let _ = (offset, buf);
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "the image source is read-only",
+        ))
+    }
+}
+
+impl<T: ImageSource + ?Sized> ImageSource for Arc<T> {
+    fn open(&self) -> io::Result<()> {
+        (**self).open()
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        (**self).len()
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        (**self).read_at(offset, buf)
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> io::Result<usize> {
+        (**self).write_at(offset, buf)
+    }
+}
+
+impl ImageSource for File {
+    fn len(&self) -> io::Result<u64> {
+        // Block devices report a size of zero in their metadata, seeking finds their real size
+        (&*self).seek(SeekFrom::End(0))
+    }
+
+    #[cfg(unix)]
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        std::os::unix::fs::FileExt::read_at(self, buf, offset)
+    }
+
+    #[cfg(unix)]
+    fn write_at(&self, offset: u64, buf: &[u8]) -> io::Result<usize> {
+        std::os::unix::fs::FileExt::write_at(self, buf, offset)
+    }
+
+    #[cfg(windows)]
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        std::os::windows::fs::FileExt::seek_read(self, buf, offset)
+    }
+
+    #[cfg(windows)]
+    fn write_at(&self, offset: u64, buf: &[u8]) -> io::Result<usize> {
+        std::os::windows::fs::FileExt::seek_write(self, buf, offset)
+    }
+}
+
+#[cfg(feature = "mmap")]
+impl ImageSource for memmap2::Mmap {
+    fn len(&self) -> io::Result<u64> {
+        Ok((**self).len() as u64)
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        Ok(read_slice(self, offset, buf))
+    }
+}
+
+impl ImageSource for Vec<u8> {
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.as_slice().len() as u64)
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        Ok(read_slice(self, offset, buf))
+    }
+}
+
+impl ImageSource for &'static [u8] {
+    fn len(&self) -> io::Result<u64> {
+        Ok((*self).len() as u64)
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        Ok(read_slice(self, offset, buf))
+    }
+}
+
+/// Copies the bytes of `bytes` starting at `offset` into `buf`, returning how many there were.
+fn read_slice(bytes: &[u8], offset: u64, buf: &mut [u8]) -> usize {
+    let start = offset.min(bytes.len() as u64) as usize;
+    let n = (bytes.len() - start).min(buf.len());
+    buf[..n].copy_from_slice(&bytes[start..start + n]);
+    n
+}
+
+/// A seekable stream over an [`ImageSource`], as handed to fatfs.
+pub(crate) struct SourceDisk {
+    source: Arc<dyn ImageSource>,
+    /// The size of the image when the stream was opened.
+    len: u64,
+    pos: u64,
+}
+
+impl SourceDisk {
+    /// Opens a new stream over `source`, looking up its current size.
+    pub(crate) fn open(source: Arc<dyn ImageSource>) -> io::Result<Self> {
+        source.open()?;
+        let len = source.len()?;
+        Ok(Self {
+            source,
+            len,
+            pos: 0,
+        })
+    }
+}
+
+impl Read for SourceDisk {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let max = self.len.saturating_sub(self.pos).min(buf.len() as u64) as usize;
+        if max == 0 {
+            return Ok(0);
+        }
+        let n = self.source.read_at(self.pos, &mut buf[..max])?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Write for SourceDisk {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let max = self.len.saturating_sub(self.pos).min(buf.len() as u64) as usize;
+        if max == 0 && !buf.is_empty() {
+            return Err(io::ErrorKind::WriteZero.into());
+        }
+        let n = self.source.write_at(self.pos, &buf[..max])?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for SourceDisk {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.pos = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+        }
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "seek to a negative offset"))?;
+        Ok(self.pos)
+    }
+}
@@ -0,0 +1,230 @@
+//! Spans that follow storage operations from start to end, so that what an operation did and
+//! why it failed can be told apart from the bare FTP reply the client got.
+//!
+//! Every operation gets a [`tracing`] span named `fatfs`, with the kind of operation, the image,
+//! the user, the paths as the client gave them unless they are redacted, and once it ended the
+//! bytes transferred and how long it took. An event in the span tells how the operation ended, at
+//! `DEBUG` level when it succeeded and at `INFO` level with the error and its cause when it
+//! failed.
+//!
+//! With the `opentelemetry` feature, every operation gets an OpenTelemetry span as well, started
+//! with the tracer of the global tracer provider as a child of the OpenTelemetry context current
+//! when the operation started.
+
+use crate::{
+    Operation, PathRedaction, Shared,
+    redact::{Redacted, RedactedCause},
+};
+#[cfg(feature = "opentelemetry")]
+use opentelemetry::{
+    Context, KeyValue,
+    context::FutureExt,
+    global,
+    trace::{Status, TraceContextExt, Tracer},
+};
+use std::{error::Error as _, path::Path, time::Instant};
+use tracing::{Instrument, field};
+use unftp_core::{auth::UserDetail, storage::Error};
+
+/// The name of the OpenTelemetry tracer spans are started with.
+#[cfg(feature = "opentelemetry")]
+const TRACER: &str = "unftp-sbe-fatfs";
+
+/// The spans of an operation, which are ended once the operation is done. That's after the last
+/// byte was read for downloads, rather than when the operation returned.
+#[derive(Debug)]
+pub(crate) struct OperationSpan {
+    span: tracing::Span,
+    started: Instant,
+    /// How the paths the causes of errors tell are redacted.
+    redaction: Option<PathRedaction>,
+    #[cfg(feature = "opentelemetry")]
+    cx: Context,
+}
+
+impl OperationSpan {
+    /// Starts the spans of `operation`, which `user` asked for of the file system `shared` is the
+    /// state of, redacting paths as it is configured to.
+    pub(crate) fn start(shared: &Shared, user: &dyn UserDetail, operation: Operation<'_>) -> Self {
+        let redaction = shared.fs_options.redact_paths;
+        let (path, to) = paths(operation);
+        let span = tracing::info_span!(
+            "fatfs",
+            operation = operation.name(),
+            image = %shared.image.name(),
+            user = %user,
+            path = %Redacted(path, redaction),
+            to = field::Empty,
+            bytes = field::Empty,
+            duration = field::Empty,
+        );
+        if let Some(to) = to {
+            span.record("to", field::display(Redacted(to, redaction)));
+        }
+        Self {
+            span,
+            started: Instant::now(),
+            redaction,
+            #[cfg(feature = "opentelemetry")]
+            cx: otel_start(shared, user, operation),
+        }
+    }
+
+    /// Makes the spans current while `future` is polled, so that spans started by what it calls
+    /// become their children.
+    pub(crate) fn instrument<F: Future>(&self, future: F) -> impl Future<Output = F::Output> {
+        let future = future.instrument(self.span.clone());
+        #[cfg(feature = "opentelemetry")]
+        let future = future.with_context(self.cx.clone());
+        future
+    }
+
+    /// Records that the operation transferred `bytes`.
+    pub(crate) fn transferred(&self, bytes: u64) {
+        self.span.record("bytes", bytes);
+        #[cfg(feature = "opentelemetry")]
+        self.cx
+            .span()
+            .set_attribute(KeyValue::new("fatfs.bytes", bytes as i64));
+    }
+
+    /// Ends the spans of an operation that succeeded, or failed with `error`.
+    pub(crate) fn end(self, error: Option<&Error>) {
+        self.span
+            .record("duration", field::debug(self.started.elapsed()));
+        let cause = error
+            .and_then(|error| error.source())
+            .map(|cause| RedactedCause(cause, self.redaction).to_string());
+        match error {
+            Some(error) => {
+                tracing::info!(
+                    parent: &self.span,
+                    kind = ?error.kind(),
+                    error = %error,
+                    cause = cause.as_deref(),
+                    "operation failed"
+                );
+            }
+            None => tracing::debug!(parent: &self.span, "operation succeeded"),
+        }
+        #[cfg(feature = "opentelemetry")]
+        {
+            let span = self.cx.span();
+            if let Some(error) = error {
+                span.set_attribute(KeyValue::new("error.type", format!("{:?}", error.kind())));
+                span.set_status(Status::error(match &cause {
+                    Some(cause) => format!("{error}: {cause}"),
+                    None => error.to_string(),
+                }));
+            }
+            span.end();
+        }
+    }
+}
+
+/// Returns the path of `operation`, and the path it goes to for renames.
+fn paths(operation: Operation<'_>) -> (&Path, Option<&Path>) {
+    match operation {
+        Operation::Rename { from, to } => (from, Some(to)),
+        Operation::Metadata(path)
+        | Operation::List(path)
+        | Operation::ChangeDir(path)
+        | Operation::Download(path)
+        | Operation::Checksum(path)
+        | Operation::Upload(path)
+        | Operation::Delete(path)
+        | Operation::CreateDir(path)
+        | Operation::RemoveDir(path) => (path, None),
+    }
+}
+
+/// Starts the OpenTelemetry span of `operation`, returning a context with it.
+#[cfg(feature = "opentelemetry")]
+fn otel_start(shared: &Shared, user: &dyn UserDetail, operation: Operation<'_>) -> Context {
+    let redaction = shared.fs_options.redact_paths;
+    let (path, to) = paths(operation);
+    let mut attributes = vec![
+        KeyValue::new("fatfs.image", shared.image.name()),
+        KeyValue::new("ftp.path", Redacted(path, redaction).to_string()),
+        KeyValue::new("enduser.id", user.to_string()),
+    ];
+    if let Some(to) = to {
+        attributes.push(KeyValue::new(
+            "ftp.path.to",
+            Redacted(to, redaction).to_string(),
+        ));
+    }
+    let tracer = global::tracer(TRACER);
+    let span = tracer
+        .span_builder(format!("fatfs.{}", operation.name()))
+        .with_attributes(attributes)
+        .start(&tracer);
+    Context::current_with_span(span)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{PathRedaction, VfsBuilder};
+    use std::{
+        fs, io,
+        sync::{Arc, Mutex},
+    };
+    use unftp_core::{auth::DefaultUser, storage::StorageBackend};
+
+    /// Where the logs of a test are written to.
+    #[derive(Clone, Default)]
+    struct Logs(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Logs {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn failures_log_redacted_paths() {
+        let image = std::env::temp_dir().join(format!("span-redact-{}.img", std::process::id()));
+        let file = fs::File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&image)
+            .unwrap();
+        file.set_len(1024 * 1024).unwrap();
+        fatfs::format_volume(&file, fatfs::FormatVolumeOptions::new()).unwrap();
+        fatfs::FileSystem::new(&file, fatfs::FsOptions::new())
+            .unwrap()
+            .root_dir()
+            .create_dir("private")
+            .unwrap();
+
+        let logs = Logs::default();
+        let writer = logs.clone();
+        let _subscriber = tracing::subscriber::set_default(
+            tracing_subscriber::fmt()
+                .with_writer(move || writer.clone())
+                .with_ansi(false)
+                .finish(),
+        );
+        let vfs = VfsBuilder::new(&image)
+            .redact_paths(PathRedaction::Hash)
+            .build();
+        let result = vfs.metadata(&DefaultUser, "/private/secret.txt").await;
+        fs::remove_file(&image).unwrap();
+
+        assert!(result.is_err());
+        let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+        assert!(logs.contains("operation failed"), "{logs}");
+        assert!(logs.contains(": not found"), "{logs}");
+        assert!(
+            !logs.contains("private") && !logs.contains("secret"),
+            "{logs}"
+        );
+    }
+}
@@ -0,0 +1,132 @@
+//! Images split into several files, such as the `.001`, `.002`, ... parts that disk imaging
+//! tools write to get around file size limits.
+//!
+//! The parts are read one after the other as if they were a single file. Writes stay within the
+//! parts as they are, so the image never grows.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+/// Lists the parts of a split image. A single path with a numeric extension, like `disk.img.001`,
+/// stands for all the consecutively numbered files next to it.
+pub(crate) fn parts(paths: &[PathBuf]) -> io::Result<Vec<PathBuf>> {
+    let [first] = paths else {
+        return Ok(paths.to_vec());
+    };
+    let Some(number) = number(first) else {
+        return Ok(paths.to_vec());
+    };
+    let mut parts = vec![first.clone()];
+    for number in number + 1.. {
+        // The numbers are padded to the width of the first one, e.g. `.001` is followed by `.002`
+        let width = first.extension().map_or(0, |e| e.len());
+        let next = first.with_extension(format!("{number:0width$}"));
+        if !next.try_exists()? {
+            break;
+        }
+        parts.push(next);
+    }
+    Ok(parts)
+}
+
+/// Returns the part number of a path like `disk.img.001`.
+fn number(path: &Path) -> Option<u64> {
+    let extension = path.extension()?.to_str()?;
+    if extension.is_empty() || !extension.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    extension.parse().ok()
+}
+
+/// A seekable stream over the concatenated parts of a split image.
+pub(crate) struct Split {
+    /// The parts along with the offset in the image of their first byte.
+    parts: Vec<(u64, File)>,
+    len: u64,
+    pos: u64,
+}
+
+impl Split {
+    /// Opens all the parts at `paths`, in order.
+    pub(crate) fn open(paths: &[PathBuf], writable: bool) -> io::Result<Self> {
+        if paths.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "a split image needs at least one part",
+            ));
+        }
+        let mut parts = Vec::with_capacity(paths.len());
+        let mut len = 0;
+        for path in paths {
+            let mut file = OpenOptions::new().read(true).write(writable).open(path)?;
+            // Unlike the metadata, seeking also finds the size of block devices
+            let part_len = file.seek(SeekFrom::End(0))?;
+            parts.push((len, file));
+            len += part_len;
+        }
+        Ok(Self { parts, len, pos: 0 })
+    }
+
+    /// Returns the part that holds the byte at the current position, positioned at that byte,
+    /// and the number of bytes left in it.
+    fn part(&mut self) -> io::Result<Option<(&mut File, u64)>> {
+        if self.pos >= self.len {
+            return Ok(None);
+        }
+        let index = self.parts.partition_point(|(start, _)| *start <= self.pos) - 1;
+        let end = self
+            .parts
+            .get(index + 1)
+            .map_or(self.len, |(start, _)| *start);
+        let (start, file) = &mut self.parts[index];
+        file.seek(SeekFrom::Start(self.pos - *start))?;
+        Ok(Some((file, end - self.pos)))
+    }
+}
+
+impl Read for Split {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let Some((file, left)) = self.part()? else {
+            return Ok(0);
+        };
+        let max = left.min(buf.len() as u64) as usize;
+        let n = file.read(&mut buf[..max])?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Write for Split {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let Some((file, left)) = self.part()? else {
+            return if buf.is_empty() {
+                Ok(0)
+            } else {
+                Err(io::ErrorKind::WriteZero.into())
+            };
+        };
+        let max = left.min(buf.len() as u64) as usize;
+        let n = file.write(&buf[..max])?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.parts.iter_mut().try_for_each(|(_, file)| file.flush())
+    }
+}
+
+impl Seek for Split {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.pos = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+        }
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "seek to a negative offset"))?;
+        Ok(self.pos)
+    }
+}
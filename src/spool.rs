@@ -0,0 +1,62 @@
+//! Temporary files that uploads are received into before they are written to the image, so that
+//! the image isn't kept from other operations while clients send.
+
+use std::{
+    fs::{self, File},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::PathBuf,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+/// Distinguishes the spools of concurrent uploads of this process.
+static SPOOL_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// A temporary file in [`std::env::temp_dir`] holding what a client sent, removed once dropped.
+pub(crate) struct Spool {
+    path: PathBuf,
+    file: File,
+}
+
+impl Spool {
+    /// Creates an empty spool.
+    pub(crate) fn create() -> io::Result<Self> {
+        let path = std::env::temp_dir().join(format!(
+            "unftp-sbe-fatfs-{}-{}.upload",
+            std::process::id(),
+            SPOOL_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        Ok(Self { path, file })
+    }
+
+    /// Goes back to the start of what was spooled, to read it from there.
+    pub(crate) fn rewind(&mut self) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(0)).map(drop)
+    }
+}
+
+impl Write for Spool {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Read for Spool {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.file.read(buf)
+    }
+}
+
+impl Drop for Spool {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
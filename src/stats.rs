@@ -0,0 +1,227 @@
+//! Counts the downloads of each file, so operators can see which files are actually fetched,
+//! and times operations, so they can see when slow storage starts to hurt clients.
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::{
+        Mutex, PoisonError,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant, SystemTime},
+};
+
+/// The upper bounds of the buckets of [`LatencyHistogram`]s in microseconds, from 100 µs to 50 s.
+/// Operations that take longer go into a bucket of their own.
+pub(crate) const BOUNDS: [u64; 18] = [
+    100, 200, 500, 1_000, 2_000, 5_000, 10_000, 20_000, 50_000, 100_000, 200_000, 500_000,
+    1_000_000, 2_000_000, 5_000_000, 10_000_000, 20_000_000, 50_000_000,
+];
+
+/// How often a file, or all files together, were downloaded.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct DownloadStats {
+    /// The number of downloads started, including resumed ones and those that didn't finish.
+    pub downloads: u64,
+    /// The bytes sent to clients.
+    pub bytes_served: u64,
+    /// When a download last started, unless none did.
+    pub last_access: Option<SystemTime>,
+}
+
+/// The downloads a [`Vfs`](crate::Vfs) served, as returned by
+/// [`Vfs::stats`](crate::Vfs::stats).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Stats {
+    /// The downloads of all files together.
+    pub total: DownloadStats,
+    /// The downloads of each file that was downloaded, by its absolute path with `.` and `..`
+    /// resolved. Paths are as clients gave them otherwise, so a file downloaded with different
+    /// capitalizations shows up under each of them.
+    pub files: BTreeMap<PathBuf, DownloadStats>,
+}
+
+/// How long operations took, in buckets from 100 µs to 50 s that grow in steps of 1, 2 and 5.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LatencyHistogram {
+    /// The number of operations in each bucket, and in the one for those that took longer.
+    counts: Vec<u64>,
+    sum: Duration,
+    max: Duration,
+}
+
+impl LatencyHistogram {
+    /// Returns the number of operations timed.
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// Returns how long the operations took together.
+    pub fn sum(&self) -> Duration {
+        self.sum
+    }
+
+    /// Returns how long the slowest operation took.
+    pub fn max(&self) -> Duration {
+        self.max
+    }
+
+    /// Returns the upper bound of each bucket along with the number of operations that took at
+    /// most that long but longer than the bound before, ending with the bucket of those that took
+    /// longer than 50 s, which has no bound.
+    pub fn buckets(&self) -> impl Iterator<Item = (Option<Duration>, u64)> + '_ {
+        let bounds = BOUNDS
+            .iter()
+            .map(|&micros| Some(Duration::from_micros(micros)));
+        bounds
+            .chain([None])
+            .zip(self.counts.iter().copied().chain(std::iter::repeat(0)))
+    }
+
+    /// Returns the duration that the fraction `q` of operations took at most, such as `0.99` for
+    /// the 99th percentile, as the upper bound of the bucket it falls into, or `None` if no
+    /// operations were timed. The bucket of operations that took longer than 50 s is bounded by
+    /// the slowest of them.
+    pub fn quantile(&self, q: f64) -> Option<Duration> {
+        let count = self.count();
+        if count == 0 {
+            return None;
+        }
+        let rank = ((q.clamp(0.0, 1.0) * count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (bound, n) in self.buckets() {
+            seen += n;
+            if seen >= rank {
+                return Some(bound.map_or(self.max, |bound| bound.min(self.max)));
+            }
+        }
+        Some(self.max)
+    }
+}
+
+/// How long the operations of a [`Vfs`](crate::Vfs) took, as returned by
+/// [`Vfs::latencies`](crate::Vfs::latencies).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Latencies {
+    /// Opening the image and mounting its filesystem, which happens when the filesystem is first
+    /// used, when the image was replaced, and when downloads need a filesystem of their own.
+    pub open: LatencyHistogram,
+    /// Reading directories for listings.
+    pub list: LatencyHistogram,
+    /// Looking up files and directories for their metadata.
+    pub metadata: LatencyHistogram,
+    /// Finding files for downloads and reading their first chunk, the time until clients get
+    /// their first byte.
+    pub get: LatencyHistogram,
+}
+
+/// The operations timed for [`Latencies`].
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Timed {
+    Open,
+    List,
+    Metadata,
+    Get,
+}
+
+/// A [`LatencyHistogram`] that operations are added to as they complete.
+#[derive(Debug, Default)]
+struct Timings {
+    counts: [AtomicU64; BOUNDS.len() + 1],
+    sum_micros: AtomicU64,
+    max_micros: AtomicU64,
+}
+
+impl Timings {
+    fn add(&self, duration: Duration) {
+        let micros = u64::try_from(duration.as_micros()).unwrap_or(u64::MAX);
+        let bucket = BOUNDS.partition_point(|&bound| bound < micros);
+        self.counts[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add(micros, Ordering::Relaxed);
+        self.max_micros.fetch_max(micros, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> LatencyHistogram {
+        LatencyHistogram {
+            counts: self
+                .counts
+                .iter()
+                .map(|count| count.load(Ordering::Relaxed))
+                .collect(),
+            sum: Duration::from_micros(self.sum_micros.load(Ordering::Relaxed)),
+            max: Duration::from_micros(self.max_micros.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+/// Keeps the [`Stats`] and [`Latencies`] of a file system up to date.
+#[derive(Debug, Default)]
+pub(crate) struct Recorder {
+    stats: Mutex<Stats>,
+    timings: [Timings; 4],
+}
+
+impl Recorder {
+    /// Counts a download of the file at `path` that started now.
+    pub(crate) fn started(&self, path: &Path) {
+        let now = SystemTime::now();
+        self.update(path, |stats| {
+            stats.downloads += 1;
+            stats.last_access = Some(now);
+        });
+        #[cfg(feature = "metrics")]
+        crate::metrics::download_started();
+    }
+
+    /// Counts `bytes` sent from the file at `path`.
+    pub(crate) fn served(&self, path: &Path, bytes: u64) {
+        self.update(path, |stats| stats.bytes_served += bytes);
+        #[cfg(feature = "metrics")]
+        crate::metrics::served(bytes);
+    }
+
+    /// Adds an operation that started at `started` and completed now.
+    pub(crate) fn timed(&self, operation: Timed, started: Instant) {
+        let duration = started.elapsed();
+        self.timings[operation as usize].add(duration);
+        #[cfg(feature = "metrics")]
+        crate::metrics::timed(operation, duration);
+    }
+
+    /// Returns the latencies as they are now.
+    pub(crate) fn latencies(&self) -> Latencies {
+        let [open, list, metadata, get] = self.timings.each_ref().map(Timings::snapshot);
+        Latencies {
+            open,
+            list,
+            metadata,
+            get,
+        }
+    }
+
+    /// Returns the stats as they are now.
+    pub(crate) fn snapshot(&self) -> Stats {
+        self.stats
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    /// Applies `f` to the stats of the file at `path` and to the totals. Counters are only ever
+    /// added to, so a panic while they were locked leaves nothing to clean up.
+    fn update(&self, path: &Path, f: impl Fn(&mut DownloadStats)) {
+        let mut stats = self.stats.lock().unwrap_or_else(PoisonError::into_inner);
+        f(&mut stats.total);
+        match stats.files.get_mut(path) {
+            Some(file) => f(file),
+            None => {
+                let mut file = DownloadStats::default();
+                f(&mut file);
+                stats.files.insert(path.to_path_buf(), file);
+            }
+        }
+    }
+}
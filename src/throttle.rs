@@ -0,0 +1,113 @@
+//! Slows transfers down to a number of bytes per second.
+//!
+//! A rate limit keeps track of when the bytes transferred under it would have been transferred at
+//! its rate. Once a transfer gets ahead of that, it waits until the limit catches up before reading
+//! more. Limits can be shared by several transfers, which then split the rate between them.
+
+use std::{
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex, PoisonError},
+    task::{Context, Poll, ready},
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncRead, ReadBuf},
+    time::Sleep,
+};
+
+/// How long a rate limit may go unused and still have that time made up for by a burst.
+const BURST: Duration = Duration::from_millis(250);
+
+/// The least a throttled transfer reads at once, however low its rate.
+const MIN_READ: u64 = 512;
+
+/// A limit on the bytes per second of the transfers that share it.
+#[derive(Debug)]
+pub(crate) struct RateLimit {
+    bytes_per_second: u64,
+    /// When the bytes transferred so far would have been transferred at the limit's rate.
+    caught_up_at: Mutex<Instant>,
+}
+
+impl RateLimit {
+    pub(crate) fn new(bytes_per_second: u64) -> Self {
+        Self {
+            bytes_per_second: bytes_per_second.max(1),
+            caught_up_at: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Accounts for `n` bytes transferred, returning how long to wait before transferring more.
+    fn transferred(&self, n: usize) -> Duration {
+        let now = Instant::now();
+        let mut caught_up_at = self
+            .caught_up_at
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let from = (*caught_up_at).max(now.checked_sub(BURST).unwrap_or(now));
+        *caught_up_at = from + Duration::from_secs_f64(n as f64 / self.bytes_per_second as f64);
+        caught_up_at.saturating_duration_since(now)
+    }
+}
+
+/// An async reader that reads from `inner` no faster than all of its rate limits allow.
+pub(crate) struct Throttled<R> {
+    inner: R,
+    limits: Vec<Arc<RateLimit>>,
+    wait: Option<Pin<Box<Sleep>>>,
+}
+
+impl<R> Throttled<R> {
+    pub(crate) fn new(inner: R, limits: Vec<Arc<RateLimit>>) -> Self {
+        Self {
+            inner,
+            limits,
+            wait: None,
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for Throttled<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.limits.is_empty() {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        }
+        if let Some(wait) = &mut this.wait {
+            ready!(wait.as_mut().poll(cx));
+            this.wait = None;
+        }
+
+        // Reading a tenth of a second's worth at a time keeps the transfer from stalling between
+        // large reads
+        let max = this
+            .limits
+            .iter()
+            .map(|limit| (limit.bytes_per_second / 10).max(MIN_READ))
+            .min()
+            .unwrap_or(MIN_READ);
+        let max = buf
+            .remaining()
+            .min(usize::try_from(max).unwrap_or(usize::MAX));
+        let mut limited = ReadBuf::new(buf.initialize_unfilled_to(max));
+        ready!(Pin::new(&mut this.inner).poll_read(cx, &mut limited))?;
+        let n = limited.filled().len();
+        buf.advance(n);
+
+        let wait = this
+            .limits
+            .iter()
+            .map(|limit| limit.transferred(n))
+            .max()
+            .unwrap_or_default();
+        if !wait.is_zero() {
+            this.wait = Some(Box::pin(tokio::time::sleep(wait)));
+        }
+        Poll::Ready(Ok(()))
+    }
+}
@@ -0,0 +1,549 @@
+//! Local images read and written through io_uring on Linux.
+//!
+//! Reads and writes are submitted to a ring owned by the open image rather than made with a
+//! system call each. While a file is read sequentially, the read that follows is submitted along
+//! with each one, so that the kernel works on it while the data read so far is passed on to the
+//! client, and finding it completed later takes no system call at all.
+//!
+//! The ring is set up with the raw system calls, the layout of the shared structures is that of
+//! `<linux/io_uring.h>`.
+
+use std::{
+    fs::File,
+    io::{self, Read, Seek, SeekFrom, Write},
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    ptr::NonNull,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+/// Reads smaller than this are taken to be metadata rather than file data, and don't trigger
+/// reading ahead.
+const MIN_DATA_READ: usize = 512;
+
+/// The largest read made ahead.
+const MAX_READ_AHEAD: usize = 1024 * 1024;
+
+/// The number of submission queue entries. At most two requests are in flight at a time.
+const RING_ENTRIES: u32 = 4;
+
+const IORING_OFF_SQ_RING: libc::off_t = 0;
+const IORING_OFF_CQ_RING: libc::off_t = 0x8000000;
+const IORING_OFF_SQES: libc::off_t = 0x10000000;
+const IORING_FEAT_SINGLE_MMAP: u32 = 1;
+const IORING_ENTER_GETEVENTS: u32 = 1;
+const IORING_OP_READ: u8 = 22;
+const IORING_OP_WRITE: u8 = 23;
+
+/// Tags the completion of the request the caller waits for.
+const REQUEST: u64 = 0;
+/// Tags the completion of the read made ahead.
+const READ_AHEAD: u64 = 1;
+
+#[repr(C)]
+#[derive(Default)]
+struct SqRingOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    flags: u32,
+    dropped: u32,
+    array: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct CqRingOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    overflow: u32,
+    cqes: u32,
+    flags: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct Params {
+    sq_entries: u32,
+    cq_entries: u32,
+    flags: u32,
+    sq_thread_cpu: u32,
+    sq_thread_idle: u32,
+    features: u32,
+    wq_fd: u32,
+    resv: [u32; 3],
+    sq_off: SqRingOffsets,
+    cq_off: CqRingOffsets,
+}
+
+/// A submission queue entry.
+#[repr(C)]
+#[derive(Default)]
+struct Sqe {
+    opcode: u8,
+    flags: u8,
+    ioprio: u16,
+    fd: i32,
+    off: u64,
+    addr: u64,
+    len: u32,
+    rw_flags: u32,
+    user_data: u64,
+    buf_index: u16,
+    personality: u16,
+    splice_fd_in: i32,
+    addr3: u64,
+    pad: u64,
+}
+
+/// A completion queue entry.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Cqe {
+    user_data: u64,
+    res: i32,
+    flags: u32,
+}
+
+/// A memory mapping of one of the ring's shared structures.
+struct Mapping {
+    ptr: NonNull<u8>,
+    len: usize,
+}
+
+impl Mapping {
+    fn new(fd: &OwnedFd, len: usize, offset: libc::off_t) -> io::Result<Self> {
+        // SAFETY: Maps a fresh region shared with the kernel, nothing else refers to it yet.
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED | libc::MAP_POPULATE,
+                fd.as_raw_fd(),
+                offset,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self {
+            ptr: NonNull::new(ptr.cast()).expect("mmap succeeded"),
+            len,
+        })
+    }
+
+    /// Returns a pointer to the value of type `T` at `offset` in the mapping.
+    fn at<T>(&self, offset: u32) -> *mut T {
+        debug_assert!(offset as usize + size_of::<T>() <= self.len);
+        // SAFETY: The kernel reported the offset to be within the mapping.
+        unsafe { self.ptr.as_ptr().add(offset as usize).cast() }
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        // SAFETY: The region was mapped with this length and is no longer referred to.
+        unsafe { libc::munmap(self.ptr.as_ptr().cast(), self.len) };
+    }
+}
+
+/// An io_uring instance along with its submission and completion queues.
+struct Ring {
+    // Dropped after the mappings
+    sq: Mapping,
+    /// The completion queue, unless it shares the mapping of the submission queue.
+    cq: Option<Mapping>,
+    sqes: Mapping,
+    params: Params,
+    /// The number of entries queued, not counting those taken off the queue again.
+    queued: u64,
+    /// The number of entries submitted to the kernel.
+    submitted: u64,
+    fd: OwnedFd,
+}
+
+// SAFETY: The ring's memory is only accessed through `&mut self`, and the kernel doesn't care
+// which thread submits.
+unsafe impl Send for Ring {}
+
+impl Ring {
+    fn new(entries: u32) -> io::Result<Self> {
+        let mut params = Params::default();
+        // SAFETY: `params` is an `io_uring_params` that lives for the duration of the call.
+        let fd = unsafe { libc::syscall(libc::SYS_io_uring_setup, entries, &raw mut params) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: The system call returned a new file descriptor that nothing else owns.
+        let fd = unsafe { OwnedFd::from_raw_fd(fd as i32) };
+
+        let sq_len = params.sq_off.array as usize + params.sq_entries as usize * 4;
+        let cq_len = params.cq_off.cqes as usize + params.cq_entries as usize * size_of::<Cqe>();
+        let (sq, cq) = if params.features & IORING_FEAT_SINGLE_MMAP != 0 {
+            (
+                Mapping::new(&fd, sq_len.max(cq_len), IORING_OFF_SQ_RING)?,
+                None,
+            )
+        } else {
+            (
+                Mapping::new(&fd, sq_len, IORING_OFF_SQ_RING)?,
+                Some(Mapping::new(&fd, cq_len, IORING_OFF_CQ_RING)?),
+            )
+        };
+        let sqes = Mapping::new(
+            &fd,
+            params.sq_entries as usize * size_of::<Sqe>(),
+            IORING_OFF_SQES,
+        )?;
+        Ok(Self {
+            sq,
+            cq,
+            sqes,
+            params,
+            queued: 0,
+            submitted: 0,
+            fd,
+        })
+    }
+
+    fn cq(&self) -> &Mapping {
+        self.cq.as_ref().unwrap_or(&self.sq)
+    }
+
+    /// Queues `sqe` to be submitted with the next call to [`Ring::enter`], returning its sequence
+    /// number.
+    fn push(&mut self, sqe: Sqe) -> u64 {
+        let off = &self.params.sq_off;
+        // SAFETY: The offsets point at the queue's fields in the mapping, and no more entries
+        // are queued than the queue holds.
+        unsafe {
+            let tail = &*self.sq.at::<AtomicU32>(off.tail);
+            let mask = *self.sq.at::<u32>(off.ring_mask);
+            let index = tail.load(Ordering::Relaxed) & mask;
+            self.sqes.at::<Sqe>(0).add(index as usize).write(sqe);
+            self.sq
+                .at::<u32>(off.array)
+                .add(index as usize)
+                .write(index);
+            tail.fetch_add(1, Ordering::Release);
+        }
+        self.queued += 1;
+        self.queued - 1
+    }
+
+    /// Tells whether the entry with the given sequence number is still queued or was submitted,
+    /// rather than taken off the queue again because submitting failed.
+    fn is_queued(&self, seq: u64) -> bool {
+        seq < self.queued
+    }
+
+    /// Submits the queued entries and, if `wait` is set, waits for at least one completion.
+    fn enter(&mut self, wait: bool) -> io::Result<()> {
+        let (min_complete, flags) = if wait {
+            (1, IORING_ENTER_GETEVENTS)
+        } else {
+            (0, 0)
+        };
+        loop {
+            // SAFETY: The file descriptor is the ring's, and no signal mask is passed.
+            let submitted = unsafe {
+                libc::syscall(
+                    libc::SYS_io_uring_enter,
+                    self.fd.as_raw_fd(),
+                    (self.queued - self.submitted) as u32,
+                    min_complete,
+                    flags,
+                    std::ptr::null::<libc::sigset_t>(),
+                    0usize,
+                )
+            };
+            if submitted >= 0 {
+                self.submitted += submitted as u64;
+                return Ok(());
+            }
+            let e = io::Error::last_os_error();
+            match e.raw_os_error() {
+                Some(libc::EINTR | libc::EAGAIN | libc::EBUSY) => {}
+                _ => {
+                    self.discard_unsubmitted();
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    /// Takes the entries that weren't submitted off the queue again, so that the buffers they
+    /// refer to aren't written to after the caller gave up on them.
+    fn discard_unsubmitted(&mut self) {
+        // SAFETY: The offset points at the queue's tail, which only this side moves. Without
+        // a submission the kernel hasn't looked at entries beyond what it consumed.
+        let tail = unsafe { &*self.sq.at::<AtomicU32>(self.params.sq_off.tail) };
+        tail.fetch_sub((self.queued - self.submitted) as u32, Ordering::Release);
+        self.queued = self.submitted;
+    }
+
+    /// Takes the next completion off the queue, if there is one.
+    fn pop(&mut self) -> Option<Cqe> {
+        let off = &self.params.cq_off;
+        let cq = self.cq();
+        // SAFETY: The offsets point at the queue's fields in the mapping, and the kernel has
+        // filled the entries between head and tail.
+        unsafe {
+            let head = &*cq.at::<AtomicU32>(off.head);
+            let tail = &*cq.at::<AtomicU32>(off.tail);
+            let mask = *cq.at::<u32>(off.ring_mask);
+            let at = head.load(Ordering::Relaxed);
+            if at == tail.load(Ordering::Acquire) {
+                return None;
+            }
+            let cqe = cq.at::<Cqe>(off.cqes).add((at & mask) as usize).read();
+            head.store(at.wrapping_add(1), Ordering::Release);
+            Some(cqe)
+        }
+    }
+}
+
+/// A read submitted ahead of the reads fatfs makes.
+struct ReadAhead {
+    offset: u64,
+    /// The sequence number of the read in the ring.
+    seq: u64,
+    /// The buffer read into, which the kernel writes to until the read completed.
+    buf: Vec<u8>,
+    /// The number of bytes read, once the read completed, or the error it failed with.
+    result: Option<io::Result<usize>>,
+}
+
+/// A seekable stream over a local image that is read and written through io_uring.
+pub(crate) struct UringDisk {
+    ring: Ring,
+    file: File,
+    len: u64,
+    pos: u64,
+    ahead: Option<ReadAhead>,
+    /// Where the last data read ended, which is where a sequential read continues.
+    data_end: u64,
+}
+
+impl UringDisk {
+    /// Sets up a ring for `file`, or returns the file back if the kernel doesn't offer io_uring,
+    /// which includes kernels that have it disabled and sandboxes that block it.
+    pub(crate) fn new(mut file: File) -> Result<Self, File> {
+        let Ok(ring) = Ring::new(RING_ENTRIES) else {
+            return Err(file);
+        };
+        // Block devices report a size of zero in their metadata, seeking finds their real size
+        let Ok(len) = file.seek(SeekFrom::End(0)) else {
+            return Err(file);
+        };
+        Ok(Self {
+            ring,
+            file,
+            len,
+            pos: 0,
+            ahead: None,
+            data_end: u64::MAX,
+        })
+    }
+
+    /// Queues a read or write of `len` bytes at `addr` from or to `offset`, returning its
+    /// sequence number.
+    fn push(
+        &mut self,
+        opcode: u8,
+        addr: *const u8,
+        len: usize,
+        offset: u64,
+        user_data: u64,
+    ) -> u64 {
+        self.ring.push(Sqe {
+            opcode,
+            fd: self.file.as_raw_fd(),
+            off: offset,
+            addr: addr as u64,
+            len: len.min(i32::MAX as usize) as u32,
+            user_data,
+            ..Sqe::default()
+        })
+    }
+
+    /// Queues a read of the `len` bytes at `offset` into a buffer of its own.
+    fn push_read_ahead(&mut self, offset: u64, len: usize) {
+        let len = len.min((self.len - offset) as usize);
+        let ahead = self.ahead.insert(ReadAhead {
+            offset,
+            seq: 0,
+            buf: vec![0; len],
+            result: None,
+        });
+        let addr = ahead.buf.as_mut_ptr();
+        let seq = self.push(IORING_OP_READ, addr, len, offset, READ_AHEAD);
+        if let Some(ahead) = &mut self.ahead {
+            ahead.seq = seq;
+        }
+    }
+
+    /// Submits what was queued and, if `wait` is set, waits for a completion.
+    fn enter(&mut self, wait: bool) -> io::Result<()> {
+        let result = self.ring.enter(wait);
+        // A read ahead that couldn't be submitted won't complete
+        if let Some(ahead) = &self.ahead
+            && ahead.result.is_none()
+            && !self.ring.is_queued(ahead.seq)
+        {
+            self.ahead = None;
+        }
+        result
+    }
+
+    /// Submits what was queued and waits for the completion tagged `user_data`, recording the
+    /// completion of the read ahead along the way.
+    fn complete(&mut self, user_data: u64) -> io::Result<usize> {
+        loop {
+            while let Some(cqe) = self.ring.pop() {
+                let result = if cqe.res < 0 {
+                    Err(io::Error::from_raw_os_error(-cqe.res))
+                } else {
+                    Ok(cqe.res as usize)
+                };
+                if cqe.user_data == READ_AHEAD {
+                    if let Some(ahead) = &mut self.ahead {
+                        ahead.result = Some(result);
+                    }
+                    if user_data == READ_AHEAD {
+                        return Ok(0);
+                    }
+                } else if user_data == REQUEST {
+                    return result;
+                }
+            }
+            self.enter(true)?;
+        }
+    }
+
+    /// Waits for the read ahead, if one is in flight.
+    fn finish_read_ahead(&mut self) -> io::Result<()> {
+        if self
+            .ahead
+            .as_ref()
+            .is_some_and(|ahead| ahead.result.is_none())
+        {
+            self.complete(READ_AHEAD)?;
+        }
+        Ok(())
+    }
+
+    /// Serves a read from the data read ahead if that covers the current position, returning
+    /// the number of bytes read.
+    fn read_from_ahead(&mut self, buf: &mut [u8]) -> io::Result<Option<usize>> {
+        let covers = |ahead: &ReadAhead, pos: u64| {
+            (ahead.offset..ahead.offset + ahead.buf.len() as u64).contains(&pos)
+        };
+        if !self
+            .ahead
+            .as_ref()
+            .is_some_and(|ahead| covers(ahead, self.pos))
+        {
+            return Ok(None);
+        }
+        self.finish_read_ahead()?;
+        let ahead = self.ahead.as_ref().expect("covers the position");
+        let filled = match &ahead.result {
+            Some(Ok(filled)) => *filled,
+            // Retried by a read of its own, which reports the error if it persists
+            _ => return Ok(None),
+        };
+        let offset = (self.pos - ahead.offset) as usize;
+        if offset >= filled {
+            return Ok(None);
+        }
+        let n = (filled - offset).min(buf.len());
+        buf[..n].copy_from_slice(&ahead.buf[offset..offset + n]);
+
+        // Keep the next read in flight while this one is used up
+        let end = ahead.offset + filled as u64;
+        if self.pos + n as u64 == end && end < self.len {
+            self.push_read_ahead(end, ahead.buf.len());
+            self.enter(false)?;
+        }
+        Ok(Some(n))
+    }
+}
+
+impl Drop for UringDisk {
+    fn drop(&mut self) {
+        // The kernel may still write to the buffer read into
+        if self.finish_read_ahead().is_err() {
+            // Leak the buffer rather than free memory the kernel might write to
+            if let Some(ahead) = self.ahead.take() {
+                std::mem::forget(ahead.buf);
+            }
+        }
+    }
+}
+
+impl Read for UringDisk {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let max = self.len.saturating_sub(self.pos).min(buf.len() as u64) as usize;
+        if max == 0 {
+            return Ok(0);
+        }
+        let is_data = max >= MIN_DATA_READ;
+        let sequential = is_data && self.pos == self.data_end;
+        let n = match self.read_from_ahead(&mut buf[..max])? {
+            Some(n) => n,
+            None => {
+                let end = self.pos + max as u64;
+                self.push(IORING_OP_READ, buf.as_mut_ptr(), max, self.pos, REQUEST);
+                // Read what follows a sequential read along with it, unless a read ahead is
+                // underway already
+                let in_flight = self.ahead.as_ref().is_some_and(|a| a.result.is_none());
+                if sequential && end < self.len && !in_flight {
+                    self.push_read_ahead(end, max.min(MAX_READ_AHEAD));
+                }
+                self.complete(REQUEST)?
+            }
+        };
+        self.pos += n as u64;
+        if is_data {
+            self.data_end = self.pos;
+        }
+        Ok(n)
+    }
+}
+
+impl Write for UringDisk {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // What was read ahead may be outdated by the write
+        self.finish_read_ahead()?;
+        self.ahead = None;
+        self.push(IORING_OP_WRITE, buf.as_ptr(), buf.len(), self.pos, REQUEST);
+        let n = self.complete(REQUEST)?;
+        self.pos += n as u64;
+        self.len = self.len.max(self.pos);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for UringDisk {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.pos = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+        }
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "seek to a negative offset"))?;
+        Ok(self.pos)
+    }
+}
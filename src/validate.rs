@@ -0,0 +1,597 @@
+//! Checks FAT filesystems the way fsck.fat does, walking the whole directory tree and the FAT,
+//! as returned by [`Vfs::validate`](crate::Vfs::validate).
+
+use crate::{
+    CorruptionPolicy,
+    raw::Raw,
+    volume::{FatEntry, FatVolume},
+};
+use fatfs::{Date, Time};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    error::Error as _,
+    fmt,
+    ops::Range,
+    path::{Path, PathBuf},
+};
+use unftp_core::storage::Result;
+
+/// The characters that no name on FAT filesystems holds, besides control characters.
+const INVALID_CHARS: &str = "\"*/:<>?\\|";
+
+/// The characters that short names don't hold on top of those, besides lowercase letters.
+const INVALID_SHORT_CHARS: &[u8] = b"+,;=[]";
+
+/// What [`Vfs::validate`](crate::Vfs::validate) found wrong with a FAT filesystem.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// use unftp_sbe_fatfs::Vfs;
+///
+/// # async fn run() -> Result<(), Box<dyn std::error::Error>> {
+/// let report = Vfs::new("path/to/fat/image.img").validate().await?;
+/// if !report.is_clean() {
+///     eprintln!("{report}");
+///     std::process::exit(1);
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+#[non_exhaustive]
+pub struct ValidationReport {
+    /// The runs of clusters the FAT marks as in use that no file or directory leads to, which
+    /// were likely lost when a system went away while writing.
+    pub orphaned_clusters: Vec<Range<u32>>,
+    /// The files and directories whose cluster chains run into each other, so that at least one
+    /// of them holds what was written to the other.
+    pub cross_links: Vec<CrossLink>,
+    /// The entries whose names FAT doesn't allow.
+    pub invalid_names: Vec<Finding>,
+    /// The entries whose creation, modification or access dates or times don't exist.
+    pub bad_timestamps: Vec<Finding>,
+    /// The files and directories whose cluster chains loop, end elsewhere than at the end of a
+    /// chain, or hold fewer or more clusters than their sizes take.
+    pub broken_chains: Vec<Finding>,
+}
+
+impl ValidationReport {
+    /// Returns whether nothing was found wrong.
+    pub fn is_clean(&self) -> bool {
+        self.orphaned_clusters.is_empty()
+            && self.cross_links.is_empty()
+            && self.invalid_names.is_empty()
+            && self.bad_timestamps.is_empty()
+            && self.broken_chains.is_empty()
+    }
+}
+
+/// Tells what was found wrong, a line each.
+impl fmt::Display for ValidationReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_clean() {
+            return f.write_str("no problems found");
+        }
+        let mut lines = Vec::new();
+        for clusters in &self.orphaned_clusters {
+            lines.push(match clusters.len() {
+                1 => format!("cluster {} is orphaned", clusters.start),
+                _ => format!(
+                    "clusters {} to {} are orphaned",
+                    clusters.start,
+                    clusters.end - 1
+                ),
+            });
+        }
+        lines.extend(self.cross_links.iter().map(ToString::to_string));
+        let findings = [
+            &self.invalid_names,
+            &self.bad_timestamps,
+            &self.broken_chains,
+        ];
+        lines.extend(findings.into_iter().flatten().map(ToString::to_string));
+        f.write_str(&lines.join("\n"))
+    }
+}
+
+/// Something found wrong with the file or directory at a path.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Finding {
+    /// The path of the file or directory.
+    pub path: PathBuf,
+    /// What is wrong with it.
+    pub problem: String,
+}
+
+impl Finding {
+    fn new(path: &Path, problem: String) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            problem,
+        }
+    }
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.path.display(), self.problem)
+    }
+}
+
+/// Two files or directories whose cluster chains run into each other.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct CrossLink {
+    /// The paths of the file or directory whose chain was walked first, and of the other.
+    pub paths: [PathBuf; 2],
+    /// The first cluster both chains hold.
+    pub first_cluster: u32,
+    /// How many clusters both chains hold.
+    pub clusters: u64,
+}
+
+impl fmt::Display for CrossLink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} and {} are cross-linked, sharing {} clusters from cluster {} on",
+            self.paths[0].display(),
+            self.paths[1].display(),
+            self.clusters,
+            self.first_cluster
+        )
+    }
+}
+
+/// The owner of clusters no file or directory leads to, and of fixes that leave cluster chains
+/// alone.
+const NO_OWNER: u32 = u32::MAX;
+
+/// The offsets within directory entries of the creation time, with the hundredths of a second
+/// kept apart, and of the creation date, access date, modification time and modification date.
+const CREATED_TIME: u64 = 14;
+const CREATED_HUNDREDTHS: u64 = 13;
+const CREATED_DATE: u64 = 16;
+const ACCESSED_DATE: u64 = 18;
+const MODIFIED_TIME: u64 = 22;
+const MODIFIED_DATE: u64 = 24;
+
+/// The offset within directory entries of the size of files.
+const SIZE: u64 = 28;
+
+/// 1980-01-01, the earliest date FAT can keep, as it keeps it.
+const EPOCH: u16 = 1 << 5 | 1;
+
+/// A change to the image that fixes something found wrong with it, as
+/// [`Vfs::repair`](crate::Vfs::repair) makes them.
+pub(crate) enum Fix {
+    /// Sets the entry of a cluster in the FAT.
+    Link(u32, u32),
+    /// Writes bytes to the image at an offset.
+    Write(u64, Vec<u8>),
+}
+
+/// Walks the directory tree of a filesystem, claiming the clusters of every file and directory.
+struct Walk<'a> {
+    raw: &'a Raw,
+    /// The file or directory that leads to each cluster, as an index into `paths`.
+    owners: Vec<u32>,
+    paths: Vec<PathBuf>,
+    /// The offsets of the entries of the owners, `None` for the root directory.
+    offsets: Vec<Option<u64>>,
+    /// The first cluster and the number of clusters each pair of owners share.
+    cross_links: BTreeMap<(u32, u32), (u32, u64)>,
+    report: ValidationReport,
+    /// The fixes for what was found, along with the file or directory whose chain they change.
+    fixes: Vec<(u32, Fix)>,
+    /// Whether a directory couldn't be read to the end, so that the clusters of the entries
+    /// after that weren't claimed.
+    incomplete: bool,
+}
+
+/// The cluster chain of a file or directory, once claimed.
+struct Claimed {
+    /// The clusters it holds, up to where it loops back on itself.
+    chain: Vec<u32>,
+    /// Whether it loops back on itself.
+    looped: bool,
+    /// Whether no other file or directory led to its first cluster.
+    first_claimed: bool,
+}
+
+/// What [`check`] found.
+pub(crate) struct Checked {
+    pub(crate) report: ValidationReport,
+    pub(crate) fixes: Vec<Fix>,
+    /// The path of the file or directory each cross-linked entry, by its offset, shares
+    /// clusters with, the first one found if there are several.
+    pub(crate) cross_linked: HashMap<u64, PathBuf>,
+    /// Whether a directory couldn't be read to the end, in which case the clusters reported as
+    /// orphaned may belong to the entries that weren't read.
+    pub(crate) incomplete: bool,
+}
+
+/// Checks the filesystem of `volume`, walking its directory tree and its FAT.
+///
+/// # Errors
+///
+/// Fails if the image can't be read.
+pub(crate) fn validate(volume: &FatVolume) -> Result<ValidationReport> {
+    check(volume).map(|checked| checked.report)
+}
+
+/// Checks the filesystem of `volume` like [`validate`], also returning the fixes for what was
+/// found wrong, but for orphaned clusters, which are freed unless [`Checked::incomplete`], and
+/// cross-linked files and directories and invalid names, which are left as they are. Chains are
+/// only fixed where they aren't cross-linked, as the fix of one could cut short the other.
+///
+/// # Errors
+///
+/// Fails if the image can't be read.
+pub(crate) fn check(volume: &FatVolume) -> Result<Checked> {
+    let raw = volume.raw();
+    let mut walk = Walk {
+        raw,
+        owners: vec![NO_OWNER; raw.end_cluster() as usize],
+        paths: vec![PathBuf::from("/")],
+        offsets: vec![None],
+        cross_links: BTreeMap::new(),
+        report: ValidationReport::default(),
+        fixes: Vec::new(),
+        incomplete: false,
+    };
+
+    let root_looped = match raw.root_cluster() {
+        Some(cluster) => walk.claim(0, cluster, None)?.looped,
+        None => false,
+    };
+    let mut dirs: Vec<(PathBuf, Option<FatEntry<'_>>, bool)> =
+        vec![(PathBuf::from("/"), None, root_looped)];
+    while let Some((path, dir, looped)) = dirs.pop() {
+        // Lists directories that loop up to the loop, which was found when claiming them
+        for entry in volume.read_dir(&path, dir.as_ref(), CorruptionPolicy::Lenient)? {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(_) if looped => break,
+                Err(e) => {
+                    let cause = e
+                        .source()
+                        .map_or_else(|| e.to_string(), ToString::to_string);
+                    let problem = format!("can't be read any further: {cause}");
+                    walk.report.broken_chains.push(Finding::new(&path, problem));
+                    walk.incomplete = true;
+                    break;
+                }
+            };
+            if matches!(entry.short_file_name_as_bytes(), b"." | b"..") {
+                continue;
+            }
+
+            let entry_path = path.join(entry.file_name());
+            walk.check_name(&entry_path, &entry);
+            walk.check_timestamps(&entry_path, &entry);
+            let offset = entry.offset();
+            let owner = walk.paths.len() as u32;
+            walk.paths.push(entry_path.clone());
+            walk.offsets.push(offset);
+            let claimed = match entry.cluster() {
+                Some(first) => {
+                    let file = offset.filter(|_| !entry.is_dir());
+                    Some(walk.claim(owner, first, file)?)
+                }
+                None => None,
+            };
+
+            if entry.is_dir() {
+                match claimed {
+                    // A directory that others lead to as well was or will be walked through them
+                    Some(claimed) if claimed.first_claimed => {
+                        dirs.push((entry_path, Some(entry), claimed.looped));
+                    }
+                    Some(_) => {}
+                    None => walk.broken(&entry_path, "is a directory without clusters".into()),
+                }
+                continue;
+            }
+            let cluster_len = raw.cluster_len();
+            let needed = entry.len().div_ceil(cluster_len);
+            let chain = claimed.as_ref().map_or(&[][..], |claimed| &claimed.chain);
+            let held = chain.len() as u64;
+            if let Some(offset) = offset {
+                walk.fit(owner, offset, chain, needed);
+            }
+            // The loop was found wrong with the file already
+            if claimed.is_some_and(|claimed| claimed.looped) {
+                continue;
+            }
+            if held < needed {
+                let problem = format!(
+                    "claims to be {} bytes long but its cluster chain holds {} bytes",
+                    entry.len(),
+                    held * cluster_len
+                );
+                walk.broken(&entry_path, problem);
+            } else if held > needed {
+                let problem = format!(
+                    "its cluster chain goes on for {} clusters past its {} bytes",
+                    held - needed,
+                    entry.len()
+                );
+                walk.broken(&entry_path, problem);
+            }
+        }
+    }
+
+    let Walk {
+        owners,
+        paths,
+        offsets,
+        cross_links,
+        mut report,
+        fixes,
+        incomplete,
+        ..
+    } = walk;
+    raw.for_each_link(|cluster, link| {
+        if link == 0 || raw.is_bad(link) || owners[cluster as usize] != NO_OWNER {
+            return;
+        }
+        match report.orphaned_clusters.last_mut() {
+            Some(run) if run.end == cluster => run.end += 1,
+            _ => report.orphaned_clusters.push(cluster..cluster + 1),
+        }
+    })?;
+    let mut cross_linked = HashMap::new();
+    let mut crossed = HashSet::new();
+    for &(a, b) in cross_links.keys() {
+        for (owner, other) in [(a, b), (b, a)] {
+            crossed.insert(owner);
+            if let Some(offset) = offsets[owner as usize] {
+                let other = &paths[other as usize];
+                cross_linked.entry(offset).or_insert_with(|| other.clone());
+            }
+        }
+    }
+    report.cross_links = cross_links
+        .into_iter()
+        .map(|((first, other), (first_cluster, clusters))| CrossLink {
+            paths: [paths[first as usize].clone(), paths[other as usize].clone()],
+            first_cluster,
+            clusters,
+        })
+        .collect();
+    let fixes = fixes
+        .into_iter()
+        .filter(|(owner, _)| !crossed.contains(owner))
+        .map(|(_, fix)| fix)
+        .collect();
+    Ok(Checked {
+        report,
+        fixes,
+        cross_linked,
+        incomplete,
+    })
+}
+
+impl Walk<'_> {
+    fn broken(&mut self, path: &Path, problem: String) {
+        self.report.broken_chains.push(Finding::new(path, problem));
+    }
+
+    /// Claims the clusters of the chain that starts at `first` for `owner`, whose entry is at
+    /// `entry` if it is a file, noting where other files or directories claimed them before, and
+    /// checks that the chain ends properly.
+    fn claim(&mut self, owner: u32, first: u32, entry: Option<u64>) -> Result<Claimed> {
+        let path = self.paths[owner as usize].clone();
+        if !self.raw.is_cluster(first) {
+            let problem = format!("its first cluster {first} is outside the filesystem");
+            self.broken(&path, problem);
+            if let Some(entry) = entry {
+                self.detach(owner, entry);
+            }
+            return Ok(Claimed {
+                chain: Vec::new(),
+                looped: false,
+                first_claimed: false,
+            });
+        }
+
+        let first_claimed = self.owners[first as usize] == NO_OWNER;
+        let (chain, looped) = self.raw.follow(&path, first, u64::MAX)?;
+        for &cluster in &chain {
+            match self.owners[cluster as usize] {
+                NO_OWNER => self.owners[cluster as usize] = owner,
+                other if other != owner => {
+                    let shared = self.cross_links.entry((other, owner));
+                    shared.or_insert((cluster, 0)).1 += 1;
+                }
+                _ => {}
+            }
+        }
+
+        // Chains are only followed to clusters of the filesystem, so they end at the last one
+        let last = chain[chain.len() - 1];
+        let link = self.raw.link(last)?;
+        if looped.is_some() {
+            self.broken(
+                &path,
+                format!("its cluster chain loops back to cluster {link}"),
+            );
+        } else if !self.raw.is_end(link) {
+            let problem = match link {
+                0 => format!("its cluster chain leads from cluster {last} to a free cluster"),
+                _ if self.raw.is_bad(link) => {
+                    format!("its cluster chain leads from cluster {last} to a bad cluster")
+                }
+                _ => format!(
+                    "its cluster chain leads from cluster {last} to cluster {link}, which is \
+                     outside the filesystem"
+                ),
+            };
+            self.broken(&path, problem);
+        }
+        if looped.is_some() || !self.raw.is_end(link) {
+            let end = self.raw.end_link();
+            self.fixes.push((owner, Fix::Link(last, end)));
+        }
+        Ok(Claimed {
+            chain,
+            looped: looped.is_some(),
+            first_claimed,
+        })
+    }
+
+    /// Fixes the file `owner`, whose entry is at `entry` and whose cluster chain is `chain`, to
+    /// take `needed` clusters: sets its size to what its chain holds if that's less, or cuts the
+    /// chain short and frees the rest if it holds more.
+    fn fit(&mut self, owner: u32, entry: u64, chain: &[u32], needed: u64) {
+        let held = chain.len() as u64;
+        if held < needed {
+            let len = (held * self.raw.cluster_len()) as u32;
+            let fix = Fix::Write(entry + SIZE, len.to_le_bytes().to_vec());
+            self.fixes.push((owner, fix));
+        } else if held > needed {
+            let needed = needed as usize;
+            match needed.checked_sub(1) {
+                Some(last) => {
+                    let end = self.raw.end_link();
+                    self.fixes.push((owner, Fix::Link(chain[last], end)));
+                }
+                None => self.detach(owner, entry),
+            }
+            let freed = chain[needed..]
+                .iter()
+                .map(|&cluster| (owner, Fix::Link(cluster, 0)));
+            self.fixes.extend(freed);
+        }
+    }
+
+    /// Leaves the file `owner`, whose entry is at `entry`, empty and without clusters.
+    fn detach(&mut self, owner: u32, entry: u64) {
+        self.fixes.push((owner, Fix::Write(entry + 20, vec![0; 2])));
+        self.fixes.push((owner, Fix::Write(entry + 26, vec![0; 6])));
+    }
+
+    /// Checks that the name of `entry`, the entry at `path`, is one FAT allows: its long name if
+    /// it has one, and its short name.
+    fn check_name(&mut self, path: &Path, entry: &FatEntry<'_>) {
+        let name = entry.file_name();
+        let problem = if name.is_empty() {
+            Some("has no name".to_string())
+        } else if let Some(c) = name
+            .chars()
+            .find(|&c| c.is_control() || INVALID_CHARS.contains(c))
+        {
+            Some(format!("its name holds {c:?}, which names can't"))
+        } else {
+            short_name_problem(entry.short_file_name_as_bytes())
+        };
+        if let Some(problem) = problem {
+            self.report.invalid_names.push(Finding::new(path, problem));
+        }
+    }
+
+    /// Checks that the dates and times of `entry`, the entry at `path`, exist, leaving out
+    /// those that were never set.
+    fn check_timestamps(&mut self, path: &Path, entry: &FatEntry<'_>) {
+        let (created, modified) = (entry.created(), entry.modified());
+        let timestamps = [
+            (
+                "creation",
+                created.date,
+                CREATED_DATE,
+                Some((created.time, CREATED_TIME)),
+            ),
+            (
+                "modification",
+                modified.date,
+                MODIFIED_DATE,
+                Some((modified.time, MODIFIED_TIME)),
+            ),
+            ("access", entry.accessed(), ACCESSED_DATE, None),
+        ];
+        for (what, date, date_field, time) in timestamps {
+            if is_unset(date) && time.is_none_or(|(time, _)| time.hour + time.min == 0) {
+                continue;
+            }
+            if !date_exists(date) {
+                let problem = format!(
+                    "its {what} date {}-{:02}-{:02} doesn't exist",
+                    date.year, date.month, date.day
+                );
+                self.report.bad_timestamps.push(Finding::new(path, problem));
+                if let Some(offset) = entry.offset() {
+                    let fix = Fix::Write(offset + date_field, EPOCH.to_le_bytes().to_vec());
+                    self.fixes.push((NO_OWNER, fix));
+                }
+            }
+            if let Some((time, time_field)) = time.filter(|&(time, _)| !time_exists(time)) {
+                let problem = format!(
+                    "its {what} time {:02}:{:02}:{:02} doesn't exist",
+                    time.hour, time.min, time.sec
+                );
+                self.report.bad_timestamps.push(Finding::new(path, problem));
+                if let Some(offset) = entry.offset() {
+                    self.fixes
+                        .push((NO_OWNER, Fix::Write(offset + time_field, vec![0; 2])));
+                    if time_field == CREATED_TIME {
+                        let fix = Fix::Write(offset + CREATED_HUNDREDTHS, vec![0]);
+                        self.fixes.push((NO_OWNER, fix));
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Tells what is wrong with `name`, a short name as fatfs gives it with a dot before its
+/// extension, if anything.
+fn short_name_problem(name: &[u8]) -> Option<String> {
+    let (base, extension) = match name.iter().position(|&b| b == b'.') {
+        Some(dot) => (&name[..dot], &name[dot + 1..]),
+        None => (name, &[][..]),
+    };
+    if base.is_empty() || base.len() > 8 || extension.len() > 3 {
+        return Some(format!(
+            "its short name {:?} doesn't fit 8.3",
+            String::from_utf8_lossy(name)
+        ));
+    }
+    let invalid = base.iter().chain(extension).find(|&&b| {
+        b < 0x20
+            || b.is_ascii_lowercase()
+            || INVALID_CHARS.as_bytes().contains(&b)
+            || INVALID_SHORT_CHARS.contains(&b)
+    })?;
+    Some(format!(
+        "its short name {:?} holds {:?}, which short names can't",
+        String::from_utf8_lossy(name),
+        char::from(*invalid)
+    ))
+}
+
+/// Whether `date` is the date of entries that never had it set, which is stored as zero.
+fn is_unset(date: Date) -> bool {
+    date.year == 1980 && date.month == 0 && date.day == 0
+}
+
+fn date_exists(date: Date) -> bool {
+    let days = match date.month {
+        1 | 3 | 5 | 7 | 8 | 10 | 12 => 31,
+        4 | 6 | 9 | 11 => 30,
+        2 if date.year.is_multiple_of(4)
+            && (!date.year.is_multiple_of(100) || date.year.is_multiple_of(400)) =>
+        {
+            29
+        }
+        2 => 28,
+        _ => return false,
+    };
+    (1..=days).contains(&date.day)
+}
+
+fn time_exists(time: Time) -> bool {
+    time.hour < 24 && time.min < 60 && time.sec < 60 && time.millis < 1000
+}
@@ -0,0 +1,237 @@
+//! Virtual disks in the VHD and VHDX formats used by Hyper-V and Azure.
+//!
+//! Fixed VHDs are a raw disk followed by a footer, so they need no special handling. Dynamic VHDs
+//! and VHDX images allocate the disk in large blocks, which are looked up in a block allocation
+//! table (BAT). Differencing disks, which need a parent disk, aren't supported.
+
+use crate::{
+    Guid,
+    block_map::{Block, BlockMap},
+    format::{invalid_data, unsupported},
+    image::Disk,
+};
+use std::io::{self, SeekFrom};
+
+/// The cookie that starts a VHD footer, a copy of which starts dynamic VHDs.
+const VHD_COOKIE: &[u8; 8] = b"conectix";
+
+/// The signature that starts VHDX images.
+const VHDX_SIGNATURE: &[u8; 8] = b"vhdxfile";
+
+/// Identifies the BAT region of a VHDX image.
+const VHDX_BAT_REGION: &str = "2DC27766-F623-4200-9D64-115E9BFD4A08";
+
+/// Identifies the metadata region of a VHDX image.
+const VHDX_METADATA_REGION: &str = "8B7CA206-4790-4B9A-B8FE-575F050F886E";
+
+/// Identifies the metadata item that holds the block size and whether the disk has a parent.
+const VHDX_FILE_PARAMETERS: &str = "CAA16737-FA36-4D43-B3B6-33F0AA44E76B";
+
+/// Identifies the metadata item that holds the size of the virtual disk.
+const VHDX_VIRTUAL_DISK_SIZE: &str = "2FA54224-CD1B-4876-B211-5DBED83BF4B8";
+
+/// Identifies the metadata item that holds the sector size of the virtual disk.
+const VHDX_LOGICAL_SECTOR_SIZE: &str = "8141BF1D-A96F-4709-BA47-F233A8FAAB5F";
+
+/// Opens the dynamic VHD on `disk`.
+pub(crate) fn open_vhd(mut disk: Box<dyn Disk>) -> io::Result<BlockMap> {
+    let mut footer = [0u8; 512];
+    disk.seek(SeekFrom::Start(0))?;
+    disk.read_exact(&mut footer)?;
+    if &footer[..8] != VHD_COOKIE {
+        return Err(invalid_data("not a dynamic VHD image"));
+    }
+    match be_u32(&footer, 60) {
+        3 => {}
+        4 => return Err(unsupported("differencing VHD images aren't supported")),
+        disk_type => {
+            return Err(invalid_data(format!(
+                "unexpected VHD disk type {disk_type}"
+            )));
+        }
+    }
+    let len = be_u64(&footer, 48);
+
+    let mut header = [0u8; 1024];
+    disk.seek(SeekFrom::Start(be_u64(&footer, 16)))?;
+    disk.read_exact(&mut header)?;
+    if &header[..8] != b"cxsparse" {
+        return Err(invalid_data("the VHD dynamic disk header is missing"));
+    }
+    let table_offset = be_u64(&header, 16);
+    let entries = be_u32(&header, 28) as usize;
+    let block_size = u64::from(be_u32(&header, 32));
+    if !block_size.is_power_of_two() || block_size < 512 {
+        return Err(invalid_data("the VHD block size is invalid"));
+    }
+    if (entries as u64) * block_size < len {
+        return Err(invalid_data("the VHD block allocation table is too small"));
+    }
+
+    let table = read_region(&mut *disk, table_offset, entries * 4, "the VHD BAT")?;
+    // Every block starts with a bitmap of the sectors in it, padded to a whole sector
+    let bitmap_len = (block_size / 512).div_ceil(8).next_multiple_of(512);
+    let blocks = (0..entries)
+        .map(|i| match be_u32(&table, i * 4) {
+            u32::MAX => Block::Zero,
+            sector => Block::Stored(u64::from(sector) * 512 + bitmap_len),
+        })
+        .collect();
+    Ok(BlockMap::new(disk, block_size, len, blocks))
+}
+
+/// Opens the VHDX image on `disk`.
+pub(crate) fn open_vhdx(mut disk: Box<dyn Disk>) -> io::Result<BlockMap> {
+    let mut signature = [0u8; 8];
+    disk.seek(SeekFrom::Start(0))?;
+    disk.read_exact(&mut signature)?;
+    if &signature != VHDX_SIGNATURE {
+        return Err(invalid_data("not a VHDX image"));
+    }
+
+    // There are two copies of the header, the current one has the higher sequence number
+    let mut header = None;
+    for offset in [64 * 1024, 128 * 1024] {
+        let mut candidate = vec![0u8; 4096];
+        disk.seek(SeekFrom::Start(offset))?;
+        disk.read_exact(&mut candidate)?;
+        if &candidate[..4] == b"head"
+            && header
+                .as_ref()
+                .is_none_or(|h: &Vec<u8>| le_u64(&candidate, 8) > le_u64(h, 8))
+        {
+            header = Some(candidate);
+        }
+    }
+    let header = header.ok_or_else(|| invalid_data("the VHDX header is missing"))?;
+    if header[48..64].iter().any(|&b| b != 0) {
+        return Err(unsupported(
+            "the VHDX image has a log that needs replaying, open it in Hyper-V first",
+        ));
+    }
+
+    let mut regions = vec![0u8; 64 * 1024];
+    disk.seek(SeekFrom::Start(192 * 1024))?;
+    disk.read_exact(&mut regions)?;
+    if &regions[..4] != b"regi" {
+        return Err(invalid_data("the VHDX region table is missing"));
+    }
+    let (mut bat, mut metadata) = (None, None);
+    for i in 0..(le_u32(&regions, 8) as usize).min(2047) {
+        let entry = &regions[16 + i * 32..48 + i * 32];
+        let region = (le_u64(entry, 16), le_u32(entry, 24) as usize);
+        match guid(entry).as_str() {
+            VHDX_BAT_REGION => bat = Some(region),
+            VHDX_METADATA_REGION => metadata = Some(region),
+            other if le_u32(entry, 28) & 1 != 0 => {
+                return Err(unsupported(format!(
+                    "the VHDX image requires the unknown region {other}"
+                )));
+            }
+            _ => {}
+        }
+    }
+    let (bat_offset, bat_len) = bat.ok_or_else(|| invalid_data("the VHDX BAT is missing"))?;
+    let (metadata_offset, metadata_len) =
+        metadata.ok_or_else(|| invalid_data("the VHDX metadata is missing"))?;
+
+    let metadata = read_region(
+        &mut *disk,
+        metadata_offset,
+        metadata_len,
+        "the VHDX metadata",
+    )?;
+    if metadata.get(..8) != Some(b"metadata") {
+        return Err(invalid_data("the VHDX metadata table is missing"));
+    }
+    let (mut block_size, mut len, mut sector_size) = (None, None, None);
+    for i in 0..(le_u16(&metadata, 10) as usize).min(2047) {
+        let entry = metadata
+            .get(32 + i * 32..64 + i * 32)
+            .ok_or_else(|| invalid_data("the VHDX metadata table is truncated"))?;
+        let offset = le_u32(entry, 16) as usize;
+        let item = metadata
+            .get(offset..offset + 8)
+            .ok_or_else(|| invalid_data("a VHDX metadata item is out of bounds"))?;
+        match guid(entry).as_str() {
+            VHDX_FILE_PARAMETERS => {
+                if le_u32(item, 4) & 2 != 0 {
+                    return Err(unsupported("differencing VHDX images aren't supported"));
+                }
+                block_size = Some(u64::from(le_u32(item, 0)));
+            }
+            VHDX_VIRTUAL_DISK_SIZE => len = Some(le_u64(item, 0)),
+            VHDX_LOGICAL_SECTOR_SIZE => sector_size = Some(u64::from(le_u32(item, 0))),
+            _ => {}
+        }
+    }
+    let (Some(block_size), Some(len), Some(sector_size)) = (block_size, len, sector_size) else {
+        return Err(invalid_data("the VHDX metadata is incomplete"));
+    };
+    if !block_size.is_power_of_two() || block_size < 1024 * 1024 || sector_size == 0 {
+        return Err(invalid_data("the VHDX block or sector size is invalid"));
+    }
+
+    let table = read_region(&mut *disk, bat_offset, bat_len, "the VHDX BAT")?;
+    // Each run of this many payload block entries is followed by a sector bitmap block entry
+    let chunk_ratio = ((1 << 23) * sector_size / block_size).max(1) as usize;
+    let blocks = (0..len.div_ceil(block_size) as usize)
+        .map(|i| {
+            let index = i + i / chunk_ratio;
+            let entry = table
+                .get(index * 8..index * 8 + 8)
+                .map(|entry| le_u64(entry, 0))
+                .ok_or_else(|| invalid_data("the VHDX BAT is too small"))?;
+            // The low bits hold the state of the block and the high ones its offset in MiB
+            match entry & 7 {
+                6 => Ok(Block::Stored(entry & !0xf_ffff)),
+                7 => Err(unsupported("differencing VHDX images aren't supported")),
+                _ => Ok(Block::Zero),
+            }
+        })
+        .collect::<io::Result<_>>()?;
+    Ok(BlockMap::new(disk, block_size, len, blocks))
+}
+
+/// Reads the `len` bytes at `offset` that the header of the image points to as `what`, failing
+/// rather than allocating them if they would end beyond the end of `disk`.
+fn read_region(disk: &mut dyn Disk, offset: u64, len: usize, what: &str) -> io::Result<Vec<u8>> {
+    let disk_len = disk.seek(SeekFrom::End(0))?;
+    if offset
+        .checked_add(len as u64)
+        .is_none_or(|end| end > disk_len)
+    {
+        return Err(invalid_data(format!(
+            "{what} lies beyond the end of the image"
+        )));
+    }
+    let mut region = vec![0u8; len];
+    disk.seek(SeekFrom::Start(offset))?;
+    disk.read_exact(&mut region)?;
+    Ok(region)
+}
+
+/// Formats the GUID that starts `bytes`.
+fn guid(bytes: &[u8]) -> String {
+    Guid::from_bytes(bytes[..16].try_into().expect("16 bytes")).to_string()
+}
+
+fn be_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(bytes[offset..offset + 4].try_into().expect("4 bytes"))
+}
+
+fn be_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_be_bytes(bytes[offset..offset + 8].try_into().expect("8 bytes"))
+}
+
+fn le_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(bytes[offset..offset + 2].try_into().expect("2 bytes"))
+}
+
+fn le_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().expect("4 bytes"))
+}
+
+fn le_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().expect("8 bytes"))
+}
@@ -0,0 +1,289 @@
+//! Virtual disks in VMware's VMDK format.
+//!
+//! Monolithic sparse disks, including the stream-optimized ones VMware exports, keep the disk in
+//! grains that are looked up through a grain directory and grain tables, and are read in place.
+//! Flat disks are a small text descriptor next to a raw extent file, which is found relative to
+//! the descriptor and so only for images on the local filesystem.
+
+use crate::{
+    format::{invalid_data, read_up_to, unsupported},
+    image::{Disk, Image, ReadOnly, Slice},
+};
+use flate2::read::ZlibDecoder;
+use std::{
+    fs::File,
+    io::{self, Read, Seek, SeekFrom},
+};
+
+/// The magic bytes sparse extents start with.
+const SPARSE_MAGIC: &[u8; 4] = b"KDMV";
+
+/// Marks sparse extents whose grains are compressed.
+const COMPRESSED_GRAINS: u32 = 1 << 16;
+
+/// The grain directory offset of stream-optimized extents, whose real header is in the footer.
+const GD_AT_END: u64 = u64::MAX;
+
+/// The grain table entry of grains that read as zeros.
+const ZERO_GRAIN: u32 = 1;
+
+/// The largest descriptor file that is read.
+const MAX_DESCRIPTOR: usize = 64 * 1024;
+
+/// The largest grain that is read, well beyond the 64 KiB VMware writes, so that a corrupt header
+/// can't have a compressed grain take up all memory.
+const MAX_GRAIN_SIZE: u64 = 16 * 1024 * 1024;
+
+/// The number of grain tables kept in memory.
+const CACHED_TABLES: usize = 16;
+
+/// A seekable stream over the disk in a sparse extent.
+pub(crate) struct SparseVmdk {
+    inner: Box<dyn Disk>,
+    /// The size of a grain in bytes.
+    grain_size: u64,
+    /// The number of entries in each grain table.
+    table_len: u64,
+    compressed: bool,
+    /// The size of the disk.
+    len: u64,
+    /// The sector of each grain table, or zero if none of its grains were written.
+    directory: Vec<u32>,
+    /// Grain tables by their sector, most recently used last.
+    tables: Vec<(u32, Vec<u32>)>,
+    /// The last compressed grain read, by its sector.
+    grain: Option<(u32, Vec<u8>)>,
+    pos: u64,
+}
+
+impl SparseVmdk {
+    /// Reads the header and the grain directory of the sparse extent on `inner`.
+    pub(crate) fn open(mut inner: Box<dyn Disk>) -> io::Result<Self> {
+        let mut header = read_header(&mut *inner, SeekFrom::Start(0))?;
+        if u64_at(&header, 56) == GD_AT_END {
+            // Stream-optimized extents are written in one go, so the header is repeated in a
+            // footer once the grain directory is known
+            header = read_header(&mut *inner, SeekFrom::End(-1024))?;
+        }
+        let version = u32_at(&header, 4);
+        if !(1..=3).contains(&version) {
+            return Err(unsupported(format!(
+                "VMDK sparse extent version {version} isn't supported"
+            )));
+        }
+        let flags = u32_at(&header, 8);
+        let compressed = flags & COMPRESSED_GRAINS != 0;
+        if compressed && u16::from_le_bytes([header[77], header[78]]) != 1 {
+            return Err(unsupported("the VMDK grain compression isn't supported"));
+        }
+
+        let len = u64_at(&header, 12)
+            .checked_mul(512)
+            .ok_or_else(|| invalid_data("the VMDK capacity is invalid"))?;
+        let grain_size = u64_at(&header, 20)
+            .checked_mul(512)
+            .filter(|size| size.is_power_of_two() && (4096..=MAX_GRAIN_SIZE).contains(size))
+            .ok_or_else(|| invalid_data("the VMDK grain size is invalid"))?;
+        let table_len = u64::from(u32_at(&header, 44));
+        let table_span = grain_size
+            .checked_mul(table_len)
+            .filter(|&span| span != 0)
+            .ok_or_else(|| invalid_data("the VMDK grain table size is invalid"))?;
+        let directory_len = usize::try_from(len.div_ceil(table_span))
+            .map_err(|_| invalid_data("the VMDK grain directory is too large"))?;
+        let directory = read_table(&mut *inner, u64_at(&header, 56), directory_len)?;
+
+        Ok(Self {
+            inner,
+            grain_size,
+            table_len,
+            compressed,
+            len,
+            directory,
+            tables: Vec::new(),
+            grain: None,
+            pos: 0,
+        })
+    }
+
+    /// Returns the grain table entry that describes the grain with the given index.
+    fn entry(&mut self, grain: u64) -> io::Result<u32> {
+        let table_sector = self.directory[(grain / self.table_len) as usize];
+        if table_sector == 0 {
+            return Ok(0);
+        }
+        let index = (grain % self.table_len) as usize;
+
+        let position = match self.tables.iter().position(|(s, _)| *s == table_sector) {
+            Some(position) => {
+                let table = self.tables.remove(position);
+                self.tables.push(table);
+                self.tables.len() - 1
+            }
+            None => {
+                let table = read_table(
+                    &mut *self.inner,
+                    u64::from(table_sector),
+                    self.table_len as usize,
+                )?;
+                if self.tables.len() == CACHED_TABLES {
+                    self.tables.remove(0);
+                }
+                self.tables.push((table_sector, table));
+                self.tables.len() - 1
+            }
+        };
+        Ok(self.tables[position].1[index])
+    }
+
+    /// Decompresses the compressed grain stored at the given sector.
+    fn compressed_grain(&mut self, sector: u32) -> io::Result<&[u8]> {
+        if self.grain.as_ref().is_none_or(|(s, _)| *s != sector) {
+            // Each compressed grain is preceded by the sector it belongs at and its size
+            let mut marker = [0u8; 12];
+            self.inner.seek(SeekFrom::Start(u64::from(sector) * 512))?;
+            self.inner.read_exact(&mut marker)?;
+            let compressed_len = u32_at(&marker, 8);
+
+            let mut grain = vec![0u8; self.grain_size as usize];
+            let mut decoder = ZlibDecoder::new((&mut self.inner).take(u64::from(compressed_len)));
+            // The last grain may hold less than a full grain
+            read_up_to(&mut decoder, &mut grain)
+                .map_err(|_| invalid_data("a compressed VMDK grain is corrupt"))?;
+            self.grain = Some((sector, grain));
+        }
+        Ok(&self.grain.as_ref().expect("just decompressed").1)
+    }
+}
+
+impl Read for SparseVmdk {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let in_grain = self.pos % self.grain_size;
+        let n = (self.grain_size - in_grain)
+            .min(self.len.saturating_sub(self.pos))
+            .min(buf.len() as u64) as usize;
+        if n == 0 {
+            return Ok(0);
+        }
+
+        let sector = self.entry(self.pos / self.grain_size)?;
+        if sector == 0 || sector == ZERO_GRAIN {
+            buf[..n].fill(0);
+        } else if self.compressed {
+            let grain = self.compressed_grain(sector)?;
+            let start = in_grain as usize;
+            buf[..n].copy_from_slice(&grain[start..start + n]);
+        } else {
+            self.inner
+                .seek(SeekFrom::Start(u64::from(sector) * 512 + in_grain))?;
+            self.inner.read_exact(&mut buf[..n])?;
+        }
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for SparseVmdk {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.pos = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+        }
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "seek to a negative offset"))?;
+        Ok(self.pos)
+    }
+}
+
+/// Opens the disk described by the descriptor file on `disk`, which must consist of a single
+/// flat or sparse extent next to `image`.
+pub(crate) fn open_descriptor(image: &Image, mut disk: Box<dyn Disk>) -> io::Result<Box<dyn Disk>> {
+    let mut descriptor = vec![0u8; MAX_DESCRIPTOR];
+    let n = read_up_to(&mut *disk, &mut descriptor)?;
+    let descriptor = String::from_utf8_lossy(&descriptor[..n]);
+
+    // Extent lines look like `RW 2097152 FLAT "disk-flat.vmdk" 0`
+    let extents: Vec<Vec<&str>> = descriptor
+        .lines()
+        .map(|line| line.split_whitespace().collect::<Vec<_>>())
+        .filter(|words| matches!(words.first(), Some(&("RW" | "RDONLY" | "NOACCESS"))))
+        .collect();
+    let [extent] = &extents[..] else {
+        return Err(unsupported(format!(
+            "VMDK disks with {} extents aren't supported",
+            extents.len()
+        )));
+    };
+    let (Some(sectors), Some(kind), Some(name)) = (
+        extent.get(1).and_then(|s| s.parse::<u64>().ok()),
+        extent.get(2),
+        extent
+            .get(3)
+            .and_then(|s| s.strip_prefix('"')?.strip_suffix('"')),
+    ) else {
+        return Err(invalid_data("a VMDK extent line is malformed"));
+    };
+
+    let Some(dir) = image.path().and_then(|path| path.parent()) else {
+        return Err(unsupported(
+            "the extents of VMDK descriptor files are only found next to local images",
+        ));
+    };
+    let file = Box::new(ReadOnly(File::open(dir.join(name))?));
+    match *kind {
+        "FLAT" => {
+            let offset = extent
+                .get(4)
+                .and_then(|s| s.parse::<u64>().ok())
+                .unwrap_or(0);
+            Ok(Box::new(Slice::new(file, offset * 512, sectors * 512)?))
+        }
+        "SPARSE" => Ok(Box::new(ReadOnly(SparseVmdk::open(file)?))),
+        kind => Err(unsupported(format!(
+            "VMDK extents of type {kind} aren't supported"
+        ))),
+    }
+}
+
+/// Reads the sparse extent header at `pos`.
+fn read_header(disk: &mut dyn Disk, pos: SeekFrom) -> io::Result<[u8; 512]> {
+    let mut header = [0u8; 512];
+    disk.seek(pos)?;
+    disk.read_exact(&mut header)?;
+    if &header[..4] != SPARSE_MAGIC {
+        return Err(invalid_data("not a VMDK sparse extent"));
+    }
+    Ok(header)
+}
+
+/// Reads a table of `len` little-endian 32-bit entries at the given sector, failing rather than
+/// allocating it if it would end beyond the end of `disk`.
+fn read_table(disk: &mut dyn Disk, sector: u64, len: usize) -> io::Result<Vec<u32>> {
+    let disk_len = disk.seek(SeekFrom::End(0))?;
+    let offset = sector.checked_mul(512);
+    let size = (len as u64).checked_mul(4);
+    if offset
+        .zip(size)
+        .and_then(|(offset, size)| offset.checked_add(size))
+        .is_none_or(|end| end > disk_len)
+    {
+        return Err(invalid_data(
+            "a VMDK grain table lies beyond the end of the extent",
+        ));
+    }
+    let mut bytes = vec![0u8; len * 4];
+    disk.seek(SeekFrom::Start(sector * 512))?;
+    disk.read_exact(&mut bytes)?;
+    Ok(bytes
+        .chunks_exact(4)
+        .map(|entry| u32::from_le_bytes(entry.try_into().expect("4 bytes")))
+        .collect())
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().expect("4 bytes"))
+}
+
+fn u64_at(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().expect("8 bytes"))
+}
@@ -0,0 +1,247 @@
+//! FAT12, FAT16 and FAT32 filesystems as mounted by fatfs, whose directories are listed along
+//! with the first clusters of their entries, which fatfs keeps to itself.
+//!
+//! Crafted or damaged images can chain the clusters of a directory or a file in a loop, give a
+//! directory an entry that leads back to it, or claim files to be larger than their chains.
+//! Directories are listed and files read only once their chains turn out to hold them without
+//! looping, and lookups keep track of the directories they pass through, so that such images
+//! fail with an error rather than keep fatfs going in circles or serve files cut short.
+
+use crate::{
+    BootSectorInfo, CorruptionPolicy, FatError, Fs, error,
+    image::Disk,
+    raw::{FatMismatch, Raw, RawEntry, SharedDisk},
+};
+use fatfs::{DirEntry, DirIter, FileSystem, FsOptions};
+use std::{
+    io,
+    ops::Deref,
+    path::{Path, PathBuf},
+    vec,
+};
+use unftp_core::storage::Result;
+
+/// A FAT filesystem mounted by fatfs, along with a stream that reads what fatfs keeps to itself
+/// from the same image.
+pub(crate) struct FatVolume {
+    fs: Fs,
+    raw: Raw,
+}
+
+impl FatVolume {
+    /// Mounts the filesystem on `disk`.
+    pub(crate) fn mount(disk: Box<dyn Disk>, options: FsOptions) -> io::Result<Self> {
+        let disk = SharedDisk::new(disk);
+        let fs = FileSystem::new(Box::new(disk.clone()) as Box<dyn Disk>, options)?;
+        let raw = Raw::new(disk, fs.fat_type())?;
+        Ok(Self { fs, raw })
+    }
+
+    /// Lists the directory `dir` at `path`, or the root directory if `None`.
+    ///
+    /// With [`CorruptionPolicy::Lenient`], a directory whose cluster chain loops back on itself
+    /// is listed up to the loop, after which the listing ends with the error of the loop.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`FatError::CorruptFat`](crate::FatError::CorruptFat) if the cluster chain of
+    /// the directory loops back on itself, which fatfs would list forever, unless lenient, or
+    /// with [`CorruptionPolicy::Paranoid`] if a long name doesn't belong to its entry.
+    pub(crate) fn read_dir<'a>(
+        &'a self,
+        path: &Path,
+        dir: Option<&FatEntry<'a>>,
+        policy: CorruptionPolicy,
+    ) -> Result<Listing<'a>> {
+        let (entries, cluster) = match dir {
+            Some(dir) => (dir.entry.to_dir().iter(), dir.cluster),
+            None => (self.fs.root_dir().iter(), None),
+        };
+        let (raw_entries, looped) = self.raw.entries(path, cluster, policy)?;
+        Ok(Listing {
+            path: path.to_path_buf(),
+            entries,
+            raw_entries: raw_entries.into_iter(),
+            looped,
+            ended: false,
+        })
+    }
+
+    /// Checks that the file `entry` at `path` can be read up to the size its entry claims: that
+    /// its cluster chain holds that many bytes, without looping back on itself, which fatfs would
+    /// read over and over again up to that size. fatfs ends reads where the chain ends instead,
+    /// which would serve the file cut short. Returns how many bytes of the file can be read.
+    ///
+    /// With [`CorruptionPolicy::Lenient`] that's as many as the chain holds up to where it ends
+    /// or loops, while [`CorruptionPolicy::Paranoid`] also checks that the chain doesn't go on
+    /// past the end of the file.
+    pub(crate) fn check_file(
+        &self,
+        path: &Path,
+        entry: &FatEntry<'_>,
+        policy: CorruptionPolicy,
+    ) -> Result<u64> {
+        let cluster_len = self.raw.cluster_len();
+        let needed = entry.len().div_ceil(cluster_len);
+        if policy == CorruptionPolicy::Lenient {
+            let held = match entry.cluster {
+                Some(first) => self.raw.follow(path, first, needed)?.0.len() as u64,
+                None => 0,
+            };
+            return Ok(entry.len().min(held * cluster_len));
+        }
+        // One cluster more tells whether the chain goes on past the end of the file
+        let max = match policy {
+            CorruptionPolicy::Paranoid => needed + 1,
+            _ => needed,
+        };
+        let held = match entry.cluster {
+            Some(first) => self.raw.chain(path, first, max)?.len() as u64,
+            None => 0,
+        };
+        if held < needed {
+            return Err(FatError::CorruptFat(format!(
+                "{} claims to be {} bytes long but its cluster chain holds {} bytes",
+                path.display(),
+                entry.len(),
+                held * cluster_len
+            ))
+            .into());
+        }
+        if held > needed {
+            return Err(FatError::CorruptFat(format!(
+                "the cluster chain of {} goes on past its {} bytes",
+                path.display(),
+                entry.len()
+            ))
+            .into());
+        }
+        Ok(entry.len())
+    }
+
+    /// Returns the geometry of the filesystem as its boot sector tells it.
+    pub(crate) fn boot_sector_info(&self) -> io::Result<BootSectorInfo> {
+        let sector = self.raw.boot_sector()?;
+        Ok(BootSectorInfo::fat(&sector, self.fs.fat_type()))
+    }
+
+    /// Returns the volume label, as kept in the root directory, or in the boot sector if the root
+    /// directory doesn't keep one. `None` if neither does, or the boot sector says `NO NAME`.
+    pub(crate) fn volume_label(&self) -> Result<Option<String>> {
+        // fatfs would look for the label forever in a root directory whose chain loops
+        let root = Path::new("/");
+        let (_, looped) = (self.raw).entries(root, None, CorruptionPolicy::Lenient)?;
+        let label = match looped {
+            None => self
+                .fs
+                .read_volume_label_from_root_dir()
+                .map_err(|e| error::dir_error(root, e))?,
+            Some(_) => None,
+        };
+        let label = label.unwrap_or_else(|| self.fs.volume_label());
+        Ok(Some(label).filter(|label| !label.is_empty() && label != "NO NAME"))
+    }
+
+    /// Returns the stream that reads what fatfs keeps to itself.
+    pub(crate) fn raw(&self) -> &Raw {
+        &self.raw
+    }
+
+    /// Unmounts the filesystem, which writes the count of free clusters fatfs keeps to the FSInfo
+    /// sector if it changed, and returns the stream that reads what fatfs keeps to itself.
+    pub(crate) fn unmount(self) -> io::Result<Raw> {
+        self.fs.unmount()?;
+        Ok(self.raw)
+    }
+
+    /// Returns the entries that differ between the first FAT and its copies, up to `max` of
+    /// them, along with how many there are in all.
+    pub(crate) fn fat_mismatches(&self, max: usize) -> io::Result<(u64, Vec<FatMismatch>)> {
+        self.raw.fat_mismatches(max)
+    }
+
+    /// Returns the first cluster of the directory `dir`, or of the root directory if `None`,
+    /// which tells directories apart. That's `None` for the root directory of FAT12 and FAT16,
+    /// which isn't kept in clusters.
+    pub(crate) fn dir_cluster(&self, dir: Option<&FatEntry<'_>>) -> Option<u32> {
+        // fatfs takes directories without a first cluster for the root directory too
+        match dir.and_then(|dir| dir.cluster) {
+            Some(cluster) => Some(cluster),
+            None => self.raw.root_cluster(),
+        }
+    }
+}
+
+impl Deref for FatVolume {
+    type Target = Fs;
+
+    fn deref(&self) -> &Fs {
+        &self.fs
+    }
+}
+
+/// An entry of a directory as fatfs lists it, along with its first cluster and where it is.
+pub(crate) struct FatEntry<'a> {
+    entry: DirEntry<'a, Box<dyn Disk>>,
+    cluster: Option<u32>,
+    offset: Option<u64>,
+}
+
+impl FatEntry<'_> {
+    /// Returns the first cluster of the entry, or `None` if it has none.
+    pub(crate) fn cluster(&self) -> Option<u32> {
+        self.cluster
+    }
+
+    /// Returns the offset of the entry in the image, or `None` if it wasn't found there, which
+    /// fatfs would only list if the directory changed while being listed.
+    pub(crate) fn offset(&self) -> Option<u64> {
+        self.offset
+    }
+}
+
+impl<'a> Deref for FatEntry<'a> {
+    type Target = DirEntry<'a, Box<dyn Disk>>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.entry
+    }
+}
+
+/// The entries of a directory, as returned by [`FatVolume::read_dir`].
+pub(crate) struct Listing<'a> {
+    path: PathBuf,
+    entries: DirIter<'a, Box<dyn Disk>>,
+    /// The first clusters and offsets of the entries, which come in the same order.
+    raw_entries: vec::IntoIter<RawEntry>,
+    /// The error of the loop the cluster chain of the directory runs into after the entries.
+    looped: Option<FatError>,
+    ended: bool,
+}
+
+impl<'a> Iterator for Listing<'a> {
+    type Item = Result<FatEntry<'a>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.ended {
+            return None;
+        }
+        let raw_entry = self.raw_entries.next();
+        if raw_entry.is_none()
+            && let Some(e) = self.looped.take()
+        {
+            // fatfs would carry on into the loop
+            self.ended = true;
+            return Some(Err(e.into()));
+        }
+        let entry = match self.entries.next()? {
+            Ok(entry) => entry,
+            Err(e) => return Some(Err(error::dir_error(&self.path, e))),
+        };
+        Some(Ok(FatEntry {
+            entry,
+            cluster: raw_entry.and_then(|raw_entry| raw_entry.cluster),
+            offset: raw_entry.map(|raw_entry| raw_entry.offset),
+        }))
+    }
+}
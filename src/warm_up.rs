@@ -0,0 +1,72 @@
+//! Reads the parts of a filesystem that practically every operation needs, ahead of time.
+//!
+//! Mounting reads the boot sector and listing or opening anything walks the root directory and
+//! follows cluster chains through the FAT. For remote images each of those is a round trip the
+//! first client would otherwise wait for.
+
+use crate::image::Disk;
+use std::io::{self, SeekFrom};
+
+/// The file system name exFAT boot sectors carry at offset 3.
+const EXFAT_SIGNATURE: &[u8; 8] = b"EXFAT   ";
+
+/// The size of the reads the metadata is streamed in.
+const CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Reads the boot sector and the first FAT of the FAT or exFAT volume on `disk`, leaving it
+/// positioned at the start.
+///
+/// Boot sectors that don't make sense are left for mounting to report.
+pub(crate) fn prefetch_metadata(disk: &mut dyn Disk) -> io::Result<()> {
+    let mut boot = [0u8; 512];
+    disk.seek(SeekFrom::Start(0))?;
+    match disk.read_exact(&mut boot) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+        Err(e) => return Err(e),
+    }
+
+    let mut remaining = metadata_len(&boot);
+    let mut chunk = vec![0u8; CHUNK_SIZE];
+    disk.seek(SeekFrom::Start(0))?;
+    while remaining > 0 {
+        let len = remaining.min(CHUNK_SIZE as u64) as usize;
+        match disk.read(&mut chunk[..len])? {
+            0 => break,
+            n => remaining -= n as u64,
+        }
+    }
+    disk.seek(SeekFrom::Start(0))?;
+    Ok(())
+}
+
+/// Returns the number of bytes from the start of the volume to the end of its first FAT, or 0 if
+/// `boot` isn't a recognizable boot sector.
+fn metadata_len(boot: &[u8; 512]) -> u64 {
+    let u16_at = |offset: usize| u64::from(u16::from_le_bytes([boot[offset], boot[offset + 1]]));
+    let u32_at = |offset: usize| {
+        u64::from(u32::from_le_bytes(
+            boot[offset..offset + 4].try_into().expect("4 bytes"),
+        ))
+    };
+
+    if &boot[3..11] == EXFAT_SIGNATURE {
+        // Offset and length of the FAT in sectors, and the sector size as a power of two
+        let shift = u32::from(boot[108]);
+        if !(9..=12).contains(&shift) {
+            return 0;
+        }
+        return (u32_at(80) + u32_at(84)) << shift;
+    }
+
+    let bytes_per_sector = u16_at(11);
+    let reserved_sectors = u16_at(14);
+    let sectors_per_fat = match u16_at(22) {
+        0 => u32_at(36),
+        n => n,
+    };
+    if !bytes_per_sector.is_power_of_two() || !(512..=4096).contains(&bytes_per_sector) {
+        return 0;
+    }
+    (reserved_sectors + sectors_per_fat) * bytes_per_sector
+}
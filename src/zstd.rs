@@ -0,0 +1,243 @@
+//! Images compressed with zstd.
+//!
+//! Images in the [seekable format] are split into independently compressed frames and end with a
+//! table of them, so they can be read at arbitrary offsets by decompressing just the frames
+//! involved. Other zstd-compressed images have to be decompressed from the start.
+//!
+//! [seekable format]: https://github.com/facebook/zstd/blob/dev/contrib/seekable_format/zstd_seekable_compression_format.md
+
+use crate::{format::invalid_data, image::Disk, memory::Reservation};
+use ruzstd::decoding::{
+    BlockDecodingStrategy, FrameDecoder,
+    errors::{FrameDecoderError, ReadFrameHeaderError},
+};
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader, Read, Seek, SeekFrom},
+};
+
+/// The magic number at the very end of images in the seekable format.
+const SEEKABLE_MAGIC: u32 = 0x8F92_EAB1;
+
+/// The magic number of the skippable frame that holds the seek table.
+const SEEK_TABLE_MAGIC: u32 = 0x184D_2A5E;
+
+/// The size of the footer that ends the seek table.
+const FOOTER_SIZE: u64 = 9;
+
+/// The number of decompressed frames kept in memory.
+const CACHED_FRAMES: usize = 4;
+
+/// Tells whether the zstd-compressed image on `disk` is in the seekable format.
+pub(crate) fn is_seekable(disk: &mut dyn Disk) -> io::Result<bool> {
+    let found = match read_footer(disk) {
+        Ok(footer) => footer.is_some(),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => false,
+        Err(e) => return Err(e),
+    };
+    disk.seek(SeekFrom::Start(0))?;
+    Ok(found)
+}
+
+/// Reads the footer of the seek table, returning the number of frames and whether each has a
+/// checksum, or `None` if the image isn't in the seekable format.
+fn read_footer(disk: &mut dyn Disk) -> io::Result<Option<(u32, bool)>> {
+    let mut footer = [0u8; FOOTER_SIZE as usize];
+    disk.seek(SeekFrom::End(-(FOOTER_SIZE as i64)))?;
+    disk.read_exact(&mut footer)?;
+    let magic = u32::from_le_bytes(footer[5..9].try_into().expect("4 bytes"));
+    // The reserved bits of the descriptor must be zero
+    let descriptor = footer[4];
+    if magic != SEEKABLE_MAGIC || descriptor & 0x7c != 0 {
+        return Ok(None);
+    }
+    let frames = u32::from_le_bytes(footer[..4].try_into().expect("4 bytes"));
+    Ok(Some((frames, descriptor & 0x80 != 0)))
+}
+
+/// Decompresses an image that isn't in the seekable format, which may consist of several frames.
+pub(crate) fn expand(disk: Box<dyn Disk>, out: &mut File) -> io::Result<()> {
+    let mut input = BufReader::new(disk);
+    let mut decoder = FrameDecoder::new();
+    while !input.fill_buf()?.is_empty() {
+        match decoder.reset(&mut input) {
+            Ok(()) => {}
+            Err(FrameDecoderError::ReadFrameHeaderError(ReadFrameHeaderError::SkipFrame {
+                length,
+                ..
+            })) => {
+                io::copy(&mut (&mut input).take(u64::from(length)), &mut io::sink())?;
+                continue;
+            }
+            Err(e) => return Err(invalid_data(e)),
+        }
+        while !decoder.is_finished() {
+            decoder
+                .decode_blocks(&mut input, BlockDecodingStrategy::UptoBytes(1024 * 1024))
+                .map_err(invalid_data)?;
+            decoder.collect_to_writer(&mut *out)?;
+        }
+        decoder.collect_to_writer(&mut *out)?;
+    }
+    Ok(())
+}
+
+/// Where a frame of a seekable image is stored and which part of the image it holds.
+#[derive(Debug, Clone, Copy)]
+struct Frame {
+    /// The offset of the compressed frame in the file.
+    compressed_offset: u64,
+    compressed_len: u32,
+    /// The offset in the image of the first byte the frame decompresses to.
+    offset: u64,
+    len: u32,
+}
+
+/// A seekable stream over an image in the seekable zstd format.
+pub(crate) struct SeekableZstd {
+    inner: Box<dyn Disk>,
+    frames: Vec<Frame>,
+    len: u64,
+    pos: u64,
+    decoder: FrameDecoder,
+    /// Decompressed frames by index, most recently used last.
+    cached: Vec<(usize, Vec<u8>)>,
+    /// The memory the cached frames take at most, if reserved.
+    _memory: Reservation,
+}
+
+impl SeekableZstd {
+    /// Reads the seek table of the image on `inner`.
+    pub(crate) fn open(mut inner: Box<dyn Disk>) -> io::Result<Self> {
+        let (count, checksums) = read_footer(&mut *inner)?
+            .ok_or_else(|| invalid_data("the zstd image has no seek table"))?;
+        let entry_size = if checksums { 12 } else { 8 };
+        let table_len = u64::from(count) * entry_size;
+
+        // The table is preceded by the header of the skippable frame it is stored in
+        let mut header = [0u8; 8];
+        let table_start = inner
+            .seek(SeekFrom::End(-((FOOTER_SIZE + table_len + 8) as i64)))
+            .map_err(|_| invalid_data("the zstd seek table is truncated"))?;
+        inner.read_exact(&mut header)?;
+        let magic = u32::from_le_bytes(header[..4].try_into().expect("4 bytes"));
+        let frame_size = u32::from_le_bytes(header[4..].try_into().expect("4 bytes"));
+        if magic != SEEK_TABLE_MAGIC || u64::from(frame_size) != table_len + FOOTER_SIZE {
+            return Err(invalid_data("the zstd seek table is malformed"));
+        }
+
+        let mut table = vec![0u8; table_len as usize];
+        inner.read_exact(&mut table)?;
+        let mut frames = Vec::with_capacity(count as usize);
+        let (mut compressed_offset, mut offset) = (0, 0);
+        for entry in table.chunks(entry_size as usize) {
+            let compressed_len = u32::from_le_bytes(entry[..4].try_into().expect("4 bytes"));
+            let len = u32::from_le_bytes(entry[4..8].try_into().expect("4 bytes"));
+            frames.push(Frame {
+                compressed_offset,
+                compressed_len,
+                offset,
+                len,
+            });
+            compressed_offset += u64::from(compressed_len);
+            offset += u64::from(len);
+        }
+        if compressed_offset > table_start {
+            return Err(invalid_data("the zstd seek table doesn't match the frames"));
+        }
+
+        Ok(Self {
+            inner,
+            frames,
+            len: offset,
+            pos: 0,
+            decoder: FrameDecoder::new(),
+            cached: Vec::new(),
+            _memory: Reservation::default(),
+        })
+    }
+
+    /// Returns how much memory the decompressed frames kept around may take at most.
+    pub(crate) fn cache_len(&self) -> u64 {
+        let largest = self.frames.iter().map(|f| u64::from(f.len)).max();
+        largest.unwrap_or(0) * self.frames.len().min(CACHED_FRAMES) as u64
+    }
+
+    /// Holds `memory`, reserved for the decompressed frames, for as long as the stream is open.
+    pub(crate) fn holding(self, memory: Reservation) -> Self {
+        Self {
+            _memory: memory,
+            ..self
+        }
+    }
+
+    /// Returns the stream over the compressed image, positioned at its start.
+    pub(crate) fn into_inner(mut self) -> io::Result<Box<dyn Disk>> {
+        self.inner.seek(SeekFrom::Start(0))?;
+        Ok(self.inner)
+    }
+
+    /// Returns the decompressed contents of the frame with the given index.
+    fn frame(&mut self, index: usize) -> io::Result<&[u8]> {
+        let position = match self.cached.iter().position(|(i, _)| *i == index) {
+            Some(position) => {
+                let frame = self.cached.remove(position);
+                self.cached.push(frame);
+                self.cached.len() - 1
+            }
+            None => {
+                let frame = self.frames[index];
+                let mut compressed = vec![0u8; frame.compressed_len as usize];
+                self.inner.seek(SeekFrom::Start(frame.compressed_offset))?;
+                self.inner.read_exact(&mut compressed)?;
+                let mut data = vec![0u8; frame.len as usize];
+                let n = self
+                    .decoder
+                    .decode_all(&compressed, &mut data)
+                    .map_err(invalid_data)?;
+                if n != data.len() {
+                    return Err(invalid_data(
+                        "a zstd frame is shorter than its seek table says",
+                    ));
+                }
+                if self.cached.len() == CACHED_FRAMES {
+                    self.cached.remove(0);
+                }
+                self.cached.push((index, data));
+                self.cached.len() - 1
+            }
+        };
+        Ok(&self.cached[position].1)
+    }
+}
+
+impl Read for SeekableZstd {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.len || buf.is_empty() {
+            return Ok(0);
+        }
+        // The frame that holds the byte at the current position
+        let index = self
+            .frames
+            .partition_point(|f| f.offset + u64::from(f.len) <= self.pos);
+        let start = (self.pos - self.frames[index].offset) as usize;
+        let data = self.frame(index)?;
+
+        let n = (data.len() - start).min(buf.len());
+        buf[..n].copy_from_slice(&data[start..start + n]);
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for SeekableZstd {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.pos = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+        }
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "seek to a negative offset"))?;
+        Ok(self.pos)
+    }
+}